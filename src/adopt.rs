@@ -0,0 +1,159 @@
+use rusqlite::Connection;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::executed::Log;
+use crate::{Config, Layout, ResticMode, RunOptions, Source, versioned};
+
+
+// Number of mismatching paths to print before summarising the rest
+const MAX_REPORTED_MISMATCHES: usize = 20;
+
+
+// Where the copy of a snapshot is on the target: the target itself, or in the
+// versioned layout the version its manifest says holds the snapshot
+fn adopted_tree(source: Source, snapshot: &str) -> Result<PathBuf, String> {
+    let target_dir = source.target_dir();
+    if source.layout() != Layout::Versioned {
+        return Ok(target_dir.to_path_buf());
+    }
+    let version = versioned::version_of_snapshot(target_dir, snapshot)?.ok_or_else(|| {
+        format!("{} has no complete version of snapshot '{}' in its manifest", target_dir.display(), snapshot)
+    })?;
+    println!("Checking version {}", version);
+    Ok(target_dir.join(version))
+}
+
+
+// Check that a target seeded by other means (e.g. a disk copied at another site)
+// matches a snapshot, and if so record it as a backup of that snapshot so that
+// future runs carry on incrementally
+pub fn adopt_target(
+    config: &Config,
+    conn: &Connection,
+    options: &RunOptions,
+    source: &str,
+    snapshot: &str,
+    checksum: bool,
+) -> Result<(), String> {
+    if let Some(dataset_config) = config.dataset.iter().find(|d| d.name == source) {
+        println!("=== Adopting target for dataset: {} ===", dataset_config.name);
+
+        crate::check_target_directory(&dataset_config.target_dir)?;
+
+        // Accept either "pool/dataset@snap" or just "snap"
+        let snapshot_name = if snapshot.contains('@') {
+            snapshot.to_string()
+        } else {
+            format!("{}@{}", dataset_config.name, snapshot)
+        };
+
+        if !crate::snapshot_exists(&snapshot_name, Source::Dataset(dataset_config), &Log::default())? {
+            return Err(format!("Snapshot '{}' does not exist", snapshot_name));
+        }
+
+        let snapshot_mountpoint = crate::get_snapshot_mountpoint(&snapshot_name, &Log::default())?;
+        let source = Source::Dataset(dataset_config);
+        verify_target(&snapshot_mountpoint, &adopted_tree(source, &snapshot_name)?, checksum, source)?;
+
+        return record_adoption(
+            conn,
+            options,
+            source,
+            &snapshot_name,
+            &snapshot_mountpoint,
+        );
+    }
+
+    if let Some(restic_config) = config.restic.iter().find(|r| r.repository == source) {
+        println!("=== Adopting target for restic repository: {} ===", restic_config.repository);
+
+        if restic_config.mode == ResticMode::Restore {
+            return Err("adopt-target needs to FUSE-mount the repository, which isn't available in restore mode".to_string());
+        }
+
+        crate::check_target_directory(&restic_config.target_dir)?;
+
+        if !crate::snapshot_exists(snapshot, Source::Restic(restic_config), &Log::default())? {
+            return Err(format!("Snapshot '{}' does not exist in repository '{}'", snapshot, restic_config.repository));
+        }
+
+        let mount_point = crate::restic_mount_point(&restic_config.repository);
+        fs::create_dir_all(&mount_point)
+            .map_err(|e| format!("Failed to create mount point: {}", e))?;
+
+        let mount_guard = crate::mount_restic_repository(restic_config, &mount_point, &Log::default())?;
+        let snapshot_path = crate::restic_snapshot_path(&mount_guard, snapshot)?;
+        let source = Source::Restic(restic_config);
+        verify_target(&snapshot_path, &adopted_tree(source, snapshot)?, checksum, source)?;
+
+        return record_adoption(
+            conn,
+            options,
+            source,
+            snapshot,
+            &snapshot_path,
+        );
+    }
+
+    Err(format!("'{}' is not a dataset or restic repository in the config file", source))
+}
+
+
+pub fn verify_target(snapshot_path: &Path, target_dir: &Path, checksum: bool, source: Source) -> Result<(), String> {
+    println!(
+        "Verifying {} against {} ({})...",
+        target_dir.display(),
+        snapshot_path.display(),
+        if checksum { "checksum" } else { "size and modification time" }
+    );
+
+    let (differing, extra) = crate::get_diff_via_rsync(snapshot_path, target_dir, checksum, source.filter(), source.user(), &Log::default())?;
+
+    if differing.is_empty() && extra.is_empty() {
+        println!("Target matches snapshot");
+        return Ok(());
+    }
+
+    let mismatches: Vec<String> = differing
+        .iter()
+        .map(|path| format!("  differs: {}", path.display()))
+        .chain(extra.iter().map(|path| format!("  not in snapshot: {}", path.display())))
+        .collect();
+
+    for mismatch in mismatches.iter().take(MAX_REPORTED_MISMATCHES) {
+        println!("{}", mismatch);
+    }
+    if mismatches.len() > MAX_REPORTED_MISMATCHES {
+        println!("  ... and {} more", mismatches.len() - MAX_REPORTED_MISMATCHES);
+    }
+
+    Err(format!(
+        "Target does not match snapshot: {} path(s) differ, {} path(s) not in snapshot",
+        differing.len(),
+        extra.len()
+    ))
+}
+
+
+fn record_adoption(
+    conn: &Connection,
+    options: &RunOptions,
+    source: Source,
+    snapshot_name: &str,
+    snapshot_path: &Path,
+) -> Result<(), String> {
+    crate::record_full_file_state(conn, source, snapshot_name, snapshot_path);
+
+    crate::record_successful_backup(
+        conn,
+        &options.hostname,
+        source,
+        snapshot_name,
+        &Log::default(),
+        None,
+    )?;
+
+    println!("Target adopted - future runs will back up incrementally from {}", snapshot_name);
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use rusqlite::{Connection, params};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::Source;
+use crate::file_state::Changes;
+use crate::report;
+
+
+// [anomalies] section: a backup that deletes most of a target, or moves far
+// more data or takes far longer than usual, more often means a wrong source,
+// a broken mount or ransomware-encrypted files than real changes. Such
+// backups still go ahead; they are flagged in the run output and summary and
+// the run exits with a warning code. 0 turns a check off.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct AnomalyConfig {
+    #[serde(default = "default_max_deleted_percent")]
+    pub max_deleted_percent: f64,
+    // Multiples of the median over the last window_days
+    #[serde(default = "default_max_transfer_factor")]
+    pub max_transfer_factor: f64,
+    #[serde(default = "default_max_duration_factor")]
+    pub max_duration_factor: f64,
+    #[serde(default = "default_window_days")]
+    pub window_days: u32,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            max_deleted_percent: default_max_deleted_percent(),
+            max_transfer_factor: default_max_transfer_factor(),
+            max_duration_factor: default_max_duration_factor(),
+            window_days: default_window_days(),
+        }
+    }
+}
+
+fn default_max_deleted_percent() -> f64 {
+    50.0
+}
+
+fn default_max_transfer_factor() -> f64 {
+    10.0
+}
+
+fn default_max_duration_factor() -> f64 {
+    2.0
+}
+
+fn default_window_days() -> u32 {
+    30
+}
+
+
+// Below these, proportions say more about noise than about the source
+const MIN_FILES: u64 = 100;
+const MIN_TRANSFER_BYTES: u64 = 64 << 20;
+const MIN_DURATION_SECS: u64 = 60;
+// Earlier backups needed before a median means anything
+const MIN_HISTORY: usize = 3;
+
+
+pub fn create_table(conn: &Connection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS transfer_stats (
+            run_id INTEGER NOT NULL REFERENCES runs(id),
+            backup_type TEXT NOT NULL,
+            source_name TEXT NOT NULL,
+            full INTEGER NOT NULL,
+            files_before INTEGER NOT NULL,
+            files_synced INTEGER NOT NULL,
+            files_deleted INTEGER NOT NULL,
+            bytes_synced INTEGER NOT NULL,
+            duration_secs INTEGER NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+
+    Ok(())
+}
+
+
+fn median(mut values: Vec<u64>) -> Option<u64> {
+    if values.len() < MIN_HISTORY {
+        return None;
+    }
+    values.sort_unstable();
+    Some(values[values.len() / 2])
+}
+
+
+// Transfer sizes and durations of the source's earlier backups of the same
+// kind (full or incremental) within the window
+fn history(conn: &Connection, config: &AnomalyConfig, source: Source, full: bool) -> Result<(Vec<u64>, Vec<u64>), String> {
+    let mut stmt = conn.prepare(
+        "SELECT transfer_stats.bytes_synced, transfer_stats.duration_secs
+         FROM transfer_stats JOIN runs ON runs.id = transfer_stats.run_id
+         WHERE backup_type = ?1 AND source_name = ?2 AND full = ?3
+           AND runs.started_at > datetime('now', ?4)",
+    ).map_err(|e| format!("Failed to read transfer statistics: {}", e))?;
+
+    let rows = stmt
+        .query_map(
+            params![source.backup_type(), source.name(), full, format!("-{} days", config.window_days)],
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+        )
+        .map_err(|e| format!("Failed to read transfer statistics: {}", e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to read transfer statistics: {}", e))?;
+    Ok(rows.into_iter().unzip())
+}
+
+
+fn find_anomalies(config: &AnomalyConfig, changes: &Changes, duration_secs: u64, bytes_history: Vec<u64>, duration_history: Vec<u64>) -> Vec<String> {
+    let mut anomalies = Vec::new();
+
+    let deleted_percent = changes.files_deleted as f64 * 100.0 / changes.files_before.max(1) as f64;
+    if config.max_deleted_percent > 0.0
+        && changes.files_before >= MIN_FILES
+        && deleted_percent >= config.max_deleted_percent
+    {
+        anomalies.push(format!(
+            "this backup deleted {:.0}% of the files on the target ({} of {})",
+            deleted_percent, changes.files_deleted, changes.files_before
+        ));
+    }
+
+    if config.max_transfer_factor > 0.0
+        && changes.bytes_synced >= MIN_TRANSFER_BYTES
+        && let Some(median) = median(bytes_history)
+        && changes.bytes_synced as f64 > median.max(1) as f64 * config.max_transfer_factor
+    {
+        anomalies.push(format!(
+            "transfer size {} is {:.0}x the {}-day median of {}",
+            report::format_bytes(changes.bytes_synced),
+            changes.bytes_synced as f64 / median.max(1) as f64,
+            config.window_days,
+            report::format_bytes(median)
+        ));
+    }
+
+    if config.max_duration_factor > 0.0
+        && duration_secs >= MIN_DURATION_SECS
+        && let Some(median) = median(duration_history)
+        && duration_secs as f64 > median.max(1) as f64 * config.max_duration_factor
+    {
+        anomalies.push(format!(
+            "took {}, {:.1}x the {}-day median of {}",
+            report::format_age(duration_secs as i64),
+            duration_secs as f64 / median.max(1) as f64,
+            config.window_days,
+            report::format_age(median as i64)
+        ));
+    }
+
+    anomalies
+}
+
+
+// Compare the source's backup in this run, as the file state update counted
+// it, with its recent history, then add it to the history. Sources that had
+// nothing to copy have nothing to compare.
+pub fn check(
+    conn: &Connection,
+    run_id: Option<i64>,
+    config: &AnomalyConfig,
+    source: Source,
+    changes: Option<Changes>,
+    duration_secs: u64,
+) -> Vec<String> {
+    let Some(changes) = changes else {
+        return Vec::new();
+    };
+
+    let anomalies = match history(conn, config, source, changes.full) {
+        Ok((bytes_history, duration_history)) => {
+            find_anomalies(config, &changes, duration_secs, bytes_history, duration_history)
+        }
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            Vec::new()
+        }
+    };
+
+    if let Some(run_id) = run_id
+        && let Err(e) = conn.execute(
+            "INSERT INTO transfer_stats (run_id, backup_type, source_name, full, files_before, files_synced, files_deleted, bytes_synced, duration_secs, bytes_allocated)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                run_id,
+                source.backup_type(),
+                source.name(),
+                changes.full,
+                changes.files_before as i64,
+                changes.files_synced as i64,
+                changes.files_deleted as i64,
+                changes.bytes_synced as i64,
+                duration_secs as i64,
+                changes.bytes_allocated as i64,
+            ],
+        )
+    {
+        eprintln!("Warning: Failed to record transfer statistics: {}", e);
+    }
+
+    anomalies
+}
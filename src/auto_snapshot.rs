@@ -0,0 +1,209 @@
+use rusqlite::Connection;
+use serde::Deserialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::executed::{Log, Record};
+use crate::{DatasetConfig, RunOptions, Source, child_env, clock, zfs_allow};
+
+
+// Name of the snapshots auto_snapshot takes, after the @, e.g.
+//
+//   snapshot_template = "filebackup-%Y%m%d-%H%M"
+//
+// %Y, %m, %d, %H, %M and %S are the UTC year, month, day, hour, minute and
+// second, %s the seconds since 1970 and %% a percent sign. The time has to
+// be there to the minute at least, so every run's snapshot is named apart,
+// and keep_auto_snapshots only ever destroys snapshots the template fits,
+// leaving those of other tools alone.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct SnapshotTemplate {
+    parts: Vec<Part>,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Part {
+    Literal(char),
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Epoch,
+}
+
+
+// What snapshots were named before snapshot_template, e.g.
+// "file-backup-20240501-023000"
+const DEFAULT_TEMPLATE: &str = "file-backup-%Y%m%d-%H%M%S";
+
+impl Default for SnapshotTemplate {
+    fn default() -> Self {
+        SnapshotTemplate::try_from(DEFAULT_TEMPLATE.to_string()).expect("the default snapshot template is valid")
+    }
+}
+
+impl TryFrom<String> for SnapshotTemplate {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let mut parts = Vec::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            let part = match c {
+                '%' => match chars.next() {
+                    Some('Y') => Part::Year,
+                    Some('m') => Part::Month,
+                    Some('d') => Part::Day,
+                    Some('H') => Part::Hour,
+                    Some('M') => Part::Minute,
+                    Some('S') => Part::Second,
+                    Some('s') => Part::Epoch,
+                    Some('%') => Part::Literal('%'),
+                    Some(other) => return Err(format!("snapshot_template '{}': %{} isn't one of %Y %m %d %H %M %S %s %%", text, other)),
+                    None => return Err(format!("snapshot_template '{}' ends in a lone %", text)),
+                },
+                // What zfs allows in a snapshot name
+                c if c.is_ascii_alphanumeric() || "-_.:".contains(c) => Part::Literal(c),
+                c => return Err(format!("snapshot_template '{}': '{}' can't be in a snapshot name", text, c)),
+            };
+            parts.push(part);
+        }
+
+        let has = |part: Part| parts.contains(&part);
+        let to_the_minute = [Part::Year, Part::Month, Part::Day, Part::Hour, Part::Minute].into_iter().all(has);
+        if !to_the_minute && !has(Part::Epoch) {
+            return Err(format!(
+                "snapshot_template '{}' would give two runs the same name: it needs %Y, %m, %d, %H and %M, or %s",
+                text
+            ));
+        }
+        Ok(SnapshotTemplate { parts })
+    }
+}
+
+
+impl SnapshotTemplate {
+    pub fn format(&self, time: SystemTime) -> String {
+        let (year, month, day, hour, minute, second) = clock::split_utc(time);
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(c) => c.to_string(),
+                Part::Year => format!("{:04}", year),
+                Part::Month => format!("{:02}", month),
+                Part::Day => format!("{:02}", day),
+                Part::Hour => format!("{:02}", hour),
+                Part::Minute => format!("{:02}", minute),
+                Part::Second => format!("{:02}", second),
+                Part::Epoch => time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()).to_string(),
+            })
+            .collect()
+    }
+
+    // When a snapshot named by the template was taken, or None if the name
+    // doesn't fit the template
+    pub fn parse(&self, name: &str) -> Option<i64> {
+        let (mut year, mut month, mut day, mut hour, mut minute, mut second) = (1970, 1, 1, 0, 0, 0);
+        let mut epoch = None;
+        let mut rest = name;
+        for part in &self.parts {
+            let width = match part {
+                Part::Literal(c) => {
+                    rest = rest.strip_prefix(*c)?;
+                    continue;
+                }
+                Part::Year => 4,
+                Part::Epoch => rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len()),
+                _ => 2,
+            };
+            let digits = rest.get(..width).filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))?;
+            rest = &rest[width..];
+            let value: i64 = digits.parse().ok()?;
+            match part {
+                Part::Year => year = value,
+                Part::Month => month = value,
+                Part::Day => day = value,
+                Part::Hour => hour = value,
+                Part::Minute => minute = value,
+                Part::Second => second = value,
+                _ => epoch = Some(value),
+            }
+        }
+        if !rest.is_empty() || !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        Some(epoch.unwrap_or_else(|| {
+            clock::days_from_civil(year, month as u32, day as u32) * 86_400 + hour * 3600 + minute * 60 + second
+        }))
+    }
+}
+
+
+// Snapshots of the dataset, oldest first
+fn list_snapshots(dataset: &str, commands: &Log) -> Result<Vec<String>, String> {
+    let output = child_env::command("zfs")
+        .args(["list", "-Hp", "-t", "snapshot", "-o", "name", "-s", "creation", dataset])
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute zfs command: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("zfs command failed: {}", stderr.trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect())
+}
+
+
+// After a successful backup of a dataset with keep_auto_snapshots, destroy
+// the oldest of the snapshots auto_snapshot took until that many are left.
+// Snapshots the template doesn't fit belong to someone else and are never
+// touched, and neither is the one just backed up, which the next backup
+// diffs from.
+pub fn prune(conn: &Connection, options: &RunOptions, dataset_config: &DatasetConfig, commands: &Log) {
+    let Some(keep) = dataset_config.keep_auto_snapshots else {
+        return;
+    };
+    let template = dataset_config.snapshot_template.clone().unwrap_or_default();
+    let snapshots = match list_snapshots(&dataset_config.name, commands) {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            eprintln!("Warning: Couldn't list the snapshots of '{}' to prune them: {}", dataset_config.name, e);
+            return;
+        }
+    };
+    let backed_up = crate::get_last_backed_up_snapshot(conn, options.host_filter(), Source::Dataset(dataset_config), commands)
+        .ok()
+        .flatten();
+
+    let mut ours: Vec<(i64, &String)> = snapshots
+        .iter()
+        .filter_map(|snapshot| {
+            let (_, name) = snapshot.split_once('@')?;
+            Some((template.parse(name)?, snapshot))
+        })
+        .collect();
+    ours.sort();
+    let excess = ours.len().saturating_sub(keep);
+
+    for (_, snapshot) in ours.into_iter().take(excess) {
+        if backed_up.as_ref() == Some(snapshot) {
+            continue;
+        }
+        match child_env::command("zfs").args(["destroy", snapshot]).recorded_output(commands) {
+            Ok(output) if output.status.success() => {
+                println!("Destroyed snapshot {}, beyond keep_auto_snapshots = {}", snapshot, keep);
+            }
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                eprintln!("Warning: {}", zfs_allow::failure("destroy", "destroy,mount", snapshot, &stderr));
+            }
+            Err(e) => eprintln!("Warning: Failed to execute zfs destroy: {}", e),
+        }
+    }
+}
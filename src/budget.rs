@@ -0,0 +1,112 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::executed::Log;
+use crate::units::ByteSize;
+use crate::{Config, Source, estimate, report};
+
+
+// [targets."/mnt/backup"]: settings for a target disk, shared by every source
+// whose target_dir is under that path
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct TargetConfig {
+    // Space the sources on the disk may take altogether, going by what the
+    // latest snapshot of each takes once copied
+    pub target_budget: Option<ByteSize>,
+    // Leave out the sources of lowest priority that would take the disk over
+    // its budget, so the more important ones still fit
+    #[serde(default)]
+    pub skip_over_budget: bool,
+}
+
+
+// What copying the source's latest snapshot takes
+fn projected_size(source: Source) -> Result<u64, String> {
+    let commands = Log::default();
+    let snapshot = match source {
+        Source::Dataset(dataset_config) => crate::get_latest_snapshot(&dataset_config.name, &commands)?,
+        Source::Restic(restic_config) => crate::get_latest_restic_snapshot(restic_config, &commands)?,
+    };
+    match snapshot {
+        Some(snapshot) => estimate::estimate_size(source, &snapshot, &commands),
+        None => Ok(0),
+    }
+}
+
+
+// Check the targets with a budget against what their sources will take, and
+// return the sources of the run that are to go ahead. Every enabled source on
+// a disk counts towards its budget, whether or not it is in this run.
+pub fn apply<'a>(config: &'a Config, sources: Vec<Source<'a>>) -> Vec<Source<'a>> {
+    let mut skipped: Vec<&str> = Vec::new();
+    for (path, target) in &config.targets {
+        let Some(ByteSize(budget)) = target.target_budget else {
+            continue;
+        };
+        let mut on_target: Vec<Source> = config
+            .sources()
+            .filter(|source| source.enabled() && source.target_dir().starts_with(path))
+            .collect();
+        if on_target.is_empty() {
+            continue;
+        }
+        // Highest priority first, keeping config order among equals
+        on_target.sort_by_key(|source| -source.priority());
+
+        let mut sizes = Vec::new();
+        for source in &on_target {
+            match projected_size(*source) {
+                Ok(size) => sizes.push(size),
+                Err(e) => {
+                    eprintln!("Warning: Can't project the size of {} '{}' for target_budget: {}", source.kind(), source.name(), e);
+                    sizes.push(0);
+                }
+            }
+        }
+        let total: u64 = sizes.iter().sum();
+        if total <= budget {
+            continue;
+        }
+        eprintln!(
+            "Warning: The sources on {} will take {}, over its target_budget of {}",
+            path.display(),
+            report::format_bytes(total),
+            report::format_bytes(budget)
+        );
+        if !target.skip_over_budget {
+            continue;
+        }
+
+        let mut used = 0;
+        for (source, size) in on_target.iter().zip(sizes) {
+            if used + size <= budget {
+                used += size;
+            } else if sources.iter().any(|s| s.name() == source.name()) {
+                println!(
+                    "Skipping {} '{}': its {} would take {} over target_budget",
+                    source.kind(),
+                    source.name(),
+                    report::format_bytes(size),
+                    path.display()
+                );
+                skipped.push(source.name());
+            }
+        }
+    }
+    sources.into_iter().filter(|source| !skipped.contains(&source.name())).collect()
+}
+
+
+pub fn validate(targets: &BTreeMap<PathBuf, TargetConfig>) -> Result<(), String> {
+    for (path, target) in targets {
+        if !path.is_absolute() {
+            return Err(format!("[targets] '{}' must be an absolute path", path.display()));
+        }
+        if target.skip_over_budget && target.target_budget.is_none() {
+            return Err(format!("[targets.\"{}\"] skip_over_budget needs target_budget", path.display()));
+        }
+    }
+    Ok(())
+}
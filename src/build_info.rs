@@ -0,0 +1,73 @@
+use serde_json::json;
+
+use crate::SCHEMA_VERSION;
+use crate::tools::{self, Version};
+
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+
+pub fn version() -> Version {
+    Version::parse_from_output(VERSION).expect("package version is a dotted number")
+}
+
+
+// An older file-backup ignores config keys it doesn't know rather than
+// failing, so a config relying on newer ones says which version it needs with
+// min_tool_version. This is checked on the bare TOML, before keys this
+// version might not be able to parse are looked at.
+pub fn check_config(contents: &str) -> Result<(), String> {
+    let Ok(table) = contents.parse::<toml::Table>() else {
+        // The full parse reports what is wrong
+        return Ok(());
+    };
+    let Some(value) = table.get("min_tool_version") else {
+        return Ok(());
+    };
+
+    let required = value
+        .as_str()
+        .and_then(Version::parse_from_output)
+        .ok_or_else(|| format!("min_tool_version must be a version string such as \"0.2.0\", not {}", value))?;
+    if required > version() {
+        return Err(format!(
+            "This config needs file-backup {} or later, but this is {}. Please upgrade file-backup.",
+            required, VERSION
+        ));
+    }
+    Ok(())
+}
+
+
+// Appended to errors about state written by a newer file-backup
+pub fn written_by(version: Option<&str>) -> String {
+    match version {
+        Some(version) => format!(" (written by file-backup {})", version),
+        None => String::new(),
+    }
+}
+
+
+// --version; --json adds what automation deploying the tool checks before
+// relying on new config keys or state
+pub fn print(as_json: bool) {
+    if !as_json {
+        println!("file-backup {}", VERSION);
+        return;
+    }
+
+    let min_tool_versions: serde_json::Map<String, serde_json::Value> = tools::minimum_versions()
+        .into_iter()
+        .map(|(name, version)| (name.to_string(), json!(version.to_string())))
+        .collect();
+    let info = json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": VERSION,
+        "schema_version": SCHEMA_VERSION,
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "debug_build": cfg!(debug_assertions),
+        "min_tool_versions": min_tool_versions,
+    });
+    println!("{}", serde_json::to_string_pretty(&info).unwrap_or_default());
+}
@@ -0,0 +1,350 @@
+use rusqlite::{Connection, OptionalExtension};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::executed::Log;
+use crate::file_state::Changes;
+use crate::rsync_exit::{RsyncConfig, SourceExits};
+
+
+// [rsync] record_changes: keep what each backup changed on its target, as
+// rsync itemized it (e.g. ">f.st...... home/alice/notes.txt") plus the paths
+// an incremental backup deleted, in the database. Searches, audits and
+// restore previews can then go by it long after the snapshots it was
+// diffed from are gone. Only backups copied with rsync have such a list.
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordChanges {
+    // A changed_files row per path, which SQL can query directly
+    Table,
+    // One prefix-compressed list per backup in changed_file_lists, far
+    // smaller for the millions of paths of a first backup
+    Blob,
+}
+
+
+// The flags deletions are recorded with, as rsync itemizes its own
+const DELETED_FLAGS: &str = "*deleting";
+
+
+// The flags rsync itemized a path with, and the path
+type Change = (String, Vec<u8>);
+
+
+// What the backup of one source changed, gathered from its rsync runs until
+// the backup is recorded, how those runs ended, what the file state update
+// counted and the commands the backup ran. Paths are kept as the bytes rsync
+// copied, which needn't be UTF-8.
+pub struct ChangedFiles {
+    mode: Option<RecordChanges>,
+    changes: Vec<Change>,
+    pub exits: SourceExits,
+    pub file_state: Option<Changes>,
+    pub commands: Log,
+}
+
+impl ChangedFiles {
+    pub fn new(config: &RsyncConfig) -> ChangedFiles {
+        ChangedFiles {
+            mode: config.record_changes,
+            changes: Vec::new(),
+            exits: SourceExits::new(config),
+            file_state: None,
+            commands: Log::default(),
+        }
+    }
+
+    // Like --itemize-changes, but without the " -> target" rsync appends to
+    // symlinks, so every line is the flags and the path alone. -8 leaves
+    // bytes above 127 as they are rather than escaping them.
+    pub fn rsync_args(&self) -> &'static [&'static str] {
+        if self.mode.is_some() { &["-8", "--out-format=%i %n"] } else { &[] }
+    }
+
+    // Pick the itemized lines out of the output of an rsync run with
+    // rsync_args(): eleven characters of flags, a space and the path
+    pub fn collect(&mut self, stdout: &[u8]) {
+        if self.mode.is_none() {
+            return;
+        }
+        let changes = stdout.split(|&b| b == b'\n').filter_map(|line| {
+            let (flags, path) = (std::str::from_utf8(line.get(..11)?).ok()?, line.get(12..)?);
+            let itemized = line[11] == b' ' && flags.starts_with(['<', '>', 'c', 'h', '.', '*']) && !path.is_empty();
+            itemized.then(|| (flags.trim_end().to_string(), unescape_rsync_path(path)))
+        });
+        self.changes.extend(changes);
+    }
+
+    // Paths deleted from the target other than by an rsync --delete
+    pub fn deleted(&mut self, files: &[PathBuf]) {
+        if self.mode.is_none() {
+            return;
+        }
+        let changes = files.iter().map(|file| {
+            let path = file.strip_prefix("/").unwrap_or(file);
+            (DELETED_FLAGS.to_string(), path.as_os_str().as_bytes().to_vec())
+        });
+        self.changes.extend(changes);
+    }
+
+    // Keep the changes of the source's backup just recorded as `backup_id`
+    // in backup_history
+    pub fn record(&mut self, conn: &Connection, backup_id: i64) -> Result<(), String> {
+        let Some(mode) = self.mode else {
+            return Ok(());
+        };
+        let mut changes = std::mem::take(&mut self.changes);
+
+        let tx = conn.unchecked_transaction()
+            .map_err(|e| format!("Failed to start transaction: {}", e))?;
+        match mode {
+            RecordChanges::Table => {
+                let mut stmt = tx.prepare("INSERT INTO changed_files (backup_id, flags, path) VALUES (?1, ?2, ?3)")
+                    .map_err(|e| format!("Failed to record changed files: {}", e))?;
+                for (flags, path) in &changes {
+                    stmt.execute(rusqlite::params![backup_id, flags, path])
+                        .map_err(|e| format!("Failed to record changed files: {}", e))?;
+                }
+            }
+            RecordChanges::Blob => {
+                let list = encode(&mut changes);
+                tx.execute(
+                    "INSERT INTO changed_file_lists (backup_id, entries, list) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![backup_id, changes.len() as i64, list],
+                ).map_err(|e| format!("Failed to record changed files: {}", e))?;
+            }
+        }
+        tx.commit().map_err(|e| format!("Failed to commit changed files: {}", e))?;
+
+        println!("Recorded {} changed path(s) in the database", changes.len());
+        Ok(())
+    }
+}
+
+
+// rsync writes the control characters in a name, and a backslash that would
+// look like such an escape, as a backslash, '#' and three octal digits, e.g.
+// "\#012" for a newline
+fn unescape_rsync_path(field: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let escape = field.get(i + 2..i + 5)
+            .filter(|digits| field[i..].starts_with(b"\\#") && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match escape {
+            Some(digits) => {
+                let value = digits.iter().fold(0u32, |value, d| value * 8 + u32::from(d - b'0'));
+                bytes.push(value as u8);
+                i += 5;
+            }
+            None => {
+                bytes.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    bytes
+}
+
+
+// backup_id is the id of the backup's row in backup_history, which isn't
+// declared a foreign key: the tables are created before migrate_database,
+// and schema version 1 rebuilds backup_history under a temporary name
+pub fn create_table(conn: &Connection) -> Result<(), String> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS changed_files (
+            backup_id INTEGER NOT NULL,
+            flags TEXT NOT NULL,
+            path BLOB NOT NULL
+         );
+         CREATE INDEX IF NOT EXISTS idx_changed_files_backup ON changed_files(backup_id);
+         CREATE TABLE IF NOT EXISTS changed_file_lists (
+            backup_id INTEGER PRIMARY KEY,
+            entries INTEGER NOT NULL,
+            list BLOB NOT NULL
+         );"
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+
+    Ok(())
+}
+
+
+// Sorted by path, each "<flags>\t<length>\t<rest>\0", with the path given
+// as how many bytes it shares with the one before and the rest of it.
+// Entries end in a NUL, which no path can hold.
+fn encode(changes: &mut [Change]) -> Vec<u8> {
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+    let mut list = Vec::new();
+    let mut previous: &[u8] = &[];
+    for (flags, path) in changes.iter() {
+        let shared = previous.iter().zip(path).take_while(|(a, b)| a == b).count();
+        list.extend_from_slice(format!("{}\t{}\t", flags, shared).as_bytes());
+        list.extend_from_slice(&path[shared..]);
+        list.push(0);
+        previous = path;
+    }
+    list
+}
+
+
+// Lists recorded before paths were kept as bytes end each entry in a newline
+// instead, and end in one themselves
+fn decode(list: &[u8]) -> Result<Vec<Change>, String> {
+    let terminator = if list.ends_with(b"\n") { b'\n' } else { 0 };
+    let mut changes = Vec::new();
+    let mut previous = Vec::new();
+    for entry in list.split(|&b| b == terminator).filter(|entry| !entry.is_empty()) {
+        let invalid = || format!("Invalid entry in changed file list: {}", String::from_utf8_lossy(entry));
+        let mut fields = entry.splitn(3, |&b| b == b'\t');
+        let (Some(flags), Some(shared), Some(rest)) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid());
+        };
+        let flags = std::str::from_utf8(flags).map_err(|_| invalid())?;
+        let shared: usize = std::str::from_utf8(shared).ok().and_then(|shared| shared.parse().ok()).ok_or_else(invalid)?;
+        let mut path = previous.get(..shared).unwrap_or(&previous).to_vec();
+        path.extend_from_slice(rest);
+        changes.push((flags.to_string(), path.clone()));
+        previous = path;
+    }
+    Ok(changes)
+}
+
+
+// The changes recorded for a backup, from whichever of the two it was kept
+// in, or None if neither has them. Paths are BLOBs, or TEXT in rows from
+// before they were kept as bytes.
+fn recorded(conn: &Connection, backup_id: i64) -> Result<Option<Vec<Change>>, String> {
+    let list: Option<Vec<u8>> = conn
+        .query_row("SELECT list FROM changed_file_lists WHERE backup_id = ?1", [backup_id], |row| row.get(0))
+        .optional()
+        .map_err(|e| format!("Failed to read changed files: {}", e))?;
+    if let Some(list) = list {
+        return decode(&list).map(Some);
+    }
+
+    let mut stmt = conn.prepare("SELECT flags, path FROM changed_files WHERE backup_id = ?1 ORDER BY path")
+        .map_err(|e| format!("Failed to read changed files: {}", e))?;
+    let changes: Vec<Change> = stmt
+        .query_map([backup_id], |row| {
+            let path = row.get_ref(1)?.as_bytes().map_err(rusqlite::Error::from)?.to_vec();
+            Ok((row.get(0)?, path))
+        })
+        .and_then(|rows| rows.collect())
+        .map_err(|e| format!("Failed to read changed files: {}", e))?;
+    Ok((!changes.is_empty()).then_some(changes))
+}
+
+
+// The changes command: what the newest backup of a source changed, or the
+// backup of `snapshot`
+pub fn print(conn: &Connection, hostname: Option<&str>, source_name: &str, snapshot: Option<&str>) -> Result<(), String> {
+    let backup: Option<(i64, String, String)> = conn
+        .query_row(
+            "SELECT id, snapshot_name, backup_timestamp FROM backup_history
+             WHERE source_name = ?1 AND (?2 IS NULL OR hostname = ?2) AND (?3 IS NULL OR snapshot_name = ?3)
+             ORDER BY backed_up_epoch DESC, id DESC LIMIT 1",
+            rusqlite::params![source_name, hostname, snapshot],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .map_err(|e| format!("Failed to query backup history: {}", e))?;
+    let Some((backup_id, snapshot, backed_up_at)) = backup else {
+        return Err(match snapshot {
+            Some(snapshot) => format!("No backup of '{}' from snapshot {}", source_name, snapshot),
+            None => format!("No backups of '{}'", source_name),
+        });
+    };
+
+    let Some(changes) = recorded(conn, backup_id)? else {
+        println!("No changes recorded for the backup of {} at {}; [rsync] record_changes keeps them", snapshot, backed_up_at);
+        return Ok(());
+    };
+    println!("Backup of {} at {}:", snapshot, backed_up_at);
+    for (flags, path) in &changes {
+        println!("{:<11} {}", flags, Path::new(OsStr::from_bytes(path)).display());
+    }
+    println!("{} changed path(s)", changes.len());
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // rsync -av --stats -8 --out-format='%i %n' as run_rsync gets it, in the
+    // C locale, of names that aren't all ASCII or even UTF-8
+    const RSYNC_OUTPUT: &[u8] = b"\
+sending incremental file list
+.d..t...... ./
+>f+++++++++ B\xc3\xbccher/\xc3\x9cbersicht.txt
+>f+++++++++ caf\xe9 latin-1.txt
+>f+++++++++ zwei\\#012zeilen
+>f+++++++++ wirklich\\#134#012
+cL+++++++++ aktuell
+cd+++++++++ neuer Ordner/
+*deleting   alt.txt
+
+Number of files: 1,204 (reg: 1,100, dir: 103, link: 1)
+Total file size: 1,234,567 bytes
+Total transferred file size: 56,789 bytes
+
+sent 60,123 bytes  received 1,234 bytes  122,714.00 bytes/sec
+total size is 1,234,567  speedup is 20.12
+";
+
+    #[test]
+    fn itemized_lines_are_picked_out() {
+        let config = RsyncConfig { record_changes: Some(RecordChanges::Table), ..RsyncConfig::default() };
+        let mut changed_files = ChangedFiles::new(&config);
+        changed_files.collect(RSYNC_OUTPUT);
+        let changes: Vec<(&str, &[u8])> =
+            changed_files.changes.iter().map(|(flags, path)| (flags.as_str(), path.as_slice())).collect();
+        assert_eq!(
+            changes,
+            [
+                (".d..t......", &b"./"[..]),
+                (">f+++++++++", "Bücher/Übersicht.txt".as_bytes()),
+                (">f+++++++++", b"caf\xe9 latin-1.txt"),
+                (">f+++++++++", b"zwei\nzeilen"),
+                (">f+++++++++", b"wirklich\\#012"),
+                ("cL+++++++++", b"aktuell"),
+                ("cd+++++++++", b"neuer Ordner/"),
+                ("*deleting", b"alt.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_is_collected_unless_recording() {
+        let mut changed_files = ChangedFiles::new(&RsyncConfig::default());
+        changed_files.collect(RSYNC_OUTPUT);
+        assert!(changed_files.changes.is_empty());
+    }
+
+    #[test]
+    fn lists_keep_paths_byte_for_byte() {
+        let mut changes = vec![
+            (">f+++++++++".to_string(), b"dir/caf\xe9".to_vec()),
+            (">f+++++++++".to_string(), b"dir/two\nlines".to_vec()),
+            ("*deleting".to_string(), b"dir/caf\xe9 2".to_vec()),
+        ];
+        let list = encode(&mut changes);
+        assert_eq!(decode(&list).unwrap(), changes);
+    }
+
+    #[test]
+    fn lists_from_before_bytes_still_decode() {
+        let list = b">f+++++++++\t0\thome/notes.txt\n>f+++++++++\t5\ttodo.txt\n";
+        assert_eq!(
+            decode(list).unwrap(),
+            [
+                (">f+++++++++".to_string(), b"home/notes.txt".to_vec()),
+                (">f+++++++++".to_string(), b"home/todo.txt".to_vec()),
+            ]
+        );
+    }
+}
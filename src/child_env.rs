@@ -0,0 +1,115 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::env;
+use std::process::Command;
+use std::sync::Mutex;
+
+use crate::restic_cache;
+
+
+// [environment] section: the tools file-backup runs get an environment built
+// from scratch rather than whatever cron or the shell left behind. The locale
+// is pinned to C since zfs and rsync output is parsed, and a translated or
+// differently formatted one breaks that. Credentials only go to restic.
+// Shell commands from the config (key_command and the like) still inherit
+// everything, as they may need an agent or a desktop session.
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct EnvironmentConfig {
+    #[serde(default = "default_path")]
+    pub path: String,
+    // Further variables passed to every tool, e.g. ["TZ", "http_proxy"]
+    #[serde(default)]
+    pub pass: Vec<String>,
+    // Variables passed to restic alone; a trailing * matches a prefix
+    #[serde(default = "default_restic_pass")]
+    pub restic_pass: Vec<String>,
+}
+
+impl Default for EnvironmentConfig {
+    fn default() -> Self {
+        EnvironmentConfig {
+            path: default_path(),
+            pass: Vec::new(),
+            restic_pass: default_restic_pass(),
+        }
+    }
+}
+
+fn default_path() -> String {
+    "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_string()
+}
+
+fn default_restic_pass() -> Vec<String> {
+    ["RESTIC_*", "AWS_*", "B2_*", "AZURE_*", "GOOGLE_*", "OS_*", "ST_*", "RCLONE_*"]
+        .map(String::from)
+        .to_vec()
+}
+
+
+// Always passed through, when set
+const BASE_VARIABLES: &[&str] = &["HOME", "USER", "LOGNAME", "TMPDIR"];
+
+
+static CONFIG: Mutex<Option<EnvironmentConfig>> = Mutex::new(None);
+
+
+pub fn configure(config: &EnvironmentConfig) {
+    *CONFIG.lock().unwrap_or_else(|e| e.into_inner()) = Some(config.clone());
+}
+
+
+// Where the tools are looked for
+pub fn path() -> String {
+    CONFIG.lock().unwrap_or_else(|e| e.into_inner()).clone().unwrap_or_default().path
+}
+
+
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+
+// A command for one of the tools file-backup drives, with its environment
+// set up as above
+pub fn command(program: &str) -> Command {
+    let config = CONFIG.lock().unwrap_or_else(|e| e.into_inner()).clone().unwrap_or_default();
+
+    let mut command = Command::new(program);
+    command.env_clear().env("PATH", &config.path).env("LC_ALL", "C").env("LANG", "C");
+    let restic = program == "restic";
+    for (name, value) in env::vars_os() {
+        let Some(name_str) = name.to_str() else {
+            continue;
+        };
+        let passed = BASE_VARIABLES.contains(&name_str)
+            || config.pass.iter().any(|pattern| matches(pattern, name_str))
+            || (restic && config.restic_pass.iter().any(|pattern| matches(pattern, name_str)));
+        if passed {
+            command.env(&name, value);
+        }
+    }
+    if restic {
+        restic_cache::apply(&mut command);
+    }
+    command
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    // Whatever locale file-backup itself was started under
+    #[test]
+    fn locale_is_pinned() {
+        let command = command("rsync");
+        let envs: Vec<(&OsStr, Option<&OsStr>)> = command.get_envs().collect();
+        assert!(envs.contains(&(OsStr::new("LC_ALL"), Some(OsStr::new("C")))));
+        assert!(envs.contains(&(OsStr::new("LANG"), Some(OsStr::new("C")))));
+        assert!(!envs.iter().any(|(name, _)| *name == "LANGUAGE" || *name == "LC_MESSAGES"));
+    }
+}
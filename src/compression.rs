@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use crate::child_env;
+use crate::executed::{Log, Record};
+use crate::images::ImageFormat;
+
+
+// compression = "zstd", "zstd:9", "lz4" or "none" for the objects of an
+// encrypted target and the files of the stream and image layouts. Objects and
+// streams are piped through the compressor, zstd on every core; encrypted
+// objects before age sees them, since encrypted data doesn't compress. Images
+// use it as their own compression, so they can still be mounted. Each manifest
+// or index records what its files were written with, which is what restore,
+// verify and the rescue kit go by rather than the config.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Compression {
+    None,
+    // With the level, 1 to 19, or zstd's own default of 3
+    Zstd(Option<u32>),
+    Lz4,
+}
+
+impl TryFrom<String> for Compression {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        Compression::parse(&text)
+    }
+}
+
+impl From<Compression> for String {
+    fn from(compression: Compression) -> String {
+        compression.to_string()
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd(None) => write!(f, "zstd"),
+            Compression::Zstd(Some(level)) => write!(f, "zstd:{}", level),
+            Compression::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl Compression {
+    pub fn parse(text: &str) -> Result<Compression, String> {
+        let invalid = || format!("invalid compression '{}', expected \"zstd\", \"zstd:9\", \"lz4\" or \"none\"", text);
+        match text.trim().split_once(':') {
+            None => match text.trim() {
+                "none" => Ok(Compression::None),
+                "zstd" => Ok(Compression::Zstd(None)),
+                "lz4" => Ok(Compression::Lz4),
+                _ => Err(invalid()),
+            },
+            Some(("zstd", level)) => match level.trim().parse() {
+                Ok(level @ 1..=19) => Ok(Compression::Zstd(Some(level))),
+                _ => Err(invalid()),
+            },
+            Some(_) => Err(invalid()),
+        }
+    }
+
+    // Added to the names of stream files written with it
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Zstd(_) => ".zst",
+            Compression::Lz4 => ".lz4",
+        }
+    }
+
+    pub fn tool(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd(_) => Some("zstd"),
+            Compression::Lz4 => Some("lz4"),
+        }
+    }
+
+    // Compresses stdin to stdout
+    pub fn compress_command(self) -> Option<Command> {
+        let mut command = child_env::command(self.tool()?);
+        match self {
+            Compression::Zstd(level) => {
+                command.args(["-q", "-c", "-T0"]);
+                if let Some(level) = level {
+                    command.arg(format!("-{}", level));
+                }
+            }
+            _ => {
+                command.args(["-q", "-c"]);
+            }
+        }
+        Some(command)
+    }
+
+    // Decompresses stdin to stdout
+    pub fn decompress_command(self) -> Option<Command> {
+        let mut command = child_env::command(self.tool()?);
+        command.args(["-q", "-d", "-c"]);
+        Some(command)
+    }
+
+    // Read a compressed file through, checking its checksums, without
+    // writing it anywhere. Uncompressed files have nothing to check here.
+    pub fn test(self, path: &Path, commands: &Log) -> Result<bool, String> {
+        let Some(tool) = self.tool() else {
+            return Ok(true);
+        };
+        let output = child_env::command(tool)
+            .args(["-q", "-t"])
+            .arg(path)
+            .recorded_output(commands)
+            .map_err(|e| format!("Failed to execute {}: {}", tool, e))?;
+        Ok(output.status.success())
+    }
+
+    // The image tool's own arguments for it
+    pub fn image_args(self, format: ImageFormat) -> Vec<String> {
+        let args: &[&str] = match (format, self) {
+            (ImageFormat::Squashfs, Compression::None) => &["-noI", "-noD", "-noF", "-noX"],
+            (ImageFormat::Squashfs, Compression::Zstd(_)) => &["-comp", "zstd"],
+            (ImageFormat::Squashfs, Compression::Lz4) => &["-comp", "lz4"],
+            (ImageFormat::Erofs, Compression::None) => &[],
+            (ImageFormat::Erofs, Compression::Zstd(_)) => &["-zzstd"],
+            (ImageFormat::Erofs, Compression::Lz4) => &["-zlz4"],
+        };
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        if let Compression::Zstd(Some(level)) = self {
+            match format {
+                ImageFormat::Squashfs => args.extend(["-Xcompression-level".to_string(), level.to_string()]),
+                ImageFormat::Erofs => args[0].push_str(&format!(",{}", level)),
+            }
+        }
+        args
+    }
+}
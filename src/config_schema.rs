@@ -0,0 +1,208 @@
+use clap::ValueEnum;
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use std::borrow::Cow;
+
+use crate::auto_snapshot::SnapshotTemplate;
+use crate::compression::Compression;
+use crate::fleet::RemoteCommand;
+use crate::units::{ByteSize, ConfigDuration, Days, Percentage, Schedule};
+use crate::{Config, MaxDelete};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    // JSON Schema (draft 2020-12), for validating configs rendered elsewhere
+    JsonSchema,
+}
+
+
+// The schema of the whole config file, from the config structs themselves,
+// so it can't drift from what load_config accepts. Checks that only
+// load_config makes, such as settings that need one another, aren't in it.
+pub fn print(format: Format) -> Result<(), String> {
+    match format {
+        Format::JsonSchema => {
+            let mut schema = schemars::schema_for!(Config);
+            schema.insert("title".to_string(), "file-backup config".into());
+            // Read on its own before the rest of the config, see build_info::check_config
+            if let Some(properties) = schema.get_mut("properties").and_then(|properties| properties.as_object_mut()) {
+                properties.insert(
+                    "min_tool_version".to_string(),
+                    json_schema!({
+                        "type": "string",
+                        "description": "Oldest version of file-backup that may load this config, e.g. \"0.2.0\""
+                    })
+                    .to_value(),
+                );
+            }
+            let json = serde_json::to_string_pretty(&schema)
+                .map_err(|e| format!("Failed to serialize config schema: {}", e))?;
+            println!("{}", json);
+        }
+    }
+    Ok(())
+}
+
+
+// The values parsed from a toml::Value take either of two forms, which the
+// derived schema can't see
+fn integer_or_string(name: &str, description: &str, examples: serde_json::Value) -> Schema {
+    json_schema!({
+        "title": name,
+        "type": ["integer", "string"],
+        "minimum": 0,
+        "description": description,
+        "examples": examples
+    })
+}
+
+
+impl JsonSchema for ConfigDuration {
+    fn schema_name() -> Cow<'static, str> {
+        "Duration".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        integer_or_string(
+            "Duration",
+            "Seconds, or a number with a unit (s, m, h, d, w), which can be combined",
+            serde_json::json!([3600, "36h", "2h30m"]),
+        )
+    }
+}
+
+
+impl JsonSchema for Days {
+    fn schema_name() -> Cow<'static, str> {
+        "Days".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        integer_or_string(
+            "Days",
+            "Days, or a duration with a unit (s, m, h, d, w)",
+            serde_json::json!([30, "36h"]),
+        )
+    }
+}
+
+
+impl JsonSchema for Schedule {
+    fn schema_name() -> Cow<'static, str> {
+        "Schedule".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        integer_or_string(
+            "Schedule",
+            "Seconds or a duration between runs, a five-field cron line, or hourly[@:MM], daily[@HH:MM] or weekly[@DAY HH:MM], in UTC",
+            serde_json::json!([86400, "6h", "30 2 * * *", "daily@02:30", "weekly@sun 03:00"]),
+        )
+    }
+}
+
+
+impl JsonSchema for ByteSize {
+    fn schema_name() -> Cow<'static, str> {
+        "ByteSize".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        integer_or_string(
+            "ByteSize",
+            "Bytes, or a number with a binary unit (K, M, G, T, P)",
+            serde_json::json!([1048576, "500M", "10G"]),
+        )
+    }
+}
+
+
+impl JsonSchema for Percentage {
+    fn schema_name() -> Cow<'static, str> {
+        "Percentage".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "title": "Percentage",
+            "description": "A number of percent, or a string with a percent sign",
+            "anyOf": [
+                { "type": "number", "minimum": 0, "maximum": 100 },
+                { "type": "string", "pattern": "^\\s*[0-9]+(\\.[0-9]+)?\\s*%\\s*$" }
+            ],
+            "examples": [1, "0.5%"]
+        })
+    }
+}
+
+
+impl JsonSchema for MaxDelete {
+    fn schema_name() -> Cow<'static, str> {
+        "MaxDelete".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "title": "MaxDelete",
+            "description": "A number of files, or a share of them with a percent sign",
+            "anyOf": [
+                { "type": "integer", "minimum": 0 },
+                { "type": "string", "pattern": "^\\s*[0-9]+(\\.[0-9]+)?\\s*%\\s*$" }
+            ],
+            "examples": [500, "10%"]
+        })
+    }
+}
+
+
+impl JsonSchema for Compression {
+    fn schema_name() -> Cow<'static, str> {
+        "Compression".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "title": "Compression",
+            "description": "zstd, optionally with a level from 1 to 19, lz4, or none",
+            "type": "string",
+            "pattern": "^\\s*(none|lz4|zstd(:([1-9]|1[0-9]))?)\\s*$",
+            "examples": ["zstd", "zstd:9", "lz4", "none"]
+        })
+    }
+}
+
+
+impl JsonSchema for RemoteCommand {
+    fn schema_name() -> Cow<'static, str> {
+        "RemoteCommand".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "title": "RemoteCommand",
+            "description": "The command split on whitespace, or a list of its words",
+            "anyOf": [
+                { "type": "string", "pattern": "\\S" },
+                { "type": "array", "items": { "type": "string" }, "minItems": 1 }
+            ],
+            "examples": ["sudo file-backup", ["sudo", "/opt/file backup/bin/file-backup"]]
+        })
+    }
+}
+
+
+impl JsonSchema for SnapshotTemplate {
+    fn schema_name() -> Cow<'static, str> {
+        "SnapshotTemplate".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "title": "SnapshotTemplate",
+            "description": "Snapshot name after the @, with %Y %m %d %H %M %S %s %% for the UTC time; needs %Y, %m, %d, %H and %M, or %s",
+            "type": "string",
+            "pattern": "^([A-Za-z0-9_.:-]|%[YmdHMSs%])+$",
+            "examples": ["file-backup-%Y%m%d-%H%M%S"]
+        })
+    }
+}
@@ -0,0 +1,214 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use crate::{clock, queue};
+
+
+// Set by `ctl abort`; the run stops before its next source
+static ABORT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Sources being backed up right now, for `ctl status`; several with
+// [concurrency] restic_jobs
+static CURRENT_SOURCES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+
+pub fn abort_requested() -> bool {
+    ABORT_REQUESTED.load(Ordering::SeqCst)
+}
+
+pub fn clear_abort() {
+    ABORT_REQUESTED.store(false, Ordering::SeqCst);
+}
+
+pub fn source_started(source: &str) {
+    if let Ok(mut current) = CURRENT_SOURCES.lock() {
+        current.push(source.to_string());
+    }
+}
+
+pub fn source_finished(source: &str) {
+    if let Ok(mut current) = CURRENT_SOURCES.lock()
+        && let Some(index) = current.iter().position(|name| name == source)
+    {
+        current.remove(index);
+    }
+}
+
+
+fn current_sources() -> Option<String> {
+    let current = CURRENT_SOURCES.lock().ok()?;
+    if current.is_empty() {
+        return None;
+    }
+    Some(current.iter().map(|name| format!("'{}'", name)).collect::<Vec<_>>().join(", "))
+}
+
+
+pub fn socket_path(database: &Path) -> PathBuf {
+    database.with_extension("sock")
+}
+
+
+// What the daemon shares with its control socket
+#[derive(Default)]
+pub struct DaemonState {
+    pub paused: AtomicBool,
+    pub next_run: Mutex<Option<SystemTime>>,
+    // Names of the configured sources, refreshed when the config is reloaded
+    pub sources: Mutex<Vec<String>>,
+}
+
+
+// Answer control commands on a Unix socket next to the database, one command
+// per connection, until the daemon exits
+pub fn serve(database: &Path, state: Arc<DaemonState>) -> Result<(), String> {
+    let path = socket_path(database);
+
+    // A socket left behind by a daemon that didn't exit cleanly
+    if UnixStream::connect(&path).is_err() {
+        let _ = fs::remove_file(&path);
+    }
+    let listener = UnixListener::bind(&path)
+        .map_err(|e| format!("Failed to listen on {}: {}", path.display(), e))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("Failed to restrict {}: {}", path.display(), e))?;
+
+    println!("Listening for control commands on {}", path.display());
+
+    let database = database.to_path_buf();
+    thread::spawn(move || {
+        for stream in listener.incoming().filter_map(|stream| stream.ok()) {
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            if reader.read_line(&mut line).is_err() {
+                continue;
+            }
+            let reply = handle_command(line.trim(), &database, &state);
+            let _ = (&stream).write_all(reply.as_bytes());
+        }
+    });
+
+    Ok(())
+}
+
+
+fn handle_command(command: &str, database: &Path, state: &DaemonState) -> String {
+    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+
+    match name {
+        "trigger" => {
+            let known = state.sources.lock().map(|s| s.iter().any(|n| n == argument)).unwrap_or(false);
+            if !known {
+                return format!("error: '{}' is not a dataset or restic repository in the config file\n", argument);
+            }
+            match queue::enqueue(database, argument) {
+                Ok(()) => format!("ok: queued '{}'\n", argument),
+                Err(e) => format!("error: {}\n", e),
+            }
+        }
+        "status" => {
+            let paused = state.paused.load(Ordering::SeqCst);
+            let current = current_sources();
+            let next_run = state.next_run.lock().ok().and_then(|n| *n);
+            format!(
+                "ok\nscheduling: {}\nrunning: {}\nnext run: {}\nqueued: {}\n",
+                if paused { "paused" } else { "active" },
+                current.as_deref().unwrap_or("nothing"),
+                next_run.map(clock::iso_utc).unwrap_or_else(|| "not scheduled".to_string()),
+                if queue::has_entries(database) { "yes" } else { "no" }
+            )
+        }
+        "pause" => {
+            state.paused.store(true, Ordering::SeqCst);
+            "ok: scheduled runs paused\n".to_string()
+        }
+        "resume" => {
+            state.paused.store(false, Ordering::SeqCst);
+            "ok: scheduled runs resumed\n".to_string()
+        }
+        "abort" => {
+            let Some(current) = current_sources() else {
+                return "error: no backup is running\n".to_string();
+            };
+            ABORT_REQUESTED.store(true, Ordering::SeqCst);
+            let killed = terminate_children();
+            format!("ok: aborting backup of {} ({} process(es) stopped)\n", current, killed)
+        }
+        _ => format!("error: unknown command '{}'\n", command),
+    }
+}
+
+
+// SIGTERM every process this one started (rsync, restic, zfs), which makes
+// the backup in progress fail
+#[cfg(target_os = "linux")]
+fn terminate_children() -> usize {
+    let own_pid = std::process::id();
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return 0;
+    };
+
+    let mut count = 0;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<i32>().ok()) else {
+            continue;
+        };
+        // The parent PID is the second field after the parenthesised command name
+        let parent = fs::read_to_string(entry.path().join("stat")).ok().and_then(|stat| {
+            let (_, rest) = stat.rsplit_once(')')?;
+            rest.split_whitespace().nth(1)?.parse::<u32>().ok()
+        });
+        if parent == Some(own_pid) && unsafe { libc::kill(pid, libc::SIGTERM) } == 0 {
+            count += 1;
+        }
+    }
+    count
+}
+
+
+// Without a Linux /proc to read, pgrep lists them
+#[cfg(not(target_os = "linux"))]
+fn terminate_children() -> usize {
+    let Ok(output) = crate::child_env::command("pgrep").args(["-P", &std::process::id().to_string()]).output() else {
+        return 0;
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.trim().parse::<i32>().ok())
+        .filter(|&pid| unsafe { libc::kill(pid, libc::SIGTERM) } == 0)
+        .count()
+}
+
+
+// Client side of `file-backup ctl`: send one command to the daemon and print
+// its reply. Returns whether the daemon reported success.
+pub fn send(database: &Path, command: &str) -> Result<bool, String> {
+    let path = socket_path(database);
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| format!("Failed to connect to {} (is the daemon running?): {}", path.display(), e))?;
+
+    writeln!(stream, "{}", command)
+        .map_err(|e| format!("Failed to send command: {}", e))?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)
+        .map_err(|e| format!("Failed to read reply: {}", e))?;
+
+    let ok = reply.starts_with("ok");
+    let body = reply.strip_prefix("ok").or_else(|| reply.strip_prefix("error")).unwrap_or(&reply);
+    let body = body.trim_start_matches(':').trim();
+    if ok {
+        if !body.is_empty() {
+            println!("{}", body);
+        }
+    } else {
+        eprintln!("Error: {}", body);
+    }
+    Ok(ok)
+}
@@ -0,0 +1,210 @@
+use rusqlite::Connection;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clock;
+use crate::control::{self, DaemonState};
+use crate::queue;
+use crate::tools::{self, ToolVersions};
+use crate::units::Schedule;
+use crate::{Config, RunOptions, child_env, events, excludes, nested, restic_cache, restic_env, template};
+
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct DaemonConfig {
+    // When the daemon starts runs, unless --schedule or --interval says;
+    // see units::Schedule
+    pub schedule: Option<Schedule>,
+}
+
+
+// Set by SIGHUP; checked between runs
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_signal: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+
+// Back up every configured source on the schedule, the one given on the
+// command line or else the config's, picking up config changes (on SIGHUP,
+// or when the file's modification time changes) between runs
+pub fn run_daemon(
+    config_path: &Path,
+    mut config: Config,
+    conn: &Connection,
+    options: &RunOptions,
+    mut tool_versions: ToolVersions,
+    schedule: Option<Schedule>,
+    config_check_interval: Duration,
+) {
+    unsafe {
+        libc::signal(libc::SIGHUP, handle_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+
+    let mut config_mtime = modification_time(config_path);
+
+    let state = Arc::new(DaemonState::default());
+    set_source_names(&state, &config);
+    if let Err(e) = control::serve(&options.database, Arc::clone(&state)) {
+        eprintln!("Warning: Control socket unavailable: {}", e);
+    }
+
+    // A day apart unless told otherwise
+    let current_schedule =
+        |config: &Config| schedule.clone().or_else(|| config.daemon.schedule.clone()).unwrap_or(Schedule::Every(86_400));
+    println!(
+        "Running in daemon mode: backing up {}, checking config every {}s",
+        current_schedule(&config),
+        config_check_interval.as_secs()
+    );
+
+    loop {
+        let started = SystemTime::now();
+        template::expand(&mut config);
+        if state.paused.load(Ordering::SeqCst) {
+            println!("Scheduling is paused, skipping this run");
+        } else if let Err(e) = crate::run_exclusive(&config, conn, options, &tool_versions, config.sources().collect()) {
+            eprintln!("Error: {}", e);
+        }
+
+        // A run that overran its slot is followed by the next one due from
+        // now rather than straight away
+        let now = SystemTime::now();
+        let mut next_at = current_schedule(&config).next_after(started);
+        if next_at < now {
+            next_at = current_schedule(&config).next_after(now);
+        }
+        let wait = next_at.duration_since(now).unwrap_or_default();
+        let next_run = Instant::now() + wait;
+        if let Ok(mut scheduled) = state.next_run.lock() {
+            *scheduled = Some(next_at);
+        }
+        println!("Next run at {}, in {}s\n", clock::iso_utc(next_at), wait.as_secs());
+
+        let mut next_config_check = Instant::now() + config_check_interval;
+        while let Some(remaining) = next_run.checked_duration_since(Instant::now()) {
+            // Wake up every second so triggered backups don't wait for the schedule
+            thread::sleep(remaining.min(Duration::from_secs(1)));
+
+            if queue::has_entries(&options.database) {
+                template::expand(&mut config);
+                if let Err(e) = crate::run_exclusive(&config, conn, options, &tool_versions, Vec::new()) {
+                    eprintln!("Error: {}", e);
+                }
+            }
+
+            let signalled = RELOAD_REQUESTED.swap(false, Ordering::SeqCst);
+            if !signalled && Instant::now() < next_config_check {
+                continue;
+            }
+            next_config_check = Instant::now() + config_check_interval;
+
+            let mtime = modification_time(config_path);
+            if !signalled && mtime == config_mtime {
+                continue;
+            }
+            config_mtime = mtime;
+
+            println!("Reloading config file '{}'...", config_path.display());
+            match reload_config(config_path) {
+                Ok((new_config, new_tool_versions)) => {
+                    // Compared as of today, so {date} moving on isn't a change
+                    template::expand(&mut config);
+                    log_config_changes(&config, &new_config);
+                    config = new_config;
+                    tool_versions = new_tool_versions;
+                    set_source_names(&state, &config);
+                }
+                Err(e) => {
+                    eprintln!("Error reloading config: {}", e);
+                    eprintln!("Keeping the previous config");
+                    child_env::configure(&config.environment);
+                    restic_env::configure(&config.restic);
+                    excludes::configure(&config.excludes);
+                    restic_cache::configure(&config.restic_cache);
+                    events::configure(&config.notify);
+                }
+            }
+        }
+    }
+}
+
+
+fn set_source_names(state: &DaemonState, config: &Config) {
+    if let Ok(mut sources) = state.sources.lock() {
+        *sources = config.sources().map(|source| source.name().to_string()).collect();
+    }
+}
+
+
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+
+// Only hand back a new config once it has parsed and the tools it needs are
+// present, so a half-edited file never replaces a working one
+fn reload_config(config_path: &Path) -> Result<(Config, ToolVersions), String> {
+    let mut config = crate::load_config(config_path)?;
+    // The tools are looked for in the new config's PATH
+    child_env::configure(&config.environment);
+    restic_env::configure(&config.restic);
+    excludes::configure(&config.excludes);
+    restic_cache::configure(&config.restic_cache);
+    events::configure(&config.notify);
+    let tool_versions = tools::detect_tool_versions(&config)?;
+    nested::expand(&mut config)?;
+    Ok((config, tool_versions))
+}
+
+
+fn log_config_changes(old: &Config, new: &Config) {
+    let mut changed = false;
+
+    for dataset in &new.dataset {
+        match old.dataset.iter().find(|d| d.name == dataset.name) {
+            None => println!("  Added dataset '{}'", dataset.name),
+            Some(previous) if previous != dataset => println!("  Changed dataset '{}'", dataset.name),
+            Some(_) => continue,
+        }
+        changed = true;
+    }
+    for dataset in &old.dataset {
+        if !new.dataset.iter().any(|d| d.name == dataset.name) {
+            println!("  Removed dataset '{}'", dataset.name);
+            changed = true;
+        }
+    }
+
+    for restic in &new.restic {
+        match old.restic.iter().find(|r| r.repository == restic.repository) {
+            None => println!("  Added restic repository '{}'", restic.repository),
+            Some(previous) if previous != restic => println!("  Changed restic repository '{}'", restic.repository),
+            Some(_) => continue,
+        }
+        changed = true;
+    }
+    for restic in &old.restic {
+        if !new.restic.iter().any(|r| r.repository == restic.repository) {
+            println!("  Removed restic repository '{}'", restic.repository);
+            changed = true;
+        }
+    }
+
+    if !changed {
+        println!("  No changes to sources");
+    }
+    if new.daemon.schedule != old.daemon.schedule {
+        match &new.daemon.schedule {
+            Some(schedule) => println!("  Schedule is now {}, from the next run", schedule),
+            None => println!("  Schedule removed, from the next run"),
+        }
+    }
+}
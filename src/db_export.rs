@@ -0,0 +1,316 @@
+use clap::ValueEnum;
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::SCHEMA_VERSION;
+use crate::build_info;
+
+
+// Trimmed copy of the database written to the root of each target after a run
+pub const TARGET_STATE_FILE: &str = ".file-backup-state.json";
+
+
+// What to do with an imported row that already exists in the database
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the existing row
+    Skip,
+    /// Replace the existing row with the imported one
+    Overwrite,
+    /// Keep whichever row is newer
+    Merge,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DatabaseExport {
+    pub schema_version: i64,
+    // Version of file-backup that wrote the export
+    #[serde(default)]
+    pub written_by: Option<String>,
+    #[serde(default)]
+    pub backup_history: Vec<BackupHistoryRow>,
+    #[serde(default)]
+    pub runs: Vec<RunRow>,
+    #[serde(default)]
+    pub file_state: Vec<FileStateRow>,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupHistoryRow {
+    // Missing from exports made before schema version 1
+    #[serde(default)]
+    pub hostname: Option<String>,
+    pub backup_type: String,
+    pub source_name: String,
+    pub snapshot_name: String,
+    pub backup_timestamp: Option<String>,
+    pub target_dir: String,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunRow {
+    #[serde(default)]
+    pub hostname: Option<String>,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub rsync_version: Option<String>,
+    pub restic_version: Option<String>,
+    pub zfs_version: Option<String>,
+    #[serde(default)]
+    pub file_backup_version: Option<String>,
+    // Missing from exports made before schema version 6
+    #[serde(default)]
+    pub uuid: Option<String>,
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileStateRow {
+    pub backup_type: String,
+    pub source_name: String,
+    pub path: String,
+    pub size: i64,
+    pub mtime: i64,
+    pub hash: Option<String>,
+    pub last_snapshot: String,
+}
+
+
+pub fn read_database(conn: &Connection) -> Result<DatabaseExport, String> {
+    let mut stmt = conn.prepare(
+        "SELECT hostname, backup_type, source_name, snapshot_name, backup_timestamp, target_dir
+         FROM backup_history ORDER BY id"
+    ).map_err(|e| format!("Failed to read backup_history: {}", e))?;
+    let backup_history = stmt.query_map([], |row| {
+        Ok(BackupHistoryRow {
+            hostname: row.get(0)?,
+            backup_type: row.get(1)?,
+            source_name: row.get(2)?,
+            snapshot_name: row.get(3)?,
+            backup_timestamp: row.get(4)?,
+            target_dir: row.get(5)?,
+        })
+    })
+    .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+    .map_err(|e| format!("Failed to read backup_history: {}", e))?;
+
+    let mut stmt = conn.prepare(
+        "SELECT hostname, started_at, finished_at, rsync_version, restic_version, zfs_version, file_backup_version, uuid
+         FROM runs ORDER BY id"
+    ).map_err(|e| format!("Failed to read runs: {}", e))?;
+    let runs = stmt.query_map([], |row| {
+        Ok(RunRow {
+            hostname: row.get(0)?,
+            started_at: row.get(1)?,
+            finished_at: row.get(2)?,
+            rsync_version: row.get(3)?,
+            restic_version: row.get(4)?,
+            zfs_version: row.get(5)?,
+            file_backup_version: row.get(6)?,
+            uuid: row.get(7)?,
+        })
+    })
+    .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+    .map_err(|e| format!("Failed to read runs: {}", e))?;
+
+    let mut stmt = conn.prepare(
+        "SELECT backup_type, source_name, path, size, mtime, hash, last_snapshot
+         FROM file_state ORDER BY backup_type, source_name, path"
+    ).map_err(|e| format!("Failed to read file_state: {}", e))?;
+    let file_state = stmt.query_map([], |row| {
+        Ok(FileStateRow {
+            backup_type: row.get(0)?,
+            source_name: row.get(1)?,
+            path: row.get(2)?,
+            size: row.get(3)?,
+            mtime: row.get(4)?,
+            hash: row.get(5)?,
+            last_snapshot: row.get(6)?,
+        })
+    })
+    .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+    .map_err(|e| format!("Failed to read file_state: {}", e))?;
+
+    Ok(DatabaseExport {
+        schema_version: SCHEMA_VERSION,
+        written_by: Some(build_info::VERSION.to_string()),
+        backup_history,
+        runs,
+        file_state,
+    })
+}
+
+
+pub fn export_json(conn: &Connection, path: &Path) -> Result<(), String> {
+    let export = read_database(conn)?;
+
+    let json = serde_json::to_string_pretty(&export)
+        .map_err(|e| format!("Failed to serialize database: {}", e))?;
+    fs::write(path, json)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    println!(
+        "Exported {} backup history row(s), {} run(s) and {} file state row(s) to {}",
+        export.backup_history.len(),
+        export.runs.len(),
+        export.file_state.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+
+// Write the state relevant to one target (its backup history and the file
+// state of the sources backed up to it) onto the target itself
+pub fn write_target_state(conn: &Connection, target_dir: &Path) -> Result<(), String> {
+    let mut export = read_database(conn)?;
+    let target = target_dir.to_string_lossy();
+
+    export.backup_history.retain(|row| row.target_dir == target);
+    export.runs.clear();
+    export.file_state.retain(|state| {
+        export.backup_history.iter().any(|row| {
+            row.backup_type == state.backup_type && row.source_name == state.source_name
+        })
+    });
+
+    let json = serde_json::to_string_pretty(&export)
+        .map_err(|e| format!("Failed to serialize database: {}", e))?;
+
+    // Write then rename so an unplugged disk never holds a half-written file
+    let path = target_dir.join(TARGET_STATE_FILE);
+    let temp_path = target_dir.join(format!("{}.tmp", TARGET_STATE_FILE));
+    fs::write(&temp_path, json)
+        .map_err(|e| format!("Failed to write {}: {}", temp_path.display(), e))?;
+    fs::rename(&temp_path, &path)
+        .map_err(|e| format!("Failed to rename {}: {}", temp_path.display(), e))?;
+
+    println!("Wrote backup state to {}", path.display());
+    Ok(())
+}
+
+
+pub fn import_json(conn: &Connection, hostname: &str, path: &Path, policy: ConflictPolicy) -> Result<(), String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let export: DatabaseExport = serde_json::from_str(&contents)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+
+    import(conn, hostname, export, policy)
+}
+
+
+pub fn import(conn: &Connection, hostname: &str, export: DatabaseExport, policy: ConflictPolicy) -> Result<(), String> {
+    if export.schema_version > SCHEMA_VERSION {
+        return Err(format!(
+            "Export has schema version {}{}, but this version of file-backup only understands up to {}. Please upgrade file-backup.",
+            export.schema_version,
+            build_info::written_by(export.written_by.as_deref()),
+            SCHEMA_VERSION
+        ));
+    }
+
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+
+    let history_sql = match policy {
+        ConflictPolicy::Skip => "ON CONFLICT DO NOTHING",
+        ConflictPolicy::Overwrite => {
+            "ON CONFLICT(hostname, backup_type, source_name, snapshot_name) DO UPDATE SET
+                backup_timestamp = excluded.backup_timestamp,
+                backed_up_epoch = excluded.backed_up_epoch,
+                target_dir = excluded.target_dir"
+        }
+        ConflictPolicy::Merge => {
+            "ON CONFLICT(hostname, backup_type, source_name, snapshot_name) DO UPDATE SET
+                backup_timestamp = excluded.backup_timestamp,
+                backed_up_epoch = excluded.backed_up_epoch,
+                target_dir = excluded.target_dir
+             WHERE excluded.backed_up_epoch > backup_history.backed_up_epoch"
+        }
+    };
+
+    let mut history_imported = 0;
+    for row in &export.backup_history {
+        // Rows exported before history was recorded per host belong to whoever imports them
+        let row_hostname = row.hostname.as_deref().unwrap_or(hostname);
+        history_imported += tx.execute(
+            &format!(
+                "INSERT INTO backup_history (hostname, backup_type, source_name, snapshot_name, backup_timestamp, target_dir, backed_up_epoch)
+                 VALUES (?1, ?2, ?3, ?4, COALESCE(?5, CURRENT_TIMESTAMP), ?6, CAST(strftime('%s', COALESCE(?5, 'now')) AS INTEGER)) {}",
+                history_sql
+            ),
+            params![row_hostname, row.backup_type, row.source_name, row.snapshot_name, row.backup_timestamp, row.target_dir],
+        ).map_err(|e| format!("Failed to import backup_history: {}", e))?;
+    }
+
+    // Runs are a log rather than state, so one that is already present is always kept
+    let mut runs_imported = 0;
+    for row in &export.runs {
+        let row_hostname = row.hostname.as_deref().unwrap_or(hostname);
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM runs WHERE hostname IS ?1 AND started_at IS ?2)",
+            params![row_hostname, row.started_at],
+            |r| r.get(0),
+        ).map_err(|e| format!("Failed to import runs: {}", e))?;
+
+        if !exists {
+            runs_imported += tx.execute(
+                "INSERT INTO runs (hostname, started_at, finished_at, rsync_version, restic_version, zfs_version, file_backup_version, uuid)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    row_hostname,
+                    row.started_at,
+                    row.finished_at,
+                    row.rsync_version,
+                    row.restic_version,
+                    row.zfs_version,
+                    row.file_backup_version,
+                    row.uuid,
+                ],
+            ).map_err(|e| format!("Failed to import runs: {}", e))?;
+        }
+    }
+
+    let file_state_sql = match policy {
+        ConflictPolicy::Skip => "ON CONFLICT DO NOTHING",
+        ConflictPolicy::Overwrite => {
+            "ON CONFLICT(backup_type, source_name, path) DO UPDATE SET
+                size = excluded.size, mtime = excluded.mtime,
+                hash = excluded.hash, last_snapshot = excluded.last_snapshot"
+        }
+        ConflictPolicy::Merge => {
+            "ON CONFLICT(backup_type, source_name, path) DO UPDATE SET
+                size = excluded.size, mtime = excluded.mtime,
+                hash = excluded.hash, last_snapshot = excluded.last_snapshot
+             WHERE excluded.mtime > file_state.mtime"
+        }
+    };
+
+    let mut file_state_imported = 0;
+    for row in &export.file_state {
+        file_state_imported += tx.execute(
+            &format!(
+                "INSERT INTO file_state (backup_type, source_name, path, size, mtime, hash, last_snapshot)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) {}",
+                file_state_sql
+            ),
+            params![row.backup_type, row.source_name, row.path, row.size, row.mtime, row.hash, row.last_snapshot],
+        ).map_err(|e| format!("Failed to import file_state: {}", e))?;
+    }
+
+    tx.commit().map_err(|e| format!("Failed to commit import: {}", e))?;
+
+    println!(
+        "Imported {} of {} backup history row(s), {} of {} run(s) and {} of {} file state row(s)",
+        history_imported, export.backup_history.len(),
+        runs_imported, export.runs.len(),
+        file_state_imported, export.file_state.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,515 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::{Config, Source, child_env, platform};
+
+
+// A mounted filesystem, as listed in the system's mount table
+#[derive(Debug)]
+pub struct Mount {
+    pub device: String,
+    pub mount_point: PathBuf,
+}
+
+
+// The mounted filesystem `path` lives on
+pub fn find_mount(path: &Path) -> Result<Mount, String> {
+    let path = fs::canonicalize(path)
+        .map_err(|e| format!("Failed to resolve {}: {}", path.display(), e))?;
+    let mounts = platform::mounts()?;
+
+    // The deepest mount point containing the path wins; later entries shadow
+    // earlier ones mounted at the same place
+    let mut best: Option<Mount> = None;
+    for (device, mount_point) in mounts {
+        if path.starts_with(&mount_point)
+            && best.as_ref().is_none_or(|b| mount_point.components().count() >= b.mount_point.components().count())
+        {
+            best = Some(Mount { device, mount_point });
+        }
+    }
+
+    best.ok_or_else(|| format!("No mounted filesystem found for {}", path.display()))
+}
+
+
+// After a run, unmount (and optionally power off) the devices of targets with
+// unmount_after, spin_down or luks_uuid set. A device is only released once every
+// source backed up to it asks for that and none of their backups failed.
+pub fn release_targets(config: &Config, failed_targets: &[&Path]) {
+    let mut mounts: Vec<(Mount, Vec<Source>)> = Vec::new();
+
+    for source in config.sources() {
+        match find_mount(source.target_dir()) {
+            Ok(mount) => match mounts.iter_mut().find(|(m, _)| m.mount_point == mount.mount_point) {
+                Some((_, sources)) => sources.push(source),
+                None => mounts.push((mount, vec![source])),
+            },
+            Err(e) if source.unmount_after() => {
+                eprintln!("Warning: Not unmounting target of '{}': {}", source.name(), e);
+            }
+            Err(_) => {}
+        }
+    }
+
+    for (mount, sources) in mounts {
+        if !sources.iter().any(|s| s.unmount_after()) {
+            continue;
+        }
+
+        if mount.mount_point == Path::new("/") {
+            eprintln!(
+                "Warning: Not unmounting {}: the target is on the root filesystem",
+                mount.mount_point.display()
+            );
+            continue;
+        }
+
+        if let Some(source) = sources.iter().find(|s| !s.unmount_after()) {
+            println!(
+                "Leaving {} mounted: {} '{}' is also backed up to it without unmount_after",
+                mount.mount_point.display(),
+                source.kind(),
+                source.name()
+            );
+            continue;
+        }
+
+        if let Some(source) = sources.iter().find(|s| failed_targets.contains(&s.target_dir())) {
+            println!(
+                "Leaving {} mounted: the backup of {} '{}' failed",
+                mount.mount_point.display(),
+                source.kind(),
+                source.name()
+            );
+            continue;
+        }
+
+        if let Err(e) = unmount(&mount) {
+            eprintln!("Warning: {}", e);
+            continue;
+        }
+
+        // Power off the disk under the LUKS container, since the mapping is gone by then
+        let mut disk_device = mount.device.clone();
+        if let Some(luks_uuid) = sources.iter().find_map(|s| s.device().luks_uuid.as_deref()) {
+            if let Err(e) = close_luks(luks_uuid) {
+                eprintln!("Warning: {}", e);
+                continue;
+            }
+            disk_device = format!("/dev/disk/by-uuid/{}", luks_uuid);
+        }
+
+        if sources.iter().any(|s| s.spin_down())
+            && let Err(e) = power_off(&disk_device)
+        {
+            eprintln!("Warning: {}", e);
+        }
+    }
+}
+
+
+// Called before a backup is recorded as done: with the device's sync_after,
+// wait until everything written to the target's filesystem is on the disk
+pub fn sync_target(target_dir: &Path, device: &DeviceConfig) -> Result<(), String> {
+    if !device.sync_after {
+        return Ok(());
+    }
+
+    println!("Syncing {} to disk...", target_dir.display());
+    let dir = File::open(target_dir).map_err(|e| format!("Failed to open {}: {}", target_dir.display(), e))?;
+    platform::sync_filesystem(&dir).map_err(|e| format!("Failed to sync {}: {}", target_dir.display(), e))?;
+    if !device.flush_write_cache {
+        return Ok(());
+    }
+
+    let disk = parent_disk(&find_mount(target_dir)?.device)?;
+    let output = child_env::command("hdparm")
+        .args(["-F", &disk])
+        .output()
+        .map_err(|e| format!("Failed to execute hdparm: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("hdparm -F {} failed: {}", disk, stderr.trim()));
+    }
+    println!("Flushed the write cache of {}", disk);
+    Ok(())
+}
+
+
+pub fn unmount(mount: &Mount) -> Result<(), String> {
+    println!("Syncing and unmounting {}...", mount.mount_point.display());
+
+    unsafe {
+        libc::sync();
+    }
+
+    let output = child_env::command("umount")
+        .arg(&mount.mount_point)
+        .output()
+        .map_err(|e| format!("Failed to execute umount: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Failed to unmount {}: {}", mount.mount_point.display(), stderr.trim()));
+    }
+
+    println!("Unmounted {}", mount.mount_point.display());
+    Ok(())
+}
+
+
+// Power off the disk holding `device`, which may be a partition or a mapper
+// device. udisksctl also detaches USB disks; hdparm is the fallback for hosts
+// without udisks.
+pub fn power_off(device: &str) -> Result<(), String> {
+    let disk = parent_disk(device)?;
+    println!("Powering off {}...", disk);
+
+    if let Ok(output) = child_env::command("udisksctl").args(["power-off", "-b", &disk]).output() {
+        if output.status.success() {
+            println!("Powered off {}, it can now be unplugged", disk);
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        eprintln!("Warning: udisksctl power-off failed: {}", stderr.trim());
+    }
+
+    let output = child_env::command("hdparm")
+        .args(["-Y", &disk])
+        .output()
+        .map_err(|e| format!("Failed to power off {}: neither udisksctl nor hdparm worked ({})", disk, e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("hdparm -Y {} failed: {}", disk, stderr.trim()));
+    }
+
+    println!("Spun down {}, it can now be unplugged", disk);
+    Ok(())
+}
+
+
+// The whole-disk device underneath a partition or device-mapper device
+fn parent_disk(device: &str) -> Result<String, String> {
+    let output = child_env::command("lsblk")
+        .args(["--noheadings", "--list", "--inverse", "--paths", "--output", "NAME,TYPE", device])
+        .output()
+        .map_err(|e| format!("Failed to execute lsblk: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("lsblk failed for {}: {}", device, stderr.trim()));
+    }
+
+    // With --inverse the device comes first and its ancestors follow, ending at the disk
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?;
+            (fields.next()? == "disk").then(|| name.to_string())
+        })
+        .next_back()
+        .ok_or_else(|| format!("Couldn't find the disk holding {}", device))
+}
+
+
+// Where to find a target's filesystem when it isn't mounted yet
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct DeviceConfig {
+    #[serde(default)]
+    pub auto_mount: bool,
+    // Filesystem UUID, as shown by `blkid` or under /dev/disk/by-uuid
+    pub device_uuid: Option<String>,
+    // Passed to mount -o
+    pub mount_options: Option<String>,
+    // UUID of a LUKS container holding the target filesystem. It is opened
+    // and mounted before the backup, and unmounted and closed afterwards.
+    pub luks_uuid: Option<String>,
+    pub keyfile: Option<PathBuf>,
+    // Shell command printing the passphrase, e.g. "pass show backup-disk"
+    pub key_command: Option<String>,
+    // Flush the target filesystem to the disk before the backup is recorded,
+    // so unplugging a removable disk straight after can't lose its tail
+    #[serde(default)]
+    pub sync_after: bool,
+    // With sync_after, also have the disk empty its own write cache (hdparm -F)
+    #[serde(default)]
+    pub flush_write_cache: bool,
+}
+
+impl DeviceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.device_uuid.is_some() {
+            platform::linux_only("device_uuid")?;
+        }
+        if self.luks_uuid.is_some() {
+            platform::linux_only("luks_uuid")?;
+        }
+        if self.flush_write_cache {
+            platform::linux_only("flush_write_cache")?;
+        }
+        if self.auto_mount && self.device_uuid.is_none() && self.luks_uuid.is_none() {
+            return Err("auto_mount needs device_uuid or luks_uuid".to_string());
+        }
+        if self.flush_write_cache && !self.sync_after {
+            return Err("flush_write_cache needs sync_after".to_string());
+        }
+        if self.luks_uuid.is_some() {
+            match (&self.keyfile, &self.key_command) {
+                (Some(_), Some(_)) => return Err("keyfile and key_command can't both be set".to_string()),
+                (None, None) => return Err("luks_uuid needs keyfile or key_command".to_string()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // The device holding the filesystem: the opened LUKS mapping if there is
+    // one, else the filesystem's own UUID
+    fn filesystem_device(&self) -> Option<PathBuf> {
+        match (&self.luks_uuid, &self.device_uuid) {
+            (Some(luks_uuid), _) => Some(Path::new("/dev/mapper").join(luks_mapping_name(luks_uuid))),
+            (None, Some(uuid)) => Some(Path::new("/dev/disk/by-uuid").join(uuid)),
+            (None, None) => None,
+        }
+    }
+}
+
+
+fn luks_mapping_name(luks_uuid: &str) -> String {
+    format!("file-backup-{}", luks_uuid)
+}
+
+
+// A missing device is expected for rotating removable disks, so it is kept
+// apart from real mount failures
+pub enum MountError {
+    NotPresent(String),
+    Failed(String),
+}
+
+
+fn is_mount_point(dir: &Path) -> bool {
+    match (fs::canonicalize(dir), find_mount(dir)) {
+        (Ok(dir), Ok(mount)) => mount.mount_point == dir,
+        _ => false,
+    }
+}
+
+
+// Whether a target lives on the disk `--target` names: a directory (the
+// disk's mount point, or a folder on it) or a filesystem label as listed
+// under /dev/disk/by-label. A directory prefix also matches while the disk
+// isn't mounted yet, for targets mounted by auto_mount.
+pub fn on_target(target_dir: &Path, device: &DeviceConfig, selector: &str) -> bool {
+    let selector_path = Path::new(selector);
+    if selector_path.is_absolute() {
+        if target_dir.starts_with(selector_path) {
+            return true;
+        }
+        return is_mount_point(selector_path)
+            && match (find_mount(target_dir), fs::canonicalize(selector_path)) {
+                (Ok(mount), Ok(selector_path)) => mount.mount_point == selector_path,
+                _ => false,
+            };
+    }
+
+    let Ok(labelled) = fs::canonicalize(Path::new("/dev/disk/by-label").join(selector)) else {
+        return false;
+    };
+    let configured = device.filesystem_device().and_then(|path| fs::canonicalize(path).ok());
+    let mounted = find_mount(target_dir)
+        .ok()
+        .filter(|mount| mount.mount_point != Path::new("/"))
+        .and_then(|mount| fs::canonicalize(mount.device).ok());
+    configured.as_ref() == Some(&labelled) || mounted.as_ref() == Some(&labelled)
+}
+
+
+static MOUNTING: Mutex<()> = Mutex::new(());
+
+
+// Mount the target's device on the target directory if auto_mount or
+// luks_uuid is set and nothing is mounted there yet. Root uses mount(8); other
+// users go through udisks2, which only mounts on the target if /etc/fstab
+// says so.
+pub fn mount_target(target_dir: &Path, device: &DeviceConfig) -> Result<(), MountError> {
+    // Sources backed up side by side may share a target device
+    let _mounting = MOUNTING.lock().unwrap_or_else(|e| e.into_inner());
+    if !device.auto_mount && device.luks_uuid.is_none() {
+        return Ok(());
+    }
+
+    if is_mount_point(target_dir) {
+        return Ok(());
+    }
+
+    if let Some(luks_uuid) = &device.luks_uuid {
+        open_luks(luks_uuid, device)?;
+    }
+
+    let Some(device_path) = device.filesystem_device() else {
+        return Ok(());
+    };
+    if !device_path.exists() {
+        return Err(MountError::NotPresent(format!(
+            "{} isn't attached for target {}",
+            device_path.display(),
+            target_dir.display()
+        )));
+    }
+
+    if !target_dir.is_dir() {
+        return Err(MountError::Failed(format!(
+            "Mount point {} doesn't exist",
+            target_dir.display()
+        )));
+    }
+
+    println!("Mounting {} on {}...", device_path.display(), target_dir.display());
+
+    let mut command;
+    if unsafe { libc::geteuid() } == 0 {
+        command = child_env::command("mount");
+        if let Some(options) = &device.mount_options {
+            command.args(["-o", options]);
+        }
+        command.arg(&device_path).arg(target_dir);
+    } else {
+        command = child_env::command("udisksctl");
+        command.args(["mount", "--no-user-interaction", "-b"]).arg(&device_path);
+        if let Some(options) = &device.mount_options {
+            command.args(["-o", options]);
+        }
+    }
+
+    let output = command
+        .output()
+        .map_err(|e| MountError::Failed(format!("Failed to mount {}: {}", device_path.display(), e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(MountError::Failed(format!(
+            "Failed to mount {} on {}: {}",
+            device_path.display(),
+            target_dir.display(),
+            stderr.trim()
+        )));
+    }
+
+    if !is_mount_point(target_dir) {
+        return Err(MountError::Failed(format!(
+            "{} was mounted, but not on {}; add an /etc/fstab entry for it or run as root",
+            device_path.display(),
+            target_dir.display()
+        )));
+    }
+
+    println!("Mounted {}", target_dir.display());
+    Ok(())
+}
+
+
+fn open_luks(luks_uuid: &str, device: &DeviceConfig) -> Result<(), MountError> {
+    let name = luks_mapping_name(luks_uuid);
+    if Path::new("/dev/mapper").join(&name).exists() {
+        return Ok(());
+    }
+
+    let container = Path::new("/dev/disk/by-uuid").join(luks_uuid);
+    if !container.exists() {
+        return Err(MountError::NotPresent(format!("no LUKS container with UUID {} is attached", luks_uuid)));
+    }
+
+    println!("Opening LUKS container {} as {}...", container.display(), name);
+
+    let mut command = child_env::command("cryptsetup");
+    command.arg("open").arg(&container).arg(&name);
+
+    let passphrase = match (&device.keyfile, &device.key_command) {
+        (Some(keyfile), _) => {
+            command.arg("--key-file").arg(keyfile);
+            None
+        }
+        (None, Some(key_command)) => {
+            command.args(["--key-file", "-"]).stdin(Stdio::piped());
+            Some(run_key_command(key_command).map_err(MountError::Failed)?)
+        }
+        (None, None) => None,
+    };
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| MountError::Failed(format!("Failed to execute cryptsetup: {}", e)))?;
+
+    if let Some(passphrase) = passphrase
+        && let Some(mut stdin) = child.stdin.take()
+    {
+        stdin
+            .write_all(&passphrase)
+            .map_err(|e| MountError::Failed(format!("Failed to pass key to cryptsetup: {}", e)))?;
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| MountError::Failed(format!("Failed to execute cryptsetup: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(MountError::Failed(format!(
+            "Failed to open LUKS container {}: {}",
+            container.display(),
+            stderr.trim()
+        )));
+    }
+
+    Ok(())
+}
+
+
+fn run_key_command(key_command: &str) -> Result<Vec<u8>, String> {
+    let output = Command::new("sh")
+        .args(["-c", key_command])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to execute key_command: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!("key_command exited with {}", output.status));
+    }
+
+    // A passphrase printed by a password manager ends in a newline that isn't part of it
+    let mut key = output.stdout;
+    if key.last() == Some(&b'\n') {
+        key.pop();
+    }
+    Ok(key)
+}
+
+
+pub fn close_luks(luks_uuid: &str) -> Result<(), String> {
+    let name = luks_mapping_name(luks_uuid);
+    println!("Closing LUKS mapping {}...", name);
+
+    let output = child_env::command("cryptsetup")
+        .args(["close", &name])
+        .output()
+        .map_err(|e| format!("Failed to execute cryptsetup: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Failed to close LUKS mapping {}: {}", name, stderr.trim()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::device;
+
+
+// A target device giving out part way through a backup, as opposed to the
+// backup itself going wrong. A disk on a flaky USB link drops off the bus, or
+// its filesystem turns read-only after errors, and every later source backed
+// up to it would then fail the same way, only slowly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    // The filesystem was unmounted from under the backup, or the device went
+    Vanished,
+    Full,
+    IoError,
+}
+
+impl Fault {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fault::Vanished => "disappeared",
+            Fault::Full => "ran out of space",
+            Fault::IoError => "reported I/O errors",
+        }
+    }
+}
+
+
+// What the errno messages of each fault look like in rsync's stderr
+const MESSAGES: [(&str, Fault); 7] = [
+    ("No such device", Fault::Vanished),
+    ("Transport endpoint is not connected", Fault::Vanished),
+    ("Stale file handle", Fault::Vanished),
+    ("No space left on device", Fault::Full),
+    ("Disk quota exceeded", Fault::Full),
+    ("Input/output error", Fault::IoError),
+    ("Read-only file system", Fault::IoError),
+];
+
+
+// The filesystem a target was on when its backup started
+pub struct Target {
+    pub device: String,
+    pub mount_point: PathBuf,
+    target_dir: PathBuf,
+}
+
+impl Target {
+    pub fn of(target_dir: &Path) -> Option<Target> {
+        let mount = device::find_mount(target_dir).ok()?;
+        Some(Target { device: mount.device, mount_point: mount.mount_point, target_dir: target_dir.to_path_buf() })
+    }
+
+    // Whether a backup that failed with `error` did so because the device
+    // did. Errors reading the source look much the same, so only the
+    // receiving side's messages, or ones naming the target, count.
+    pub fn fault(&self, error: &str) -> Option<Fault> {
+        let still_mounted = device::find_mount(&self.target_dir)
+            .is_ok_and(|mount| mount.mount_point == self.mount_point && mount.device == self.device);
+        if !still_mounted {
+            return Some(Fault::Vanished);
+        }
+
+        let target = self.target_dir.to_string_lossy();
+        error
+            .lines()
+            .filter(|line| line.contains("[receiver]") || line.contains("[generator]") || line.contains(target.as_ref()))
+            .find_map(|line| MESSAGES.iter().find(|(message, _)| line.contains(message)).map(|&(_, fault)| fault))
+    }
+}
+
+
+// Whether `target_dir` is on the failed device mounted at `mount_point`. Once
+// the device has gone its mount has too, so a target under the mount point
+// counts as well.
+pub fn is_on(target_dir: &Path, mount_point: &Path) -> bool {
+    if mount_point != Path::new("/") && target_dir.starts_with(mount_point) {
+        return true;
+    }
+    device::find_mount(target_dir).is_ok_and(|mount| mount.mount_point == mount_point)
+}
+
+
+// Target devices that failed during the run, by mount point, with the source
+// whose backup ran into it. Shared by the jobs of a batch backed up side by
+// side, so a job starting after another's device failed skips it too.
+static RUN_FAILURES: Mutex<Vec<(PathBuf, String)>> = Mutex::new(Vec::new());
+
+
+pub fn start_run() {
+    RUN_FAILURES.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+
+// The source's backup found its target device failing: note it for the rest
+// of the run
+pub fn failed(source_name: &str, mount_point: &Path) {
+    RUN_FAILURES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((mount_point.to_path_buf(), source_name.to_string()));
+}
+
+
+// Whether the target is on a device that failed so far in the run: if so,
+// its mount point and the source whose backup failed
+pub fn failure_of(target_dir: &Path) -> Option<(PathBuf, String)> {
+    let failures = RUN_FAILURES.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    failures.into_iter().find(|(mount_point, _)| is_on(target_dir, mount_point))
+}
@@ -0,0 +1,240 @@
+use rusqlite::{Connection, OptionalExtension, params};
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+
+use crate::executed::{Log, Record};
+use crate::{SnapshotChange, child_env};
+use crate::tools::ToolVersions;
+
+
+// `zfs diff` of a dataset with millions of files takes a long time, and a run
+// that fails part way (a full target, an unplugged disk) would otherwise
+// compute it all over again on the retry. Parsed diffs are kept keyed by the
+// snapshots' GUIDs, which unlike names can't be reused for other snapshots,
+// so two targets backing up the same dataset share them too. Each is kept in
+// parts of about PART_BYTES as it is read, and only used once it was read to
+// the end, so neither storing nor replaying one holds more than a part of it.
+const MAX_AGE_DAYS: u32 = 7;
+const PART_BYTES: usize = 1 << 20;
+
+
+// Diffs were once kept whole, a row each in diff_cache
+pub fn create_table(conn: &Connection) -> Result<(), String> {
+    conn.execute_batch(
+        "DROP TABLE IF EXISTS diff_cache;
+         CREATE TABLE IF NOT EXISTS diff_cache_entries (
+            old_guid TEXT NOT NULL,
+            new_guid TEXT NOT NULL,
+            parts INTEGER NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY(old_guid, new_guid)
+         );
+         CREATE TABLE IF NOT EXISTS diff_cache_parts (
+            old_guid TEXT NOT NULL,
+            new_guid TEXT NOT NULL,
+            part INTEGER NOT NULL,
+            changes BLOB NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY(old_guid, new_guid, part)
+         );"
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+
+    Ok(())
+}
+
+
+pub fn snapshot_guid(snapshot: &str, commands: &Log) -> Result<String, String> {
+    let output = child_env::command("zfs")
+        .args(["get", "-H", "-o", "value", "guid", snapshot])
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute zfs get: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("zfs get failed: {}", stderr.trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+
+// Each change is stored as its change and file type, path and new path
+// (empty unless it was renamed), each ended by a NUL since that is the one
+// byte no path contains. Entries from before the file type was kept have the
+// change type alone.
+fn encode(change: &SnapshotChange, bytes: &mut Vec<u8>) {
+    bytes.push(change.change_type as u8);
+    bytes.extend(change.file_type.map(|file_type| file_type as u8));
+    bytes.push(0);
+    bytes.extend_from_slice(change.path.as_os_str().as_bytes());
+    bytes.push(0);
+    if let Some(new_path) = &change.new_path {
+        bytes.extend_from_slice(new_path.as_os_str().as_bytes());
+    }
+    bytes.push(0);
+}
+
+
+fn decode(bytes: &[u8]) -> Option<Vec<SnapshotChange>> {
+    let fields: Vec<&[u8]> = bytes.strip_suffix(b"\0")?.split(|&b| b == 0).collect();
+    fields
+        .chunks(3)
+        .map(|change| match change {
+            [[change_type, file_type @ ..], path, new_path] if file_type.len() <= 1 => Some(SnapshotChange {
+                change_type: *change_type as char,
+                file_type: file_type.first().map(|&file_type| file_type as char),
+                path: PathBuf::from(OsString::from_vec(path.to_vec())),
+                new_path: (!new_path.is_empty()).then(|| PathBuf::from(OsString::from_vec(new_path.to_vec()))),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+
+// How many parts the cached diff between two snapshots has, if one was read
+// to the end and all of them are still there
+fn lookup(conn: &Connection, old_guid: &str, new_guid: &str) -> Result<Option<i64>, String> {
+    conn.query_row(
+        "SELECT parts FROM diff_cache_entries
+         WHERE old_guid = ?1 AND new_guid = ?2
+           AND parts = (SELECT COUNT(*) FROM diff_cache_parts WHERE old_guid = ?1 AND new_guid = ?2)",
+        [old_guid, new_guid],
+        |row| row.get(0),
+    ).optional().map_err(|e| format!("Failed to read diff cache: {}", e))
+}
+
+
+fn replay(
+    conn: &Connection,
+    old_guid: &str,
+    new_guid: &str,
+    parts: i64,
+    each: &mut dyn FnMut(SnapshotChange) -> Result<(), String>,
+) -> Result<(), String> {
+    for part in 0..parts {
+        let bytes: Vec<u8> = conn.query_row(
+            "SELECT changes FROM diff_cache_parts WHERE old_guid = ?1 AND new_guid = ?2 AND part = ?3",
+            params![old_guid, new_guid, part],
+            |row| row.get(0),
+        ).map_err(|e| format!("Failed to read diff cache: {}", e))?;
+        let Some(changes) = decode(&bytes) else {
+            // Some of it was passed on already, so the diff can't just be
+            // computed instead; the retry will
+            let _ = forget(conn, old_guid, new_guid);
+            return Err("Corrupt diff cache entry, dropped for the next run to compute the diff again".to_string());
+        };
+        for change in changes {
+            each(change)?;
+        }
+    }
+    Ok(())
+}
+
+
+fn forget(conn: &Connection, old_guid: &str, new_guid: &str) -> Result<(), String> {
+    conn.execute("DELETE FROM diff_cache_entries WHERE old_guid = ?1 AND new_guid = ?2", [old_guid, new_guid])
+        .and_then(|_| conn.execute("DELETE FROM diff_cache_parts WHERE old_guid = ?1 AND new_guid = ?2", [old_guid, new_guid]))
+        .map(|_| ())
+        .map_err(|e| format!("Failed to clear diff cache: {}", e))
+}
+
+
+// A diff being written to the cache as it is read, a part at a time
+struct Store<'a> {
+    conn: &'a Connection,
+    old_guid: &'a str,
+    new_guid: &'a str,
+    parts: i64,
+    bytes: Vec<u8>,
+}
+
+impl<'a> Store<'a> {
+    // Parts left by a run that stopped before the end of the diff are cleared first
+    fn start(conn: &'a Connection, old_guid: &'a str, new_guid: &'a str) -> Result<Store<'a>, String> {
+        forget(conn, old_guid, new_guid)?;
+        Ok(Store { conn, old_guid, new_guid, parts: 0, bytes: Vec::new() })
+    }
+
+    fn add(&mut self, change: &SnapshotChange) -> Result<(), String> {
+        encode(change, &mut self.bytes);
+        if self.bytes.len() >= PART_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.conn.execute(
+            "INSERT INTO diff_cache_parts (old_guid, new_guid, part, changes) VALUES (?1, ?2, ?3, ?4)",
+            params![self.old_guid, self.new_guid, self.parts, self.bytes],
+        ).map_err(|e| format!("Failed to write diff cache: {}", e))?;
+        self.parts += 1;
+        self.bytes.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        if !self.bytes.is_empty() {
+            self.flush()?;
+        }
+        let age = format!("-{} days", MAX_AGE_DAYS);
+        self.conn.execute("DELETE FROM diff_cache_entries WHERE created_at < datetime('now', ?1)", [&age])
+            .and_then(|_| self.conn.execute("DELETE FROM diff_cache_parts WHERE created_at < datetime('now', ?1)", [&age]))
+            .map_err(|e| format!("Failed to expire diff cache: {}", e))?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO diff_cache_entries (old_guid, new_guid, parts) VALUES (?1, ?2, ?3)",
+            params![self.old_guid, self.new_guid, self.parts],
+        ).map_err(|e| format!("Failed to write diff cache: {}", e))?;
+        Ok(())
+    }
+}
+
+
+// Pass each change between two snapshots to `each`, from the cache if an
+// earlier run already worked them out. The cache is only an optimisation, so
+// problems with it are warnings and the diff is computed as usual.
+pub fn snapshot_diff(
+    conn: &Connection,
+    old_snapshot: &str,
+    new_snapshot: &str,
+    tool_versions: &ToolVersions,
+    commands: &Log,
+    each: &mut dyn FnMut(SnapshotChange) -> Result<(), String>,
+) -> Result<(), String> {
+    let guids = match (snapshot_guid(old_snapshot, commands), snapshot_guid(new_snapshot, commands)) {
+        (Ok(old_guid), Ok(new_guid)) => Some((old_guid, new_guid)),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Warning: Not using the diff cache: {}", e);
+            None
+        }
+    };
+
+    if let Some((old_guid, new_guid)) = &guids {
+        match lookup(conn, old_guid, new_guid) {
+            Ok(Some(parts)) => {
+                println!("Using cached differences between snapshots");
+                return replay(conn, old_guid, new_guid, parts, each);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Warning: {}", e),
+        }
+    }
+
+    let mut store = guids.as_ref().and_then(|(old_guid, new_guid)| {
+        Store::start(conn, old_guid, new_guid).map_err(|e| eprintln!("Warning: {}", e)).ok()
+    });
+    crate::get_snapshot_diff(old_snapshot, new_snapshot, tool_versions, commands, &mut |change| {
+        if let Some(Err(e)) = store.as_mut().map(|writing| writing.add(&change)) {
+            eprintln!("Warning: {}", e);
+            store = None;
+        }
+        each(change)
+    })?;
+
+    if let Some(store) = store
+        && let Err(e) = store.finish()
+    {
+        eprintln!("Warning: {}", e);
+    }
+    Ok(())
+}
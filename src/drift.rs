@@ -0,0 +1,194 @@
+use rusqlite::Connection;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::{RunOptions, Source, estimate, excludes, file_state};
+
+
+// Paths listed before the prompt; the rest are only counted
+const MAX_LISTED_PATHS: usize = 20;
+
+
+// What to do when a mirror target no longer matches the file state recorded
+// for it, e.g. files changed on the disk by hand, which an incremental
+// backup would never put right. With on_drift set the target is audited
+// before every incremental backup, and whoever is at the terminal is asked,
+// with the policy as the answer when nobody is.
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnDrift {
+    // Rsync the whole snapshot with --checksum this run, as full_resync_every would
+    Resync,
+    // Fail the backup, leaving the target as it is
+    Fail,
+    // Take the target as it now is into the file state, and back up from there
+    Adopt,
+}
+
+impl OnDrift {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OnDrift::Resync => "resync",
+            OnDrift::Fail => "fail",
+            OnDrift::Adopt => "adopt",
+        }
+    }
+}
+
+
+// How the target differs from the file state, as paths relative to it
+#[derive(Debug, Default)]
+pub struct Drift {
+    // A different size or modification time
+    pub modified: Vec<String>,
+    pub missing: Vec<String>,
+    // On the target but not in the file state
+    pub unexpected: Vec<String>,
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.modified.is_empty() && self.missing.is_empty() && self.unexpected.is_empty()
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} modified, {} missing and {} unexpected path(s)",
+            self.modified.len(),
+            self.missing.len(),
+            self.unexpected.len()
+        )
+    }
+
+    fn print(&self) {
+        let listed = [("modified", &self.modified), ("missing", &self.missing), ("unexpected", &self.unexpected)]
+            .into_iter()
+            .flat_map(|(what, paths)| paths.iter().map(move |path| (what, path)));
+        for (what, path) in listed.take(MAX_LISTED_PATHS) {
+            println!("  {}: {}", what, path);
+        }
+        let total = self.modified.len() + self.missing.len() + self.unexpected.len();
+        if total > MAX_LISTED_PATHS {
+            println!("  ... and {} more", total - MAX_LISTED_PATHS);
+        }
+    }
+}
+
+
+// Files recorded for the source, by path: (size, mtime)
+fn recorded(conn: &Connection, source: Source) -> Result<HashMap<String, (i64, i64)>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT path, size, mtime FROM file_state WHERE backup_type = ?1 AND source_name = ?2"
+    ).map_err(|e| format!("Failed to read file state: {}", e))?;
+    stmt.query_map([source.backup_type(), source.name()], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))
+        .and_then(|rows| rows.collect())
+        .map_err(|e| format!("Failed to read file state: {}", e))
+}
+
+
+// Compare every file on the target with the file state: rsync keeps sizes
+// and modification times, so a file where either differs was changed since.
+// Symlink times aren't kept everywhere, so only their presence counts, as
+// with the stubs written for them under symlinks = "stub".
+pub fn audit(conn: &Connection, source: Source) -> Result<Drift, String> {
+    let mut recorded = recorded(conn, source)?;
+    let root = source.target_dir();
+    // Child datasets backed up into the parent's target have their own state
+    let nested: &[PathBuf] = match source {
+        Source::Dataset(d) => &d.nested_excludes,
+        Source::Restic(_) => &[],
+    };
+    let mut drift = Drift::default();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = fs::read_dir(&dir).map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+            let path = entry.path();
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            if dir == root && crate::TARGET_INTERNAL_NAMES.iter().any(|name| entry.file_name() == *name) {
+                continue;
+            }
+            let metadata = fs::symlink_metadata(&path).map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+            if excludes::matches(relative, metadata.is_dir(), source.filter()) || nested.iter().any(|child| relative == child) {
+                continue;
+            }
+            if metadata.is_dir() {
+                pending.push(path);
+                continue;
+            }
+
+            let relative = relative.to_string_lossy().into_owned();
+            match recorded.remove(&relative) {
+                None => drift.unexpected.push(relative),
+                Some(_) if metadata.is_symlink() => {}
+                // A stub in place of a symlink, see symlinks::Symlinks::Stub
+                Some(_) if crate::metadata::link_target(root, Path::new(&relative)).is_some() => {}
+                Some((size, mtime)) if size != metadata.size() as i64 || mtime != metadata.mtime() => {
+                    drift.modified.push(relative)
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    drift.missing = recorded.into_keys().collect();
+
+    drift.modified.sort();
+    drift.missing.sort();
+    drift.unexpected.sort();
+    Ok(drift)
+}
+
+
+fn choose(policy: OnDrift) -> Result<OnDrift, String> {
+    let question = format!("[r]esync the whole snapshot, [f]ail the backup or [a]dopt the target as it is? [{}] ", policy.as_str());
+    loop {
+        let answer = estimate::read_answer(&question)?;
+        match answer.trim().to_lowercase().as_str() {
+            "" => return Ok(policy),
+            "r" | "resync" => return Ok(OnDrift::Resync),
+            "f" | "fail" => return Ok(OnDrift::Fail),
+            "a" | "adopt" => return Ok(OnDrift::Adopt),
+            _ => println!("Answer r, f or a"),
+        }
+    }
+}
+
+
+// Audit the target of a source about to be backed up incrementally from
+// `last_snapshot`, and deal with any drift. Returns whether the whole
+// snapshot has to be rsynced again.
+pub fn check(conn: &Connection, options: &RunOptions, source: Source, last_snapshot: &str) -> Result<bool, String> {
+    let Some(policy) = source.on_drift() else {
+        return Ok(false);
+    };
+    println!("Auditing {} against the recorded file state...", source.target_dir().display());
+    let drift = audit(conn, source)?;
+    if drift.is_empty() {
+        return Ok(false);
+    }
+
+    println!("Target has drifted from the file state: {}", drift.describe());
+    drift.print();
+    let action = if options.confirm_first_backup { choose(policy)? } else { policy };
+
+    match action {
+        OnDrift::Resync => {
+            println!("Resyncing the whole snapshot to put the drift right");
+            Ok(true)
+        }
+        OnDrift::Fail => Err(format!("Target {} has drifted: {}", source.target_dir().display(), drift.describe())),
+        OnDrift::Adopt => {
+            let changed: Vec<PathBuf> = drift.modified.iter().chain(&drift.unexpected).map(PathBuf::from).collect();
+            let missing: Vec<PathBuf> = drift.missing.iter().map(PathBuf::from).collect();
+            file_state::apply_changes(conn, source.backup_type(), source.name(), last_snapshot, source.target_dir(), &changed, &missing)?;
+            println!("Adopted the target as it is into the file state");
+            Ok(false)
+        }
+    }
+}
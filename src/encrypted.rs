@@ -0,0 +1,804 @@
+use rusqlite::{Connection, params};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::Source;
+use crate::child_env;
+use crate::compression::Compression;
+
+
+// Encrypted targets hold one age-encrypted object per file under objects/,
+// named by a random ID, plus an encrypted manifest mapping paths to objects.
+// Without the identity the target doesn't reveal file names, contents or the
+// directory layout, but it does show how many files there are and, from each
+// object's size, roughly how big each one is (compressed first when the source
+// sets compression). The backup host only needs the recipient.
+pub const OBJECTS_DIR: &str = "objects";
+pub const MANIFEST_FILE: &str = ".file-backup-manifest.age";
+
+
+// `encrypt = "age:age1..."` encrypts to that recipient; plain `encrypt = "age"`
+// takes the recipients from age_recipients_file
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct AgeEncryption {
+    pub recipient: Option<String>,
+}
+
+impl TryFrom<String> for AgeEncryption {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.split_once(':') {
+            None if value == "age" => Ok(AgeEncryption { recipient: None }),
+            Some(("age", recipient)) if !recipient.is_empty() => Ok(AgeEncryption {
+                recipient: Some(recipient.to_string()),
+            }),
+            _ => Err(format!("invalid encrypt '{}', expected \"age\" or \"age:<recipient>\"", value)),
+        }
+    }
+}
+
+
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct EncryptionConfig {
+    pub encrypt: Option<AgeEncryption>,
+    pub age_recipients_file: Option<PathBuf>,
+    // Only needed to restore or verify, so usually kept off the backup host
+    pub age_identity_file: Option<PathBuf>,
+    pub age_identity_command: Option<String>,
+}
+
+impl EncryptionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(encryption) = &self.encrypt
+            && encryption.recipient.is_none()
+            && self.age_recipients_file.is_none()
+        {
+            return Err("encrypt = \"age\" needs age_recipients_file, or give the recipient as \"age:<recipient>\"".to_string());
+        }
+        if self.age_identity_file.is_some() && self.age_identity_command.is_some() {
+            return Err("age_identity_file and age_identity_command can't both be set".to_string());
+        }
+        Ok(())
+    }
+
+    fn recipient_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(recipient) = self.encrypt.as_ref().and_then(|e| e.recipient.as_ref()) {
+            args.push("-r".to_string());
+            args.push(recipient.clone());
+        }
+        if let Some(file) = &self.age_recipients_file {
+            args.push("-R".to_string());
+            args.push(file.to_string_lossy().into_owned());
+        }
+        args
+    }
+}
+
+
+pub fn create_table(conn: &Connection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS encrypted_files (
+            backup_type TEXT NOT NULL,
+            source_name TEXT NOT NULL,
+            path BLOB NOT NULL,
+            kind TEXT NOT NULL,
+            object_id TEXT,
+            size INTEGER NOT NULL,
+            mtime INTEGER NOT NULL,
+            mode INTEGER NOT NULL,
+            link_target BLOB,
+            compression TEXT,
+            PRIMARY KEY(backup_type, source_name, path)
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+
+    Ok(())
+}
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct ManifestEntry {
+    #[serde(with = "escaped")]
+    path: PathBuf,
+    // "file", "dir" or "symlink"
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    object: Option<String>,
+    size: i64,
+    mtime: i64,
+    mode: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "escaped::option")]
+    target: Option<PathBuf>,
+    // What the object was compressed with, if anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<Compression>,
+}
+
+
+// Paths are raw bytes, but JSON only holds text: bytes that aren't UTF-8 are
+// written as \xHH, and a backslash itself as \\
+fn escape_path(path: &Path) -> String {
+    let mut text = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        text.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            text.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    text
+}
+
+
+fn unescape_path(text: &str) -> Result<PathBuf, String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\\') => bytes.push(b'\\'),
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    let byte = u8::from_str_radix(&hex, 16)
+                        .ok()
+                        .filter(|_| hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+                        .ok_or_else(|| format!("invalid escape \\x{} in path {:?}", hex, text))?;
+                    bytes.push(byte);
+                }
+                _ => return Err(format!("invalid escape in path {:?}", text)),
+            },
+            _ => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+
+mod escaped {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&escape_path(path))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        unescape_path(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+            match path {
+                Some(path) => serializer.serialize_some(&escape_path(path)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PathBuf>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|text| unescape_path(&text))
+                .transpose()
+                .map_err(de::Error::custom)
+        }
+    }
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    snapshot: String,
+    entries: Vec<ManifestEntry>,
+}
+
+
+fn object_path(target_dir: &Path, object_id: &str) -> PathBuf {
+    target_dir.join(OBJECTS_DIR).join(&object_id[..2]).join(format!("{}.age", object_id))
+}
+
+
+fn new_object_id() -> Result<String, String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .map_err(|e| format!("Failed to read /dev/urandom: {}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+
+fn read_tree(root: &Path) -> Result<Vec<ManifestEntry>, String> {
+    let mut entries = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let listing = fs::read_dir(&dir)
+            .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+
+        for entry in listing {
+            let entry = entry.map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+            let path = entry.path();
+            let metadata = fs::symlink_metadata(&path)
+                .map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+            let relative_path = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+
+            let (kind, target) = if metadata.is_dir() {
+                pending.push(path.clone());
+                ("dir", None)
+            } else if metadata.file_type().is_symlink() {
+                let target = fs::read_link(&path)
+                    .map_err(|e| format!("Failed to read link {}: {}", path.display(), e))?;
+                ("symlink", Some(target))
+            } else if metadata.is_file() {
+                ("file", None)
+            } else {
+                eprintln!("Warning: Skipping special file {}", path.display());
+                continue;
+            };
+
+            entries.push(ManifestEntry {
+                path: relative_path,
+                kind: kind.to_string(),
+                object: None,
+                size: metadata.size() as i64,
+                mtime: metadata.mtime(),
+                mode: metadata.mode() & 0o7777,
+                target,
+                compression: None,
+            });
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+
+fn load_entries(conn: &Connection, backup_type: &str, source_name: &str) -> Result<HashMap<PathBuf, ManifestEntry>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT path, kind, object_id, size, mtime, mode, link_target, compression FROM encrypted_files
+         WHERE backup_type = ?1 AND source_name = ?2"
+    ).map_err(|e| format!("Failed to read encrypted file state: {}", e))?;
+
+    stmt.query_map([backup_type, source_name], |row| {
+        let path = row.get_ref(0)?.as_bytes().map_err(rusqlite::Error::from)?;
+        let target = row.get_ref(6)?.as_bytes_or_null().map_err(rusqlite::Error::from)?;
+        Ok(ManifestEntry {
+            path: PathBuf::from(OsString::from_vec(path.to_vec())),
+            kind: row.get(1)?,
+            object: row.get(2)?,
+            size: row.get(3)?,
+            mtime: row.get(4)?,
+            mode: row.get(5)?,
+            target: target.map(|target| PathBuf::from(OsString::from_vec(target.to_vec()))),
+            compression: row
+                .get::<_, Option<String>>(7)?
+                .and_then(|text| Compression::parse(&text).ok()),
+        })
+    })
+    .and_then(|rows| rows.map(|row| row.map(|entry| (entry.path.clone(), entry))).collect())
+    .map_err(|e| format!("Failed to read encrypted file state: {}", e))
+}
+
+
+fn encrypt_to(encryption: &EncryptionConfig, compression: Compression, input: &Path, output: &Path) -> Result<(), String> {
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+
+    // Write then rename so an interrupted run never leaves a truncated object
+    let temp_path = output.with_extension("age.tmp");
+    let mut age = child_env::command("age");
+    age.arg("-e")
+        .args(encryption.recipient_args())
+        .arg("-o")
+        .arg(&temp_path);
+
+    let result = match compression.compress_command() {
+        None => age
+            .arg(input)
+            .output()
+            .map_err(|e| format!("Failed to execute age: {}", e))?,
+        Some(mut compress) => {
+            let file = File::open(input)
+                .map_err(|e| format!("Failed to open {}: {}", input.display(), e))?;
+            let mut compressor = compress
+                .stdin(file)
+                .stdout(Stdio::piped())
+                .spawn()
+                .map_err(|e| format!("Failed to execute {}: {}", compression, e))?;
+            let compressed = compressor.stdout.take().map(Stdio::from).unwrap_or_else(Stdio::null);
+            let result = age
+                .stdin(compressed)
+                .output()
+                .map_err(|e| format!("Failed to execute age: {}", e))?;
+            let status = compressor.wait()
+                .map_err(|e| format!("Failed to execute {}: {}", compression, e))?;
+            if !status.success() {
+                let _ = fs::remove_file(&temp_path);
+                return Err(format!("{} failed to compress {}: exited with {}", compression, input.display(), status));
+            }
+            result
+        }
+    };
+
+    if !result.status.success() {
+        let _ = fs::remove_file(&temp_path);
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(format!("age failed to encrypt {}: {}", input.display(), stderr.trim()));
+    }
+
+    fs::rename(&temp_path, output)
+        .map_err(|e| format!("Failed to rename {}: {}", temp_path.display(), e))
+}
+
+
+fn write_manifest(encryption: &EncryptionConfig, target_dir: &Path, manifest: &Manifest) -> Result<(), String> {
+    let json = serde_json::to_vec(manifest)
+        .map_err(|e| format!("Failed to serialize manifest: {}", e))?;
+
+    let path = target_dir.join(MANIFEST_FILE);
+    let temp_path = target_dir.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut child = child_env::command("age")
+        .arg("-e")
+        .args(encryption.recipient_args())
+        .arg("-o")
+        .arg(&temp_path)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute age: {}", e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&json)
+            .map_err(|e| format!("Failed to write manifest to age: {}", e))?;
+    }
+    let output = child.wait_with_output()
+        .map_err(|e| format!("Failed to execute age: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("age failed to encrypt the manifest: {}", stderr.trim()));
+    }
+
+    fs::rename(&temp_path, &path)
+        .map_err(|e| format!("Failed to rename {}: {}", temp_path.display(), e))
+}
+
+
+// Bring an encrypted target up to date with the tree at `snapshot_path`:
+// files that are new or changed since the last backup are encrypted into fresh
+// objects, the manifest is rewritten, and objects of removed files are deleted.
+// Unchanged files keep their objects, and so whatever compression they had.
+pub fn backup(conn: &Connection, source: Source, snapshot_name: &str, snapshot_path: &Path) -> Result<(), String> {
+    let (backup_type, source_name) = (source.backup_type(), source.name());
+    let target_dir = source.target_dir();
+    let encryption = source.encryption();
+    let compression = source.compression().unwrap_or(Compression::None);
+
+    let previous = load_entries(conn, backup_type, source_name)?;
+    let mut entries = read_tree(snapshot_path)?;
+
+    println!("Encrypting changes from {} into {}...", snapshot_path.display(), target_dir.display());
+
+    let mut encrypted = 0;
+    for entry in entries.iter_mut().filter(|entry| entry.kind == "file") {
+        let unchanged = previous.get(&entry.path).filter(|old| {
+            old.kind == "file" && old.size == entry.size && old.mtime == entry.mtime
+        });
+        if let Some(object) = unchanged.and_then(|old| old.object.clone())
+            && object_path(target_dir, &object).is_file()
+        {
+            entry.object = Some(object);
+            entry.compression = unchanged.and_then(|old| old.compression);
+            continue;
+        }
+
+        let object = new_object_id()?;
+        encrypt_to(encryption, compression, &snapshot_path.join(&entry.path), &object_path(target_dir, &object))?;
+        entry.object = Some(object);
+        entry.compression = Some(compression).filter(|&c| c != Compression::None);
+        encrypted += 1;
+    }
+
+    let manifest = Manifest {
+        snapshot: snapshot_name.to_string(),
+        entries,
+    };
+    write_manifest(encryption, target_dir, &manifest)?;
+    save_entries(conn, backup_type, source_name, &manifest.entries)?;
+
+    // Objects no longer referenced: removed files, and old copies of changed
+    // ones. Only now that neither the manifest nor the database points at them,
+    // so a run cut short before this leaves the old backup restorable.
+    let current: HashMap<&Path, &str> = manifest
+        .entries
+        .iter()
+        .filter_map(|entry| Some((entry.path.as_path(), entry.object.as_deref()?)))
+        .collect();
+    let mut removed = 0;
+    for old in previous.values() {
+        if let Some(object) = &old.object
+            && current.get(old.path.as_path()) != Some(&object.as_str())
+        {
+            match fs::remove_file(object_path(target_dir, object)) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!("Warning: Failed to remove object {}: {}", object, e),
+            }
+        }
+    }
+
+    println!(
+        "Encrypted {} file(s), removed {} stale object(s), {} entries in manifest",
+        encrypted,
+        removed,
+        manifest.entries.len()
+    );
+    Ok(())
+}
+
+
+fn save_entries(conn: &Connection, backup_type: &str, source_name: &str, entries: &[ManifestEntry]) -> Result<(), String> {
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+
+    tx.execute(
+        "DELETE FROM encrypted_files WHERE backup_type = ?1 AND source_name = ?2",
+        [backup_type, source_name],
+    ).map_err(|e| format!("Failed to clear encrypted file state: {}", e))?;
+
+    for entry in entries {
+        tx.execute(
+            "INSERT INTO encrypted_files (backup_type, source_name, path, kind, object_id, size, mtime, mode, link_target, compression)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                backup_type,
+                source_name,
+                entry.path.as_os_str().as_bytes(),
+                entry.kind,
+                entry.object,
+                entry.size,
+                entry.mtime,
+                entry.mode,
+                entry.target.as_ref().map(|target| target.as_os_str().as_bytes()),
+                entry.compression.map(|c| c.to_string()),
+            ],
+        ).map_err(|e| format!("Failed to update encrypted file state: {}", e))?;
+    }
+
+    tx.commit().map_err(|e| format!("Failed to commit encrypted file state: {}", e))
+}
+
+
+// An identity written out by age_identity_command, removed again on drop
+pub struct Identity {
+    path: PathBuf,
+    temporary: bool,
+}
+
+impl Drop for Identity {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+
+pub fn identity(encryption: &EncryptionConfig, override_file: Option<&Path>) -> Result<Identity, String> {
+    if let Some(file) = override_file.or(encryption.age_identity_file.as_deref()) {
+        return Ok(Identity { path: file.to_path_buf(), temporary: false });
+    }
+
+    let Some(command) = &encryption.age_identity_command else {
+        return Err("No age identity: pass --identity or set age_identity_file or age_identity_command".to_string());
+    };
+
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to execute age_identity_command: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("age_identity_command exited with {}", output.status));
+    }
+
+    // A random name, so another user can't claim it first; create_new also
+    // refuses to write through anything, a symlink included, put there anyway
+    let path = std::env::temp_dir().join(format!("file-backup-identity-{}", new_object_id()?));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    let identity = Identity { path, temporary: true };
+    file.write_all(&output.stdout)
+        .map_err(|e| format!("Failed to write {}: {}", identity.path.display(), e))?;
+
+    Ok(identity)
+}
+
+
+fn decrypt(identity: &Identity, input: &Path) -> Command {
+    let mut command = child_env::command("age");
+    command.arg("-d").arg("-i").arg(&identity.path).arg(input);
+    command
+}
+
+
+// Spawn the decryption of an entry's object, decompressing it as well if it
+// was compressed, with the plaintext going to `stdout`. The children are
+// returned in pipeline order, for the caller to wait on.
+fn spawn_plaintext(identity: &Identity, target_dir: &Path, entry: &ManifestEntry, object: &str, stdout: Stdio) -> Result<Vec<Child>, String> {
+    let mut age = decrypt(identity, &object_path(target_dir, object));
+    age.stderr(Stdio::null());
+
+    let Some(mut decompress) = entry.compression.and_then(|c| c.decompress_command()) else {
+        let age = age.stdout(stdout).spawn().map_err(|e| format!("Failed to execute age: {}", e))?;
+        return Ok(vec![age]);
+    };
+
+    let mut age = age
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute age: {}", e))?;
+    let ciphertext = age.stdout.take().map(Stdio::from).unwrap_or_else(Stdio::null);
+    let decompressor = decompress
+        .stdin(ciphertext)
+        .stdout(stdout)
+        .stderr(Stdio::null())
+        .spawn();
+    match decompressor {
+        Ok(decompressor) => Ok(vec![age, decompressor]),
+        Err(e) => {
+            let _ = age.kill();
+            let _ = age.wait();
+            Err(format!("Failed to execute {}: {}", entry.compression.map(|c| c.to_string()).unwrap_or_default(), e))
+        }
+    }
+}
+
+
+// True when every stage of the pipeline succeeded
+fn wait_all(children: Vec<Child>) -> Result<bool, String> {
+    let mut success = true;
+    for mut child in children {
+        let status = child.wait().map_err(|e| format!("Failed to wait for decryption: {}", e))?;
+        success &= status.success();
+    }
+    Ok(success)
+}
+
+
+fn read_manifest(target_dir: &Path, identity: &Identity) -> Result<Manifest, String> {
+    let path = target_dir.join(MANIFEST_FILE);
+    let output = decrypt(identity, &path)
+        .output()
+        .map_err(|e| format!("Failed to execute age: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Failed to decrypt {}: {}", path.display(), stderr.trim()));
+    }
+
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("Failed to parse manifest {}: {}", path.display(), e))
+}
+
+
+// Decrypt the whole target into `destination`, restoring names, modes,
+// modification times and symlinks from the manifest
+pub fn restore(target_dir: &Path, destination: &Path, identity: &Identity) -> Result<(), String> {
+    let manifest = read_manifest(target_dir, identity)?;
+    if let Some(entry) = manifest.entries.iter().find(|entry| !is_contained(&entry.path)) {
+        return Err(format!(
+            "Refusing to restore: manifest entry {} is not a path inside the destination",
+            entry.path.display()
+        ));
+    }
+    println!("Restoring {} entries from snapshot {}...", manifest.entries.len(), manifest.snapshot);
+
+    fs::create_dir_all(destination)
+        .map_err(|e| format!("Failed to create {}: {}", destination.display(), e))?;
+    let mut directories = Vec::new();
+    for entry in &manifest.entries {
+        let path = destination.join(&entry.path);
+        let parent = entry.path.parent().unwrap_or(Path::new(""));
+        match (entry.kind.as_str(), &entry.object, &entry.target) {
+            ("dir", _, _) => {
+                create_dirs(destination, &entry.path)?;
+                directories.push((path, entry));
+                continue;
+            }
+            ("symlink", _, Some(target)) => {
+                create_dirs(destination, parent)?;
+                std::os::unix::fs::symlink(target, &path)
+                    .map_err(|e| format!("Failed to create symlink {}: {}", path.display(), e))?;
+                continue;
+            }
+            ("file", Some(object), _) => {
+                create_dirs(destination, parent)?;
+                // Whatever is already there, a symlink isn't followed
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .custom_flags(libc::O_NOFOLLOW)
+                    .open(&path)
+                    .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+                let children = spawn_plaintext(identity, target_dir, entry, object, Stdio::from(file))?;
+                if !wait_all(children)? {
+                    return Err(format!("Failed to decrypt {}", entry.path.display()));
+                }
+            }
+            _ => {
+                eprintln!("Warning: Skipping malformed manifest entry {}", entry.path.display());
+                continue;
+            }
+        }
+        set_attributes(&path, entry)?;
+    }
+
+    // Directory times change as their contents are written, so set them last
+    for (path, entry) in directories.iter().rev() {
+        set_attributes(path, entry)?;
+    }
+
+    println!("Restored to {}", destination.display());
+    Ok(())
+}
+
+
+// Manifest paths come from the target, so any that could reach outside the
+// destination are refused rather than trusted
+fn is_contained(path: &Path) -> bool {
+    path.components().next().is_some() && path.components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+
+// Create `relative`'s directories under `destination` one at a time, refusing
+// to pass through anything that isn't a real directory, a symlink above all
+fn create_dirs(destination: &Path, relative: &Path) -> Result<(), String> {
+    let mut path = destination.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => return Err(format!("Refusing to restore through {}: not a directory", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir(&path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+            }
+            Err(e) => return Err(format!("Failed to stat {}: {}", path.display(), e)),
+        }
+    }
+    Ok(())
+}
+
+
+fn set_attributes(path: &Path, entry: &ManifestEntry) -> Result<(), String> {
+    fs::set_permissions(path, fs::Permissions::from_mode(entry.mode))
+        .map_err(|e| format!("Failed to set permissions on {}: {}", path.display(), e))?;
+    let mtime = UNIX_EPOCH + Duration::from_secs(entry.mtime.max(0) as u64);
+    File::open(path)
+        .and_then(|file| file.set_modified(mtime))
+        .map_err(|e| format!("Failed to set modification time on {}: {}", path.display(), e))
+}
+
+
+// Decrypt every object and check it against the size in the manifest
+pub fn verify(target_dir: &Path, identity: &Identity) -> Result<(), String> {
+    let manifest = read_manifest(target_dir, identity)?;
+    let files: Vec<&ManifestEntry> = manifest.entries.iter().filter(|e| e.kind == "file").collect();
+    println!("Verifying {} encrypted file(s) from snapshot {}...", files.len(), manifest.snapshot);
+
+    let mut failures = 0;
+    for entry in files {
+        let Some(object) = &entry.object else {
+            continue;
+        };
+        // Stream the plaintext rather than holding whole files in memory
+        let mut children = spawn_plaintext(identity, target_dir, entry, object, Stdio::piped())?;
+        let size = children
+            .last_mut()
+            .and_then(|child| child.stdout.take())
+            .map(|mut stdout| io::copy(&mut stdout, &mut io::sink()));
+        let success = wait_all(children)?;
+
+        match size {
+            Some(Ok(size)) if success => {
+                if size as i64 != entry.size {
+                    println!("  wrong size: {} ({} bytes, expected {})", entry.path.display(), size, entry.size);
+                    failures += 1;
+                }
+            }
+            _ => {
+                println!("  can't decrypt: {}", entry.path.display());
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(format!("{} encrypted file(s) failed verification", failures));
+    }
+    println!("All encrypted files verified");
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_paths_round_trip_raw_bytes() {
+        let path = PathBuf::from(OsString::from_vec(b"caf\xe9/back\\slash/\xff\xfe.txt".to_vec()));
+        let text = escape_path(&path);
+        assert_eq!(text, "caf\\xe9/back\\\\slash/\\xff\\xfe.txt");
+        assert_eq!(unescape_path(&text), Ok(path));
+        assert_eq!(escape_path(Path::new("plain/naïve")), "plain/naïve");
+    }
+
+    #[test]
+    fn manifest_entries_keep_non_utf8_names() {
+        let entry = ManifestEntry {
+            path: PathBuf::from(OsString::from_vec(b"dir/\x80name".to_vec())),
+            kind: "symlink".to_string(),
+            object: None,
+            size: 0,
+            mtime: 0,
+            mode: 0o777,
+            target: Some(PathBuf::from(OsString::from_vec(b"../\xc3".to_vec()))),
+            compression: None,
+        };
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(serde_json::from_str::<ManifestEntry>(&json).unwrap(), entry);
+    }
+
+    #[test]
+    fn only_plain_relative_paths_are_restored() {
+        assert!(is_contained(Path::new("dir/file")));
+        assert!(is_contained(Path::new("file")));
+        assert!(!is_contained(Path::new("")));
+        assert!(!is_contained(Path::new("/etc/passwd")));
+        assert!(!is_contained(Path::new("../outside")));
+        assert!(!is_contained(Path::new("dir/../../outside")));
+        assert!(!is_contained(Path::new("./file")));
+    }
+
+    #[test]
+    fn restore_does_not_create_directories_through_symlinks() {
+        let root = std::env::temp_dir().join(format!("file-backup-test-{}", new_object_id().unwrap()));
+        let outside = root.join("outside");
+        let destination = root.join("destination");
+        fs::create_dir_all(&outside).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        std::os::unix::fs::symlink(&outside, destination.join("link")).unwrap();
+
+        assert!(create_dirs(&destination, Path::new("link/sub")).is_err());
+        assert!(!outside.join("sub").exists());
+        assert!(create_dirs(&destination, Path::new("real/sub")).is_ok());
+        assert!(destination.join("real/sub").is_dir());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn bad_escapes_are_rejected() {
+        assert!(unescape_path("a\\qb").is_err());
+        assert!(unescape_path("a\\xZZ").is_err());
+        assert!(unescape_path("trailing\\").is_err());
+    }
+}
@@ -0,0 +1,115 @@
+use std::ffi::CString;
+use std::io::{self, BufRead, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::executed::{Log, Record};
+use crate::{RunOptions, Source, child_env, privileges, report};
+
+
+// Bytes the first full copy of a snapshot will write: the data referenced by
+// a ZFS snapshot, or the restore size of a restic snapshot
+pub fn estimate_size(source: Source, snapshot: &str, commands: &Log) -> Result<u64, String> {
+    match source {
+        Source::Dataset(_) => {
+            let output = child_env::command("zfs")
+                .args(["get", "-Hp", "-o", "value", "referenced", snapshot])
+                .recorded_output(commands)
+                .map_err(|e| format!("Failed to execute zfs get: {}", e))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("zfs get failed: {}", stderr.trim()));
+            }
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            stdout.trim().parse().map_err(|_| format!("Unexpected zfs get output '{}'", stdout.trim()))
+        }
+        Source::Restic(restic_config) => {
+            let output = privileges::restic(&restic_config.repository, restic_config.user.as_ref())
+                .args(["stats", "--json", "--mode", "restore-size", snapshot])
+                .recorded_output(commands)
+                .map_err(|e| format!("Failed to execute restic stats: {}", e))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("restic stats failed: {}", stderr.trim()));
+            }
+            let stats: serde_json::Value = serde_json::from_slice(&output.stdout)
+                .map_err(|e| format!("Failed to parse restic stats output: {}", e))?;
+            stats["total_size"].as_u64().ok_or_else(|| "restic stats didn't report total_size".to_string())
+        }
+    }
+}
+
+
+pub fn free_space(dir: &Path) -> Result<u64, String> {
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| format!("Invalid path {}", dir.display()))?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(format!("Failed to stat filesystem of {}: {}", dir.display(), io::Error::last_os_error()));
+    }
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+
+// Before the first full copy of a source, say how much is about to be written
+// where, and when someone is at the terminal ask them to confirm, so a wrong
+// disk is caught before an overnight copy rather than after
+pub fn confirm_first_backup(source: Source, snapshot: &str, options: &RunOptions, commands: &Log) -> Result<(), String> {
+    let target_dir = source.target_dir();
+
+    let estimate = match estimate_size(source, snapshot, commands) {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            eprintln!("Warning: Couldn't estimate the size of {}: {}", snapshot, e);
+            None
+        }
+    };
+    let free = match free_space(target_dir) {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            None
+        }
+    };
+
+    println!(
+        "First backup: about {} to copy to {} ({} free)",
+        estimate.map(report::format_bytes).unwrap_or_else(|| "an unknown amount".to_string()),
+        target_dir.display(),
+        free.map(report::format_bytes).unwrap_or_else(|| "unknown".to_string())
+    );
+    if let (Some(estimate), Some(free)) = (estimate, free)
+        && estimate > free
+    {
+        eprintln!("Warning: {} doesn't have enough free space for this backup", target_dir.display());
+    }
+
+    if !options.confirm_first_backup {
+        return Ok(());
+    }
+
+    if ask("Continue?")? {
+        Ok(())
+    } else {
+        Err(format!("First backup of '{}' not confirmed", source.name()))
+    }
+}
+
+
+// Put a yes/no question to whoever is at the terminal; anything but yes is no
+pub fn ask(question: &str) -> Result<bool, String> {
+    let answer = read_answer(&format!("{} [y/N] ", question))?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+
+pub fn read_answer(prompt: &str) -> Result<String, String> {
+    print!("{}", prompt);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|e| format!("Failed to read answer: {}", e))?;
+    Ok(answer)
+}
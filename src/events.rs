@@ -0,0 +1,343 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::runlog::SourceSummary;
+use crate::{Source, child_env, clock, run_id};
+
+
+// What a run tells the outside world about itself goes through here: the
+// backup code only emits events, and each [[notify]] sink in the config gets
+// the ones it asks for, e.g.
+//
+//   [[notify]]
+//   sink = "webhook"
+//   url = "https://status.example.com/hooks/backup"
+//   events = ["job_finished", "run_finished"]
+//
+// A sink that fails only warns; it never fails a backup.
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct NotifyConfig {
+    #[serde(flatten)]
+    pub sink: Sink,
+    // The events to send, all of them when left out, or for email only
+    // run_finished
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+}
+
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(tag = "sink", rename_all = "lowercase")]
+pub enum Sink {
+    // A line of output for each event, which lands in the run log too
+    Console,
+    // Each event as a line of JSON appended to the file
+    Log { path: PathBuf },
+    // A mail through sendmail -t, with the event as JSON for its body
+    Email {
+        to: Vec<String>,
+        #[serde(default)]
+        from: Option<String>,
+    },
+    // The event as JSON POSTed to the URL with curl
+    Webhook { url: String },
+    // A shell command, given the event as JSON on stdin and its name in
+    // FILE_BACKUP_EVENT
+    Command { command: String },
+}
+
+impl Sink {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Sink::Console => "console",
+            Sink::Log { .. } => "log",
+            Sink::Email { .. } => "email",
+            Sink::Webhook { .. } => "webhook",
+            Sink::Command { .. } => "command",
+        }
+    }
+}
+
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    JobStarted,
+    SnapshotSelected,
+    TransferProgress,
+    JobFinished,
+    RunFinished,
+}
+
+impl EventKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::JobStarted => "job_started",
+            EventKind::SnapshotSelected => "snapshot_selected",
+            EventKind::TransferProgress => "transfer_progress",
+            EventKind::JobFinished => "job_finished",
+            EventKind::RunFinished => "run_finished",
+        }
+    }
+}
+
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    JobStarted {
+        target_dir: String,
+    },
+    // The snapshot a backup is taken of, and the one it is incremental from
+    SnapshotSelected {
+        snapshot: &'a str,
+        base: Option<&'a str>,
+    },
+    // A step that copied data to the target finished, e.g. an rsync
+    TransferProgress {
+        step: &'a str,
+        files: Option<usize>,
+        bytes: Option<u64>,
+    },
+    JobFinished {
+        status: &'static str,
+        error: Option<&'a str>,
+        duration_secs: u64,
+        anomalies: &'a [String],
+    },
+    RunFinished {
+        sources: usize,
+        ok: usize,
+        // Names of the sources that weren't backed up
+        not_ok: Vec<&'a str>,
+        duration_secs: u64,
+    },
+}
+
+impl Event<'_> {
+    fn kind(&self) -> EventKind {
+        match self {
+            Event::JobStarted { .. } => EventKind::JobStarted,
+            Event::SnapshotSelected { .. } => EventKind::SnapshotSelected,
+            Event::TransferProgress { .. } => EventKind::TransferProgress,
+            Event::JobFinished { .. } => EventKind::JobFinished,
+            Event::RunFinished { .. } => EventKind::RunFinished,
+        }
+    }
+
+    fn describe(&self, source: Option<&(&'static str, String)>) -> String {
+        let source = source.map(|(kind, name)| format!("{} '{}'", kind, name)).unwrap_or_else(|| "backup".to_string());
+        match self {
+            Event::JobStarted { target_dir } => format!("{} started, to {}", source, target_dir),
+            Event::SnapshotSelected { snapshot, base: Some(base) } => format!("{}: snapshot {} from {}", source, snapshot, base),
+            Event::SnapshotSelected { snapshot, base: None } => format!("{}: snapshot {} in full", source, snapshot),
+            Event::TransferProgress { step, files, bytes } => {
+                let files = files.map(|files| format!(", {} file(s)", files)).unwrap_or_default();
+                let bytes = bytes.map(|bytes| format!(", {}", crate::report::format_bytes(bytes))).unwrap_or_default();
+                format!("{}: {} done{}{}", source, step, files, bytes)
+            }
+            Event::JobFinished { status, error: Some(error), duration_secs, .. } => {
+                format!("{} {} after {}s: {}", source, status, duration_secs, error)
+            }
+            Event::JobFinished { status, duration_secs, .. } => format!("{} {} in {}s", source, status, duration_secs),
+            Event::RunFinished { sources, ok, not_ok, .. } if not_ok.is_empty() => {
+                format!("run finished: {} of {} source(s) ok", ok, sources)
+            }
+            Event::RunFinished { sources, ok, not_ok, .. } => {
+                format!("run finished: {} of {} source(s) ok, not {}", ok, sources, not_ok.join(", "))
+            }
+        }
+    }
+}
+
+
+// What every event carries besides its own fields
+#[derive(Serialize)]
+struct Envelope<'a> {
+    time: String,
+    hostname: String,
+    run_id: Option<String>,
+    job_id: Option<String>,
+    kind: Option<&'static str>,
+    source: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+
+static SINKS: Mutex<Vec<NotifyConfig>> = Mutex::new(Vec::new());
+static HOSTNAME: Mutex<String> = Mutex::new(String::new());
+
+// The source being backed up on this thread, as (kind, name)
+thread_local! {
+    static SOURCE: RefCell<Option<(&'static str, String)>> = const { RefCell::new(None) };
+}
+
+
+pub fn configure(notify: &[NotifyConfig]) {
+    *SINKS.lock().unwrap_or_else(|e| e.into_inner()) = notify.to_vec();
+}
+
+
+pub fn validate(notify: &[NotifyConfig]) -> Result<(), String> {
+    for config in notify {
+        match &config.sink {
+            Sink::Log { path } if !path.is_absolute() => {
+                return Err(format!("notify: log path '{}' must be absolute", path.display()));
+            }
+            Sink::Email { to, .. } if to.is_empty() => return Err("notify: email needs at least one address in to".to_string()),
+            Sink::Webhook { url } if !url.starts_with("http://") && !url.starts_with("https://") => {
+                return Err(format!("notify: webhook url '{}' isn't http:// or https://", url));
+            }
+            Sink::Command { command } if command.trim().is_empty() => return Err("notify: command is empty".to_string()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+
+pub fn start_run(hostname: &str) {
+    *HOSTNAME.lock().unwrap_or_else(|e| e.into_inner()) = hostname.to_string();
+}
+
+
+pub fn start_source(source: Option<Source>) {
+    SOURCE.set(source.map(|source| (source.kind(), source.name().to_string())));
+}
+
+
+fn wanted(config: &NotifyConfig, kind: EventKind) -> bool {
+    match (&config.sink, config.events.is_empty()) {
+        (_, false) => config.events.contains(&kind),
+        (Sink::Email { .. }, true) => kind == EventKind::RunFinished,
+        (_, true) => true,
+    }
+}
+
+
+// A header field ends at a line break, so one in its value would start
+// headers of its own
+fn header_value(value: &str) -> String {
+    value.chars().map(|c| if c.is_control() { ' ' } else { c }).collect()
+}
+
+
+// Hand the event to every sink that wants it
+pub fn emit(event: Event) {
+    let kind = event.kind();
+    let sinks: Vec<NotifyConfig> = SINKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|config| wanted(config, kind))
+        .cloned()
+        .collect();
+    if sinks.is_empty() {
+        return;
+    }
+
+    let source = SOURCE.with_borrow(|source| source.clone());
+    let hostname = HOSTNAME.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let envelope = Envelope {
+        time: clock::iso_utc(SystemTime::now()),
+        hostname: hostname.clone(),
+        run_id: run_id::current(),
+        job_id: run_id::job(),
+        kind: source.as_ref().map(|(kind, _)| *kind),
+        source: source.as_ref().map(|(_, name)| name.as_str()),
+        event: &event,
+    };
+    let json = match serde_json::to_string(&envelope) {
+        Ok(json) => json,
+        Err(e) => {
+            eprintln!("Warning: Failed to serialize {} event: {}", kind.as_str(), e);
+            return;
+        }
+    };
+    let description = event.describe(source.as_ref());
+
+    for config in &sinks {
+        let delivered = match &config.sink {
+            Sink::Console => {
+                println!("[{}] {}", kind.as_str(), description);
+                Ok(())
+            }
+            Sink::Log { path } => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut log| log.write_all(format!("{}\n", json).as_bytes()))
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e)),
+            Sink::Email { to, from } => {
+                // The description can hold anything, an error's output
+                // included, so it goes in the body with the JSON
+                let mut mail = format!("To: {}\n", header_value(&to.join(", ")));
+                if let Some(from) = from {
+                    mail.push_str(&format!("From: {}\n", header_value(from)));
+                }
+                mail.push_str(&format!("Subject: file-backup on {}: {}\n\n", header_value(&hostname), kind.as_str()));
+                mail.push_str(&format!("{}\n\n{}\n", description, json));
+                let mut command = child_env::command("sendmail");
+                command.args(["-t", "-i"]);
+                deliver(&mut command, "sendmail", &mail)
+            }
+            Sink::Webhook { url } => {
+                let mut command = child_env::command("curl");
+                command
+                    .args(["-fsS", "--max-time", "30", "-X", "POST", "-H", "Content-Type: application/json", "--data-binary", "@-"])
+                    .arg(url);
+                deliver(&mut command, "curl", &json)
+            }
+            // Run as the hooks of quiesce are, with the environment of file-backup itself
+            Sink::Command { command } => {
+                let mut shell = Command::new("sh");
+                shell.args(["-c", command]).env("FILE_BACKUP_EVENT", kind.as_str());
+                deliver(&mut shell, command, &json)
+            }
+        };
+        if let Err(e) = delivered {
+            eprintln!("Warning: {} notification of {} failed: {}", config.sink.as_str(), kind.as_str(), e);
+        }
+    }
+}
+
+
+// Run a sink's command with `input` on its stdin. These aren't recorded with
+// the commands a backup ran.
+fn deliver(command: &mut Command, name: &str, input: &str) -> Result<(), String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute {}: {}", name, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // One that doesn't read its input says why in its exit status
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    let output = child.wait_with_output().map_err(|e| format!("Failed to execute {}: {}", name, e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("'{}' exited with {}: {}", name, output.status, stderr.trim()));
+    }
+    Ok(())
+}
+
+
+// The job's own summary, once it has been backed up or given up on
+pub fn job_finished(summary: &SourceSummary) {
+    emit(Event::JobFinished {
+        status: summary.status.as_str(),
+        error: summary.error.as_deref(),
+        duration_secs: summary.duration_secs,
+        anomalies: &summary.anomalies,
+    });
+}
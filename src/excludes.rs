@@ -0,0 +1,81 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::filter_file::FilterFile;
+
+
+// [excludes] section: paths never backed up from any source, whether they
+// turn up in a full copy or in a snapshot diff. A name on its own (".zfs")
+// matches at any depth, one starting with / only from the top of the source.
+// The defaults are the snapshot directory a nested dataset with
+// snapdir=visible shows, which a full rsync would otherwise descend into
+// snapshot after snapshot, and fsck's lost+found.
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct ExcludesConfig {
+    #[serde(default = "default_auto")]
+    pub auto: Vec<String>,
+}
+
+impl Default for ExcludesConfig {
+    fn default() -> Self {
+        ExcludesConfig { auto: default_auto() }
+    }
+}
+
+fn default_auto() -> Vec<String> {
+    [".zfs", "/lost+found"].map(String::from).to_vec()
+}
+
+
+static PATTERNS: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+
+pub fn configure(config: &ExcludesConfig) {
+    *PATTERNS.lock().unwrap_or_else(|e| e.into_inner()) = Some(config.auto.clone());
+}
+
+
+fn patterns() -> Vec<String> {
+    PATTERNS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_else(default_auto)
+}
+
+
+pub fn validate(config: &ExcludesConfig) -> Result<(), String> {
+    for pattern in &config.auto {
+        let name = pattern.trim_start_matches('/');
+        if name.is_empty() || pattern.contains(['*', '?', '[']) {
+            return Err(format!("[excludes] '{}' should be a plain name or a path starting with /", pattern));
+        }
+    }
+    Ok(())
+}
+
+
+// What rsync is given for them and for the source's filter_file, if it has
+// one; rsync reads the patterns the same way
+pub fn rsync_args(filter: Option<&FilterFile>) -> Vec<OsString> {
+    let mut args: Vec<OsString> = patterns().iter().map(|pattern| format!("--exclude={}", pattern).into()).collect();
+    if let Some(filter) = filter {
+        let mut arg = OsString::from("--filter=merge ");
+        arg.push(&filter.path);
+        args.push(arg);
+    }
+    args
+}
+
+
+// Whether a path relative to the top of the source is excluded, by [excludes]
+// or the source's filter_file, or is under something that is
+pub fn matches(relative: &Path, is_dir: bool, filter: Option<&FilterFile>) -> bool {
+    patterns().iter().any(|pattern| match pattern.strip_prefix('/') {
+        Some(anchored) => relative.starts_with(anchored),
+        None => relative.components().any(|component| component.as_os_str() == pattern.as_str()),
+    }) || filter.is_some_and(|filter| filter.excludes(relative, is_dir))
+}
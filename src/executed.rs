@@ -0,0 +1,165 @@
+use rusqlite::Connection;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::process::{Child, Command, ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use crate::runlog::SourceSummary;
+
+
+// [commands] section
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq, Default)]
+pub struct CommandsConfig {
+    // Keep the commands each source ran in the database as well as in the
+    // run log on its target
+    #[serde(default)]
+    pub record_in_database: bool,
+}
+
+
+// A zfs, rsync or restic command a backup ran, as it could be typed into a
+// shell. The environment is left out, as it carries restic's credentials.
+#[derive(Debug, Serialize, Clone)]
+pub struct ExecutedCommand {
+    pub command: String,
+    // None when it was killed by a signal, couldn't be started, or was left
+    // running in the background (restic mount)
+    pub exit_code: Option<i32>,
+    pub duration_secs: f64,
+}
+
+
+// --show-commands: print each command as it is run
+static SHOW: AtomicBool = AtomicBool::new(false);
+
+
+// The commands run for one source, for its summary. Clones share the list,
+// so guards that run commands when dropped can keep one. Commands run outside
+// a source's backup go to a Log of their own that nothing reads.
+#[derive(Debug, Clone, Default)]
+pub struct Log {
+    commands: Arc<Mutex<Vec<ExecutedCommand>>>,
+}
+
+impl Log {
+    pub fn take(&self) -> Vec<ExecutedCommand> {
+        std::mem::take(&mut *self.commands.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+
+pub fn show(show: bool) {
+    SHOW.store(show, Ordering::Relaxed);
+}
+
+
+fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+
+fn describe(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+
+// A command that was started and is noted in its log once it exits
+pub struct Started {
+    command: String,
+    since: Instant,
+    log: Log,
+}
+
+impl Started {
+    pub fn new(command: &Command, log: &Log) -> Started {
+        let command = describe(command);
+        if SHOW.load(Ordering::Relaxed) {
+            println!("+ {}", command);
+        }
+        Started { command, since: Instant::now(), log: log.clone() }
+    }
+
+    pub fn finished(self, status: Option<&ExitStatus>) {
+        let exit_code = status.and_then(ExitStatus::code);
+        let duration_secs = self.since.elapsed().as_secs_f64();
+        if SHOW.load(Ordering::Relaxed) && exit_code != Some(0) {
+            let exit = exit_code.map_or("no exit code".to_string(), |code| format!("exit code {}", code));
+            println!("+ {} ({}, {:.1}s)", self.command, exit, duration_secs);
+        }
+        self.log
+            .commands
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(ExecutedCommand { command: self.command, exit_code, duration_secs });
+    }
+}
+
+
+// Run a command the way Command does, noting it in `log` for the run log
+pub trait Record {
+    fn recorded_output(&mut self, log: &Log) -> io::Result<Output>;
+    fn recorded_spawn(&mut self, log: &Log) -> io::Result<(Child, Started)>;
+}
+
+impl Record for Command {
+    fn recorded_output(&mut self, log: &Log) -> io::Result<Output> {
+        let started = Started::new(self, log);
+        let output = self.output();
+        started.finished(output.as_ref().ok().map(|output| &output.status));
+        output
+    }
+
+    fn recorded_spawn(&mut self, log: &Log) -> io::Result<(Child, Started)> {
+        let started = Started::new(self, log);
+        match self.spawn() {
+            Ok(child) => Ok((child, started)),
+            Err(e) => {
+                started.finished(None);
+                Err(e)
+            }
+        }
+    }
+}
+
+
+pub fn create_table(conn: &Connection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS executed_commands (
+            run_id INTEGER NOT NULL REFERENCES runs(id),
+            backup_type TEXT NOT NULL,
+            source_name TEXT NOT NULL,
+            command TEXT NOT NULL,
+            exit_code INTEGER,
+            duration_secs REAL NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+
+    Ok(())
+}
+
+
+pub fn record(conn: &Connection, run_id: i64, backup_type: &str, summary: &SourceSummary) -> Result<(), String> {
+    for executed in &summary.commands {
+        conn.execute(
+            "INSERT INTO executed_commands (run_id, backup_type, source_name, command, exit_code, duration_secs)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![run_id, backup_type, summary.name, executed.command, executed.exit_code, executed.duration_secs],
+        )
+        .map_err(|e| format!("Failed to record commands of '{}' in database: {}", summary.name, e))?;
+    }
+    Ok(())
+}
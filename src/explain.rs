@@ -0,0 +1,229 @@
+use rusqlite::Connection;
+
+use crate::executed::Log;
+use crate::tools::ToolVersions;
+use crate::zvol::ZvolMode;
+use crate::{Config, Layout, ResticMode, RunOptions, Source, device, estimate, excludes, pause, report, resync};
+
+
+fn lowercase(value: impl std::fmt::Debug) -> String {
+    format!("{:?}", value).to_lowercase()
+}
+
+
+fn print_config(source: Source) {
+    println!("Config:");
+    let template = match source {
+        Source::Dataset(d) => d.target_template.as_ref(),
+        Source::Restic(r) => r.target_template.as_ref(),
+    };
+    match template {
+        Some(template) => println!("  target_dir: {} (from {})", source.target_dir().display(), template.display()),
+        None => println!("  target_dir: {}", source.target_dir().display()),
+    }
+    println!("  layout: {}", lowercase(source.layout()));
+    println!("  priority: {}", source.priority());
+    if !source.after().is_empty() {
+        println!("  after: {}", source.after().join(", "));
+    }
+    if source.encryption().encrypt.is_some() {
+        println!("  encrypt: yes");
+    }
+    match source {
+        Source::Dataset(d) => {
+            if let Some(mode) = d.zvol_mode {
+                println!("  zvol_mode: {}", lowercase(mode));
+            }
+            println!("  descend: {}", lowercase(d.descend));
+            println!("  auto_snapshot: {}", d.auto_snapshot);
+        }
+        Source::Restic(r) => println!("  mode: {}", lowercase(r.mode)),
+    }
+    if let Some(every) = source.full_resync_every() {
+        println!("  full_resync_every: {}s", every.0);
+    }
+}
+
+
+// How the backup goes about it, given whether there is a diff base to go from
+fn method(source: Source, incremental: bool) -> String {
+    match source {
+        Source::Dataset(d) if d.zvol_mode == Some(ZvolMode::Send) => "the volume's zfs send stream, stored in restic".to_string(),
+        Source::Dataset(d) if d.zvol_mode == Some(ZvolMode::Device) => "the snapshot's volume device, stored in restic".to_string(),
+        _ if source.layout() == Layout::Stream && incremental => "zfs send -i from the base into a new stream file".to_string(),
+        _ if source.layout() == Layout::Stream => "zfs send of the whole snapshot into a stream file".to_string(),
+        Source::Dataset(d) if d.layout == Layout::Image => {
+            format!("{} of the whole snapshot into a new image file", d.image_format.unwrap_or_default().tool())
+        }
+        _ if source.layout() == Layout::Versioned && source.link_pool().is_some() => format!(
+            "rsync of the whole snapshot into a new version, hard-linking files unchanged since the last or in common with link_pool '{}'",
+            source.link_pool().unwrap_or_default()
+        ),
+        _ if source.layout() == Layout::Versioned => {
+            "rsync of the whole snapshot into a new version, hard-linking files unchanged since the last".to_string()
+        }
+        _ if source.encryption().encrypt.is_some() => "rsync of the whole snapshot, encrypting what changed".to_string(),
+        Source::Restic(r) if r.mode == ResticMode::Restore => "restic restore of the snapshot into the target".to_string(),
+        Source::Dataset(_) if incremental => "zfs diff from the base, then rsync of the changed paths".to_string(),
+        Source::Restic(_) if incremental => "rsync --dry-run between the base and latest snapshots of the mounted repository, then rsync of what differs".to_string(),
+        _ => "rsync of the whole snapshot".to_string(),
+    }
+}
+
+
+fn print_target(source: Source) {
+    println!("Target:");
+    let target_dir = source.target_dir();
+    if let Err(e) = crate::check_target_directory(target_dir) {
+        println!("  {}", e);
+        if source.device().auto_mount || source.device().luks_uuid.is_some() {
+            println!("  the device is mounted there when the backup starts");
+        }
+        return;
+    }
+    match device::find_mount(target_dir) {
+        Ok(mount) => println!("  on {} mounted at {}", mount.device, mount.mount_point.display()),
+        Err(e) => println!("  {}", e),
+    }
+    match estimate::free_space(target_dir) {
+        Ok(free) => println!("  {} free", report::format_bytes(free)),
+        Err(e) => println!("  {}", e),
+    }
+}
+
+
+// The changes an incremental mirror backup of a dataset would copy and delete
+fn print_dataset_changes(
+    source: Source,
+    conn: &Connection,
+    options: &RunOptions,
+    tool_versions: &ToolVersions,
+    base: &str,
+    latest: &str,
+) -> Result<(), String> {
+    let Source::Dataset(dataset_config) = source else {
+        return Ok(());
+    };
+    let mountpoint = crate::get_dataset_mountpoint(&dataset_config.name, &Log::default())?;
+    let snapshot_mountpoint = crate::get_snapshot_mountpoint(latest, &Log::default())?;
+    let filter = source.filter();
+    let (mut changes, mut files_to_sync, mut files_to_delete, mut replaced) = (0, 0, 0, 0);
+    crate::get_snapshot_diff(base, latest, tool_versions, &Log::default(), &mut |change| {
+        changes += 1;
+        // As in the backup, a path removed and added back isn't deleted
+        if let Some((path, is_dir)) = crate::deleted_path(&change, &mountpoint)
+            && !excludes::matches(&path, is_dir, filter)
+            && std::fs::symlink_metadata(snapshot_mountpoint.join(&path)).is_err()
+        {
+            files_to_delete += 1;
+        }
+        if let Some((path, is_dir)) = crate::synced_path(&change, &mountpoint)
+            && !excludes::matches(&path, is_dir, filter)
+        {
+            if crate::replaces_directory(&snapshot_mountpoint, &dataset_config.target_dir, &path, dataset_config.symlinks) {
+                replaced += 1;
+            }
+            files_to_sync += 1;
+        }
+        Ok(())
+    })?;
+    println!(
+        "  incremental from {} to {}: {} change(s), {} path(s) to sync, {} to delete",
+        base, latest, changes, files_to_sync, files_to_delete
+    );
+    if replaced > 0 {
+        println!("  {} path(s) replace a directory on the target, synced once it is removed", replaced);
+    }
+
+    let delete_limit = crate::resolve_delete_limit(
+        conn,
+        options,
+        "dataset",
+        &dataset_config.name,
+        dataset_config.max_delete.as_ref(),
+        &dataset_config.target_dir,
+    )?;
+    if let Err(e) = crate::check_delete_limit(delete_limit, files_to_delete, &dataset_config.target_dir) {
+        println!("  but max_delete would stop it: {}", e);
+    }
+    Ok(())
+}
+
+
+// Walk through what a backup of the source would do and why, without
+// changing anything: what the config says, the snapshots and recorded state
+// it goes by, the diff base and method that follow, and the resulting plan
+pub fn explain(config: &Config, conn: &Connection, options: &RunOptions, tool_versions: &ToolVersions, name: &str) -> Result<(), String> {
+    let commands = Log::default();
+    let source = config.find_source(name)?;
+    println!("=== Explaining {} '{}' ===", source.kind(), source.name());
+
+    print_config(source);
+    if !source.enabled() {
+        println!("Plan:\n  nothing: the source is disabled");
+        return Ok(());
+    }
+    if let Some(reason) = pause::reason(conn, options.host_filter(), source)? {
+        println!("Plan:\n  skipped: {}", reason);
+        return Ok(());
+    }
+
+    println!("Snapshots:");
+    let latest = match source {
+        Source::Dataset(d) => crate::get_latest_snapshot(&d.name, &commands)?,
+        Source::Restic(r) => crate::get_latest_restic_snapshot(r, &commands)?,
+    };
+    match &latest {
+        Some(snapshot) => println!("  latest: {}", snapshot),
+        None => println!("  none yet"),
+    }
+
+    println!("Database:");
+    let freshness = report::source_freshness(conn, options.host_filter(), source)?;
+    match (&freshness.last_snapshot, &freshness.last_backup) {
+        (Some(snapshot), Some(at)) => println!("  last backup recorded: {} at {}", snapshot, at),
+        _ => println!("  no backup recorded"),
+    }
+    println!(
+        "  file state: {} file(s), {}",
+        freshness.file_count,
+        report::format_bytes(freshness.size_bytes)
+    );
+    if let Some(status) = &freshness.last_status {
+        println!("  last attempt: {}", status);
+    }
+
+    println!("Diff base:");
+    let base = crate::backup_base(conn, options, source, &commands)?;
+    let full_resync = base.is_some() && resync::is_due(conn, options.host_filter(), source);
+    match &base {
+        Some(_) if full_resync => println!("  not used: a full resync is due, as full_resync_every says"),
+        Some(base) => println!("  {}", base),
+        None => println!("  none, so the whole snapshot is copied"),
+    }
+    let base = base.filter(|_| !full_resync);
+
+    println!("Method:");
+    println!("  {}", method(source, base.is_some()));
+
+    print_target(source);
+
+    println!("Plan:");
+    let Some(latest) = latest else {
+        println!("  nothing: there is no snapshot to back up");
+        return Ok(());
+    };
+    let incremental_mirror = source.layout() == Layout::Mirror
+        && source.encryption().encrypt.is_none()
+        && matches!(source, Source::Dataset(d) if d.zvol_mode.is_none());
+    match base {
+        Some(base) if base == latest => println!("  nothing: {} is backed up already", latest),
+        Some(base) if incremental_mirror => print_dataset_changes(source, conn, options, tool_versions, &base, &latest)?,
+        Some(base) => println!("  back up {}, following on from {}", latest, base),
+        None => match estimate::estimate_size(source, &latest, &commands) {
+            Ok(size) => println!("  {} of {}, about {}", if full_resync { "full resync" } else { "full copy" }, latest, report::format_bytes(size)),
+            Err(_) => println!("  {} of {}", if full_resync { "full resync" } else { "full copy" }, latest),
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,330 @@
+use rusqlite::{Connection, DropBehavior, Transaction, params};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::excludes;
+use crate::filter_file::FilterFile;
+
+
+// Per-file record of what was last copied to the target for each source, so a
+// target that was seeded by other means can be checked against it instead of
+// being re-transferred from scratch
+pub fn create_table(conn: &Connection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS file_state (
+            backup_type TEXT NOT NULL,
+            source_name TEXT NOT NULL,
+            path TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            mtime INTEGER NOT NULL,
+            hash TEXT,
+            last_snapshot TEXT NOT NULL,
+            PRIMARY KEY(backup_type, source_name, path)
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+
+    Ok(())
+}
+
+
+pub fn count(conn: &Connection, backup_type: &str, source_name: &str) -> Result<u64, String> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM file_state WHERE backup_type = ?1 AND source_name = ?2",
+        [backup_type, source_name],
+        |row| row.get(0),
+    ).map_err(|e| format!("Failed to count file state: {}", e))
+}
+
+
+// What one backup did to the recorded state of a source, kept as the run's
+// statistics
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Changes {
+    pub full: bool,
+    pub files_before: u64,
+    pub files_synced: u64,
+    // For a full backup only the net drop in files is known
+    pub files_deleted: u64,
+    // Apparent size, and the space it takes up; far apart for sparse files
+    pub bytes_synced: u64,
+    pub bytes_allocated: u64,
+}
+
+
+// Replace the whole file state of a source with the contents of a snapshot tree,
+// used after a full backup
+pub fn record_full(
+    conn: &Connection,
+    backup_type: &str,
+    source_name: &str,
+    snapshot_name: &str,
+    root: &Path,
+    filter: Option<&FilterFile>,
+) -> Result<Changes, String> {
+    let files_before = count(conn, backup_type, source_name)?;
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+
+    tx.execute(
+        "DELETE FROM file_state WHERE backup_type = ?1 AND source_name = ?2",
+        [backup_type, source_name],
+    ).map_err(|e| format!("Failed to clear file state: {}", e))?;
+
+    let mut count = 0;
+    let mut bytes = 0;
+    let mut allocated = 0;
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = fs::read_dir(&dir)
+            .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+            let path = entry.path();
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            if excludes::matches(path.strip_prefix(root).unwrap_or(&path), is_dir, filter) {
+                continue;
+            }
+            let metadata = fs::symlink_metadata(&path)
+                .map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+
+            if metadata.is_dir() {
+                pending.push(path);
+                continue;
+            }
+
+            let relative_path = path.strip_prefix(root).unwrap_or(&path).to_string_lossy();
+            upsert(&tx, backup_type, source_name, snapshot_name, &relative_path, &metadata)?;
+            count += 1;
+            bytes += metadata.len();
+            allocated += metadata.blocks() * 512;
+        }
+    }
+
+    tx.commit().map_err(|e| format!("Failed to commit file state: {}", e))?;
+
+    Ok(Changes {
+        full: true,
+        files_before,
+        files_synced: count,
+        files_deleted: files_before.saturating_sub(count),
+        bytes_synced: bytes,
+        bytes_allocated: allocated,
+    })
+}
+
+
+// Apply an incremental diff to the file state of a source. `synced` paths are
+// re-read from the snapshot tree at `root`; `deleted` paths (and anything
+// beneath them) are dropped.
+pub fn apply_changes(
+    conn: &Connection,
+    backup_type: &str,
+    source_name: &str,
+    snapshot_name: &str,
+    root: &Path,
+    synced: &[PathBuf],
+    deleted: &[PathBuf],
+) -> Result<Changes, String> {
+    let mut update = Update::start(conn, backup_type, source_name, snapshot_name);
+    for path in deleted {
+        update.deleted(path);
+    }
+    for path in synced {
+        update.synced(root, path);
+    }
+    update.finish()
+}
+
+
+// Paths updated between commits of an Update
+const UPDATE_BATCH: u64 = 10_000;
+
+
+// The file state of a source updated a path at a time, as an incremental
+// backup comes to them rather than from lists of them. It is committed every
+// UPDATE_BATCH paths and when dropped, so a backup that fails part way leaves
+// the paths it got to updated; the retry diffs from the same snapshot and
+// updates them again. The first failure stops the updates and is returned by
+// finish.
+pub struct Update<'a> {
+    conn: &'a Connection,
+    backup_type: &'a str,
+    source_name: &'a str,
+    snapshot_name: &'a str,
+    tx: Option<Transaction<'a>>,
+    pending: u64,
+    changes: Changes,
+    error: Option<String>,
+}
+
+impl<'a> Update<'a> {
+    pub fn start(conn: &'a Connection, backup_type: &'a str, source_name: &'a str, snapshot_name: &'a str) -> Update<'a> {
+        let (files_before, error) = match count(conn, backup_type, source_name) {
+            Ok(files_before) => (files_before, None),
+            Err(e) => (0, Some(e)),
+        };
+        Update {
+            conn,
+            backup_type,
+            source_name,
+            snapshot_name,
+            tx: None,
+            pending: 0,
+            changes: Changes { files_before, ..Changes::default() },
+            error,
+        }
+    }
+
+    // A path re-read from the snapshot tree at `root`
+    pub fn synced(&mut self, root: &Path, path: &Path) {
+        let path = path.strip_prefix("/").unwrap_or(path);
+        match fs::symlink_metadata(root.join(path)) {
+            Ok(metadata) if !metadata.is_dir() => {
+                let (backup_type, source_name, snapshot_name) = (self.backup_type, self.source_name, self.snapshot_name);
+                self.apply(|conn| upsert(conn, backup_type, source_name, snapshot_name, &path.to_string_lossy(), &metadata));
+                self.changes.files_synced += 1;
+                self.changes.bytes_synced += metadata.len();
+                self.changes.bytes_allocated += metadata.blocks() * 512;
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Warning: Failed to stat {}: {}", path.display(), e),
+        }
+    }
+
+    // A path dropped with anything beneath it
+    pub fn deleted(&mut self, path: &Path) {
+        let path = path.to_string_lossy();
+        let path = path.trim_start_matches('/').trim_end_matches('/');
+        let (backup_type, source_name) = (self.backup_type, self.source_name);
+        let deleted = self.apply(|conn| {
+            conn.execute(
+                "DELETE FROM file_state
+                 WHERE backup_type = ?1 AND source_name = ?2
+                   AND (path = ?3 OR substr(path, 1, length(?3) + 1) = ?3 || '/')",
+                [backup_type, source_name, path],
+            ).map_err(|e| format!("Failed to update file state: {}", e))
+        });
+        self.changes.files_deleted += deleted.unwrap_or(0) as u64;
+    }
+
+    pub fn finish(mut self) -> Result<Changes, String> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.commit().map(|()| self.changes),
+        }
+    }
+
+    // Statements on the connection run in the open transaction, which is
+    // unchecked so the connection can still be shared meanwhile
+    fn apply<T>(&mut self, statement: impl FnOnce(&Connection) -> Result<T, String>) -> Option<T> {
+        if self.error.is_some() {
+            return None;
+        }
+        let result = self.begin().and_then(|()| statement(self.conn));
+        let result = result.and_then(|value| {
+            self.pending += 1;
+            if self.pending == UPDATE_BATCH {
+                self.commit()?;
+            }
+            Ok(value)
+        });
+        result.map_err(|e| self.error = Some(e)).ok()
+    }
+
+    fn begin(&mut self) -> Result<(), String> {
+        if self.tx.is_none() {
+            let mut tx = self.conn.unchecked_transaction()
+                .map_err(|e| format!("Failed to start transaction: {}", e))?;
+            tx.set_drop_behavior(DropBehavior::Commit);
+            self.tx = Some(tx);
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), String> {
+        self.pending = 0;
+        match self.tx.take() {
+            Some(tx) => tx.commit().map_err(|e| format!("Failed to commit file state: {}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+
+fn upsert(
+    conn: &Connection,
+    backup_type: &str,
+    source_name: &str,
+    snapshot_name: &str,
+    path: &str,
+    metadata: &fs::Metadata,
+) -> Result<(), String> {
+    // The hash is only known once a checksum pass has looked at the file, so a
+    // changed file loses its old one
+    conn.execute(
+        "INSERT INTO file_state (backup_type, source_name, path, size, mtime, hash, last_snapshot)
+         VALUES (?1, ?2, ?3, ?4, ?5, NULL, ?6)
+         ON CONFLICT(backup_type, source_name, path) DO UPDATE SET
+            size = excluded.size,
+            mtime = excluded.mtime,
+            hash = NULL,
+            last_snapshot = excluded.last_snapshot",
+        params![backup_type, source_name, path, metadata.size() as i64, metadata.mtime(), snapshot_name],
+    ).map_err(|e| format!("Failed to update file state: {}", e))?;
+
+    Ok(())
+}
+
+
+pub struct FoundFile {
+    pub backup_type: String,
+    pub source_name: String,
+    pub path: String,
+    pub size: i64,
+    pub mtime: i64,
+    // Snapshot the file was last copied from, i.e. when it last changed
+    pub last_snapshot: String,
+    // Latest backup of the source, which still holds the file
+    pub latest_snapshot: Option<String>,
+    pub backed_up_at: Option<String>,
+    pub target_dir: Option<String>,
+}
+
+
+// Files of every source matching a glob. A pattern without a "/" is matched
+// against file names alone, so "invoice-2023.pdf" is found in any directory.
+pub fn find(conn: &Connection, hostname: Option<&str>, pattern: &str) -> Result<Vec<FoundFile>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT f.backup_type, f.source_name, f.path, f.size, f.mtime, f.last_snapshot,
+                h.snapshot_name, h.backup_timestamp, h.target_dir
+         FROM file_state f
+         LEFT JOIN backup_history h ON h.id = (
+             SELECT id FROM backup_history
+             WHERE backup_type = f.backup_type AND source_name = f.source_name
+               AND (?2 IS NULL OR hostname = ?2)
+             ORDER BY backed_up_epoch DESC, id DESC LIMIT 1
+         )
+         WHERE f.path GLOB ?1 OR (instr(?1, '/') = 0 AND f.path GLOB '*/' || ?1)
+         ORDER BY f.backup_type, f.source_name, f.path"
+    ).map_err(|e| format!("Failed to search file state: {}", e))?;
+
+    stmt.query_map(params![pattern.trim_start_matches('/'), hostname], |row| {
+        Ok(FoundFile {
+            backup_type: row.get(0)?,
+            source_name: row.get(1)?,
+            path: row.get(2)?,
+            size: row.get(3)?,
+            mtime: row.get(4)?,
+            last_snapshot: row.get(5)?,
+            latest_snapshot: row.get(6)?,
+            backed_up_at: row.get(7)?,
+            target_dir: row.get(8)?,
+        })
+    })
+    .and_then(|rows| rows.collect())
+    .map_err(|e| format!("Failed to search file state: {}", e))
+}
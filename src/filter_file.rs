@@ -0,0 +1,193 @@
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::Config;
+
+
+// A source's filter_file: rsync filter rules kept outside the config, e.g.
+//
+//   - node_modules/
+//   - *.tmp
+//   + /home/
+//   + /home/alice/***
+//   - /home/*
+//
+// rsync reads the file itself, through --filter="merge <file>", and the same
+// rules are applied to the paths taken from a snapshot diff so the two agree.
+// Only include (+) and exclude (-) rules are understood here; the other rule
+// types of rsync(1)'s FILTER RULES are refused when the config is loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterFile {
+    pub path: PathBuf,
+    rules: Vec<Rule>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    include: bool,
+    // Starts with /, so matches from the top of the source
+    anchored: bool,
+    // Ends with /, so only matches directories
+    dir_only: bool,
+    // Has a / or **, so matches against the path rather than the name
+    full_path: bool,
+    pattern: String,
+}
+
+
+fn parse_rule(line: &str) -> Option<Rule> {
+    let (include, pattern) = match line.split_once([' ', '_']) {
+        Some(("+" | "include", pattern)) => (true, pattern),
+        Some(("-" | "exclude", pattern)) => (false, pattern),
+        _ => return None,
+    };
+    if pattern.is_empty() {
+        return None;
+    }
+    let anchored = pattern.starts_with('/');
+    let dir_only = pattern.ends_with('/') && pattern.len() > 1;
+    let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+    Some(Rule {
+        include,
+        anchored,
+        dir_only,
+        full_path: pattern.contains('/') || pattern.contains("**"),
+        pattern: pattern.to_string(),
+    })
+}
+
+
+fn load(path: &Path) -> Result<FilterFile, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read filter_file {}: {}", path.display(), e))?;
+    let mut rules = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        let rule = parse_rule(line).ok_or_else(|| {
+            format!(
+                "filter_file {} line {}: '{}' isn't an include (+) or exclude (-) rule, the only ones supported",
+                path.display(),
+                number + 1,
+                line
+            )
+        })?;
+        rules.push(rule);
+    }
+    Ok(FilterFile { path: path.to_path_buf(), rules })
+}
+
+
+// Read the sources' filter files. Called when the config is loaded, so a
+// file with rules rsync would read differently from us fails up front.
+pub fn prepare(config: &mut Config) -> Result<(), String> {
+    for dataset_config in &mut config.dataset {
+        if let Some(path) = &dataset_config.filter_file {
+            let filter = load(path).map_err(|e| format!("Dataset '{}': {}", dataset_config.name, e))?;
+            dataset_config.filter = Some(filter);
+        }
+    }
+    for restic_config in &mut config.restic {
+        if let Some(path) = &restic_config.filter_file {
+            let filter = load(path).map_err(|e| format!("Restic repository '{}': {}", restic_config.repository, e))?;
+            restic_config.filter = Some(filter);
+        }
+    }
+    Ok(())
+}
+
+
+// Shell-style matching as rsync does it: * and ? stop at a /, ** doesn't.
+// Like rsync's, it works on bytes, so names needn't be UTF-8.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|skip| glob(rest, &text[skip..])),
+        [b'*', rest @ ..] => {
+            let run = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=run).any(|skip| glob(rest, &text[skip..]))
+        }
+        [b'?', rest @ ..] => matches!(text, [c, ..] if *c != b'/') && glob(rest, &text[1..]),
+        [b'[', rest @ ..] => {
+            let Some(end) = rest.iter().skip(1).position(|&c| c == b']').map(|end| end + 1) else {
+                return matches!(text, [b'[', ..]) && glob(rest, &text[1..]);
+            };
+            let (negated, class) = match &rest[..end] {
+                [b'!' | b'^', class @ ..] => (true, class),
+                class => (false, class),
+            };
+            let Some(&c) = text.first() else {
+                return false;
+            };
+            let mut in_class = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    in_class |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    in_class |= class[i] == c;
+                    i += 1;
+                }
+            }
+            in_class != negated && c != b'/' && glob(&rest[end + 1..], &text[1..])
+        }
+        [b'\\', c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+
+impl Rule {
+    fn matches(&self, relative: &[u8], is_dir: bool) -> bool {
+        // dir/*** is dir itself as well as everything under it
+        if let Some(dir) = self.pattern.strip_suffix("/***") {
+            let shorter = Rule { pattern: dir.to_string(), full_path: dir.contains('/') || dir.contains("**"), ..self.clone() };
+            let under = Rule { pattern: format!("{}/**", dir), full_path: true, ..self.clone() };
+            return (is_dir && shorter.matches(relative, is_dir)) || under.matches(relative, is_dir);
+        }
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let pattern = self.pattern.as_bytes();
+        if !self.full_path {
+            let name = relative.rsplit(|&c| c == b'/').next().unwrap_or(relative);
+            return glob(pattern, name);
+        }
+        if self.anchored {
+            return glob(pattern, relative);
+        }
+        // Unanchored, a path pattern can match the end of the path after any /
+        glob(pattern, relative)
+            || relative.iter().enumerate().any(|(i, &c)| c == b'/' && glob(pattern, &relative[i + 1..]))
+    }
+}
+
+
+impl FilterFile {
+    // Whether rsync would leave out a path relative to the top of the source.
+    // It doesn't go into a directory it leaves out, so neither is anything
+    // under one; the directories a path is in are tried first.
+    pub fn excludes(&self, relative: &Path, is_dir: bool) -> bool {
+        let components: Vec<_> = relative
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.as_bytes()),
+                _ => None,
+            })
+            .collect();
+        (1..=components.len()).any(|depth| {
+            let path = components[..depth].join(&b'/');
+            let is_dir = depth < components.len() || is_dir;
+            self.rules
+                .iter()
+                .find(|rule| rule.matches(&path, is_dir))
+                .is_some_and(|rule| !rule.include)
+        })
+    }
+}
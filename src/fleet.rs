@@ -0,0 +1,265 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::report;
+use crate::rescue::quote;
+use crate::status::State;
+
+
+// A machine `fleet run` backs up over SSH, from the [hosts] section:
+//
+//   [hosts.nas]
+//   ssh = "root@nas.lan"
+//
+// It needs file-backup installed and configured itself; this only starts its
+// run and collects the summary.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct HostConfig {
+    // SSH destination [default: the host's name]
+    ssh: Option<String>,
+    // Extra arguments for ssh, e.g. ["-p", "2222"]
+    #[serde(default)]
+    ssh_options: Vec<String>,
+    // file-backup on that host, e.g. "sudo file-backup"
+    #[serde(default = "default_command")]
+    command: RemoteCommand,
+    // Config file on that host [default: file-backup's default]
+    config: Option<String>,
+}
+
+fn default_command() -> RemoteCommand {
+    RemoteCommand(vec!["file-backup".to_string()])
+}
+
+
+// The command as words, each quoted for the remote shell: a string is split
+// on whitespace, and a list (["sudo", "/opt/file backup/bin/file-backup"])
+// is taken as it is for words that have spaces of their own
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "toml::Value")]
+pub struct RemoteCommand(Vec<String>);
+
+impl TryFrom<toml::Value> for RemoteCommand {
+    type Error = String;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        let words: Vec<String> = match &value {
+            toml::Value::String(text) => text.split_whitespace().map(String::from).collect(),
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| item.as_str().map(String::from))
+                .collect::<Option<_>>()
+                .ok_or_else(|| format!("invalid command {}, expected a list of strings", value))?,
+            _ => return Err(format!("invalid command {}, expected a string or a list of strings", value)),
+        };
+        if words.is_empty() {
+            return Err("command is empty".to_string());
+        }
+        Ok(RemoteCommand(words))
+    }
+}
+
+
+// The parts of a remote run's summary the combined report shows
+#[derive(Debug, Deserialize)]
+struct RemoteRun {
+    sources: Vec<RemoteSource>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RemoteSource {
+    kind: String,
+    name: String,
+    status: String,
+    error: Option<String>,
+    #[serde(default)]
+    anomalies: Vec<String>,
+    duration_secs: u64,
+}
+
+impl RemoteSource {
+    // Missing disks and locked keys are what status would warn about rather
+    // than failures of the backup
+    fn state(&self) -> State {
+        match self.status.as_str() {
+            "ok" if self.anomalies.is_empty() => State::Ok,
+            "ok" | "device-not-present" | "locked" | "deferred" => State::Warn,
+            _ => State::Crit,
+        }
+    }
+}
+
+
+struct HostResult {
+    name: String,
+    // The remote run's summary as it came, for --json
+    summary: Option<serde_json::Value>,
+    error: Option<String>,
+}
+
+impl HostResult {
+    fn run(&self) -> Option<RemoteRun> {
+        self.summary.as_ref().and_then(|summary| RemoteRun::deserialize(summary).ok())
+    }
+
+    fn state(&self) -> State {
+        match (self.run(), &self.error) {
+            (Some(run), None) => run.sources.iter().map(RemoteSource::state).max().unwrap_or(State::Ok),
+            _ => State::Crit,
+        }
+    }
+}
+
+
+// The remote run sends its progress to stderr, shown here with the host's
+// name in front, and only the JSON summary to stdout
+fn run_host(name: &str, host: &HostConfig) -> HostResult {
+    let mut remote = host.command.0.clone();
+    if let Some(config) = &host.config {
+        remote.extend(["--config".to_string(), config.clone()]);
+    }
+    remote.extend(["--yes", "--summary", "-"].map(String::from));
+    let remote_command: Vec<String> = remote.iter().map(|arg| quote(arg)).collect();
+
+    let failed = |error: String| HostResult { name: name.to_string(), summary: None, error: Some(error) };
+
+    // ssh would take it for an option
+    let destination = host.ssh.as_deref().unwrap_or(name);
+    if destination.starts_with('-') {
+        return failed(format!("SSH destination '{}' starts with '-'", destination));
+    }
+
+    let mut child = match Command::new("ssh")
+        .args(["-o", "BatchMode=yes"])
+        .args(&host.ssh_options)
+        .arg(destination)
+        .arg("--")
+        .arg(remote_command.join(" "))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => return failed(format!("Failed to run ssh: {}", e)),
+    };
+
+    // The last error the remote printed explains a run that left no summary
+    let last_error = Arc::new(Mutex::new(None));
+    let stderr = child.stderr.take().map(|stderr| {
+        let name = name.to_string();
+        let last_error = Arc::clone(&last_error);
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                println!("[{}] {}", name, line);
+                if line.starts_with("Error") || line.starts_with("ssh:") {
+                    *last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(line);
+                }
+            }
+        })
+    });
+
+    let mut stdout = String::new();
+    if let Some(mut out) = child.stdout.take()
+        && let Err(e) = out.read_to_string(&mut stdout)
+    {
+        eprintln!("Warning: Failed to read the summary from {}: {}", name, e);
+    }
+    let status = child.wait();
+    if let Some(stderr) = stderr {
+        let _ = stderr.join();
+    }
+    let last_error = last_error.lock().unwrap_or_else(|e| e.into_inner()).take();
+
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => return failed(format!("Failed to wait for ssh: {}", e)),
+    };
+    // ssh itself exits 255 when it can't connect
+    if status.code() == Some(255) {
+        return failed(last_error.unwrap_or_else(|| "ssh failed to connect".to_string()));
+    }
+    match serde_json::from_str::<serde_json::Value>(&stdout) {
+        Ok(summary) => HostResult { name: name.to_string(), summary: Some(summary), error: None },
+        Err(_) => failed(last_error.unwrap_or_else(|| format!("Run left no summary ({})", status))),
+    }
+}
+
+
+// Back up every host in the [hosts] section at once, or only `only`, and
+// print a combined report. Returns the worst state among them.
+pub fn run(hosts: &BTreeMap<String, HostConfig>, only: &[String], json_path: Option<&Path>) -> Result<State, String> {
+    if let Some(unknown) = only.iter().find(|name| !hosts.contains_key(*name)) {
+        return Err(format!("'{}' is not a host in the [hosts] section of the config file", unknown));
+    }
+    let selected: Vec<(&String, &HostConfig)> = hosts
+        .iter()
+        .filter(|(name, _)| only.is_empty() || only.contains(name))
+        .collect();
+    if selected.is_empty() {
+        return Err("No hosts in the [hosts] section of the config file".to_string());
+    }
+
+    println!("Backing up {} host(s): {}\n", selected.len(), selected.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", "));
+    let results: Vec<HostResult> = thread::scope(|scope| {
+        let handles: Vec<_> = selected
+            .iter()
+            .map(|(name, host)| scope.spawn(move || run_host(name, host)))
+            .collect();
+        handles.into_iter().filter_map(|handle| handle.join().ok()).collect()
+    });
+
+    println!("\nFleet run:");
+    for result in &results {
+        let state = result.state();
+        match (result.run(), &result.error) {
+            (Some(run), None) => {
+                println!("  {}: {}, {} source(s)", result.name, state.label(), run.sources.len());
+                for source in &run.sources {
+                    let detail = match (&source.error, source.anomalies.is_empty()) {
+                        (Some(error), _) => format!(" - {}", error),
+                        (None, false) => format!(" - {}", source.anomalies.join("; ")),
+                        (None, true) => String::new(),
+                    };
+                    println!(
+                        "    {} '{}': {} in {}{}",
+                        source.kind,
+                        source.name,
+                        source.status,
+                        report::format_age(source.duration_secs as i64),
+                        detail
+                    );
+                }
+            }
+            (_, error) => println!("  {}: {} - {}", result.name, state.label(), error.as_deref().unwrap_or("unreadable summary")),
+        }
+    }
+
+    if let Some(path) = json_path {
+        let combined: Vec<serde_json::Value> = results
+            .iter()
+            .map(|result| {
+                json!({
+                    "host": result.name,
+                    "state": result.state().label(),
+                    "error": result.error,
+                    "summary": result.summary,
+                })
+            })
+            .collect();
+        let text = serde_json::to_string_pretty(&json!({ "hosts": combined }))
+            .map_err(|e| format!("Failed to serialize fleet report: {}", e))?;
+        fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        println!("\nWrote {}", path.display());
+    }
+
+    Ok(results.iter().map(HostResult::state).max().unwrap_or(State::Ok))
+}
@@ -0,0 +1,349 @@
+use rusqlite::Connection;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::compression::Compression;
+use crate::executed::{Log, Record};
+use crate::{DatasetConfig, RunOptions, Source, child_env, clock, estimate, events};
+
+
+// In the image layout each backup is a compressed, read-only filesystem
+// image of the whole snapshot in a file of its own, for archival targets that
+// are shipped to cold storage: a handful of large files copies and checks
+// far better than millions of small ones, and any one image can be mounted
+// or unpacked without the others. The index maps each snapshot to its image,
+// oldest first, so the target describes itself without the database.
+pub const IMAGE_INDEX: &str = ".file-backup-images";
+
+
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    // Built with mksquashfs, unpacked with unsquashfs
+    #[default]
+    Squashfs,
+    // Built with mkfs.erofs, unpacked with fsck.erofs --extract
+    Erofs,
+}
+
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Squashfs => "sqfs",
+            ImageFormat::Erofs => "erofs",
+        }
+    }
+
+    pub fn tool(self) -> &'static str {
+        match self {
+            ImageFormat::Squashfs => "mksquashfs",
+            ImageFormat::Erofs => "mkfs.erofs",
+        }
+    }
+
+    // Used unless image_compression says otherwise
+    fn default_compression(self) -> &'static str {
+        match self {
+            ImageFormat::Squashfs => "zstd",
+            ImageFormat::Erofs => "lz4hc",
+        }
+    }
+}
+
+
+pub struct ImageFile {
+    pub file: String,
+    pub snapshot: String,
+    // When it was written, as clock::compact_utc gives it
+    pub created: String,
+    pub size: u64,
+    // As compression = gave it; None for images written with the tool's
+    // default or image_compression
+    pub compression: Option<Compression>,
+}
+
+
+// Images listed in the index, oldest first
+pub fn read_index(target_dir: &Path) -> Result<Vec<ImageFile>, String> {
+    let path = target_dir.join(IMAGE_INDEX);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+
+    // One "<file>\t<snapshot>\t<created>\t<size>[\t<compression>]" line per image
+    contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let (file, snapshot, created, size, compression) = match fields.as_slice() {
+                [file, snapshot, created, size] => (file, snapshot, created, size, None),
+                [file, snapshot, created, size, compression] => (
+                    file,
+                    snapshot,
+                    created,
+                    size,
+                    Some(Compression::parse(compression).map_err(|e| format!("Invalid line in {}: {}", path.display(), e))?),
+                ),
+                _ => return Err(format!("Invalid line in {}: {}", path.display(), line)),
+            };
+            Ok(ImageFile {
+                file: file.to_string(),
+                snapshot: snapshot.to_string(),
+                created: created.to_string(),
+                size: size.parse().map_err(|_| format!("Invalid size in {}: {}", path.display(), line))?,
+                compression,
+            })
+        })
+        .collect()
+}
+
+
+fn index_line(image: &ImageFile) -> String {
+    match image.compression {
+        Some(compression) => format!("{}\t{}\t{}\t{}\t{}\n", image.file, image.snapshot, image.created, image.size, compression),
+        None => format!("{}\t{}\t{}\t{}\n", image.file, image.snapshot, image.created, image.size),
+    }
+}
+
+
+fn append_index(target_dir: &Path, image: &ImageFile) -> Result<(), String> {
+    let path = target_dir.join(IMAGE_INDEX);
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    index.write_all(index_line(image).as_bytes())
+        .and_then(|()| index.sync_all())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+
+fn build_image(format: ImageFormat, compression_args: &[String], root: &Path, path: &Path, commands: &Log) -> Result<u64, String> {
+    // Built under a temporary name so a partial image is never indexed, and
+    // one left by an interrupted run is built again from scratch
+    let temp_path = path.with_extension(format!("{}.tmp", format.extension()));
+    if let Err(e) = fs::remove_file(&temp_path)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        return Err(format!("Failed to remove {}: {}", temp_path.display(), e));
+    }
+
+    let mut command = child_env::command(format.tool());
+    match format {
+        ImageFormat::Squashfs => {
+            command.arg(root).arg(&temp_path).args(["-noappend", "-no-progress"]).args(compression_args);
+        }
+        ImageFormat::Erofs => {
+            command.args(compression_args).arg(&temp_path).arg(root);
+        }
+    }
+    let output = command
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute {}: {}", format.tool(), e))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&temp_path);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{} failed: {}", format.tool(), stderr.trim()));
+    }
+
+    let file = fs::File::open(&temp_path)
+        .map_err(|e| format!("Failed to open {}: {}", temp_path.display(), e))?;
+    file.sync_all()
+        .map_err(|e| format!("Failed to sync {}: {}", temp_path.display(), e))?;
+    let size = file.metadata()
+        .map_err(|e| format!("Failed to stat {}: {}", temp_path.display(), e))?
+        .len();
+    fs::rename(&temp_path, path)
+        .map_err(|e| format!("Failed to rename {}: {}", temp_path.display(), e))?;
+
+    Ok(size)
+}
+
+
+pub fn backup(dataset_config: &DatasetConfig, conn: &Connection, options: &RunOptions, commands: &Log) -> Result<(), String> {
+    let target_dir = &dataset_config.target_dir;
+    crate::check_target_directory(target_dir)?;
+    // The image is built from the snapshot's files under .zfs/snapshot
+    if !crate::is_dataset_mounted(&dataset_config.name, commands)? {
+        return Err(format!("Dataset '{}' is NOT mounted", dataset_config.name));
+    }
+
+    let last_backup = match crate::get_last_backed_up_snapshot(conn, options.host_filter(), Source::Dataset(dataset_config), commands) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("Warning: Failed to query database: {}", e);
+            None
+        }
+    };
+    let latest_snapshot = crate::get_latest_snapshot(&dataset_config.name, commands)?
+        .ok_or_else(|| format!("No snapshots found for dataset '{}'", dataset_config.name))?;
+    println!("Latest snapshot: {}", latest_snapshot);
+    println!("Target directory: {}", target_dir.display());
+    events::emit(events::Event::SnapshotSelected { snapshot: &latest_snapshot, base: last_backup.as_deref() });
+
+    if last_backup.as_deref() == Some(latest_snapshot.as_str()) {
+        println!("Already backed up - nothing to do");
+        println!();
+        return Ok(());
+    }
+    if last_backup.is_none() {
+        estimate::confirm_first_backup(Source::Dataset(dataset_config), &latest_snapshot, options, commands)?;
+    }
+
+    let format = dataset_config.image_format.unwrap_or_default();
+    let (compression, compression_args) = match (dataset_config.compression, &dataset_config.image_compression) {
+        (Some(compression), _) => (compression.to_string(), compression.image_args(format)),
+        (None, image_compression) => {
+            let compression = image_compression.as_deref().unwrap_or(format.default_compression());
+            let args = match format {
+                ImageFormat::Squashfs => vec!["-comp".to_string(), compression.to_string()],
+                ImageFormat::Erofs => vec![format!("-z{}", compression)],
+            };
+            (compression.to_string(), args)
+        }
+    };
+    // Numbered on from the last image, as gc removes images from the start
+    let index = read_index(target_dir)?;
+    let number = index
+        .last()
+        .and_then(|image| image.file.split('-').next()?.parse::<usize>().ok())
+        .unwrap_or(0) + 1;
+    let created = clock::compact_utc(SystemTime::now());
+    let file = format!("{:06}-{}.{}", number, created, format.extension());
+    let path = target_dir.join(&file);
+
+    println!("Building {} image of {} in {} ({})...", format.tool(), latest_snapshot, file, compression);
+    let snapshot_mountpoint = crate::get_snapshot_mountpoint(&latest_snapshot, commands)?;
+    let size = build_image(format, &compression_args, &snapshot_mountpoint, &path, commands)?;
+    println!("Wrote {}", crate::report::format_bytes(size));
+    events::emit(events::Event::TransferProgress { step: format.tool(), files: None, bytes: Some(size) });
+
+    append_index(target_dir, &ImageFile {
+        file,
+        snapshot: latest_snapshot.clone(),
+        created,
+        size,
+        compression: dataset_config.compression,
+    })?;
+    crate::record_successful_backup(
+        conn,
+        &options.hostname,
+        Source::Dataset(dataset_config),
+        &latest_snapshot,
+        commands,
+        None,
+    )?;
+
+    println!("Backup recorded successfully");
+    println!();
+    Ok(())
+}
+
+
+// The image to restore: the newest, the one of snapshot `as_of`, or the
+// newest written at or before `as_of` as a UTC time
+pub fn select(target_dir: &Path, as_of: Option<&str>) -> Result<PathBuf, String> {
+    let index = read_index(target_dir)?;
+    let image = match as_of {
+        None => index.last(),
+        Some(as_of) if let Some(image) = index.iter().find(|image| image.snapshot == as_of) => Some(image),
+        Some(as_of) => {
+            let limit = clock::compact_from_iso(as_of)
+                .ok_or_else(|| format!("Invalid --as-of time '{}': expected e.g. 2024-05-01 or 2024-05-01T02:30:00", as_of))?;
+            index.iter().rfind(|image| image.created <= limit)
+        }
+    };
+    let image = image.ok_or_else(|| match as_of {
+        Some(as_of) => format!("No image in {} as old as {}", target_dir.display(), as_of),
+        None => format!("No images in {}", target_dir.display()),
+    })?;
+    match image.compression {
+        Some(compression) => println!("Restoring from image {}, snapshot {} ({})", image.file, image.snapshot, compression),
+        None => println!("Restoring from image {}, snapshot {}", image.file, image.snapshot),
+    }
+    Ok(target_dir.join(&image.file))
+}
+
+
+// Unpack an image into `destination`, or only `paths` in it, which
+// unsquashfs takes as paths in the image and erofs images don't support
+pub fn extract(image: &Path, destination: &Path, paths: &[String]) -> Result<(), String> {
+    let is_erofs = image.extension().is_some_and(|extension| extension == ImageFormat::Erofs.extension());
+    let mut command = if is_erofs {
+        if !paths.is_empty() {
+            return Err("An erofs image can only be restored in full".to_string());
+        }
+        let mut command = child_env::command("fsck.erofs");
+        command.arg(format!("--extract={}", destination.display())).arg(image);
+        command
+    } else {
+        let mut command = child_env::command("unsquashfs");
+        command.args(["-f", "-no-progress", "-d"]).arg(destination).arg(image).args(paths);
+        command
+    };
+
+    println!("Unpacking {} to {}...", image.display(), destination.display());
+    let tool = if is_erofs { "fsck.erofs" } else { "unsquashfs" };
+    let output = command
+        .recorded_output(&Log::default())
+        .map_err(|e| format!("Failed to execute {}: {}", tool, e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{} failed: {}", tool, stderr.trim()));
+    }
+    println!("Restored to {}", destination.display());
+    Ok(())
+}
+
+
+// Remove all but the newest `keep` images. Each image holds a whole
+// snapshot, so unlike streams none depends on another.
+pub fn gc(target_dir: &Path, keep: usize, dry_run: bool) -> Result<(), String> {
+    let index = read_index(target_dir)?;
+    let (obsolete, kept) = index.split_at(index.len().saturating_sub(keep));
+
+    println!(
+        "{} image(s) in {}: keeping {} from {}, removing {}",
+        index.len(),
+        target_dir.display(),
+        kept.len(),
+        kept.first().map(|image| image.file.as_str()).unwrap_or("none"),
+        obsolete.len()
+    );
+    if obsolete.is_empty() {
+        return Ok(());
+    }
+    if dry_run {
+        for image in obsolete {
+            println!("  Would remove {} ({})", image.file, image.snapshot);
+        }
+        return Ok(());
+    }
+
+    // Drop the images from the index first, so an interrupted gc never
+    // leaves the index listing files that are gone
+    let path = target_dir.join(IMAGE_INDEX);
+    let temp_path = target_dir.join(format!("{}.tmp", IMAGE_INDEX));
+    let contents: String = kept.iter().map(index_line).collect();
+    fs::write(&temp_path, contents)
+        .map_err(|e| format!("Failed to write {}: {}", temp_path.display(), e))?;
+    fs::rename(&temp_path, &path)
+        .map_err(|e| format!("Failed to rename {}: {}", temp_path.display(), e))?;
+
+    for image in obsolete {
+        println!("  Removing {} ({})", image.file, image.snapshot);
+        let file = target_dir.join(&image.file);
+        fs::remove_file(&file)
+            .map_err(|e| format!("Failed to remove {}: {}", file.display(), e))?;
+    }
+
+    Ok(())
+}
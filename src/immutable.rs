@@ -0,0 +1,94 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::child_env;
+
+
+// How much of a target to mark immutable (chattr +i) between backups
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImmutableScope {
+    // The target directory and the entries directly inside it
+    Top,
+    // Everything under the target
+    Tree,
+}
+
+
+// RAII guard: the target's immutable flag is lifted while the guard is alive
+// and set again when it is dropped, whether or not the backup succeeded
+pub struct ImmutableGuard {
+    target_dir: PathBuf,
+    scope: ImmutableScope,
+}
+
+impl Drop for ImmutableGuard {
+    fn drop(&mut self) {
+        println!("Marking {} immutable...", self.target_dir.display());
+        if let Err(e) = chattr(&self.target_dir, self.scope, "+i") {
+            eprintln!("Warning: Failed to mark {} immutable: {}", self.target_dir.display(), e);
+        }
+    }
+}
+
+
+pub fn unlock(target_dir: &Path, scope: Option<ImmutableScope>) -> Option<ImmutableGuard> {
+    let scope = scope?;
+
+    // A missing target is reported by the backup itself
+    if !target_dir.is_dir() {
+        return None;
+    }
+
+    println!("Lifting immutable flag on {}...", target_dir.display());
+    if let Err(e) = chattr(target_dir, scope, "-i") {
+        eprintln!("Warning: Failed to lift immutable flag on {}: {}", target_dir.display(), e);
+    }
+
+    Some(ImmutableGuard {
+        target_dir: target_dir.to_path_buf(),
+        scope,
+    })
+}
+
+
+fn chattr(target_dir: &Path, scope: ImmutableScope, flag: &str) -> Result<(), String> {
+    let mut command = child_env::command("chattr");
+
+    match scope {
+        ImmutableScope::Tree => {
+            command.args(["-R", flag]).arg(target_dir);
+        }
+        ImmutableScope::Top => {
+            command.arg(flag).arg(target_dir);
+            let entries = fs::read_dir(target_dir)
+                .map_err(|e| format!("Failed to read {}: {}", target_dir.display(), e))?;
+            // chattr refuses to touch symlinks and special files, so skip them
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                if entry.file_type().is_ok_and(|t| t.is_dir() || t.is_file()) {
+                    command.arg(entry.path());
+                }
+            }
+        }
+    }
+
+    let output = command.output().map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            "chattr is not installed".to_string()
+        } else {
+            format!("Failed to execute chattr: {}", e)
+        }
+    })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("Operation not supported") || stderr.contains("Inappropriate ioctl") {
+            return Err("the target filesystem doesn't support the immutable attribute".to_string());
+        }
+        return Err(format!("chattr failed: {}", stderr.trim()));
+    }
+
+    Ok(())
+}
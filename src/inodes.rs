@@ -0,0 +1,89 @@
+use rusqlite::Connection;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::executed::{Log, Record};
+use crate::{Layout, Source, file_state, privileges};
+
+
+fn statvfs(dir: &Path) -> Result<libc::statvfs, String> {
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| format!("Invalid path {}", dir.display()))?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(format!("Failed to stat filesystem of {}: {}", dir.display(), io::Error::last_os_error()));
+    }
+    Ok(stats)
+}
+
+
+// Files, directories and links in the snapshot. ZFS counts the objects in
+// use, which stand in for that closely enough; restic counts them itself.
+fn snapshot_entries(source: Source, snapshot: &str, commands: &Log) -> Result<u64, String> {
+    match source {
+        Source::Dataset(_) => {
+            let stats = statvfs(&crate::get_snapshot_mountpoint(snapshot, commands)?)?;
+            Ok(stats.f_files.saturating_sub(stats.f_ffree))
+        }
+        Source::Restic(restic_config) => {
+            let output = privileges::restic(&restic_config.repository, restic_config.user.as_ref())
+                .args(["stats", "--json", snapshot])
+                .recorded_output(commands)
+                .map_err(|e| format!("Failed to execute restic stats: {}", e))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("restic stats failed: {}", stderr.trim()));
+            }
+            let stats: serde_json::Value = serde_json::from_slice(&output.stdout)
+                .map_err(|e| format!("Failed to parse restic stats output: {}", e))?;
+            stats["total_file_count"].as_u64().ok_or_else(|| "restic stats didn't report total_file_count".to_string())
+        }
+    }
+}
+
+
+// Before copying a snapshot, check the target filesystem has inodes enough
+// for it. Small ext4 disks run out of inodes long before space with maildirs
+// and the like, and rsync then fails part way with a misleading "No space
+// left on device". Filesystems that allocate inodes as needed (btrfs, xfs,
+// zfs) report none free or plenty, and pass.
+pub fn preflight(conn: &Connection, source: Source, snapshot: &str, commands: &Log) -> Result<(), String> {
+    let target = statvfs(source.target_dir())?;
+    if target.f_files == 0 {
+        return Ok(());
+    }
+    let free = target.f_favail;
+
+    let entries = match snapshot_entries(source, snapshot, commands) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Warning: Can't count the files in {} to check the target's inodes: {}", snapshot, e);
+            return Ok(());
+        }
+    };
+    // Files already on the target keep their inodes: rsync replaces changed
+    // files one at a time, and versions hard-link unchanged ones
+    let on_target = file_state::count(conn, source.backup_type(), source.name()).unwrap_or(0);
+    let needed = entries.saturating_sub(on_target);
+    if needed <= free {
+        return Ok(());
+    }
+
+    let suggestion = match source {
+        Source::Dataset(_) if !matches!(source.layout(), Layout::Stream | Layout::Image) => {
+            "; use layout = \"stream\" or \"image\", which store the dataset in a few large files, or a filesystem with more inodes (mkfs.ext4 -i)"
+        }
+        _ => "; use a filesystem with more inodes (mkfs.ext4 -i)",
+    };
+    Err(format!(
+        "{} has {} free inode(s), but {} needs about {} more for {} entries{}",
+        source.target_dir().display(),
+        free,
+        snapshot,
+        needed,
+        entries,
+        suggestion
+    ))
+}
@@ -1,171 +1,2562 @@
-use clap::Parser;
-use rusqlite::{Connection, Result as SqliteResult};
+use clap::{Parser, Subcommand};
+use rusqlite::{Connection, OpenFlags, Result as SqliteResult};
+use schemars::JsonSchema;
 use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::{Command, exit};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio, exit};
+use std::time::{Duration, Instant, SystemTime};
+
+
+// println! and eprintln! everywhere go through output::print, which tells
+// apart the lines of sources backed up side by side
+macro_rules! println {
+    () => { $crate::output::print(false, format_args!("")) };
+    ($($arg:tt)*) => { $crate::output::print(false, format_args!($($arg)*)) };
+}
+
+macro_rules! eprintln {
+    () => { $crate::output::print(true, format_args!("")) };
+    ($($arg:tt)*) => { $crate::output::print(true, format_args!($($arg)*)) };
+}
+
+mod adopt;
+mod anomaly;
+mod auto_snapshot;
+mod budget;
+mod build_info;
+mod changed_files;
+mod child_env;
+mod clock;
+mod compression;
+mod concurrency;
+mod config_schema;
+mod control;
+mod daemon;
+mod db_export;
+mod encrypted;
+mod estimate;
+mod events;
+mod excludes;
+mod executed;
+mod explain;
+mod device;
+mod device_fault;
+mod diff_cache;
+mod drift;
+mod file_state;
+mod filter_file;
+mod fleet;
+mod images;
+mod immutable;
+mod inodes;
+mod metadata;
+mod migrate;
+mod nested;
+mod order;
+mod output;
+mod ownership;
+mod pause;
+mod platform;
+mod pool;
+mod privileges;
+mod report;
+mod rescue;
+mod rsync_exit;
+mod selftest;
+mod snapshot_age;
+mod sha256;
+mod sparse;
+mod special_files;
+mod symlinks;
+mod template;
+mod timeline;
+mod queue;
+mod quiesce;
+mod rename;
+mod resources;
+mod restic_cache;
+mod restic_env;
+mod restic_lock;
+mod restic_stats;
+mod restic_verify;
+mod restore;
+mod resync;
+mod run_id;
+mod runlog;
+mod state;
+mod status;
+mod streams;
+mod tools;
+mod trash;
+mod units;
+mod verify_sample;
+mod versioned;
+mod zfs_allow;
+mod zfs_keys;
+mod zvol;
+
+use anomaly::AnomalyConfig;
+use auto_snapshot::SnapshotTemplate;
+use changed_files::ChangedFiles;
+use child_env::EnvironmentConfig;
+use compression::Compression;
+use concurrency::ConcurrencyConfig;
+use db_export::ConflictPolicy;
+use device::{DeviceConfig, MountError};
+use encrypted::EncryptionConfig;
+use filter_file::FilterFile;
+use executed::{CommandsConfig, Log, Record};
+use immutable::ImmutableScope;
+use pool::PoolError;
+use report::ReportConfig;
+use restic_cache::ResticCacheConfig;
+use resources::{ResourcesConfig, SchedulingConfig};
+use rsync_exit::RsyncConfig;
+use runlog::{SourceStatus, SourceSummary};
+use snapshot_age::SnapshotAgeConfig;
+use special_files::SpecialFiles;
+use symlinks::Symlinks;
+use status::Thresholds;
+use zfs_keys::{DatasetKeyConfig, UnlockError};
+use tools::ToolVersions;
+use units::{ConfigDuration, Days, Percentage, Schedule};
 
 #[derive(Parser, Debug)]
 #[command(name = "file-backup")]
 #[command(about = "Backup ZFS filesystems, ZVOLs, and Restic repositories", long_about = None)]
 struct Args {
     /// Path to configuration file
-    #[arg(short, long, default_value = "/etc/file-backup/backup-config.toml")]
+    #[arg(short, long, global = true, default_value = "/etc/file-backup/backup-config.toml")]
     config: PathBuf,    
     
-    /// Path to database file
-    #[arg(short, long, default_value = "/var/lib/file-backup/backup.db")]
+    /// Path to database file [default: the config's [state] path, or /var/lib/file-backup/backup.db]
+    #[arg(short, long, global = true)]
+    database: Option<PathBuf>,
+    
+    /// Use backup history recorded by any host sharing the database, not just this one
+    #[arg(long, global = true)]
+    any_host: bool,
+    
+    /// Carry out deletions even when they exceed a source's max_delete
+    #[arg(long, global = true)]
+    force_delete: bool,
+    
+    /// Back up datasets from a DEGRADED pool even where unhealthy_pool = "fail" would keep their targets as they are
+    #[arg(long, global = true)]
+    allow_degraded: bool,
+    
+    /// Don't ask for confirmation before the first full backup of a source, or what to do about a drifted target
+    #[arg(long, global = true)]
+    yes: bool,
+    
+    /// Run without root, relying on `zfs allow` delegations; sources needing root for other things fail up front
+    #[arg(long, global = true)]
+    unprivileged: bool,
+    
+    /// Run restic without its local cache
+    #[arg(long, global = true)]
+    no_cache: bool,
+    
+    /// Print each zfs, rsync and restic command as it is run
+    #[arg(long, global = true)]
+    show_commands: bool,
+    
+    /// Print the output of each source backed up side by side in one block when it is done, rather than line by line prefixed with its name
+    #[arg(long, global = true)]
+    group_output: bool,
+    
+    // Running without a subcommand is the same as `run`, and takes its options
+    #[command(flatten)]
+    run: RunArgs,
+    
+    /// Print the version and exit
+    #[arg(short = 'V', long)]
+    version: bool,
+    
+    /// With --version, print build information as JSON
+    #[arg(long, requires = "version")]
+    json: bool,
+    
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+
+// Which sources a backup run covers, and what it reports
+#[derive(clap::Args, Debug, Default, PartialEq)]
+struct RunArgs {
+    /// Back up only the sources whose target is on this disk, given as a mount point or filesystem label
+    #[arg(long, value_name = "PATH|LABEL")]
+    target: Option<String>,
+    
+    /// Back up only this source, a dataset name or restic repository as written in the config
+    #[arg(long, value_name = "NAME", conflicts_with = "target")]
+    source: Option<String>,
+    
+    /// With --source, start the incremental backup from this snapshot rather than the last one recorded, e.g. after the database was restored from an older copy
+    #[arg(long, value_name = "SNAPSHOT", requires = "source")]
+    since: Option<String>,
+    
+    /// Also write a JSON summary of the run to this file; "-" sends it to stdout and everything else to stderr
+    #[arg(long, value_name = "FILE")]
+    summary: Option<PathBuf>,
+    
+    /// List every change zfs diff finds, rather than the counts and the first few
+    #[arg(long)]
+    print_changes: bool,
+}
+
+
+// Settings that apply to the whole invocation rather than to one source
+struct RunOptions {
+    // Backup history is recorded per host so machines sharing a database
+    // don't pick up each other's incremental state
+    hostname: String,
+    any_host: bool,
+    force_delete: bool,
+    allow_degraded: bool,
+    // Ask before the first full copy of a source, and what to do about a
+    // drifted target; only when someone is at the terminal
+    confirm_first_backup: bool,
+    // The run lock and trigger queue live next to the database
     database: PathBuf,
+    unprivileged: bool,
+    // --since: the snapshot to diff against instead of the last one backed up
+    since: Option<String>,
+    print_changes: bool,
+}
+
+impl RunOptions {
+    fn host_filter(&self) -> Option<&str> {
+        if self.any_host { None } else { Some(&self.hostname) }
+    }
+}
+
+
+// Running without a subcommand backs up every configured source
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Back up every configured source, or the ones picked with --target or --source; the same as running without a subcommand
+    Run(RunArgs),
+    
+    /// Record a target that was seeded by other means as a backup of a snapshot
+    AdoptTarget {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+        
+        /// Snapshot the target was seeded from
+        #[arg(long)]
+        snapshot: String,
+        
+        /// Compare file contents rather than just sizes and modification times
+        #[arg(long)]
+        checksum: bool,
+    },
+    
+    /// Keep running, backing up all sources on a schedule
+    Daemon {
+        /// Time between the start of one run and the next, e.g. 6h [default: [daemon] schedule, or 1d]
+        #[arg(long, value_parser = units::parse_duration, conflicts_with = "schedule")]
+        interval: Option<u64>,
+        
+        /// When to start runs, e.g. daily@02:00 or "0 */6 * * *" (UTC) [default: [daemon] schedule]
+        #[arg(long, value_parser = units::parse_schedule)]
+        schedule: Option<Schedule>,
+        
+        /// Time between checks of the config file for changes
+        #[arg(long, value_parser = units::parse_duration, default_value = "60")]
+        config_check_interval: u64,
+    },
+    
+    /// Remove files kept in target trash directories past their retention
+    PurgeTrash {
+        /// Only purge the target of this dataset or restic repository
+        source: Option<String>,
+        
+        /// Remove everything in the trash, regardless of retention
+        #[arg(long)]
+        all: bool,
+    },
+    
+    /// Remove the oldest versions of a source using the versioned layout
+    Prune {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+        
+        /// Number of most recent versions to keep
+        #[arg(long)]
+        keep: usize,
+        
+        /// Actually remove versions rather than listing what would be removed
+        #[arg(long)]
+        confirm: bool,
+    },
+    
+    /// Remove the versions, stream files or images of a source that aren't needed to restore its newest backups
+    Gc {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+        
+        /// Number of most recent backups that must stay restorable
+        #[arg(long)]
+        keep: usize,
+        
+        /// List what would be removed without removing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    
+    /// Stop backing up a source for a while, e.g. during a migration; status reports it as paused
+    Pause {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+        
+        /// How long to pause for, e.g. "7d" or "12h" [default: until resumed]
+        #[arg(long = "for", value_name = "DURATION")]
+        duration: Option<String>,
+    },
+    
+    /// Lift a pause set with pause
+    Resume {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+    },
+    
+    /// Find which sources' backups hold files matching a glob, from the recorded file state
+    Find {
+        /// Glob such as "invoice-2023.pdf" or "home/alice/*.pdf"; without a "/" only file names are matched
+        pattern: String,
+    },
+    
+    /// List what a backup changed on its target, as kept by [rsync] record_changes
+    Changes {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+        
+        /// The backup of this snapshot rather than the newest
+        #[arg(long)]
+        snapshot: Option<String>,
+    },
+    
+    /// Write a restore script and the machine's dataset, pool and disk layout onto a source's target
+    MakeRescue {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+    },
+    
+    /// Render run history and the state of each source to a static HTML page
+    Report {
+        /// Number of most recent runs to include [default: from config, else 20]
+        #[arg(long)]
+        last: Option<usize>,
+        
+        /// File to write [default: html from the [report] config section]
+        #[arg(long, value_name = "FILE")]
+        html: Option<PathBuf>,
+    },
+    
+    /// List the most recent backup attempts and how each went
+    History {
+        /// Only list this dataset or restic repository
+        source: Option<String>,
+        
+        /// Number of attempts to list
+        #[arg(long, default_value_t = 20)]
+        last: usize,
+    },
+    
+    /// Show how recent each source's backup is, exiting 1 (warn) or 2 (crit) when a threshold is crossed
+    Status {
+        /// Only show this dataset or restic repository
+        source: Option<String>,
+    },
+    
+    /// Check one source as a Nagios/Icinga plugin: a single status line with perfdata, exiting 0/1/2/3
+    Check {
+        /// Dataset name or restic repository, as written in the config
+        #[arg(long)]
+        source: String,
+    },
+    
+    /// Back up one dataset now, for ZFS event daemon (zed) scripts to call when a snapshot is taken.
+    /// If another backup is running the dataset is queued behind it.
+    Trigger {
+        /// Dataset name, as written in the config
+        #[arg(long)]
+        dataset: String,
+        
+        /// The snapshot that was just created, with or without the "dataset@" prefix
+        #[arg(long)]
+        snapshot: String,
+    },
+    
+    /// Copy the latest backup of a source out of its target, decrypting it if it is encrypted
+    Restore {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+        
+        /// Directory to restore into; must be empty or not exist yet
+        #[arg(long, value_name = "DIR")]
+        to: PathBuf,
+        
+        /// age identity for an encrypted target [default: from the config]
+        #[arg(long, value_name = "FILE")]
+        identity: Option<PathBuf>,
+        
+        /// Only restore paths matching this rsync pattern, relative to the backup's root (repeatable)
+        #[arg(long = "path", value_name = "PATTERN")]
+        paths: Vec<String>,
+        
+        /// Restore the latest version at or before this UTC time, or the version of this snapshot, for the versioned layout
+        #[arg(long, value_name = "TIME|SNAPSHOT")]
+        as_of: Option<String>,
+        
+        /// List what would be restored and overwritten without copying anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    
+    /// Check a target against the last snapshot backed up to it, or decrypt every file of an encrypted target
+    Verify {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+        
+        /// age identity for an encrypted target [default: from the config]
+        #[arg(long, value_name = "FILE")]
+        identity: Option<PathBuf>,
+        
+        /// Compare file contents rather than just sizes and modification times
+        #[arg(long)]
+        checksum: bool,
+        
+        /// Percentage of a restic source's files to compare by contents, read back with restic dump [default: verify_sample, or 1]
+        #[arg(long, value_name = "PERCENT", conflicts_with = "checksum")]
+        sample: Option<f64>,
+    },
+    
+    /// Check the stream files of sources using the stream layout, with par2 where there is parity data
+    VerifyStreams {
+        /// Only check the target of this dataset
+        source: Option<String>,
+        
+        /// Repair damaged stream files from their parity data
+        #[arg(long)]
+        repair: bool,
+    },
+    
+    /// Send a command to a running daemon over its control socket
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+    
+    /// Export or import the backup database
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    
+    /// Back up the machines in the [hosts] section over SSH
+    Fleet {
+        #[command(subcommand)]
+        command: FleetCommand,
+    },
+    
+    /// Move a source's backups to a new target, e.g. a disk replacing a failing one, switching over once the copy checks out
+    MigrateTarget {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+        
+        /// Empty directory on the new disk
+        #[arg(long)]
+        to: PathBuf,
+        
+        /// Copy the last snapshot backed up instead of the old target, for when the old disk can't be read
+        #[arg(long)]
+        reseed: bool,
+        
+        /// Compare file contents rather than size and modification time when checking the copy
+        #[arg(long)]
+        checksum: bool,
+    },
+    
+    /// Show what backing up a source would do and why, without doing it
+    Explain {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+    },
+    
+    /// Show a source's snapshots against the ones backed up and the ones still on the target, with the gaps
+    Timeline {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+        
+        /// Print the timeline as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    
+    /// Carry a source's backups over to its new name after the dataset is renamed or the restic repository moved
+    RenameSource {
+        /// Name it was backed up under
+        old: String,
+        
+        /// Name it now has
+        new: String,
+    },
+    
+    /// Back up throwaway data in full and then incrementally, check the copies and clean up, to test this host's tools
+    Selftest {
+        /// What to back up [default: zfs as root, otherwise restic]
+        #[arg(long, value_enum)]
+        kind: Option<selftest::Kind>,
+    },
+    
+    /// Print a schema of every config option, for validating configs before they are deployed
+    ConfigSchema {
+        #[arg(long, value_enum, default_value = "json-schema")]
+        format: config_schema::Format,
+    },
+}
+
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// Back up a source now, after anything already running
+    Trigger {
+        /// Dataset name or restic repository, as written in the config
+        source: String,
+    },
+    
+    /// Show whether scheduling is paused, what is running and when the next run is
+    Status,
+    
+    /// Stop starting scheduled runs until resumed
+    Pause,
+    
+    /// Start scheduled runs again
+    Resume,
+    
+    /// Stop the backup in progress and skip the rest of the run
+    Abort,
+}
+
+
+#[derive(Subcommand, Debug)]
+enum FleetCommand {
+    /// Run file-backup on each host at once and print a combined report, exiting 0/1/2 like status
+    Run {
+        /// Only back up these hosts
+        hosts: Vec<String>,
+        
+        /// Also write the hosts' run summaries to this file
+        #[arg(long, value_name = "FILE")]
+        json: Option<PathBuf>,
+    },
+}
+
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Write the contents of the database to a JSON file
+    Export {
+        #[arg(long, value_name = "FILE")]
+        json: PathBuf,
+    },
+    
+    /// Load the contents of a JSON export, or the state left on a target, into the database
+    Import {
+        #[arg(long, value_name = "FILE", required_unless_present = "from_target", conflicts_with = "from_target")]
+        json: Option<PathBuf>,
+        
+        /// Target directory of an earlier backup to rebuild state from
+        #[arg(long, value_name = "DIR")]
+        from_target: Option<PathBuf>,
+        
+        /// What to do with rows that are already in the database
+        #[arg(long, value_enum, default_value_t = ConflictPolicy::Skip)]
+        on_conflict: ConflictPolicy,
+    },
 }
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct Config {
     #[serde(default)]
     dataset: Vec<DatasetConfig>,
     #[serde(default)]
     restic: Vec<ResticConfig>,
+    #[serde(default)]
+    resources: ResourcesConfig,
+    #[serde(default)]
+    report: ReportConfig,
+    #[serde(default)]
+    anomalies: AnomalyConfig,
+    #[serde(default)]
+    rsync: RsyncConfig,
+    #[serde(default)]
+    environment: EnvironmentConfig,
+    #[serde(default)]
+    restic_cache: ResticCacheConfig,
+    #[serde(default)]
+    concurrency: ConcurrencyConfig,
+    #[serde(default)]
+    commands: CommandsConfig,
+    #[serde(default)]
+    excludes: excludes::ExcludesConfig,
+    #[serde(default)]
+    daemon: daemon::DaemonConfig,
+    // Where events of each run are sent, see events::NotifyConfig
+    #[serde(default)]
+    notify: Vec<events::NotifyConfig>,
+    // Target disks, by mount point
+    #[serde(default)]
+    targets: BTreeMap<PathBuf, budget::TargetConfig>,
+    // Other machines `fleet run` backs up, by name
+    #[serde(default)]
+    hosts: BTreeMap<String, fleet::HostConfig>,
+}
+
+
+#[derive(Debug, Deserialize, JsonSchema, PartialEq, Clone)]
+struct DatasetConfig {
+    name: String,
+    target_dir: PathBuf,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    // Higher runs first, among sources not ordered by after
+    #[serde(default)]
+    priority: i32,
+    // Sources to back up before this one when both are in a run
+    #[serde(default)]
+    after: Vec<String>,
+    max_delete: Option<MaxDelete>,
+    #[serde(default)]
+    delete_mode: DeleteMode,
+    trash_retention_days: Option<Days>,
+    #[serde(default)]
+    layout: Layout,
+    immutable: Option<ImmutableScope>,
+    // Versioned sources with the same link_pool share files on the disk
+    link_pool: Option<String>,
+    #[serde(default)]
+    unmount_after: bool,
+    #[serde(default)]
+    spin_down: bool,
+    // Rsync the whole snapshot with --checksum this often instead of applying
+    // the diff, to put right anything that drifted on the target
+    full_resync_every: Option<ConfigDuration>,
+    // Audit the target before each incremental backup, see drift::OnDrift
+    on_drift: Option<drift::OnDrift>,
+    #[serde(default)]
+    special_files: SpecialFiles,
+    #[serde(default)]
+    symlinks: Symlinks,
+    // rsync filter rules, merged after [excludes]; see filter_file.rs
+    filter_file: Option<PathBuf>,
+    #[serde(skip)]
+    filter: Option<FilterFile>,
+    // rsync --sparse; when unset it is used for changes that look sparse
+    // and for sources holding disk images
+    sparse: Option<bool>,
+    // Share of the files copied by each backup to hash on both the snapshot
+    // and the target afterwards, failing the backup if any differ
+    verify_sample: Option<Percentage>,
+    // User to run rsync and restic as instead of root
+    run_as: Option<String>,
+    #[serde(skip)]
+    user: Option<privileges::User>,
+    // Snapshot the dataset at the start of its backup rather than backing up
+    // the latest snapshot other tools took
+    #[serde(default)]
+    auto_snapshot: bool,
+    // Name of those snapshots; see auto_snapshot.rs
+    snapshot_template: Option<SnapshotTemplate>,
+    // Destroy the oldest of them after each backup until this many are left
+    keep_auto_snapshots: Option<usize>,
+    // Commands run just before and after that snapshot
+    quiesce: Option<quiesce::QuiesceConfig>,
+    #[serde(flatten)]
+    device: DeviceConfig,
+    #[serde(flatten)]
+    scheduling: SchedulingConfig,
+    #[serde(flatten)]
+    thresholds: Thresholds,
+    #[serde(flatten)]
+    snapshot_age: SnapshotAgeConfig,
+    #[serde(flatten)]
+    encryption: EncryptionConfig,
+    // Compression of the encrypted target's objects, the stream files or the
+    // images: "zstd", "zstd:9", "lz4" or "none" [default: none, or the image
+    // tool's own for images]
+    compression: Option<Compression>,
+    #[serde(flatten)]
+    ownership: ownership::OwnershipConfig,
+    #[serde(flatten)]
+    keys: DatasetKeyConfig,
+    #[serde(flatten)]
+    pool_checks: pool::PoolChecksConfig,
+    #[serde(default)]
+    descend: nested::Descend,
+    // Back a ZVOL up into a restic repository at target_dir
+    zvol_mode: Option<zvol::ZvolMode>,
+    // Percentage of par2 parity data written with each stream file
+    par2_redundancy: Option<u32>,
+    // Tool and compression (its -comp or -z) of the images written with
+    // layout = "image" [default: squashfs with zstd, erofs with lz4hc]
+    image_format: Option<images::ImageFormat>,
+    image_compression: Option<String>,
+    // Also keep extended attributes and ACLs in a store of their own on the
+    // target, for target filesystems that can't hold them
+    #[serde(default)]
+    metadata_sidecar: bool,
+    // Mountpoints of included child datasets, relative to this one's, which
+    // the full rsync leaves alone on the target
+    #[serde(skip)]
+    nested_excludes: Vec<PathBuf>,
+    // target_dir as written, when it has placeholders for template::expand
+    #[serde(skip)]
+    target_template: Option<PathBuf>,
+}
+
+
+#[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+struct ResticConfig {
+    repository: String,
+    target_dir: PathBuf,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    // Higher runs first, among sources not ordered by after
+    #[serde(default)]
+    priority: i32,
+    // Sources to back up before this one when both are in a run
+    #[serde(default)]
+    after: Vec<String>,
+    #[serde(default)]
+    mode: ResticMode,
+    // Only used in restore mode with restic older than 0.17
+    staging_dir: Option<PathBuf>,
+    max_delete: Option<MaxDelete>,
+    #[serde(default)]
+    delete_mode: DeleteMode,
+    trash_retention_days: Option<Days>,
+    #[serde(default)]
+    layout: Layout,
+    immutable: Option<ImmutableScope>,
+    // Versioned sources with the same link_pool share files on the disk
+    link_pool: Option<String>,
+    #[serde(default)]
+    unmount_after: bool,
+    #[serde(default)]
+    spin_down: bool,
+    // Rsync the whole snapshot with --checksum this often instead of applying
+    // the diff, to put right anything that drifted on the target
+    full_resync_every: Option<ConfigDuration>,
+    // Audit the target before each incremental backup, see drift::OnDrift
+    on_drift: Option<drift::OnDrift>,
+    #[serde(default)]
+    special_files: SpecialFiles,
+    #[serde(default)]
+    symlinks: Symlinks,
+    // rsync filter rules, merged after [excludes]; see filter_file.rs
+    filter_file: Option<PathBuf>,
+    #[serde(skip)]
+    filter: Option<FilterFile>,
+    // rsync --sparse; when unset it is used for changes that look sparse
+    // and for sources holding disk images
+    sparse: Option<bool>,
+    // Share of the files copied by each backup to hash on both the snapshot
+    // and the target afterwards, failing the backup if any differ
+    verify_sample: Option<Percentage>,
+    // User to run rsync and restic as instead of root
+    run_as: Option<String>,
+    #[serde(skip)]
+    user: Option<privileges::User>,
+    // When the repository is locked, remove the locks if all are at least
+    // this old and try again, e.g. after a run that crashed
+    unlock_stale_after: Option<ConfigDuration>,
+    // Name of the server the repository is on, for max_concurrent_per_backend
+    // [default: worked out from the repository location]
+    backend: Option<String>,
+    #[serde(flatten)]
+    device: DeviceConfig,
+    #[serde(flatten)]
+    scheduling: SchedulingConfig,
+    #[serde(flatten)]
+    thresholds: Thresholds,
+    #[serde(flatten)]
+    snapshot_age: SnapshotAgeConfig,
+    #[serde(flatten)]
+    encryption: EncryptionConfig,
+    // Compression of the encrypted target's objects: "zstd", "zstd:9", "lz4"
+    // or "none" [default: none]
+    compression: Option<Compression>,
+    #[serde(flatten)]
+    ownership: ownership::OwnershipConfig,
+    #[serde(flatten)]
+    restic_env: restic_env::ResticEnvConfig,
+    // target_dir as written, when it has placeholders for template::expand
+    #[serde(skip)]
+    target_template: Option<PathBuf>,
+}
+
+
+fn default_enabled() -> bool {
+    true
+}
+
+
+// A configured source of either kind, for code that treats both alike
+#[derive(Debug, Clone, Copy)]
+enum Source<'a> {
+    Dataset(&'a DatasetConfig),
+    Restic(&'a ResticConfig),
+}
+
+impl<'a> Source<'a> {
+    // Dataset name or restic repository, as written in the config
+    fn name(&self) -> &'a str {
+        match self {
+            Source::Dataset(d) => &d.name,
+            Source::Restic(r) => &r.repository,
+        }
+    }
+    
+    // As recorded in backup_history and file_state
+    fn backup_type(&self) -> &'static str {
+        match self {
+            Source::Dataset(_) => "dataset",
+            Source::Restic(_) => "restic",
+        }
+    }
+    
+    fn kind(&self) -> &'static str {
+        match self {
+            Source::Dataset(_) => "dataset",
+            Source::Restic(_) => "restic repository",
+        }
+    }
+    
+    fn target_dir(&self) -> &'a Path {
+        match self {
+            Source::Dataset(d) => &d.target_dir,
+            Source::Restic(r) => &r.target_dir,
+        }
+    }
+    
+    fn layout(&self) -> Layout {
+        match self {
+            Source::Dataset(d) => d.layout,
+            Source::Restic(r) => r.layout,
+        }
+    }
+    
+    fn trash_retention_days(&self) -> Option<Days> {
+        match self {
+            Source::Dataset(d) => d.trash_retention_days,
+            Source::Restic(r) => r.trash_retention_days,
+        }
+    }
+    
+    fn immutable(&self) -> Option<ImmutableScope> {
+        match self {
+            Source::Dataset(d) => d.immutable,
+            Source::Restic(r) => r.immutable,
+        }
+    }
+    
+    fn link_pool(&self) -> Option<&'a str> {
+        match self {
+            Source::Dataset(d) => d.link_pool.as_deref(),
+            Source::Restic(r) => r.link_pool.as_deref(),
+        }
+    }
+    
+    // spin_down implies unmounting, since a mounted disk can't be powered off,
+    // and LUKS targets are always closed again after the run
+    fn unmount_after(&self) -> bool {
+        let luks = self.device().luks_uuid.is_some();
+        match self {
+            Source::Dataset(d) => d.unmount_after || d.spin_down || luks,
+            Source::Restic(r) => r.unmount_after || r.spin_down || luks,
+        }
+    }
+    
+    fn symlinks(&self) -> Symlinks {
+        match self {
+            Source::Dataset(d) => d.symlinks,
+            Source::Restic(r) => r.symlinks,
+        }
+    }
+    
+    fn spin_down(&self) -> bool {
+        match self {
+            Source::Dataset(d) => d.spin_down,
+            Source::Restic(r) => r.spin_down,
+        }
+    }
+    
+    fn device(&self) -> &'a DeviceConfig {
+        match self {
+            Source::Dataset(d) => &d.device,
+            Source::Restic(r) => &r.device,
+        }
+    }
+    
+    fn scheduling(&self) -> &'a SchedulingConfig {
+        match self {
+            Source::Dataset(d) => &d.scheduling,
+            Source::Restic(r) => &r.scheduling,
+        }
+    }
+    
+    fn encryption(&self) -> &'a EncryptionConfig {
+        match self {
+            Source::Dataset(d) => &d.encryption,
+            Source::Restic(r) => &r.encryption,
+        }
+    }
+    
+    fn compression(&self) -> Option<Compression> {
+        match self {
+            Source::Dataset(d) => d.compression,
+            Source::Restic(r) => r.compression,
+        }
+    }
+    
+    fn ownership(&self) -> &'a ownership::OwnershipConfig {
+        match self {
+            Source::Dataset(d) => &d.ownership,
+            Source::Restic(r) => &r.ownership,
+        }
+    }
+    
+    fn thresholds(&self) -> &'a Thresholds {
+        match self {
+            Source::Dataset(d) => &d.thresholds,
+            Source::Restic(r) => &r.thresholds,
+        }
+    }
+    
+    fn enabled(&self) -> bool {
+        match self {
+            Source::Dataset(d) => d.enabled,
+            Source::Restic(r) => r.enabled,
+        }
+    }
+    
+    fn priority(&self) -> i32 {
+        match self {
+            Source::Dataset(d) => d.priority,
+            Source::Restic(r) => r.priority,
+        }
+    }
+    
+    fn after(&self) -> &'a [String] {
+        match self {
+            Source::Dataset(d) => &d.after,
+            Source::Restic(r) => &r.after,
+        }
+    }
+    
+    fn snapshot_age(&self) -> &'a SnapshotAgeConfig {
+        match self {
+            Source::Dataset(d) => &d.snapshot_age,
+            Source::Restic(r) => &r.snapshot_age,
+        }
+    }
+    
+    fn on_drift(&self) -> Option<drift::OnDrift> {
+        match self {
+            Source::Dataset(d) => d.on_drift,
+            Source::Restic(r) => r.on_drift,
+        }
+    }
+    
+    fn full_resync_every(&self) -> Option<ConfigDuration> {
+        match self {
+            Source::Dataset(d) => d.full_resync_every,
+            Source::Restic(r) => r.full_resync_every,
+        }
+    }
+    
+    fn verify_sample(&self) -> Option<Percentage> {
+        match self {
+            Source::Dataset(d) => d.verify_sample,
+            Source::Restic(r) => r.verify_sample,
+        }
+    }
+    
+    fn filter(&self) -> Option<&'a FilterFile> {
+        match self {
+            Source::Dataset(d) => d.filter.as_ref(),
+            Source::Restic(r) => r.filter.as_ref(),
+        }
+    }
+    
+    fn run_as(&self) -> Option<&'a str> {
+        match self {
+            Source::Dataset(d) => d.run_as.as_deref(),
+            Source::Restic(r) => r.run_as.as_deref(),
+        }
+    }
+    
+    // run_as, as looked up when the config was loaded
+    fn user(&self) -> Option<&'a privileges::User> {
+        match self {
+            Source::Dataset(d) => d.user.as_ref(),
+            Source::Restic(r) => r.user.as_ref(),
+        }
+    }
+    
+    // What the source's backups share disks and network with
+    fn backend(&self) -> String {
+        match self {
+            Source::Dataset(d) => format!("zfs:{}", d.name.split('/').next().unwrap_or(&d.name)),
+            Source::Restic(r) => r.backend.clone().unwrap_or_else(|| concurrency::backend(&r.repository)),
+        }
+    }
+}
+
+impl Config {
+    // Datasets first, then restic repositories, each in config file order
+    fn sources(&self) -> impl Iterator<Item = Source<'_>> {
+        self.dataset.iter().map(Source::Dataset).chain(self.restic.iter().map(Source::Restic))
+    }
+    
+    fn find_source(&self, name: &str) -> Result<Source<'_>, String> {
+        self.sources()
+            .find(|source| source.name() == name)
+            .ok_or_else(|| format!("'{}' is not a dataset or restic repository in the config file", name))
+    }
+    
+    // Target directories of the other sources in the source's link pool
+    fn link_pool_dirs(&self, source: Source) -> Vec<PathBuf> {
+        let Some(pool) = source.link_pool() else {
+            return Vec::new();
+        };
+        self.sources()
+            .filter(|other| other.link_pool() == Some(pool) && other.target_dir() != source.target_dir())
+            .map(|other| other.target_dir().to_path_buf())
+            .collect()
+    }
+}
+
+
+// How backups are laid out on the target
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Layout {
+    // The target is kept identical to the latest snapshot
+    #[default]
+    Mirror,
+    // Append-only: each backup goes into a new dated directory, hard-linked
+    // against the previous one, and nothing on the target is changed or deleted
+    Versioned,
+    // Datasets only: `zfs send` stream files, a full one followed by incrementals
+    Stream,
+    // Datasets only: a compressed read-only image of each snapshot, see images.rs
+    Image,
+}
+
+
+// What happens to files the incremental pass removes from the target
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum DeleteMode {
+    #[default]
+    Delete,
+    // Move them under .file-backup-trash/<timestamp>/ on the target
+    Trash,
+}
+
+
+// Upper bound on how much a single backup may delete from the target, either
+// a number of files (max_delete = 500) or a share of them (max_delete = "10%")
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(try_from = "toml::Value")]
+enum MaxDelete {
+    Count(u64),
+    Percent(f64),
+}
+
+impl TryFrom<toml::Value> for MaxDelete {
+    type Error = String;
+    
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        match &value {
+            toml::Value::Integer(count) if *count >= 0 => Ok(MaxDelete::Count(*count as u64)),
+            toml::Value::String(text) => text
+                .trim()
+                .strip_suffix('%')
+                .and_then(|number| number.trim().parse::<f64>().ok())
+                .filter(|percent| (0.0..=100.0).contains(percent))
+                .map(MaxDelete::Percent)
+                .ok_or_else(|| format!("invalid max_delete '{}', expected a count or a percentage like \"10%\"", text)),
+            _ => Err(format!("invalid max_delete {}, expected a count or a percentage like \"10%\"", value)),
+        }
+    }
+}
+
+impl std::fmt::Display for MaxDelete {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MaxDelete::Count(count) => write!(f, "{}", count),
+            MaxDelete::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+
+// How snapshot contents are read out of a restic repository
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ResticMode {
+    // FUSE-mount the repository and rsync from the mounted snapshot
+    #[default]
+    Mount,
+    // Use `restic restore`, for hosts without FUSE
+    Restore,
+}
+
+
+fn main() {
+    let args = Args::parse();
+    
+    if args.version {
+        build_info::print(args.json);
+        return;
+    }
+    
+    // Needs no config, which may not exist yet
+    if let Some(Commands::ConfigSchema { format }) = &args.command {
+        if let Err(e) = config_schema::print(*format) {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+    
+    let database = match state::database_path(&args.config, args.database.as_deref()) {
+        Ok(database) => database,
+        Err(e) => {
+            eprintln!("Error loading config file '{}': {}", args.config.display(), e);
+            exit(1);
+        }
+    };
+    // From here on `run` and no subcommand at all are the same
+    let (command, run_args) = match args.command {
+        Some(Commands::Run(run_args)) if args.run == RunArgs::default() => (None, run_args),
+        Some(Commands::Run(_)) => {
+            eprintln!("Error: Give --target, --source, --since, --summary and --print-changes after run, not before it");
+            exit(1);
+        }
+        command => (command, args.run),
+    };
+    
+    let options = RunOptions {
+        hostname: get_hostname(),
+        any_host: args.any_host,
+        force_delete: args.force_delete,
+        allow_degraded: args.allow_degraded,
+        confirm_first_backup: !args.yes && command.is_none() && io::stdin().is_terminal(),
+        database,
+        unprivileged: args.unprivileged,
+        since: run_args.since.clone(),
+        print_changes: run_args.print_changes,
+    };
+    
+    if run_args.target.is_some() && command.is_some() {
+        eprintln!("Error: --target only applies to a backup run, not to subcommands");
+        exit(1);
+    }
+    if run_args.source.is_some() && command.is_some() {
+        eprintln!("Error: --source only applies to a backup run, not to subcommands");
+        exit(1);
+    }
+    if run_args.summary.is_some() && command.is_some() {
+        eprintln!("Error: --summary only applies to a backup run, not to subcommands");
+        exit(1);
+    }
+    let summary_output = match run_args.summary.as_deref().map(runlog::SummaryOutput::open).transpose() {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    };
+
+    // The daemon owns everything ctl touches, so it needs nothing else here
+    if let Some(Commands::Ctl { command }) = &command {
+        let line = match command {
+            CtlCommand::Trigger { source } => format!("trigger {}", source),
+            CtlCommand::Status => "status".to_string(),
+            CtlCommand::Pause => "pause".to_string(),
+            CtlCommand::Resume => "resume".to_string(),
+            CtlCommand::Abort => "abort".to_string(),
+        };
+        match control::send(&options.database, &line) {
+            Ok(true) => return,
+            Ok(false) => exit(1),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+    }
+
+    // The self test brings its own config, database and data
+    if let Some(Commands::Selftest { kind }) = &command {
+        if let Err(e) = selftest::run_selftest(*kind) {
+            eprintln!("Error: Self test failed: {}", e);
+            exit(1);
+        }
+        println!("Self test passed");
+        return;
+    }
+
+    // Reporting commands only read the database, so they work for users and
+    // monitoring agents that can't write to it
+    let read_only = matches!(
+        &command,
+        Some(
+            Commands::Status { .. }
+            | Commands::Check { .. }
+            | Commands::History { .. }
+            | Commands::Report { .. }
+            | Commands::Find { .. }
+            | Commands::Changes { .. }
+            | Commands::Explain { .. }
+            | Commands::Timeline { .. }
+            | Commands::Db { command: DbCommand::Export { .. } },
+        )
+    );
+    let conn = if read_only {
+        open_database_read_only(&options.database)
+    } else {
+        init_database(&options.database, &options.hostname)
+    };
+    let conn = match conn {
+        Ok(conn) => conn,
+        // Monitoring plugins report problems of their own as UNKNOWN
+        Err(e) if matches!(&command, Some(Commands::Status { .. } | Commands::Check { .. })) => {
+            eprintln!("UNKNOWN - database '{}': {}", options.database.display(), e);
+            exit(status::State::Unknown.exit_code());
+        }
+        Err(e) => {
+            eprintln!("Error initializing database '{}': {}", options.database.display(), e);
+            exit(1);
+        }
+    };
+    
+    // Database maintenance doesn't need the config or any external tools
+    if let Some(Commands::Db { command }) = &command {
+        let result = match command {
+            DbCommand::Export { json } => db_export::export_json(&conn, json),
+            DbCommand::Import { json, from_target, on_conflict } => {
+                let path = match (json, from_target) {
+                    (Some(json), _) => json.clone(),
+                    (None, Some(target_dir)) => target_dir.join(db_export::TARGET_STATE_FILE),
+                    (None, None) => unreachable!("clap requires one of --json or --from-target"),
+                };
+                db_export::import_json(&conn, &options.hostname, &path, *on_conflict)
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+    
+    if let Some(Commands::History { source, last }) = &command {
+        if let Err(e) = report::print_history(&conn, options.host_filter(), source.as_deref(), *last) {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+
+    // Load configuration
+    let mut config = match load_config(&args.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error loading config file '{}': {}", args.config.display(), e);
+            exit(1);
+        }
+    };   
+    
+    child_env::configure(&config.environment);
+    restic_env::configure(&config.restic);
+    excludes::configure(&config.excludes);
+    restic_cache::configure(&config.restic_cache);
+    events::configure(&config.notify);
+    if args.no_cache {
+        restic_cache::disable();
+    }
+    executed::show(args.show_commands);
+    output::group(args.group_output);
+    
+    // Other hosts keep their own state, so a fleet run only needs the config
+    if let Some(Commands::Fleet { command: FleetCommand::Run { hosts, json } }) = &command {
+        match fleet::run(&config.hosts, hosts, json.as_deref()) {
+            Ok(state) => exit(state.exit_code()),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+    }
+    
+    // Trash housekeeping only touches the targets
+    if let Some(Commands::PurgeTrash { source, all }) = &command {
+        if let Err(e) = purge_trash(&config, source.as_deref(), *all) {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::Prune { source, keep, confirm }) = &command {
+        if let Err(e) = prune_versions(&config, source, *keep, *confirm) {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::Gc { source, keep, dry_run }) = &command {
+        if let Err(e) = gc(&config, source, *keep, *dry_run) {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::Pause { source, duration }) = &command {
+        if let Err(e) = pause_source(&config, &conn, &options, source, duration.as_deref()) {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::Resume { source }) = &command {
+        let result = config.find_source(source).and_then(|source| pause::resume(&conn, &options.hostname, source.name()));
+        match result {
+            Ok(true) => println!("Resumed '{}'", source),
+            Ok(false) => println!("'{}' wasn't paused", source),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Commands::Find { pattern }) = &command {
+        if let Err(e) = find_files(&conn, options.host_filter(), pattern) {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::Changes { source, snapshot }) = &command {
+        if let Err(e) = changed_files::print(&conn, options.host_filter(), source, snapshot.as_deref()) {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+    
+    if let Some(Commands::MakeRescue { source }) = &command {
+        if let Err(e) = rescue::make_rescue(&config, &conn, source) {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::Status { source }) = &command {
+        match status::print_status(&config, &conn, options.host_filter(), source.as_deref()) {
+            Ok(state) => exit(state.exit_code()),
+            Err(e) => {
+                eprintln!("UNKNOWN - {}", e);
+                exit(status::State::Unknown.exit_code());
+            }
+        }
+    }
+
+    if let Some(Commands::Check { source }) = &command {
+        exit(status::check(&config, &conn, options.host_filter(), source).exit_code());
+    }
+
+    if let Some(Commands::Report { last, html }) = &command {
+        let result = html
+            .as_ref()
+            .or(config.report.html.as_ref())
+            .ok_or_else(|| "No report file given: pass --html or set html in the [report] config section".to_string())
+            .and_then(|path| {
+                report::write_html(&config, &conn, options.host_filter(), last.unwrap_or(config.report.last), path)
+            });
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::Timeline { source, json }) = &command {
+        let result = config.find_source(source).and_then(|source| timeline::timeline(&conn, options.host_filter(), source, *json));
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+        return;
+    }
+    
+    // Everything from here on runs backups, so apply the configured limits
+    resources::enter_scope(&config.resources);
+
+    // Check that the external tools needed by this config are installed and recent enough
+    let tool_versions = match tools::detect_tool_versions(&config) {
+        Ok(versions) => versions,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    };
+    
+    if let Err(e) = nested::expand(&mut config) {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+
+    match command {
+        Some(Commands::AdoptTarget { source, snapshot, checksum }) => {
+            if let Err(e) = adopt::adopt_target(&config, &conn, &options, &source, &snapshot, checksum) {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        Some(Commands::Daemon { interval, schedule, config_check_interval }) => daemon::run_daemon(
+            &args.config,
+            config,
+            &conn,
+            &options,
+            tool_versions,
+            schedule.or(interval.map(Schedule::Every)),
+            Duration::from_secs(config_check_interval.max(1)),
+        ),
+        Some(
+            Commands::Run(_)
+            | Commands::Db { .. }
+            | Commands::History { .. }
+            | Commands::PurgeTrash { .. }
+            | Commands::Prune { .. }
+            | Commands::Gc { .. }
+            | Commands::MakeRescue { .. }
+            | Commands::Find { .. }
+            | Commands::Changes { .. }
+            | Commands::Timeline { .. }
+            | Commands::Pause { .. }
+            | Commands::Resume { .. }
+            | Commands::Report { .. }
+            | Commands::Status { .. }
+            | Commands::Check { .. }
+            | Commands::Ctl { .. }
+            | Commands::Fleet { .. }
+            | Commands::Selftest { .. }
+            | Commands::ConfigSchema { .. },
+        ) => {
+            unreachable!("handled above")
+        }
+        Some(Commands::MigrateTarget { source, to, reseed, checksum }) => {
+            let migration = migrate::Migration { to, reseed, checksum };
+            if let Err(e) = migrate::migrate_target(&config, &args.config, &conn, &options, &source, &migration) {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        Some(Commands::Explain { source }) => {
+            if let Err(e) = explain::explain(&config, &conn, &options, &tool_versions, &source) {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        Some(Commands::RenameSource { old, new }) => {
+            if let Err(e) = rename::rename_source(&args.config, &conn, &options, &old, &new) {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        Some(Commands::Restore { source, to, identity, paths, as_of, dry_run }) => {
+            let selection = restore::Selection { paths, as_of, dry_run };
+            if let Err(e) = restore::restore(&config, &source, &to, identity.as_deref(), &selection) {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        Some(Commands::Verify { source, identity, checksum, sample }) => {
+            if let Err(e) = restore::verify(&config, &conn, &options, &source, identity.as_deref(), checksum, sample) {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        Some(Commands::VerifyStreams { source, repair }) => {
+            if let Err(e) = verify_streams(&config, source.as_deref(), repair) {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        Some(Commands::Trigger { dataset, snapshot }) => {
+            if let Err(e) = trigger(&config, &conn, &options, &tool_versions, &dataset, &snapshot) {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        None => {
+            let sources = match (&run_args.target, &run_args.source) {
+                (Some(selector), _) => sources_on_target(&config, &conn, &options, selector),
+                (None, Some(source)) => config.find_source(source).map(|source| vec![source]),
+                (None, None) => Ok(config.sources().collect()),
+            };
+            let started_at = SystemTime::now();
+            match sources.and_then(|sources| run_exclusive(&config, &conn, &options, &tool_versions, sources)) {
+                Ok(summaries) => {
+                    if let Some(output) = summary_output {
+                        let summary = runlog::RunSummary {
+                            run_id: run_id::current(),
+                            hostname: options.hostname.clone(),
+                            started_at: clock::iso_utc(started_at),
+                            finished_at: clock::iso_utc(SystemTime::now()),
+                            pools: pool::run_health(),
+                            sources: summaries.clone(),
+                        };
+                        if let Err(e) = output.write(&summary) {
+                            eprintln!("Warning: {}", e);
+                        }
+                    }
+                    // The worst source decides the code, as for status, so cron and
+                    // monitoring see failed backups as well as unusual ones
+                    let state = summaries.iter().map(|summary| summary.state()).max().unwrap_or(status::State::Ok);
+                    exit(state.exit_code());
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    exit(1);
+                }
+            }
+        }
+    }
+}
+
+
+// The sources backing up to the disk given with --target, after listing what
+// each of them is going to do
+fn sources_on_target<'a>(
+    config: &'a Config,
+    conn: &Connection,
+    options: &RunOptions,
+    selector: &str,
+) -> Result<Vec<Source<'a>>, String> {
+    let sources: Vec<Source> = config
+        .sources()
+        .filter(|source| device::on_target(source.target_dir(), source.device(), selector))
+        .collect();
+    if sources.is_empty() {
+        return Err(format!("No source in the config backs up to {}", selector));
+    }
+    
+    println!("{} source(s) back up to {}:", sources.len(), selector);
+    for source in &sources {
+        let plan = match pause::reason(conn, options.host_filter(), *source)? {
+            Some(reason) => format!("skipped, {}", reason),
+            None => {
+                let freshness = report::source_freshness(conn, options.host_filter(), *source)?;
+                match (freshness.last_snapshot, freshness.age_secs) {
+                    (Some(snapshot), Some(age)) => {
+                        format!("incremental from {}, backed up {} ago", snapshot, report::format_age(age))
+                    }
+                    (Some(snapshot), None) => format!("incremental from {}, backed up at an unknown time", snapshot),
+                    (None, _) => "first full backup".to_string(),
+                }
+            }
+        };
+        println!("  {} '{}' -> {}: {}", source.kind(), source.name(), source.target_dir().display(), plan);
+    }
+    
+    Ok(sources)
+}
+
+
+// Back up `sources` once no other run is going, then anything queued
+// meanwhile. Returns how each backup went.
+fn run_exclusive<'a>(
+    config: &'a Config,
+    conn: &Connection,
+    options: &RunOptions,
+    tool_versions: &ToolVersions,
+    sources: Vec<Source<'a>>,
+) -> Result<Vec<SourceSummary>, String> {
+    let lock = queue::lock(&options.database)?;
+    Ok(run_holding_lock(config, conn, options, tool_versions, lock, sources))
+}
+
+
+fn run_holding_lock<'a>(
+    config: &'a Config,
+    conn: &Connection,
+    options: &RunOptions,
+    tool_versions: &ToolVersions,
+    mut lock: queue::RunLock,
+    mut sources: Vec<Source<'a>>,
+) -> Vec<SourceSummary> {
+    let mut summaries = Vec::new();
+    loop {
+        if !sources.is_empty() {
+            summaries.extend(run_backups(config, conn, options, tool_versions, &sources));
+        }
+        
+        sources = take_queued_sources(config, &lock, &options.database);
+        if !sources.is_empty() {
+            continue;
+        }
+        
+        // Something queued just before the lock was released would otherwise wait for the next run
+        drop(lock);
+        match queue::try_lock(&options.database) {
+            Ok(Some(relocked)) if queue::has_entries(&options.database) => lock = relocked,
+            _ => return summaries,
+        }
+    }
+}
+
+
+fn take_queued_sources<'a>(config: &'a Config, lock: &queue::RunLock, database: &Path) -> Vec<Source<'a>> {
+    let names = match queue::take(lock, database) {
+        Ok(names) => names,
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            return Vec::new();
+        }
+    };
+    
+    names
+        .iter()
+        .filter_map(|name| match config.find_source(name) {
+            Ok(source) => {
+                println!("Running queued backup of {} '{}'", source.kind(), source.name());
+                Some(source)
+            }
+            Err(e) => {
+                eprintln!("Warning: Dropping queued backup: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+
+fn trigger(
+    config: &Config,
+    conn: &Connection,
+    options: &RunOptions,
+    tool_versions: &ToolVersions,
+    dataset: &str,
+    snapshot: &str,
+) -> Result<(), String> {
+    let dataset_config = config.dataset
+        .iter()
+        .find(|d| d.name == dataset)
+        .ok_or_else(|| format!("'{}' is not a dataset in the config file", dataset))?;
+    
+    // zed reports every snapshot on the pool, so make sure this one is ours
+    let snapshot_name = match snapshot.split_once('@') {
+        Some((snapshot_dataset, _)) if snapshot_dataset != dataset => {
+            return Err(format!("Snapshot '{}' doesn't belong to dataset '{}'", snapshot, dataset));
+        }
+        Some(_) => snapshot.to_string(),
+        None => format!("{}@{}", dataset, snapshot),
+    };
+    if !snapshot_exists(&snapshot_name, Source::Dataset(dataset_config), &Log::default())? {
+        return Err(format!("Snapshot '{}' doesn't exist", snapshot_name));
+    }
+    
+    println!("Snapshot {} created, backing up dataset '{}'", snapshot_name, dataset);
+    
+    if let Some(lock) = queue::try_lock(&options.database)? {
+        run_holding_lock(config, conn, options, tool_versions, lock, vec![Source::Dataset(dataset_config)]);
+        return Ok(());
+    }
+    
+    queue::enqueue(&options.database, dataset)?;
+    
+    // The other run may have finished while this was being queued
+    match queue::try_lock(&options.database)? {
+        Some(lock) => {
+            run_holding_lock(config, conn, options, tool_versions, lock, Vec::new());
+        }
+        None => println!("Another backup is running; queued dataset '{}' to follow it", dataset),
+    }
+    Ok(())
+}
+
+
+fn run_backups(config: &Config, conn: &Connection, options: &RunOptions, tool_versions: &ToolVersions, sources: &[Source]) -> Vec<SourceSummary> {
+    let run_uuid = run_id::start_run();
+    events::start_run(&options.hostname);
+    let run_id = match start_run(conn, options, tool_versions, &run_uuid) {
+        Ok(id) => Some(id),
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            None
+        }
+    };
+    
+    let started_at = SystemTime::now();
+    let capture = match runlog::Capture::start() {
+        Ok(capture) => Some(capture),
+        Err(e) => {
+            eprintln!("Warning: Not keeping a run log: {}", e);
+            None
+        }
+    };
+
+    let sources = budget::apply(config, order::order(sources.to_vec()));
+    // Jobs are numbered from 1 in the order the sources are backed up in
+    let job_number = |source: Source| {
+        sources.iter().position(|s| s.kind() == source.kind() && s.name() == source.name()).map_or(0, |index| index + 1)
+    };
+    device_fault::start_run();
+    pool::check_run(&sources);
+    let dataset_count = sources.iter().filter(|s| matches!(s, Source::Dataset(_))).count();
+    let restic_count = sources.len() - dataset_count;
+    println!("Run {}", run_uuid);
+    println!("Processing {} dataset{} and {} restic repositor{}...\n", 
+        dataset_count, 
+        if dataset_count == 1 { "" } else { "s" },
+        restic_count,
+        if restic_count == 1 { "y" } else { "ies" }
+    );
+            
+    // Targets that received at least one successful backup this run, and
+    // targets where at least one backup failed
+    let mut updated_targets: Vec<&Path> = Vec::new();
+    let mut failed_targets: Vec<&Path> = Vec::new();
+    let mut summaries = Vec::new();
+    
+    // Targets stay writable until the end of the run, when these are dropped
+    let mut immutable_guards = Vec::new();
+    
+    let mut next = 0;
+    while next < sources.len() {
+        let batch = &sources[next..next + concurrency::batch_len(&sources[next..], &config.concurrency)];
+        next += batch.len();
+        
+        let results = match batch_connections(options, batch, &config.concurrency) {
+            Some(connections) => {
+                println!("Backing up {} restic repositories side by side...\n", batch.len());
+                let results = concurrency::run(batch, &config.concurrency, connections, |conn, source| {
+                    output::start_job(source.name());
+                    run_id::start_job(&run_uuid, job_number(source));
+                    let result = run_source(config, conn, options, tool_versions, run_id, source, &summaries);
+                    run_id::finish_job();
+                    output::finish_job();
+                    result
+                });
+                print_batch_results(batch, &results);
+                results
+            }
+            None => batch
+                .iter()
+                .map(|&source| {
+                    run_id::start_job(&run_uuid, job_number(source));
+                    let result = run_source(config, conn, options, tool_versions, run_id, source, &summaries);
+                    run_id::finish_job();
+                    result
+                })
+                .collect(),
+        };
+        
+        for (source, result) in batch.iter().zip(results) {
+            let Some((summary, guards)) = result else {
+                continue;
+            };
+            if summary.status == SourceStatus::Ok {
+                updated_targets.push(source.target_dir());
+            } else {
+                failed_targets.push(source.target_dir());
+            }
+            immutable_guards.extend(guards);
+            summaries.push(summary);
+        }
+    }
+    control::clear_abort();
+    
+    // Leave a copy of the relevant state on each target so it can be rebuilt from the disk alone
+    updated_targets.sort();
+    updated_targets.dedup();
+    for target_dir in &updated_targets {
+        if let Err(e) = db_export::write_target_state(conn, target_dir) {
+            eprintln!("Warning: Failed to write state to target '{}': {}", target_dir.display(), e);
+        }
+        let by_name = config.sources().any(|source| source.target_dir() == *target_dir && !source.ownership().numeric_ids);
+        if by_name && let Err(e) = ownership::record_names(target_dir) {
+            eprintln!("Warning: Failed to record user and group names on target '{}': {}", target_dir.display(), e);
+        }
+    }
+    
+    if let Some(capture) = capture {
+        let log = capture.finish();
+        write_run_logs(options, &run_uuid, started_at, &log, &summaries);
+    }
+    drop(immutable_guards);
+    
+    device::release_targets(config, &failed_targets);
+    
+    if let Some(run_id) = run_id
+        && let Err(e) = finish_run(conn, run_id)
+    {
+        eprintln!("Warning: {}", e);
+    }
+    
+    if let Some(path) = &config.report.html
+        && let Err(e) = report::write_html(config, conn, options.host_filter(), config.report.last, path)
+    {
+        eprintln!("Warning: Failed to write report: {}", e);
+    }
+    
+    let special_files_skipped: u64 = summaries.iter().map(|summary| summary.special_files_skipped).sum();
+    if special_files_skipped > 0 {
+        println!("Skipped {} special file(s) (devices, sockets, FIFOs) as special_files says", special_files_skipped);
+    }
+    
+    if sources.iter().any(|source| matches!(source, Source::Restic(_))) {
+        restic_cache::print_size();
+    }
+    
+    for summary in summaries.iter().filter(|summary| !summary.anomalies.is_empty()) {
+        println!("Check {} '{}': {}", summary.kind, summary.name, summary.anomalies.join("; "));
+    }
+    
+    let mut failed_devices: Vec<&str> = summaries.iter().filter_map(|summary| summary.failed_device.as_deref()).collect();
+    failed_devices.sort();
+    failed_devices.dedup();
+    for mount_point in failed_devices {
+        let on_it: Vec<&str> = summaries
+            .iter()
+            .filter(|summary| summary.failed_device.as_deref() == Some(mount_point))
+            .map(|summary| summary.name.as_str())
+            .collect();
+        eprintln!("Target device at {} failed; not backed up to it: {}", mount_point, on_it.join(", "));
+    }
+    
+    events::emit(events::Event::RunFinished {
+        sources: summaries.len(),
+        ok: summaries.iter().filter(|summary| summary.status == SourceStatus::Ok).count(),
+        not_ok: summaries.iter().filter(|summary| summary.status != SourceStatus::Ok).map(|summary| summary.name.as_str()).collect(),
+        duration_secs: started_at.elapsed().map_or(0, |elapsed| elapsed.as_secs()),
+    });
+    println!("Done! (run {})", run_uuid);
+    summaries
+}
+
+
+// Back up one source of a run and record how it went; None when it was
+// skipped without trying, having been paused or the run aborted
+fn run_source(
+    config: &Config,
+    conn: &Connection,
+    options: &RunOptions,
+    tool_versions: &ToolVersions,
+    run_id: Option<i64>,
+    source: Source<'_>,
+    summaries: &[SourceSummary],
+) -> Option<(SourceSummary, Vec<immutable::ImmutableGuard>)> {
+    if control::abort_requested() {
+        println!("Run aborted, skipping {} '{}'", source.kind(), source.name());
+        return None;
+    }
+    match pause::reason(conn, Some(&options.hostname), source) {
+        Ok(Some(reason)) => {
+            println!("Skipping {} '{}': {}\n", source.kind(), source.name(), reason);
+            return None;
+        }
+        Ok(None) => {}
+        Err(e) => eprintln!("Warning: {}", e),
+    }
+    control::source_started(source.name());
+    let source_started = Instant::now();
+    
+    // Whatever this source runs after has to have worked, e.g. the dump it backs up
+    let failed_dependency = source.after().iter().find(|name| {
+        summaries.iter().any(|summary| summary.name == **name && summary.status != SourceStatus::Ok)
+    });
+    special_files::start_source();
+    events::start_source(Some(source));
+    events::emit(events::Event::JobStarted { target_dir: source.target_dir().to_string_lossy().into_owned() });
+    let mut immutable_guards = Vec::new();
+    let mut changed_files = ChangedFiles::new(&config.rsync);
+    let result = match failed_dependency {
+        Some(name) => Err((SourceStatus::Failed, format!("'{}', which this runs after, wasn't backed up", name))),
+        // Nor is there any point waiting on a target device that already gave
+        // out, when every source on it would fail the same way
+        None => match device_fault::failure_of(source.target_dir()) {
+            Some((mount_point, name)) => Err((
+                SourceStatus::SkippedDeviceFailed,
+                format!("The target device at {} failed during the backup of '{}'", mount_point.display(), name),
+            )),
+            None => backup_source(config, source, conn, options, tool_versions, &mut immutable_guards, &mut changed_files),
+        },
+    };
+    
+    let mut anomalies = Vec::new();
+    let (status, error) = match result {
+        Ok(()) => {
+            anomalies = anomaly::check(
+                conn,
+                run_id,
+                &config.anomalies,
+                source,
+                changed_files.file_state.take(),
+                source_started.elapsed().as_secs(),
+            );
+            for anomaly in &anomalies {
+                eprintln!("Warning: Unusual backup of {} '{}': {}", source.kind(), source.name(), anomaly);
+            }
+            (SourceStatus::Ok, None)
+        }
+        Err((status, e)) => {
+            if status == SourceStatus::DeviceNotPresent {
+                println!("Device not present: {}", e);
+                println!("Skipping {} '{}'\n", source.kind(), source.name());
+            } else if matches!(status, SourceStatus::Locked | SourceStatus::Deferred | SourceStatus::SkippedDeviceFailed) {
+                println!("{}", e);
+                println!("Skipping {} '{}'\n", source.kind(), source.name());
+            } else {
+                eprintln!("Error: {}", e);
+                eprintln!("Skipping {} '{}'\n", source.kind(), source.name());
+            }
+            (status, Some(e))
+        }
+    };
+    
+    // The device it failed on, whether during this backup or an earlier one
+    let failed_device = matches!(status, SourceStatus::DeviceFailed | SourceStatus::SkippedDeviceFailed)
+        .then(|| device_fault::failure_of(source.target_dir()))
+        .flatten();
+    let rsync_exits = changed_files.exits;
+    let summary = SourceSummary {
+        job_id: run_id::job(),
+        kind: source.kind(),
+        name: source.name().to_string(),
+        target_dir: source.target_dir().to_string_lossy().into_owned(),
+        status,
+        error,
+        anomalies,
+        rsync_exit_code: rsync_exits.exit_code,
+        skipped_files: rsync_exits.skipped,
+        special_files_skipped: special_files::skipped(),
+        duration_secs: source_started.elapsed().as_secs(),
+        failed_device: failed_device.map(|(mount_point, _)| mount_point.to_string_lossy().into_owned()),
+        commands: changed_files.commands.take(),
+    };
+    if let Some(run_id) = run_id
+        && let Err(e) = record_source_result(conn, run_id, source.backup_type(), &summary)
+    {
+        eprintln!("Warning: {}", e);
+    }
+    if let Some(run_id) = run_id
+        && config.commands.record_in_database
+        && let Err(e) = executed::record(conn, run_id, source.backup_type(), &summary)
+    {
+        eprintln!("Warning: {}", e);
+    }
+    events::job_finished(&summary);
+    events::start_source(None);
+    control::source_finished(source.name());
+    Some((summary, immutable_guards))
+}
+
+
+// How each source of a batch backed up side by side went, in config order, as
+// their own output came in whatever order they finished
+fn print_batch_results(batch: &[Source], results: &[Option<(SourceSummary, Vec<immutable::ImmutableGuard>)>]) {
+    println!("Side by side:");
+    for (source, result) in batch.iter().zip(results) {
+        match result {
+            Some((summary, _)) => println!(
+                "  {} '{}': {} in {}s{}",
+                source.kind(),
+                source.name(),
+                summary.status.as_str(),
+                summary.duration_secs,
+                summary.error.as_deref().map(|e| format!(" ({})", e)).unwrap_or_default()
+            ),
+            None => println!("  {} '{}': skipped", source.kind(), source.name()),
+        }
+    }
+    println!();
+}
+
+
+// A database connection for each job when the batch is to be backed up side
+// by side, each waiting its turn for SQLite's write lock
+fn batch_connections(options: &RunOptions, batch: &[Source], config: &ConcurrencyConfig) -> Option<Vec<Connection>> {
+    if batch.len() < 2 {
+        return None;
+    }
+    let connections: Result<Vec<Connection>, rusqlite::Error> = (0..config.restic_jobs.min(batch.len()))
+        .map(|_| {
+            let conn = Connection::open(&options.database)?;
+            conn.busy_timeout(Duration::from_secs(600))?;
+            Ok(conn)
+        })
+        .collect();
+    match connections {
+        Ok(connections) => Some(connections),
+        Err(e) => {
+            eprintln!("Warning: Failed to open the database for parallel jobs, backing up one at a time: {}", e);
+            None
+        }
+    }
+}
+
+
+// Mount the source's target if needed and back it up. Errors carry whether
+// the target device was simply missing rather than the backup failing.
+fn backup_source(
+    config: &Config,
+    source: Source<'_>,
+    conn: &Connection,
+    options: &RunOptions,
+    tool_versions: &ToolVersions,
+    immutable_guards: &mut Vec<immutable::ImmutableGuard>,
+    changed_files: &mut ChangedFiles,
+) -> Result<(), (SourceStatus, String)> {
+    let commands = changed_files.commands.clone();
+    if options.unprivileged {
+        zfs_allow::check_unprivileged(source).map_err(|e| (SourceStatus::Failed, e))?;
+    }
+    privileges::check(source.user()).map_err(|e| (SourceStatus::Failed, e))?;
+    snapshot_age::check(source, &commands).map_err(|e| (SourceStatus::StaleSnapshot, e))?;
+    if let Source::Dataset(dataset_config) = source {
+        match pool::preflight(conn, options, dataset_config, &commands) {
+            Ok(()) => {}
+            Err(PoolError::Deferred(e)) => return Err((SourceStatus::Deferred, e)),
+            Err(PoolError::Failed(e)) => return Err((SourceStatus::Failed, e)),
+        }
+    }
+    
+    match device::mount_target(source.target_dir(), source.device()) {
+        Ok(()) => {}
+        Err(MountError::NotPresent(e)) => return Err((SourceStatus::DeviceNotPresent, e)),
+        Err(MountError::Failed(e)) => return Err((SourceStatus::Failed, e)),
+    }
+    
+    let target = device_fault::Target::of(source.target_dir());
+    resources::apply(&config.resources.scheduling, source.scheduling());
+    immutable_guards.extend(immutable::unlock(source.target_dir(), source.immutable()));
+    let link_pool = config.link_pool_dirs(source);
+    let result = match source {
+        Source::Dataset(dataset_config) => {
+            let _key_guard = match zfs_keys::unlock(&dataset_config.name, &dataset_config.keys, &commands) {
+                Ok(guard) => guard,
+                Err(UnlockError::Locked(e)) => return Err((SourceStatus::Locked, e)),
+                Err(UnlockError::Failed(e)) => return Err((SourceStatus::Failed, e)),
+            };
+            nested::check(config, dataset_config, &commands)
+                .and_then(|()| backup_dataset(dataset_config, conn, options, tool_versions, &link_pool, changed_files))
+                .inspect(|()| auto_snapshot::prune(conn, options, dataset_config, &commands))
+        }
+        Source::Restic(restic_config) => {
+            let before = restic_stats::before(restic_config, &commands);
+            backup_restic(restic_config, conn, options, tool_versions, &link_pool, changed_files)
+                .inspect(|()| restic_stats::record(conn, &options.hostname, restic_config, before, &commands))
+        }
+    };
+    result.map_err(|e| match target.as_ref().and_then(|target| Some((target, target.fault(&e)?))) {
+        Some((target, fault)) => {
+            device_fault::failed(source.name(), &target.mount_point);
+            (SourceStatus::DeviceFailed, format!("Target device {} {}: {}", target.device, fault.as_str(), e))
+        }
+        None => (SourceStatus::Failed, e),
+    })
+}
+
+
+// Leave the run's log on every target that is present, with the summary
+// trimmed to the sources backed up to it
+fn write_run_logs(options: &RunOptions, run_uuid: &str, started_at: SystemTime, log: &str, summaries: &[SourceSummary]) {
+    let run_name = clock::compact_utc(started_at);
+    let finished_at = clock::iso_utc(SystemTime::now());
+    
+    let mut target_dirs: Vec<&str> = summaries
+        .iter()
+        .filter(|summary| summary.status != SourceStatus::DeviceNotPresent && summary.failed_device.is_none())
+        .map(|summary| summary.target_dir.as_str())
+        .collect();
+    target_dirs.sort();
+    target_dirs.dedup();
+    
+    for target_dir in target_dirs {
+        if !Path::new(target_dir).is_dir() {
+            continue;
+        }
+        let summary = runlog::RunSummary {
+            run_id: Some(run_uuid.to_string()),
+            hostname: options.hostname.clone(),
+            started_at: clock::iso_utc(started_at),
+            finished_at: finished_at.clone(),
+            pools: pool::run_health(),
+            sources: summaries.iter().filter(|s| s.target_dir == target_dir).cloned().collect(),
+        };
+        if let Err(e) = runlog::write(Path::new(target_dir), &run_name, log, &summary) {
+            eprintln!("Warning: Failed to write run log to target '{}': {}", target_dir, e);
+        }
+    }
+}
+
+
+fn init_database(db_path: &Path, hostname: &str) -> Result<Connection, String> {
+    // Create parent directory if it doesn't exist
+    if let Some(parent) = db_path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create database directory: {}", e))?;
+    }
+    
+    let conn = Connection::open(db_path)
+        .map_err(|e| format!("Failed to open database: {}", e))?;
+    
+    // Create the backup_history table if it doesn't exist
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS backup_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            backup_type TEXT NOT NULL,
+            source_name TEXT NOT NULL,
+            snapshot_name TEXT NOT NULL,
+            backup_timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+            target_dir TEXT NOT NULL,
+            UNIQUE(backup_type, source_name, snapshot_name)
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+    
+    // Create index for faster lookups
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_source_lookup 
+         ON backup_history(backup_type, source_name)",
+        [],
+    ).map_err(|e| format!("Failed to create index: {}", e))?;
+    
+    file_state::create_table(&conn)?;
+    encrypted::create_table(&conn)?;
+    diff_cache::create_table(&conn)?;
+    pause::create_table(&conn)?;
+    anomaly::create_table(&conn)?;
+    resync::create_table(&conn)?;
+    zvol::create_table(&conn)?;
+    executed::create_table(&conn)?;
+    restic_stats::create_table(&conn)?;
+    changed_files::create_table(&conn)?;
+    
+    // Create the runs table, one row per invocation, recording the tool versions used
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            started_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            finished_at DATETIME,
+            rsync_version TEXT,
+            restic_version TEXT,
+            zfs_version TEXT
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+    
+    // Outcome of each source within a run
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS run_sources (
+            run_id INTEGER NOT NULL REFERENCES runs(id),
+            backup_type TEXT NOT NULL,
+            source_name TEXT NOT NULL,
+            status TEXT NOT NULL,
+            error TEXT,
+            duration_secs INTEGER NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+    
+    migrate_database(&conn, hostname)?;
+    
+    Ok(conn)
+}
+
+
+// Open an existing database without creating or migrating anything, for
+// commands that only read it
+fn open_database_read_only(db_path: &Path) -> Result<Connection, String> {
+    if !db_path.exists() {
+        return Err("No database yet; it is created by the first backup run".to_string());
+    }
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("Failed to open database: {}", e))?;
+    
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| format!("Failed to read schema version: {}", e))?;
+    if version < SCHEMA_VERSION {
+        return Err(format!(
+            "Database has schema version {} and needs upgrading to {}, which takes write access. Run any other file-backup command as its owner first.",
+            version, SCHEMA_VERSION
+        ));
+    }
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "Database has schema version {}, but this version of file-backup only understands up to {}. Please upgrade file-backup.",
+            version, SCHEMA_VERSION
+        ));
+    }
+    
+    Ok(conn)
+}
+
+
+// Schema changes made since the tables were first created, applied in order.
+// PRAGMA user_version records how many of them a database has had applied.
+const SCHEMA_VERSION: i64 = 7;
+
+fn migrate_database(conn: &Connection, hostname: &str) -> Result<(), String> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| format!("Failed to read schema version: {}", e))?;
+    
+    if version > SCHEMA_VERSION {
+        // Only databases from schema version 4 on record who wrote them
+        let written_by: Option<String> = conn.query_row(
+            "SELECT file_backup_version FROM runs WHERE file_backup_version IS NOT NULL ORDER BY id DESC LIMIT 1",
+            [],
+            |row| row.get(0),
+        ).ok();
+        return Err(format!(
+            "Database has schema version {}{}, but this version of file-backup only understands up to {}. Please upgrade file-backup.",
+            version,
+            build_info::written_by(written_by.as_deref()),
+            SCHEMA_VERSION
+        ));
+    }
+    
+    if version < 1 {
+        add_hostname_columns(conn, hostname)?;
+    }
+    if version < 2 {
+        add_rsync_exit_columns(conn)?;
+    }
+    if version < 3 {
+        add_allocated_size_column(conn)?;
+    }
+    if version < 4 {
+        add_file_backup_version_column(conn)?;
+    }
+    if version < 5 {
+        add_snapshot_guid_column(conn)?;
+    }
+    if version < 6 {
+        add_run_uuid_columns(conn)?;
+    }
+    if version < 7 {
+        add_backed_up_epoch_column(conn)?;
+    }
+    
+    Ok(())
+}
+
+
+// Schema version 1: backup history and runs are recorded per host. Existing
+// rows are assumed to belong to the host doing the upgrade.
+fn add_hostname_columns(conn: &Connection, hostname: &str) -> Result<(), String> {
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    
+    // The unique constraint has to include the host, which needs a new table
+    tx.execute_batch(
+        "ALTER TABLE backup_history RENAME TO backup_history_old;
+         CREATE TABLE backup_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hostname TEXT NOT NULL,
+            backup_type TEXT NOT NULL,
+            source_name TEXT NOT NULL,
+            snapshot_name TEXT NOT NULL,
+            backup_timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+            target_dir TEXT NOT NULL,
+            UNIQUE(hostname, backup_type, source_name, snapshot_name)
+         );"
+    ).map_err(|e| format!("Failed to migrate backup_history: {}", e))?;
+    
+    tx.execute(
+        "INSERT INTO backup_history (id, hostname, backup_type, source_name, snapshot_name, backup_timestamp, target_dir)
+         SELECT id, ?1, backup_type, source_name, snapshot_name, backup_timestamp, target_dir
+         FROM backup_history_old",
+        [hostname],
+    ).map_err(|e| format!("Failed to migrate backup_history: {}", e))?;
+    
+    tx.execute_batch(
+        "DROP TABLE backup_history_old;
+         CREATE INDEX IF NOT EXISTS idx_source_lookup
+         ON backup_history(backup_type, source_name, hostname);
+         ALTER TABLE runs ADD COLUMN hostname TEXT;"
+    ).map_err(|e| format!("Failed to migrate backup_history: {}", e))?;
+    
+    tx.execute(
+        "UPDATE runs SET hostname = ?1",
+        [hostname],
+    ).map_err(|e| format!("Failed to migrate runs: {}", e))?;
+    
+    tx.execute_batch("PRAGMA user_version = 1")
+        .map_err(|e| format!("Failed to update schema version: {}", e))?;
+    
+    tx.commit().map_err(|e| format!("Failed to commit migration: {}", e))?;
+    
+    Ok(())
+}
+
+
+// Schema version 2: how rsync exited for each source, and what it skipped
+fn add_rsync_exit_columns(conn: &Connection) -> Result<(), String> {
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    
+    tx.execute_batch(
+        "ALTER TABLE run_sources ADD COLUMN rsync_exit_code INTEGER;
+         ALTER TABLE run_sources ADD COLUMN skipped_files TEXT;
+         PRAGMA user_version = 2;"
+    ).map_err(|e| format!("Failed to migrate run_sources: {}", e))?;
+    
+    tx.commit().map_err(|e| format!("Failed to commit migration: {}", e))?;
+    
+    Ok(())
+}
+
+
+// Schema version 3: space the synced files take up, next to their apparent size
+fn add_allocated_size_column(conn: &Connection) -> Result<(), String> {
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    
+    tx.execute_batch(
+        "ALTER TABLE transfer_stats ADD COLUMN bytes_allocated INTEGER;
+         PRAGMA user_version = 3;"
+    ).map_err(|e| format!("Failed to migrate transfer_stats: {}", e))?;
+    
+    tx.commit().map_err(|e| format!("Failed to commit migration: {}", e))?;
+    
+    Ok(())
+}
+
+
+// Schema version 4: the version of file-backup behind each run, so a
+// database a newer version has upgraded can say which one to install
+fn add_file_backup_version_column(conn: &Connection) -> Result<(), String> {
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    
+    tx.execute_batch(
+        "ALTER TABLE runs ADD COLUMN file_backup_version TEXT;
+         PRAGMA user_version = 4;"
+    ).map_err(|e| format!("Failed to migrate runs: {}", e))?;
+    
+    tx.commit().map_err(|e| format!("Failed to commit migration: {}", e))?;
+    
+    Ok(())
 }
 
 
-#[derive(Debug, Deserialize)]
-struct DatasetConfig {
-    name: String,
-    target_dir: PathBuf,
+// Schema version 5: the guid of each dataset snapshot backed up, which
+// stays the same when the dataset is renamed
+fn add_snapshot_guid_column(conn: &Connection) -> Result<(), String> {
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    
+    tx.execute_batch(
+        "ALTER TABLE backup_history ADD COLUMN snapshot_guid TEXT;
+         PRAGMA user_version = 5;"
+    ).map_err(|e| format!("Failed to migrate backup_history: {}", e))?;
+    
+    tx.commit().map_err(|e| format!("Failed to commit migration: {}", e))?;
+    
+    Ok(())
 }
 
 
-#[derive(Debug, Deserialize)]
-struct ResticConfig {
-    repository: String,
-    target_dir: PathBuf,
+// Schema version 6: the UUID of each run and the job ID of each source in
+// it, as they appear in the run's output and run logs
+fn add_run_uuid_columns(conn: &Connection) -> Result<(), String> {
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    
+    tx.execute_batch(
+        "ALTER TABLE runs ADD COLUMN uuid TEXT;
+         ALTER TABLE run_sources ADD COLUMN job_id TEXT;
+         CREATE INDEX IF NOT EXISTS idx_runs_uuid ON runs(uuid);
+         PRAGMA user_version = 6;"
+    ).map_err(|e| format!("Failed to migrate runs: {}", e))?;
+    
+    tx.commit().map_err(|e| format!("Failed to commit migration: {}", e))?;
+    
+    Ok(())
 }
 
 
-fn main() {
-    let args = Args::parse();
-
-    // Check if rsync is installed
-    if let Err(e) = check_rsync_installed() {
-        eprintln!("Error: {}", e);
-        exit(1);
-    }    
+// Schema version 7: when each backup was recorded as seconds since the
+// epoch, which backups are ordered by. backup_timestamp is text, which sorts
+// wrongly once rows were written in more than one format; rows whose text
+// SQLite can't parse are left without an epoch and sort as the oldest.
+fn add_backed_up_epoch_column(conn: &Connection) -> Result<(), String> {
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    
+    tx.execute_batch(
+        "ALTER TABLE backup_history ADD COLUMN backed_up_epoch INTEGER;
+         UPDATE backup_history SET backed_up_epoch = CAST(strftime('%s', backup_timestamp) AS INTEGER);
+         PRAGMA user_version = 7;"
+    ).map_err(|e| format!("Failed to migrate backup_history: {}", e))?;
+    
+    tx.commit().map_err(|e| format!("Failed to commit migration: {}", e))?;
+    
+    Ok(())
+}
 
-    // Check if restic is installed
-    if let Err(e) = check_restic_installed() {
-        eprintln!("Error: {}", e);
-        exit(1);
-    }    
 
-    // Initialize database
-    let conn = match init_database(&args.database) {
-        Ok(conn) => conn,
-        Err(e) => {
-            eprintln!("Error initializing database '{}': {}", args.database.display(), e);
-            exit(1);
-        }
-    };
+fn get_hostname() -> String {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| {
+            Command::new("hostname")
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+        })
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default();
+    
+    if hostname.is_empty() { "localhost".to_string() } else { hostname }
+}
 
 
-    // Load configuration
-    let config = match load_config(&args.config) {
-        Ok(config) => config,
-        Err(e) => {
-            eprintln!("Error loading config file '{}': {}", args.config.display(), e);
-            exit(1);
-        }
-    };   
+fn start_run(conn: &Connection, options: &RunOptions, tool_versions: &ToolVersions, run_uuid: &str) -> Result<i64, String> {
+    conn.execute(
+        "INSERT INTO runs (hostname, rsync_version, restic_version, zfs_version, file_backup_version, uuid) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        [
+            Some(options.hostname.clone()),
+            tool_versions.rsync.map(|v| v.to_string()),
+            tool_versions.restic.map(|v| v.to_string()),
+            tool_versions.zfs.map(|v| v.to_string()),
+            Some(build_info::VERSION.to_string()),
+            Some(run_uuid.to_string()),
+        ],
+    )
+    .map_err(|e| format!("Failed to record run in database: {}", e))?;
+    
+    Ok(conn.last_insert_rowid())
+}
 
-    println!("Processing {} dataset{} and {} restic repositor{}...\n", 
-        config.dataset.len(), 
-        if config.dataset.len() == 1 { "" } else { "s" },
-        config.restic.len(),
-        if config.restic.len() == 1 { "y" } else { "ies" }
-    );
-            
-    // Process each dataset
-    for dataset_config in &config.dataset {
-        match backup_dataset(dataset_config, &conn) {
-            Ok(()) => {}
-            Err(e) => {
-                eprintln!("Error: {}", e);
-                eprintln!("Skipping dataset '{}'\n", dataset_config.name);
-            }
-        }
-    }
 
-    // Process each restic repository
-    for restic_config in &config.restic {
-        match backup_restic(restic_config, &conn) {
-            Ok(()) => {}
-            Err(e) => {
-                eprintln!("Error: {}", e);
-                eprintln!("Skipping restic repository '{}'\n", restic_config.repository);
-            }
-        }
-    }
+fn record_source_result(conn: &Connection, run_id: i64, backup_type: &str, summary: &SourceSummary) -> Result<(), String> {
+    conn.execute(
+        "INSERT INTO run_sources (run_id, backup_type, source_name, status, error, duration_secs, rsync_exit_code, skipped_files, job_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        rusqlite::params![
+            run_id,
+            backup_type,
+            summary.name,
+            summary.status.as_str(),
+            summary.error,
+            summary.duration_secs as i64,
+            summary.rsync_exit_code,
+            (!summary.skipped_files.is_empty()).then(|| summary.skipped_files.join("\n")),
+            summary.job_id,
+        ],
+    )
+    .map_err(|e| format!("Failed to record result of '{}' in database: {}", summary.name, e))?;
     
-    println!("Done!");
+    Ok(())
 }
 
 
-fn init_database(db_path: &PathBuf) -> Result<Connection, String> {
-    // Create parent directory if it doesn't exist
-    if let Some(parent) = db_path.parent() {
-        fs::create_dir_all(parent)
-            .map_err(|e| format!("Failed to create database directory: {}", e))?;
-    }
-    
-    let conn = Connection::open(db_path)
-        .map_err(|e| format!("Failed to open database: {}", e))?;
-    
-    // Create the backup_history table if it doesn't exist
+fn finish_run(conn: &Connection, run_id: i64) -> Result<(), String> {
     conn.execute(
-        "CREATE TABLE IF NOT EXISTS backup_history (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            backup_type TEXT NOT NULL,
-            source_name TEXT NOT NULL,
-            snapshot_name TEXT NOT NULL,
-            backup_timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
-            target_dir TEXT NOT NULL,
-            UNIQUE(backup_type, source_name, snapshot_name)
-        )",
-        [],
-    ).map_err(|e| format!("Failed to create table: {}", e))?;
+        "UPDATE runs SET finished_at = CURRENT_TIMESTAMP WHERE id = ?1",
+        [run_id],
+    )
+    .map_err(|e| format!("Failed to record run completion in database: {}", e))?;
     
-    // Create index for faster lookups
-    conn.execute(
-        "CREATE INDEX IF NOT EXISTS idx_source_lookup 
-         ON backup_history(backup_type, source_name)",
-        [],
-    ).map_err(|e| format!("Failed to create index: {}", e))?;
+    Ok(())
+}
+
+
+// The snapshot an incremental backup of the source starts from: --since when
+// it was given, otherwise the last one backed up that still exists
+fn backup_base(conn: &Connection, options: &RunOptions, source: Source, commands: &Log) -> Result<Option<String>, String> {
+    let Some(since) = &options.since else {
+        return Ok(match get_last_backed_up_snapshot(conn, options.host_filter(), source, commands) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("Warning: Failed to query database: {}", e);
+                None
+            }
+        });
+    };
     
-    Ok(conn)
+    let base = if source.backup_type() != "dataset" {
+        since.clone()
+    } else if since.contains('#') {
+        return Err("--since: zfs diff only compares snapshots, not bookmarks".to_string());
+    } else if since.contains('@') && !since.starts_with('@') {
+        since.clone()
+    } else {
+        format!("{}@{}", source.name(), since.trim_start_matches('@'))
+    };
+    if !snapshot_exists(&base, source, commands)? {
+        return Err(format!("--since: snapshot '{}' doesn't exist", base));
+    }
+    println!("Starting from {} as --since says, rather than the last backup recorded", base);
+    Ok(Some(base))
 }
 
 
 fn get_last_backed_up_snapshot(
     conn: &Connection, 
-    backup_type: &str, 
-    source_name: &str
+    hostname: Option<&str>,
+    source: Source,
+    commands: &Log,
 ) -> SqliteResult<Option<String>> {
     let mut stmt = conn.prepare(
-        "SELECT snapshot_name, backup_timestamp 
+        "SELECT snapshot_name, backup_timestamp, backed_up_epoch
          FROM backup_history 
-         WHERE backup_type = ?1 AND source_name = ?2 
-         ORDER BY backup_timestamp DESC"
+         WHERE backup_type = ?1 AND source_name = ?2 AND (?3 IS NULL OR hostname = ?3)
+         ORDER BY backed_up_epoch DESC, id DESC"
     )?;
     
-    let mut rows = stmt.query([backup_type, source_name])?;
+    let mut rows = stmt.query(rusqlite::params![source.backup_type(), source.name(), hostname])?;
+    let mut newest = true;
     
     // Walk through backup history until we find a snapshot that still exists
     while let Some(row) = rows.next()? {
         let snapshot_name: String = row.get(0)?;
         let timestamp: String = row.get(1)?;
+        if newest && let Some(epoch) = row.get::<_, Option<i64>>(2)? {
+            warn_if_ahead_of_clock(source.name(), &timestamp, epoch);
+        }
+        newest = false;
         
         // Check if this snapshot still exists
-        match snapshot_exists(&snapshot_name, backup_type, source_name) {
+        match snapshot_exists(&snapshot_name, source, commands) {
             Ok(true) => {
                 println!("Last successful backup: {} (at {})", snapshot_name, timestamp);
                 return Ok(Some(snapshot_name));
@@ -185,21 +2576,44 @@ fn get_last_backed_up_snapshot(
     Ok(None)
 }
 
-fn snapshot_exists(snapshot: &str, backup_type: &str, source_name: &str) -> Result<bool, String> {
-    match backup_type {
-        "dataset" => {
-            let output = Command::new("zfs")
-                .args(["list", "-H", "-t", "snapshot", snapshot])
-                .output()
+// Leeway for clocks that differ a little between the hosts sharing a database
+const CLOCK_SKEW_TOLERANCE_SECS: i64 = 300;
+
+// A newest backup recorded in the future means the clock has since been set
+// back, or the row came from a host whose clock is ahead. Backups recorded
+// until the clock catches up would sort before it.
+fn warn_if_ahead_of_clock(source_name: &str, timestamp: &str, epoch: i64) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0);
+    if epoch > now + CLOCK_SKEW_TOLERANCE_SECS {
+        eprintln!(
+            "Warning: The last backup of '{}' was recorded at {} UTC, {}s ahead of this host's clock; check the clock, as backups are ordered by when they were recorded",
+            source_name,
+            timestamp,
+            epoch - now
+        );
+    }
+}
+
+
+fn snapshot_exists(snapshot: &str, source: Source, commands: &Log) -> Result<bool, String> {
+    match source {
+        Source::Dataset(_) => {
+            let output = child_env::command("zfs")
+                .args(["list", "-H", "-o", "name", "-t", "snapshot", snapshot])
+                .recorded_output(commands)
                 .map_err(|e| format!("Failed to execute zfs command: {}", e))?;
             
             Ok(output.status.success())
         }
-        "restic" => {
-            // For restic, source_name is the repository path
-            let output = Command::new("restic")
-                .args(["-r", source_name, "snapshots", snapshot, "--json"])
-                .output()
+        Source::Restic(restic_config) => {
+            let output = restic_lock::output(restic_config, commands, || {
+                let mut command = privileges::restic(&restic_config.repository, restic_config.user.as_ref());
+                command.args(["snapshots", snapshot, "--json"]);
+                command
+            })
                 .map_err(|e| format!("Failed to execute restic command: {}", e))?;
             
             
@@ -224,60 +2638,193 @@ fn snapshot_exists(snapshot: &str, backup_type: &str, source_name: &str) -> Resu
             
             Ok(true)
         }
-        _ => Err(format!("Unknown backup type: {}", backup_type))
-    }
-}
-
-
-fn check_rsync_installed() -> Result<(), String> {
-    match Command::new("rsync")
-        .arg("--version")
-        .output()
-    {
-        Ok(output) if output.status.success() => Ok(()),
-        Ok(_) => Err("rsync command failed".to_string()),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            Err("rsync is not installed. Please install rsync and try again.".to_string())
-        }
-        Err(e) => Err(format!("Failed to check for rsync: {}", e)),
     }
 }
 
 
-fn check_restic_installed() -> Result<(), String> {
-    match Command::new("restic")
-        .arg("version")
-        .output()
-    {
-        Ok(output) if output.status.success() => Ok(()),
-        Ok(_) => Err("restic command failed".to_string()),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            Err("restic is not installed. Please install restic and try again.".to_string())
-        }
-        Err(e) => Err(format!("Failed to check for restic: {}", e)),
-    }
-}
-
-
-fn load_config(path: &PathBuf) -> Result<Config, String> {
+fn load_config(path: &Path) -> Result<Config, String> {
     let contents = fs::read_to_string(path)
         .map_err(|e| format!("Failed to read file: {}", e))?;
     
-    let config: Config = toml::from_str(&contents)
+    build_info::check_config(&contents)?;
+    
+    let mut config: Config = toml::from_str(&contents)
         .map_err(|e| format!("Failed to parse TOML: {}", e))?;
     
-    if config.dataset.is_empty() && config.restic.is_empty() {
-        return Err("No datasets or restic repositories defined in config file".to_string());
+    if config.dataset.is_empty() && config.restic.is_empty() && config.hosts.is_empty() {
+        return Err("No datasets, restic repositories or hosts defined in config file".to_string());
+    }
+    
+    template::prepare(&mut config)?;
+    filter_file::prepare(&mut config)?;
+    restic_env::prepare(&mut config)?;
+    privileges::prepare(&mut config)?;
+    
+    for source in config.sources() {
+        source.device()
+            .validate()
+            .and_then(|()| source.encryption().validate())
+            .map_err(|e| format!("{} '{}': {}", source.kind(), source.name(), e))?;
+        if source.encryption().encrypt.is_some() && source.layout() != Layout::Mirror {
+            return Err(format!("{} '{}': encrypt only works with the mirror layout", source.kind(), source.name()));
+        }
+        if source.compression().is_some()
+            && source.encryption().encrypt.is_none()
+            && !matches!(source.layout(), Layout::Stream | Layout::Image)
+        {
+            return Err(format!(
+                "{} '{}': compression needs encrypt, or layout = \"stream\" or \"image\"",
+                source.kind(),
+                source.name()
+            ));
+        }
+        let incremental = match source {
+            Source::Dataset(d) => d.zvol_mode.is_none(),
+            Source::Restic(r) => r.mode == ResticMode::Mount,
+        } && source.layout() == Layout::Mirror && source.encryption().encrypt.is_none();
+        if source.full_resync_every().is_some() && !incremental {
+            return Err(format!(
+                "{} '{}': full_resync_every only applies to mirror targets updated from a diff",
+                source.kind(),
+                source.name()
+            ));
+        }
+        if source.on_drift().is_some() && !incremental {
+            return Err(format!(
+                "{} '{}': on_drift only applies to mirror targets updated from a diff",
+                source.kind(),
+                source.name()
+            ));
+        }
+        // The sample is hashed on a plain copy of the snapshot's files
+        let plain_copy = match source {
+            Source::Dataset(d) => d.zvol_mode.is_none(),
+            Source::Restic(r) => r.mode == ResticMode::Mount,
+        } && source.encryption().encrypt.is_none();
+        let rsync_copy = plain_copy && matches!(source.layout(), Layout::Mirror | Layout::Versioned);
+        if source.filter().is_some() && !rsync_copy {
+            return Err(format!(
+                "{} '{}': filter_file is for sources copied file by file with rsync",
+                source.kind(),
+                source.name()
+            ));
+        }
+        source.ownership().validate().map_err(|e| format!("{} '{}': {}", source.kind(), source.name(), e))?;
+        if source.ownership().maps_ids()
+            && (source.encryption().encrypt.is_some() || !matches!(source.layout(), Layout::Mirror | Layout::Versioned))
+        {
+            return Err(format!(
+                "{} '{}': uid_map, gid_map and numeric_ids apply as restore copies files back with rsync, so need the mirror or versioned layout and no encryption",
+                source.kind(),
+                source.name()
+            ));
+        }
+        if source.symlinks() != Symlinks::Preserve && !rsync_copy {
+            return Err(format!(
+                "{} '{}': symlinks is for sources copied file by file with rsync",
+                source.kind(),
+                source.name()
+            ));
+        }
+        if source.symlinks() == Symlinks::Stub && source.layout() != Layout::Mirror {
+            return Err(format!(
+                "{} '{}': symlinks = \"stub\" keeps the link targets beside a mirror, so needs the mirror layout",
+                source.kind(),
+                source.name()
+            ));
+        }
+        if source.verify_sample().is_some() && !plain_copy {
+            return Err(format!(
+                "{} '{}': verify_sample needs a plain copy of the files on the target",
+                source.kind(),
+                source.name()
+            ));
+        }
+        if source.link_pool().is_some() && source.layout() != Layout::Versioned {
+            return Err(format!(
+                "{} '{}': link_pool hard-links versions to each other, so needs layout = \"versioned\"",
+                source.kind(),
+                source.name()
+            ));
+        }
+        // The flag is on the files themselves, which the pool shares: one
+        // source's lock would stop the others linking to or pruning them, and
+        // lifting it would unlock theirs
+        if source.link_pool().is_some() && source.immutable() == Some(ImmutableScope::Tree) {
+            return Err(format!(
+                "{} '{}': link_pool can't be used with immutable = \"tree\", as the sources share files; use \"top\"",
+                source.kind(),
+                source.name()
+            ));
+        }
+        if source.spin_down() {
+            platform::linux_only("spin_down").map_err(|e| format!("{} '{}': {}", source.kind(), source.name(), e))?;
+        }
+    }
+    
+    order::validate(&config)?;
+    concurrency::validate(&config.concurrency)?;
+    budget::validate(&config.targets)?;
+    excludes::validate(&config.excludes)?;
+    events::validate(&config.notify)?;
+    
+    for dataset_config in &config.dataset {
+        nested::validate(dataset_config)
+            .and_then(|()| dataset_config.keys.validate())
+            .map_err(|e| format!("Dataset '{}': {}", dataset_config.name, e))?;
+        if dataset_config.metadata_sidecar {
+            platform::linux_only("metadata_sidecar").map_err(|e| format!("Dataset '{}': {}", dataset_config.name, e))?;
+        }
+        if dataset_config.quiesce.is_some() && !dataset_config.auto_snapshot {
+            return Err(format!(
+                "Dataset '{}': quiesce runs around the snapshot auto_snapshot takes, so needs auto_snapshot = true",
+                dataset_config.name
+            ));
+        }
+        if (dataset_config.snapshot_template.is_some() || dataset_config.keep_auto_snapshots.is_some()) && !dataset_config.auto_snapshot {
+            return Err(format!(
+                "Dataset '{}': snapshot_template and keep_auto_snapshots are for the snapshots auto_snapshot takes, so need auto_snapshot = true",
+                dataset_config.name
+            ));
+        }
+        if dataset_config.keep_auto_snapshots == Some(0) {
+            return Err(format!(
+                "Dataset '{}': keep_auto_snapshots must be at least 1, to keep the snapshot the next backup diffs from",
+                dataset_config.name
+            ));
+        }
+    }
+    
+    for restic_config in &config.restic {
+        if matches!(restic_config.layout, Layout::Stream | Layout::Image) {
+            return Err(format!(
+                "Restic repository '{}': layout = \"stream\" and \"image\" are only for datasets",
+                restic_config.repository
+            ));
+        }
+        if (restic_config.layout == Layout::Versioned || restic_config.encryption.encrypt.is_some())
+            && restic_config.mode == ResticMode::Restore
+        {
+            return Err(format!(
+                "Restic repository '{}': layout = \"versioned\" needs mode = \"mount\"",
+                restic_config.repository
+            ));
+        }
+        if restic_config.mode == ResticMode::Mount && platform::OS == platform::Os::Illumos {
+            return Err(format!(
+                "Restic repository '{}': illumos has no FUSE for restic mount, so set mode = \"restore\"",
+                restic_config.repository
+            ));
+        }
     }
     
     Ok(config)
 }
 
-fn is_dataset_mounted(dataset: &str) -> Result<bool, String> {
-    // Run `zfs get -H mounted <dataset>`
-    let output = Command::new("zfs")
-        .args(["get", "-H", "mounted", dataset])
-        .output()
+fn is_dataset_mounted(dataset: &str, commands: &Log) -> Result<bool, String> {
+    let output = child_env::command("zfs")
+        .args(["get", "-H", "-o", "value", "mounted", dataset])
+        .recorded_output(commands)
         .map_err(|e| format!("Failed to execute zfs command: {}", e))?;
     
     if !output.status.success() {
@@ -285,20 +2832,11 @@ fn is_dataset_mounted(dataset: &str) -> Result<bool, String> {
         return Err(format!("zfs command failed: {}", stderr.trim()));
     }
     
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    
-    // Parse the output: format is "dataset\tmounted\tyes|no\tsource"
-    let is_mounted = stdout
-        .split('\t')
-        .nth(2)
-        .map(|s| s.trim() == "yes")
-        .unwrap_or(false);
-    
-    Ok(is_mounted)
+    Ok(String::from_utf8_lossy(&output.stdout).trim() == "yes")
 }
 
 
-fn check_target_directory(target_dir: &PathBuf) -> Result<(), String> {
+fn check_target_directory(target_dir: &Path) -> Result<(), String> {
     if !target_dir.exists() {
         return Err(format!(
             "Target directory '{}' does not exist. Is the removable device mounted?",
@@ -317,15 +2855,15 @@ fn check_target_directory(target_dir: &PathBuf) -> Result<(), String> {
 }
 
 
-fn get_latest_snapshot(dataset: &str) -> Result<Option<String>, String> {
+fn get_latest_snapshot(dataset: &str, commands: &Log) -> Result<Option<String>, String> {
     // Run `zfs list -t snapshot -o name -s creation -H -r <dataset>`
     // -t snapshot: only snapshots
     // -o name: only output the name
     // -s creation: sort by creation time
     // -H: no headers (scriptable)
-    let output = Command::new("zfs")
-        .args(["list", "-t", "snapshot", "-o", "name", "-s", "creation", "-H", dataset])
-        .output()
+    let output = child_env::command("zfs")
+        .args(["list", "-Hp", "-t", "snapshot", "-o", "name", "-s", "creation", dataset])
+        .recorded_output(commands)
         .map_err(|e| format!("Failed to execute zfs command: {}", e))?;
     
     if !output.status.success() {
@@ -338,38 +2876,60 @@ fn get_latest_snapshot(dataset: &str) -> Result<Option<String>, String> {
     // Get the last line (most recent due to sort order)
     let latest = stdout
         .lines()
-        .filter(|line| !line.is_empty())
-        .last()
+        .rfind(|line| !line.is_empty())
         .map(|s| s.to_string());
     
     Ok(latest)
 }
 
 
-fn backup_dataset(dataset_config: &DatasetConfig, conn: &Connection) -> Result<(), String> {
-    println!("=== Dataset: {} ===", dataset_config.name);
+fn backup_dataset(
+    dataset_config: &DatasetConfig,
+    conn: &Connection,
+    options: &RunOptions,
+    tool_versions: &ToolVersions,
+    link_pool: &[PathBuf],
+    changed_files: &mut ChangedFiles,
+) -> Result<(), String> {
+    let commands = changed_files.commands.clone();
+    println!("=== Dataset: {}{} ===", dataset_config.name, run_id::job_label());
+    
+    if dataset_config.auto_snapshot {
+        let template = dataset_config.snapshot_template.clone().unwrap_or_default();
+        quiesce::take_snapshot(&dataset_config.name, &template, dataset_config.quiesce.as_ref(), &commands)?;
+    }
+    
+    if options.since.is_some() && (dataset_config.zvol_mode.is_some() || matches!(dataset_config.layout, Layout::Stream | Layout::Image)) {
+        return Err("--since only applies to backups copied with rsync, not to zvol_mode or the stream and image layouts".to_string());
+    }
+    if let Some(mode) = dataset_config.zvol_mode {
+        return zvol::backup(dataset_config, mode, conn, options, &commands);
+    }
+    if dataset_config.layout == Layout::Stream {
+        return streams::backup(dataset_config, conn, options, &commands);
+    }
+    if dataset_config.layout == Layout::Image {
+        return images::backup(dataset_config, conn, options, &commands);
+    }
     
     // Check if target directory exists
-    if let Err(e) = check_target_directory(&dataset_config.target_dir) { return Err(e); }
+    check_target_directory(&dataset_config.target_dir)?;
     
     // Check if dataset is mounted
-    match is_dataset_mounted(&dataset_config.name) {
+    match is_dataset_mounted(&dataset_config.name, &commands) {
         Ok(true) => println!("Dataset '{}' is mounted", dataset_config.name),
         Ok(false) => { return Err(format!("Dataset '{}' is NOT mounted", dataset_config.name))}
         Err(e) => { return Err(e)}
     }
     
     // Check database for last successful backup
-    let last_backup = match get_last_backed_up_snapshot(conn, "dataset", &dataset_config.name) {
-        Ok(snapshot) => snapshot,
-        Err(e) => {
-            eprintln!("Warning: Failed to query database: {}", e);
-            None
-        }
-    };
+    let mut last_backup = backup_base(conn, options, Source::Dataset(dataset_config), &commands)?;
+    if last_backup.is_none() && rename::detect(conn, options, &dataset_config.name, &commands)? {
+        last_backup = backup_base(conn, options, Source::Dataset(dataset_config), &commands)?;
+    }
     
     // Get the latest snapshot
-    let latest_snapshot = match get_latest_snapshot(&dataset_config.name) {
+    let latest_snapshot = match get_latest_snapshot(&dataset_config.name, &commands) {
         Ok(Some(snapshot)) => {
             println!("Latest snapshot: {}", snapshot);
             snapshot
@@ -379,29 +2939,132 @@ fn backup_dataset(dataset_config: &DatasetConfig, conn: &Connection) -> Result<(
     };
     
     println!("Target directory: {}", dataset_config.target_dir.display());
+    events::emit(events::Event::SnapshotSelected { snapshot: &latest_snapshot, base: last_backup.as_deref() });
+    
+    if last_backup.is_none() {
+        estimate::confirm_first_backup(Source::Dataset(dataset_config), &latest_snapshot, options, &commands)?;
+    }
+    if last_backup.as_deref() != Some(latest_snapshot.as_str()) {
+        inodes::preflight(conn, Source::Dataset(dataset_config), &latest_snapshot, &commands)?;
+    }
+    
+    // Neither layout is updated by rsyncing a diff, so both start from the whole snapshot
+    if dataset_config.layout == Layout::Versioned || dataset_config.encryption.encrypt.is_some() {
+        if last_backup.as_deref() == Some(latest_snapshot.as_str()) {
+            println!("Already backed up - nothing to do");
+        } else {
+            let snapshot_mountpoint = get_snapshot_mountpoint(&latest_snapshot, &commands)?;
+            let staged = if dataset_config.encryption.encrypt.is_some() {
+                encrypted::backup(conn, Source::Dataset(dataset_config), &latest_snapshot, &snapshot_mountpoint)?;
+                None
+            } else {
+                let sparse = sparse::for_full(dataset_config.sparse, conn, "dataset", &dataset_config.name);
+                let guid = diff_cache::snapshot_guid(&latest_snapshot, &commands).ok();
+                Some(versioned::stage(
+                    &snapshot_mountpoint,
+                    &dataset_config.target_dir,
+                    &latest_snapshot,
+                    guid,
+                    CopyOptions {
+                        special_files: dataset_config.special_files,
+                        symlinks: dataset_config.symlinks,
+                        sparse,
+                        filter: dataset_config.filter.as_ref(),
+                        run_as: dataset_config.user.as_ref(),
+                    },
+                    link_pool,
+                    changed_files,
+                )?)
+            };
+            
+            changed_files.file_state = record_full_file_state(conn, Source::Dataset(dataset_config), &latest_snapshot, &snapshot_mountpoint);
+            // The version only joins the target once it has checked out
+            if let Some(staged) = staged {
+                verify_sample::verify(conn, Source::Dataset(dataset_config), &latest_snapshot, &snapshot_mountpoint, staged.dir())?;
+                staged.commit()?;
+            }
+            
+            record_successful_backup(
+                conn,
+                &options.hostname,
+                Source::Dataset(dataset_config),
+                &latest_snapshot,
+                &commands,
+                Some(changed_files),
+            )?;
+            
+            println!("Backup recorded successfully");
+        }
+        
+        println!();
+        return Ok(());
+    }
+    
+    let delete_limit = resolve_delete_limit(
+        conn,
+        options,
+        "dataset",
+        &dataset_config.name,
+        dataset_config.max_delete.as_ref(),
+        &dataset_config.target_dir,
+    )?;
 
+    let drifted = match &last_backup {
+        Some(last_snap) => drift::check(conn, options, Source::Dataset(dataset_config), last_snap)?,
+        None => false,
+    };
+    let full_resync = last_backup.is_some() && (drifted || resync::is_due(conn, options.host_filter(), Source::Dataset(dataset_config)));
+    
    // Determine if we need to backup
-    match last_backup {
+    match last_backup.filter(|_| !full_resync) {
         None => {
-            // No previous backup - do a full rsync
-            println!("No previous backup found - performing full backup");
+            // No previous backup, or a full resync is due - do a full rsync
+            if full_resync {
+                println!("Full resync due - rsyncing the whole snapshot with --checksum");
+            } else {
+                println!("No previous backup found - performing full backup");
+            }
             
             // Get the mountpoint of the latest snapshot
-            let snapshot_mountpoint = get_snapshot_mountpoint(&latest_snapshot)?;
-            
-            // Ensure snapshot mountpoint ends with / for rsync
-            let source_path = format!("{}/", snapshot_mountpoint);
+            let snapshot_mountpoint = get_snapshot_mountpoint(&latest_snapshot, &commands)?;
             
             // Run rsync
-            run_rsync(&source_path, &dataset_config.target_dir)?;
+            let sparse = sparse::for_full(dataset_config.sparse, conn, "dataset", &dataset_config.name);
+            run_rsync(
+                &snapshot_mountpoint,
+                &dataset_config.target_dir,
+                delete_limit,
+                &dataset_config.nested_excludes,
+                full_resync,
+                CopyOptions {
+                        special_files: dataset_config.special_files,
+                        symlinks: dataset_config.symlinks,
+                        sparse,
+                        filter: dataset_config.filter.as_ref(),
+                        run_as: dataset_config.user.as_ref(),
+                    },
+                changed_files,
+            )?;
+            record_full_resync(conn, options, Source::Dataset(dataset_config));
+            
+            if dataset_config.metadata_sidecar {
+                let count = metadata::record_full(&snapshot_mountpoint, &dataset_config.target_dir)?;
+                println!("Kept extended attributes of {} path(s) in {}", count, metadata::META_DIR);
+            }
+            // After the sidecar, which starts the store afresh
+            symlinks::stub_full(dataset_config.symlinks, &snapshot_mountpoint, &dataset_config.target_dir, dataset_config.filter.as_ref())?;
+            
+            changed_files.file_state = record_full_file_state(conn, Source::Dataset(dataset_config), &latest_snapshot, &snapshot_mountpoint);
+            verify_sample::verify(conn, Source::Dataset(dataset_config), &latest_snapshot, &snapshot_mountpoint, &dataset_config.target_dir)?;
             
             // Record successful backup
             record_successful_backup(
                 conn,
-                "dataset",
-                &dataset_config.name,
+                &options.hostname,
+                Source::Dataset(dataset_config),
                 &latest_snapshot,
-                &dataset_config.target_dir.to_string_lossy(),
+                &commands,
+                Some(changed_files),
             )?;
             
             println!("Backup recorded successfully");
@@ -412,43 +3075,113 @@ fn backup_dataset(dataset_config: &DatasetConfig, conn: &Connection) -> Result<(
             } else {
                 println!("Incremental backup needed (last: {}, current: {})", last_snap, latest_snapshot);
                 
-                // Get the diff between snapshots
-                let changes = get_snapshot_diff(&last_snap, &latest_snapshot)?;
+                let dataset_mountpoint = get_dataset_mountpoint(&dataset_config.name, &commands)?;
+                let snapshot_mountpoint = get_snapshot_mountpoint(&latest_snapshot, &commands)?;
+                let target_dir = &dataset_config.target_dir;
+                // The diff's paths are synced as zfs finds them, so which of
+                // them are sparse isn't known before rsync starts
+                let sparse = sparse::for_full(dataset_config.sparse, conn, "dataset", &dataset_config.name);
+                let mut pass = DiffPass::new(
+                    conn,
+                    dataset_config,
+                    &dataset_mountpoint,
+                    &snapshot_mountpoint,
+                    &latest_snapshot,
+                    options.print_changes,
+                );
                 
-                if changes.is_empty() {
-                    println!("No changes detected between snapshots");
-                } else {
-                    println!("Found {} change(s):", changes.len());
-                    for change in &changes {
-                        println!("  {}", change);
-                    }
-                    
-                    // Extract files that need to be synced
-                    let dataset_mountpoint = get_dataset_mountpoint(&dataset_config.name)?;
-                    let files_to_sync = extract_files_for_sync(&changes, &dataset_mountpoint);
-                    
-                    // Extract files that need to be deleted
-                    let files_to_delete = extract_files_for_deletion(&changes, &dataset_mountpoint);
-                    
-                    // Delete removed files first
-                    if !files_to_delete.is_empty() {
-                        delete_files_from_target(&dataset_config.target_dir, &files_to_delete)?;
+                println!("Syncing changed files with rsync as the diff is read...");
+                let mut diffed = Ok(());
+                let synced = run_rsync_with_files(
+                    &snapshot_mountpoint,
+                    target_dir,
+                    dataset_config.symlinks,
+                    sparse,
+                    dataset_config.user.as_ref(),
+                    changed_files,
+                    |stdin| {
+                        diffed = diff_cache::snapshot_diff(conn, &last_snap, &latest_snapshot, tool_versions, &commands, &mut |change| {
+                            pass.add(change, stdin)
+                        });
+                        if diffed.is_ok() {
+                            pass.counts.print(options.print_changes);
+                            if pass.excluded > 0 {
+                                println!("Left out {} excluded path(s)", pass.excluded);
+                            }
+                        }
+                        pass.write_error.take().map_or(Ok(()), Err)
+                    },
+                );
+                diffed?;
+                synced?;
+                events::emit(events::Event::TransferProgress { step: "rsync of changed files", files: Some(pass.synced), bytes: None });
+                
+                // A path removed and added back is the new one now
+                let mut deleted = std::mem::take(&mut pass.deleted);
+                deleted.retain(|path| fs::symlink_metadata(snapshot_mountpoint.join(path)).is_err());
+                if !deleted.is_empty() {
+                    check_delete_limit(delete_limit, deleted.len(), target_dir)?;
+                }
+                
+                let replaced = std::mem::take(&mut pass.replaced);
+                if !replaced.is_empty() {
+                    remove_replaced_paths(target_dir, &replaced, dataset_config.delete_mode)?;
+                    run_rsync_with_file_list(
+                        &snapshot_mountpoint,
+                        target_dir,
+                        &replaced,
+                        dataset_config.symlinks,
+                        sparse,
+                        dataset_config.user.as_ref(),
+                        changed_files,
+                    )?;
+                    for path in &replaced {
+                        pass.follow_up(path)?;
                     }
-                    
-                    // Then sync changed/new files
-                    if !files_to_sync.is_empty() {
-                        let snapshot_mountpoint = get_snapshot_mountpoint(&latest_snapshot)?;
-                        let source_path = format!("{}/", snapshot_mountpoint);
-                        
-                        run_rsync_with_file_list(&source_path, &dataset_config.target_dir, &files_to_sync)?;
-                    }                        
-
-                    println!("Incremental backup recorded successfully");
                 }
+                
+                delete_files_from_target(
+                    &snapshot_mountpoint,
+                    target_dir,
+                    &deleted,
+                    dataset_config.delete_mode,
+                    dataset_config.user.as_ref(),
+                    changed_files,
+                )?;
+                for path in &deleted {
+                    pass.file_state.deleted(path);
+                }
+                if dataset_config.metadata_sidecar {
+                    metadata::record_changes(&snapshot_mountpoint, target_dir, &[], &deleted)?;
+                }
+                symlinks::stub_changes(dataset_config.symlinks, &snapshot_mountpoint, target_dir, &pass.stubs, &deleted)?;
+                
+                let changes = pass.counts.total();
+                changed_files.file_state = pass.file_state.finish()
+                    .map_err(|e| eprintln!("Warning: Failed to update file state: {}", e))
+                    .ok();
+                if changes > 0 {
+                    verify_sample::verify(conn, Source::Dataset(dataset_config), &latest_snapshot, &snapshot_mountpoint, target_dir)?;
+                }
+                
+                record_successful_backup(
+                    conn,
+                    &options.hostname,
+                    Source::Dataset(dataset_config),
+                    &latest_snapshot,
+                    &commands,
+                    Some(changed_files),
+                )?;
+                
+                println!("Incremental backup recorded successfully");
             }
         }
     }
      
+    if dataset_config.delete_mode == DeleteMode::Trash {
+        purge_expired_trash(&dataset_config.target_dir, dataset_config.trash_retention_days);
+    }
+     
     println!(); // Blank line between datasets
     Ok(())
 }
@@ -456,53 +3189,175 @@ fn backup_dataset(dataset_config: &DatasetConfig, conn: &Connection) -> Result<(
 
 fn record_successful_backup(
     conn: &Connection,
-    backup_type: &str,
-    source_name: &str,
+    hostname: &str,
+    source: Source,
     snapshot_name: &str,
-    target_dir: &str,
+    commands: &Log,
+    changed_files: Option<&mut ChangedFiles>,
 ) -> Result<(), String> {
+    let target_dir = source.target_dir().to_string_lossy();
+    device::sync_target(source.target_dir(), source.device())?;
+    // A dataset's snapshots keep their guids when it is renamed, which is how
+    // rename::detect recognises it under its new name
+    let snapshot_guid = match source {
+        Source::Dataset(_) => diff_cache::snapshot_guid(snapshot_name, commands).ok(),
+        Source::Restic(_) => None,
+    };
     conn.execute(
-        "INSERT INTO backup_history (backup_type, source_name, snapshot_name, target_dir)
-         VALUES (?1, ?2, ?3, ?4)",
-        [backup_type, source_name, snapshot_name, target_dir],
+        "INSERT INTO backup_history (hostname, backup_type, source_name, snapshot_name, target_dir, snapshot_guid, backed_up_epoch)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, CAST(strftime('%s', 'now') AS INTEGER))",
+        rusqlite::params![hostname, source.backup_type(), source.name(), snapshot_name, target_dir, snapshot_guid],
     )
     .map_err(|e| format!("Failed to record backup in database: {}", e))?;
+    if let Some(changed_files) = changed_files
+        && let Err(e) = changed_files.record(conn, conn.last_insert_rowid())
+    {
+        eprintln!("Warning: {}", e);
+    }
     
     Ok(())
 }
 
 
-fn run_rsync(source_path: &str, target_dir: &PathBuf) -> Result<(), String> {
+fn record_full_resync(conn: &Connection, options: &RunOptions, source: Source) {
+    if let Err(e) = resync::record(conn, &options.hostname, source) {
+        eprintln!("Warning: {}", e);
+    }
+}
+
+
+// File state is bookkeeping on top of the backup itself, so failing to update
+// it is reported but doesn't fail the backup. What it counted goes on to
+// anomaly::check.
+fn record_full_file_state(conn: &Connection, source: Source, snapshot_name: &str, root: &Path) -> Option<file_state::Changes> {
+    match file_state::record_full(conn, source.backup_type(), source.name(), snapshot_name, root, source.filter()) {
+        Ok(changes) => {
+            println!(
+                "Recorded state of {} file(s), {} ({} allocated)",
+                changes.files_synced,
+                report::format_bytes(changes.bytes_synced),
+                report::format_bytes(changes.bytes_allocated)
+            );
+            Some(changes)
+        }
+        Err(e) => {
+            eprintln!("Warning: Failed to record file state: {}", e);
+            None
+        }
+    }
+}
+
+
+fn apply_file_state_changes(
+    conn: &Connection,
+    backup_type: &str,
+    source_name: &str,
+    snapshot_name: &str,
+    root: &Path,
+    synced: &[PathBuf],
+    deleted: &[PathBuf],
+) -> Option<file_state::Changes> {
+    file_state::apply_changes(conn, backup_type, source_name, snapshot_name, root, synced, deleted)
+        .map_err(|e| eprintln!("Warning: Failed to update file state: {}", e))
+        .ok()
+}
+
+
+// The contents of a directory, as opposed to the directory itself, in rsync's
+// trailing-slash sense. Built on the OsString so awkward names pass through intact.
+fn rsync_contents_arg(dir: &Path) -> OsString {
+    let mut arg = dir.as_os_str().to_owned();
+    arg.push("/");
+    arg
+}
+
+
+// What of a source a full rsync copies, and how, from its config
+#[derive(Debug, Clone, Copy)]
+struct CopyOptions<'a> {
+    special_files: SpecialFiles,
+    symlinks: Symlinks,
+    sparse: bool,
+    filter: Option<&'a FilterFile>,
+    run_as: Option<&'a privileges::User>,
+}
+
+
+fn run_rsync(
+    source: &Path,
+    target_dir: &Path,
+    delete_limit: Option<u64>,
+    excludes: &[PathBuf],
+    checksum: bool,
+    copy: CopyOptions,
+    changed_files: &mut ChangedFiles,
+) -> Result<(), String> {
+    let CopyOptions { special_files, symlinks, sparse, filter, run_as } = copy;
     println!("Starting rsync backup...");
-    println!("Source: {}", source_path);
+    println!("Source: {}", source.display());
     println!("Target: {}", target_dir.display());
     
-    let output = Command::new("rsync")
-        .args([
-            "-aAXHv",           // Archive mode with ACLs, extended attrs, hard links, verbose
-            "--delete",         // Delete files in target that don't exist in source
-            "--stats",          // Show transfer statistics
-            source_path,
-            &target_dir.to_string_lossy().to_string(),
-        ])
-        .output()
+    let mut command = privileges::command("rsync", run_as);
+    // Archive mode with ACLs and extended attrs where rsync has them, hard links, verbose
+    command.arg(tools::rsync_archive("v"));
+    command.args([
+        "--delete",         // Delete files in target that don't exist in source
+        "--stats",          // Show transfer statistics
+    ]);
+    if checksum {
+        command.arg("--checksum");
+    }
+    command.args(special_files.rsync_args());
+    command.args(symlinks.rsync_args());
+    command.args(changed_files.rsync_args());
+    if sparse {
+        command.arg("--sparse");
+    }
+    command.args(target_internal_excludes());
+    command.args(excludes::rsync_args(filter));
+    for exclude in excludes {
+        let mut arg = OsString::from("--exclude=/");
+        arg.push(exclude);
+        arg.push("/");
+        command.arg(arg);
+    }
+    if let Some(limit) = delete_limit {
+        command.arg(format!("--max-delete={}", limit));
+    }
+    
+    let output = command
+        .arg(rsync_contents_arg(source))
+        .arg(target_dir)
+        .recorded_output(&changed_files.commands)
         .map_err(|e| format!("Failed to execute rsync: {}", e))?;
     
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("rsync failed: {}", stderr.trim()));
+    // rsync exits with 25 when it stopped deleting at --max-delete
+    if output.status.code() == Some(25) {
+        return Err(format!(
+            "rsync stopped deleting from '{}' after reaching max_delete ({} item(s)). Check this is the right target, then re-run with --force-delete or raise max_delete.",
+            target_dir.display(),
+            delete_limit.unwrap_or(0)
+        ));
     }
     
+    rsync_exit::check(&output, &mut changed_files.exits)?;
+    
     // Print rsync output
     let stdout = String::from_utf8_lossy(&output.stdout);
-    println!("{}", stdout);
+    println!("{}", special_files::filter_output(special_files, &stdout));
+    changed_files.collect(&output.stdout);
     
     println!("Rsync completed successfully");
+    events::emit(events::Event::TransferProgress {
+        step: "rsync",
+        files: None,
+        bytes: versioned::transfer_totals(&stdout).map(|(_, transferred)| transferred),
+    });
     Ok(())
 }
 
 
-fn get_snapshot_mountpoint(snapshot: &str) -> Result<String, String> {
+fn get_snapshot_mountpoint(snapshot: &str, commands: &Log) -> Result<PathBuf, String> {
     // ZFS snapshots are accessible under the hidden .zfs/snapshot directory
     // Parse snapshot name: pool/dataset@snapshot-name
     let parts: Vec<&str> = snapshot.split('@').collect();
@@ -513,102 +3368,243 @@ fn get_snapshot_mountpoint(snapshot: &str) -> Result<String, String> {
     let dataset = parts[0];
     let snapshot_name = parts[1];
     
-    let mountpoint = match get_dataset_mountpoint(dataset) {
-        Ok(mountpoint) => mountpoint,
-        Err(e) => {return Err(e)}
-    };
+    let mountpoint = get_dataset_mountpoint(dataset, commands)?;
     
     // Construct the snapshot path
-    let snapshot_path = format!("{}/.zfs/snapshot/{}", mountpoint, snapshot_name);
-    
-    Ok(snapshot_path)
+    Ok(mountpoint.join(".zfs/snapshot").join(snapshot_name))
 }
 
 
-fn strip_mountpoint_prefix(file_path: &str, mountpoint: &str) -> String {
+fn strip_mountpoint_prefix(file_path: &Path, mountpoint: &Path) -> PathBuf {
     file_path.strip_prefix(mountpoint)
-        .and_then(|s| s.strip_prefix('/'))
         .unwrap_or(file_path)
-        .to_string()
+        .to_path_buf()
+}
+
+
+// One line of `zfs diff -HF` output
+struct SnapshotChange {
+    // M (modified), + (added), - (removed) or R (renamed)
+    change_type: char,
+    // F (file), / (directory), @ (symlink), | (fifo), = (socket), > (door),
+    // B or C (block or character device) or P (event port). None for diffs
+    // cached before the type was asked for.
+    file_type: Option<char>,
+    path: PathBuf,
+    // Where a renamed file went
+    new_path: Option<PathBuf>,
+}
+
+impl SnapshotChange {
+    fn is_dir(&self) -> bool {
+        self.file_type == Some('/')
+    }
 }
 
 
-fn get_snapshot_diff(old_snapshot: &str, new_snapshot: &str) -> Result<Vec<String>, String> {
+// Run zfs diff between two snapshots, passing each change to `each` as it is
+// read. An error from `each` stops the diff and is returned.
+fn get_snapshot_diff(
+    old_snapshot: &str,
+    new_snapshot: &str,
+    tool_versions: &ToolVersions,
+    commands: &Log,
+    each: &mut dyn FnMut(SnapshotChange) -> Result<(), String>,
+) -> Result<(), String> {
     println!("Computing differences between snapshots...");
     
-    let output = Command::new("zfs")
-        .args(["diff", "-H", old_snapshot, new_snapshot])
-        .output()
+    // Ask for unescaped paths where supported so they can be handed straight to
+    // rsync, and for each path's type, which tells a path that was replaced by
+    // one of another type from one that was only changed
+    let mut args = vec!["diff", "-H", "-F"];
+    let escaped = !tool_versions.zfs_diff_no_escape();
+    if !escaped {
+        args.push("-h");
+    }
+    args.extend([old_snapshot, new_snapshot]);
+    
+    let (mut child, started) = child_env::command("zfs")
+        .args(&args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .recorded_spawn(commands)
         .map_err(|e| format!("Failed to execute zfs diff: {}", e))?;
+    let stdout = child.stdout.take().ok_or("Failed to read zfs diff output")?;
+    let mut stderr = child.stderr.take().ok_or("Failed to read zfs diff output")?;
+    let stderr_reader = std::thread::spawn(move || {
+        let mut text = Vec::new();
+        let _ = stderr.read_to_end(&mut text);
+        text
+    });
     
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("zfs diff failed: {}", stderr.trim()));
+    // Each line is parsed and passed on as it is read, so a diff of millions
+    // of paths is never held whole. Paths are kept as raw bytes: they needn't
+    // be UTF-8 and may contain spaces.
+    let mut reader = io::BufReader::new(stdout);
+    let mut line = Vec::new();
+    let read = loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break Ok(()),
+            Ok(_) => {
+                let line = line.strip_suffix(b"\n").unwrap_or(&line);
+                if let Some(change) = parse_zfs_diff_line(line, escaped)
+                    && let Err(e) = each(change)
+                {
+                    // zfs would otherwise block on a full pipe
+                    let _ = child.kill();
+                    let status = child.wait();
+                    started.finished(status.as_ref().ok());
+                    let _ = stderr_reader.join();
+                    return Err(e);
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    drop(reader);
+    let status = child.wait();
+    started.finished(status.as_ref().ok());
+    let status = status.map_err(|e| format!("Failed to execute zfs diff: {}", e))?;
+    let stderr = stderr_reader.join().unwrap_or_default();
+    
+    if !status.success() {
+        return Err(zfs_allow::failure("diff", "diff", new_snapshot, &String::from_utf8_lossy(&stderr)));
+    }
+    read.map_err(|e| format!("Failed to read zfs diff output: {}", e))
+}
+
+
+// Changes echoed unless --print-changes asks for them all
+const LISTED_CHANGES: usize = 20;
+
+
+// How many of each type of change a diff had
+#[derive(Debug, Default)]
+struct DiffCounts {
+    modified: usize,
+    added: usize,
+    removed: usize,
+    renamed: usize,
+}
+
+impl DiffCounts {
+    fn note(&mut self, change: &SnapshotChange) {
+        match change.change_type {
+            'M' => self.modified += 1,
+            '+' => self.added += 1,
+            '-' => self.removed += 1,
+            'R' => self.renamed += 1,
+            _ => {}
+        }
     }
     
-    let stdout = String::from_utf8_lossy(&output.stdout);
+    fn total(&self) -> usize {
+        self.modified + self.added + self.removed + self.renamed
+    }
     
-    // Parse the diff output
-    // Format is: <change_type>\t<file_path>
-    // Change types: M (modified), + (added), - (removed), R (renamed)
-    let changed_files: Vec<String> = stdout
-        .lines()
-        .filter(|line| !line.is_empty())
-        .map(|line| line.to_string())
-        .collect();
+    // After the changes were echoed with print_change
+    fn print(&self, all: bool) {
+        if self.total() == 0 {
+            println!("No changes detected between snapshots");
+            return;
+        }
+        if !all && self.total() > LISTED_CHANGES {
+            println!("  ... and {} more; --print-changes lists them all", self.total() - LISTED_CHANGES);
+        }
+        println!(
+            "Found {} change(s): {} modified, {} added, {} removed, {} renamed",
+            self.total(),
+            self.modified,
+            self.added,
+            self.removed,
+            self.renamed
+        );
+    }
+}
+
+
+fn print_change(change: &SnapshotChange) {
+    let file_type = change.file_type.unwrap_or(' ');
+    match &change.new_path {
+        Some(new_path) => println!("  {}\t{}\t{}\t{}", change.change_type, file_type, change.path.display(), new_path.display()),
+        None => println!("  {}\t{}\t{}", change.change_type, file_type, change.path.display()),
+    }
+}
+
+
+fn parse_zfs_diff_line(line: &[u8], escaped: bool) -> Option<SnapshotChange> {
+    // Format: <change_type>\t<file_type>\t<file_path>[\t<new_path>], the new
+    // path only for renames
+    let mut fields = line.split(|&b| b == b'\t');
+    let change_type = *fields.next()?.first()? as char;
+    let file_type = match fields.next()? {
+        [file_type] => *file_type as char,
+        _ => return None,
+    };
+    let to_path = |field: &[u8]| {
+        let bytes = if escaped { unescape_zfs_path(field) } else { field.to_vec() };
+        PathBuf::from(OsString::from_vec(bytes))
+    };
+    let path = to_path(fields.next()?);
+    let new_path = fields.next().map(to_path);
     
-    Ok(changed_files)
+    Some(SnapshotChange { change_type, file_type: Some(file_type), path, new_path })
 }
 
 
-fn parse_zfs_diff_line(line: &str) -> Option<(char, String)> {
-    // Format: <change_type>\t<file_path>
-    let parts: Vec<&str> = line.split('\t').collect();
-    if parts.len() >= 2 {
-        let change_type = parts[0].chars().next()?;
-        let file_path = parts[1].to_string();
-        Some((change_type, file_path))
-    } else {
-        None
-    }
-}
-fn extract_files_for_sync(changes: &[String], mountpoint: &str) -> Vec<String> {
-    let mut files_to_sync = Vec::new();
-    
-    for change in changes {
-        if let Some((change_type, file_path)) = parse_zfs_diff_line(change) {
-            match change_type {
-                '+' | 'M' => {
-                    // Added or modified files need to be synced
-                    let relative_path = strip_mountpoint_prefix(&file_path, mountpoint);
-                    // Skip empty paths (the dataset root) and directory entries ending in /
-                    if !relative_path.is_empty() && !relative_path.ends_with('/') {
-                        files_to_sync.push(relative_path);
-                    }                }
-                'R' => {
-                    // For renames, we'll sync the new name
-                    if let Some(new_path) = file_path.split(" -> ").nth(1) {
-                        let relative_path = strip_mountpoint_prefix(new_path, mountpoint);
-                    // Skip empty paths (the dataset root) and directory entries ending in /
-                    if !relative_path.is_empty() && !relative_path.ends_with('/') {
-                        files_to_sync.push(relative_path);
-                    }                    }
-                }
-                '-' => {
-                    // Deletions will be handled by rsync --delete if we do a full sync
-                }
-                _ => {}
+// Without -h, zfs diff writes spaces, backslashes and non-printable bytes as
+// a backslash and four octal digits, e.g. "\0040" for a space
+fn unescape_zfs_path(field: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let escape = field.get(i + 1..i + 5)
+            .filter(|digits| field[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match escape {
+            Some(digits) => {
+                let value = digits.iter().fold(0u32, |value, d| value * 8 + u32::from(d - b'0'));
+                bytes.push(value as u8);
+                i += 5;
+            }
+            None => {
+                bytes.push(field[i]);
+                i += 1;
             }
         }
     }
+    bytes
+}
+
+
+// The path a change has rsync sync, relative to the mountpoint, with whether
+// it is a directory for the excludes that only match directories
+fn synced_path(change: &SnapshotChange, mountpoint: &Path) -> Option<(PathBuf, bool)> {
+    let file_path = match change.change_type {
+        // Added or modified files need to be synced
+        '+' | 'M' => &change.path,
+        // For renames, we'll sync the new name
+        'R' => change.new_path.as_ref()?,
+        // Deletions are handled separately
+        _ => return None,
+    };
     
-    files_to_sync
+    let relative_path = strip_mountpoint_prefix(file_path, mountpoint);
+    // Skip empty paths (the dataset root) and directory entries ending in /
+    if relative_path.as_os_str().is_empty() || relative_path.as_os_str().as_bytes().ends_with(b"/") {
+        return None;
+    }
+    Some((relative_path, change.is_dir()))
 }
 
 fn run_rsync_with_file_list(
-    source_path: &str,
-    target_dir: &PathBuf,
-    files: &[String],
+    source: &Path,
+    target_dir: &Path,
+    files: &[PathBuf],
+    symlinks: Symlinks,
+    sparse: bool,
+    run_as: Option<&privileges::User>,
+    changed_files: &mut ChangedFiles,
 ) -> Result<(), String> {
     if files.is_empty() {
         println!("No files to sync");
@@ -616,59 +3612,109 @@ fn run_rsync_with_file_list(
     }
     
     println!("Syncing {} file(s) with rsync...", files.len());
-    
-    // Create a temporary file with the list of files
-    let temp_file_path = "/tmp/rsync-files.txt";
-    let mut temp_file = fs::File::create(temp_file_path)
-        .map_err(|e| format!("Failed to create temp file: {}", e))?;
-    
-    // Write relative paths (without leading /)
-    for file in files {
-        let relative_path = file.strip_prefix('/').unwrap_or(file);
-        writeln!(temp_file, "{}", relative_path)
-            .map_err(|e| format!("Failed to write to temp file: {}", e))?;
-    }
-    
-    // Flush to ensure all data is written
-    temp_file.flush()
-        .map_err(|e| format!("Failed to flush temp file: {}", e))?;
-    
-    drop(temp_file); // Close the file
-    
-    println!("Source: {}", source_path);
+    run_rsync_with_files(source, target_dir, symlinks, sparse, run_as, changed_files, |stdin| {
+        files.iter().try_for_each(|file| write_file_list_entry(stdin, file))
+    })?;
+    events::emit(events::Event::TransferProgress { step: "rsync of changed files", files: Some(files.len()), bytes: None });
+    Ok(())
+}
+
+
+// rsync the paths `write_files` writes to its stdin with
+// write_file_list_entry. The list is streamed rather than written out first,
+// which for millions of files is a sizeable file of its own.
+fn run_rsync_with_files<F>(
+    source: &Path,
+    target_dir: &Path,
+    symlinks: Symlinks,
+    sparse: bool,
+    run_as: Option<&privileges::User>,
+    changed_files: &mut ChangedFiles,
+    write_files: F,
+) -> Result<(), String>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    println!("Source: {}", source.display());
     println!("Target: {}", target_dir.display());
     
-    let output = Command::new("rsync")
+    let mut command = privileges::command("rsync", run_as);
+    command
+        .arg(tools::rsync_archive("v"))
         .args([
-            "-aAXHv",
             "--relative",           // Preserve directory structure
-            "--files-from", temp_file_path,
-            source_path,
-            &target_dir.to_string_lossy().to_string(),
+            "--from0",
+            "--files-from=-",
         ])
-        .output()
-        .map_err(|e| format!("Failed to execute rsync: {}", e))?;
+        .args(sparse.then_some("--sparse"))
+        .args(symlinks.rsync_args())
+        .args(changed_files.rsync_args())
+        .arg(rsync_contents_arg(source))
+        .arg(target_dir);
+    let output = run_with_input(&mut command, "rsync", &changed_files.commands, write_files)?;
     
-    // Clean up temp file
-    let _ = fs::remove_file(temp_file_path);
-    
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("rsync failed: {}", stderr.trim()));
-    }
+    rsync_exit::check(&output, &mut changed_files.exits)?;
     
     let stdout = String::from_utf8_lossy(&output.stdout);
     println!("{}", stdout);
+    changed_files.collect(&output.stdout);
     
     println!("Rsync completed successfully");
     Ok(())
 }
 
 
-fn get_dataset_mountpoint(dataset: &str) -> Result<String, String> {
-    let output = Command::new("zfs")
+// A relative path (without leading /), NUL-terminated so names may contain
+// anything, newlines included
+fn write_file_list_entry(stdin: &mut dyn Write, file: &Path) -> io::Result<()> {
+    let relative_path = file.strip_prefix("/").unwrap_or(file);
+    stdin.write_all(relative_path.as_os_str().as_bytes())?;
+    stdin.write_all(b"\0")
+}
+
+
+// Run a command, feeding it input written by `write_input` while its output is
+// collected. The output is read from another thread, since it has to be read
+// at the same time for the command not to block, leaving `write_input` free
+// to use what can't be sent to one.
+fn run_with_input<F>(command: &mut Command, name: &str, commands: &Log, write_input: F) -> Result<std::process::Output, String>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let (mut child, started) = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .recorded_spawn(commands)
+        .map_err(|e| format!("Failed to execute {}: {}", name, e))?;
+    let stdin = child.stdin.take().ok_or_else(|| format!("Failed to open {}'s stdin", name))?;
+    
+    let (written, output) = std::thread::scope(|scope| {
+        let output = scope.spawn(move || child.wait_with_output());
+        // Dropped once written, so the command sees the end of its input
+        let mut stdin = io::BufWriter::new(stdin);
+        let written = write_input(&mut stdin).and_then(|()| stdin.flush());
+        drop(stdin);
+        (written, output.join())
+    });
+    let output = output.unwrap_or_else(|_| Err(io::Error::other("output reader panicked")));
+    started.finished(output.as_ref().ok().map(|output| &output.status));
+    let output = output.map_err(|e| format!("Failed to execute {}: {}", name, e))?;
+    
+    // A command that stopped reading early says why itself
+    if output.status.success()
+        && let Err(e) = written
+    {
+        return Err(format!("Failed to pass input to {}: {}", name, e));
+    }
+    Ok(output)
+}
+
+
+fn get_dataset_mountpoint(dataset: &str, commands: &Log) -> Result<PathBuf, String> {
+    let output = child_env::command("zfs")
         .args(["get", "-H", "-o", "value", "mountpoint", dataset])
-        .output()
+        .recorded_output(commands)
         .map_err(|e| format!("Failed to get dataset mountpoint: {}", e))?;
     
     if !output.status.success() {
@@ -676,50 +3722,537 @@ fn get_dataset_mountpoint(dataset: &str) -> Result<String, String> {
         return Err(format!("zfs command failed: {}", stderr.trim()));
     }
     
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    Ok(parse_mountpoint(&output.stdout))
+}
+
+
+// Only the newline is stripped: a mountpoint may begin or end with a space
+fn parse_mountpoint(stdout: &[u8]) -> PathBuf {
+    let stdout = stdout.strip_suffix(b"\n").unwrap_or(stdout);
+    PathBuf::from(OsStr::from_bytes(stdout))
+}
+
+
+// The path a change removed, relative to the mountpoint
+fn deleted_path(change: &SnapshotChange, mountpoint: &Path) -> Option<(PathBuf, bool)> {
+    if change.change_type != '-' {
+        return None;
+    }
+    let relative_path = strip_mountpoint_prefix(&change.path, mountpoint);
+    (!relative_path.as_os_str().is_empty()).then(|| (relative_path, change.is_dir()))
+}
+
+
+// Whether a path to sync replaces a directory on the target with something
+// else, e.g. a file or a symlink. rsync won't put anything in place of a
+// directory that still has files in it, so the old one has to go first,
+// which for a directory removed in the diff can only be once the whole diff
+// is in. What a symlink is copied as depends on the symlinks setting.
+fn replaces_directory(snapshot_root: &Path, target_dir: &Path, relative_path: &Path, symlinks: Symlinks) -> bool {
+    let relative_path = relative_path.strip_prefix("/").unwrap_or(relative_path);
+    let source = snapshot_root.join(relative_path);
+    let source_is_dir = match symlinks {
+        Symlinks::Follow => fs::metadata(&source),
+        _ => fs::symlink_metadata(&source),
+    }.is_ok_and(|metadata| metadata.is_dir());
+    !source_is_dir && fs::symlink_metadata(target_dir.join(relative_path)).is_ok_and(|metadata| metadata.is_dir())
+}
+
+
+// An incremental backup's pass over a dataset's diff as it is read. Paths to
+// sync go straight to rsync, and have their file state updated, their
+// metadata sidecar written and whether they need a symlink stub noted as they
+// do. Only what has to wait for the whole diff is kept: the paths removed,
+// the few that replace a directory on the target, and the symlinks to stub.
+struct DiffPass<'a> {
+    dataset_config: &'a DatasetConfig,
+    dataset_mountpoint: &'a Path,
+    snapshot_mountpoint: &'a Path,
+    print_changes: bool,
+    counts: DiffCounts,
+    excluded: usize,
+    synced: usize,
+    file_state: file_state::Update<'a>,
+    deleted: Vec<PathBuf>,
+    replaced: Vec<PathBuf>,
+    stubs: Vec<PathBuf>,
+    // Once rsync stops reading, the rest of the diff is still read for the
+    // cache, but nothing more of it is synced
+    write_error: Option<io::Error>,
+}
+
+impl<'a> DiffPass<'a> {
+    fn new(
+        conn: &'a Connection,
+        dataset_config: &'a DatasetConfig,
+        dataset_mountpoint: &'a Path,
+        snapshot_mountpoint: &'a Path,
+        snapshot_name: &'a str,
+        print_changes: bool,
+    ) -> DiffPass<'a> {
+        DiffPass {
+            dataset_config,
+            dataset_mountpoint,
+            snapshot_mountpoint,
+            print_changes,
+            counts: DiffCounts::default(),
+            excluded: 0,
+            synced: 0,
+            file_state: file_state::Update::start(conn, "dataset", &dataset_config.name, snapshot_name),
+            deleted: Vec::new(),
+            replaced: Vec::new(),
+            stubs: Vec::new(),
+            write_error: None,
+        }
+    }
+    
+    fn add(&mut self, change: SnapshotChange, rsync: &mut dyn Write) -> Result<(), String> {
+        if self.print_changes || self.counts.total() < LISTED_CHANGES {
+            print_change(&change);
+        }
+        self.counts.note(&change);
+        
+        if let Some((path, is_dir)) = deleted_path(&change, self.dataset_mountpoint) {
+            if !self.is_excluded(&path, is_dir) {
+                self.deleted.push(path);
+            }
+            return Ok(());
+        }
+        let Some((path, is_dir)) = synced_path(&change, self.dataset_mountpoint) else {
+            return Ok(());
+        };
+        if self.is_excluded(&path, is_dir)
+            || self.write_error.is_some()
+            || special_files::skip(self.dataset_config.special_files, self.snapshot_mountpoint, &path)
+        {
+            return Ok(());
+        }
+        if replaces_directory(self.snapshot_mountpoint, &self.dataset_config.target_dir, &path, self.dataset_config.symlinks) {
+            self.replaced.push(path);
+            return Ok(());
+        }
+        if let Err(e) = write_file_list_entry(rsync, &path) {
+            self.write_error = Some(e);
+            return Ok(());
+        }
+        self.synced += 1;
+        self.follow_up(&path)
+    }
+    
+    fn is_excluded(&mut self, path: &Path, is_dir: bool) -> bool {
+        let excluded = excludes::matches(path, is_dir, self.dataset_config.filter.as_ref());
+        if excluded {
+            self.excluded += 1;
+        }
+        excluded
+    }
+    
+    // What else a path rsync syncs needs
+    fn follow_up(&mut self, path: &Path) -> Result<(), String> {
+        let target_dir = &self.dataset_config.target_dir;
+        self.file_state.synced(self.snapshot_mountpoint, path);
+        if self.dataset_config.metadata_sidecar {
+            metadata::record_synced(self.snapshot_mountpoint, target_dir, path)?;
+        }
+        if symlinks::needs_stub(self.dataset_config.symlinks, self.snapshot_mountpoint, target_dir, path)? {
+            self.stubs.push(path.to_path_buf());
+        }
+        Ok(())
+    }
+}
+
+
+// Remove the directories that paths to sync replace from the target, into
+// the trash with delete_mode = "trash"
+fn remove_replaced_paths(target_dir: &Path, replaced: &[PathBuf], delete_mode: DeleteMode) -> Result<(), String> {
+    if replaced.is_empty() {
+        return Ok(());
+    }
+    
+    println!("{} path(s) replace a directory, removing the old ones first...", replaced.len());
+    let trash_dir = match delete_mode {
+        DeleteMode::Delete => None,
+        DeleteMode::Trash => Some(trash::new_trash_dir(target_dir)),
+    };
+    delete_files_locally(target_dir, replaced, trash_dir)
+}
+
+// Files the tool itself keeps on a target, which have to survive rsync --delete
+// and be left out when comparing the target with a snapshot
+const TARGET_INTERNAL_NAMES: [&str; 7] = [
+    db_export::TARGET_STATE_FILE,
+    trash::TRASH_DIR,
+    versioned::DELETIONS_MANIFEST,
+    runlog::RUN_LOG_DIR,
+    metadata::META_DIR,
+    rescue::RESCUE_DIR,
+    ownership::ID_NAMES_FILE,
+];
+
+fn target_internal_excludes() -> Vec<String> {
+    TARGET_INTERNAL_NAMES
+        .iter()
+        .map(|name| format!("--exclude=/{}", name))
+        .collect()
+}
+
+
+fn purge_expired_trash(target_dir: &Path, retention: Option<Days>) {
+    // Without a retention period trash is kept until purge-trash is run
+    let Some(Days(secs)) = retention else {
+        return;
+    };
+    
+    match trash::purge(target_dir, Some(Duration::from_secs(secs))) {
+        Ok(0) => {}
+        Ok(count) => println!("Purged {} expired trash director{}", count, if count == 1 { "y" } else { "ies" }),
+        Err(e) => eprintln!("Warning: Failed to purge trash: {}", e),
+    }
+}
+
+
+fn prune_versions(config: &Config, source: &str, keep: usize, confirm: bool) -> Result<(), String> {
+    let source_config = config.find_source(source)?;
+    let target_dir = source_config.target_dir();
+    
+    if source_config.layout() != Layout::Versioned {
+        return Err(format!("'{}' doesn't use the versioned layout, so there is nothing to prune", source));
+    }
+    
+    if keep == 0 {
+        return Err("--keep must be at least 1".to_string());
+    }
+    
+    let _immutable_guard = if confirm { immutable::unlock(target_dir, source_config.immutable()) } else { None };
+    versioned::prune(target_dir, keep, confirm, &config.link_pool_dirs(source_config))
+}
+
+
+fn gc(config: &Config, source: &str, keep: usize, dry_run: bool) -> Result<(), String> {
+    let source_config = config.find_source(source)?;
+    let target_dir = source_config.target_dir();
+    
+    if keep == 0 {
+        return Err("--keep must be at least 1".to_string());
+    }
+    
+    check_target_directory(target_dir)?;
+    let _immutable_guard = if dry_run { None } else { immutable::unlock(target_dir, source_config.immutable()) };
+    match source_config.layout() {
+        Layout::Versioned => versioned::gc(target_dir, keep, dry_run, &config.link_pool_dirs(source_config)),
+        Layout::Stream => streams::gc(target_dir, keep, dry_run),
+        Layout::Image => images::gc(target_dir, keep, dry_run),
+        Layout::Mirror => Err(format!("'{}' uses the mirror layout, which keeps nothing to collect", source)),
+    }
+}
+
+
+fn pause_source(config: &Config, conn: &Connection, options: &RunOptions, source: &str, duration: Option<&str>) -> Result<(), String> {
+    let source = config.find_source(source)?;
+    let duration_secs = duration.map(units::parse_duration).transpose()?;
+    
+    pause::pause(conn, &options.hostname, source.name(), duration_secs)?;
+    match duration_secs {
+        Some(secs) => println!("Paused {} '{}' for {}", source.kind(), source.name(), report::format_age(secs as i64)),
+        None => println!("Paused {} '{}' until it is resumed", source.kind(), source.name()),
+    }
+    Ok(())
+}
+
+
+fn find_files(conn: &Connection, hostname: Option<&str>, pattern: &str) -> Result<(), String> {
+    let found = file_state::find(conn, hostname, pattern)?;
+    if found.is_empty() {
+        println!("No backed-up files match '{}'", pattern);
+        return Ok(());
+    }
+    
+    for file in &found {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(file.mtime.max(0) as u64);
+        println!(
+            "{} {}: {} ({}, modified {})",
+            file.backup_type,
+            file.source_name,
+            file.path,
+            report::format_bytes(file.size.max(0) as u64),
+            clock::iso_utc(modified)
+        );
+        match (&file.latest_snapshot, &file.backed_up_at, &file.target_dir) {
+            (Some(snapshot), Some(at), Some(target_dir)) => {
+                println!("  last copied from {}; latest backup {} at {} in {}", file.last_snapshot, snapshot, at, target_dir);
+            }
+            _ => println!("  last copied from {}; no backup of this source recorded for this host", file.last_snapshot),
+        }
+    }
+    println!("{} file(s) found", found.len());
+    Ok(())
+}
+
+
+fn purge_trash(config: &Config, source: Option<&str>, all: bool) -> Result<(), String> {
+    let sources = match source {
+        Some(name) => vec![config.find_source(name)?],
+        None => config.sources().collect(),
+    };
+    
+    for source in sources {
+        let (name, target_dir) = (source.name(), source.target_dir());
+        let retention = match (all, source.trash_retention_days()) {
+            (true, _) => None,
+            (false, Some(Days(secs))) => Some(Duration::from_secs(secs)),
+            (false, None) => {
+                println!("Skipping '{}': no trash_retention_days set (use --all to empty its trash)", name);
+                continue;
+            }
+        };
+        
+        println!("Purging trash for '{}' in {}...", name, target_dir.display());
+        let _immutable_guard = immutable::unlock(target_dir, source.immutable());
+        let count = trash::purge(target_dir, retention)?;
+        println!("Purged {} trash director{}", count, if count == 1 { "y" } else { "ies" });
+    }
+    
+    Ok(())
+}
+
+
+fn verify_streams(config: &Config, source: Option<&str>, repair: bool) -> Result<(), String> {
+    let sources: Vec<Source> = match source {
+        Some(name) => vec![config.find_source(name)?],
+        None => config.sources().filter(|source| source.layout() == Layout::Stream).collect(),
+    };
+    
+    let mut failed = 0;
+    for source in sources {
+        if source.layout() != Layout::Stream {
+            return Err(format!("'{}' doesn't use the stream layout", source.name()));
+        }
+        
+        println!("=== Verifying streams for {}: {} ===", source.kind(), source.name());
+        let result = check_target_directory(source.target_dir())
+            .and_then(|()| streams::verify(source.target_dir(), repair));
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            failed += 1;
+        }
+        println!();
+    }
+    
+    if failed > 0 {
+        return Err(format!("{} target(s) failed verification", failed));
+    }
+    Ok(())
+}
+
+
+// Work out how many items a backup may delete from the target, or None when
+// there is no limit (none configured, or --force-delete given)
+fn resolve_delete_limit(
+    conn: &Connection,
+    options: &RunOptions,
+    backup_type: &str,
+    source_name: &str,
+    max_delete: Option<&MaxDelete>,
+    target_dir: &Path,
+) -> Result<Option<u64>, String> {
+    if options.force_delete {
+        return Ok(None);
+    }
+    
+    match max_delete {
+        None => Ok(None),
+        Some(MaxDelete::Count(count)) => Ok(Some(*count)),
+        Some(MaxDelete::Percent(percent)) => {
+            // Prefer the recorded file state over walking the whole target
+            let total = match file_state::count(conn, backup_type, source_name) {
+                Ok(count) if count > 0 => count,
+                _ => count_files(target_dir)?,
+            };
+            Ok(Some((total as f64 * percent / 100.0).ceil() as u64))
+        }
+    }
+}
+
+
+fn check_delete_limit(delete_limit: Option<u64>, deletions: usize, target_dir: &Path) -> Result<(), String> {
+    match delete_limit {
+        Some(limit) if deletions as u64 > limit => Err(format!(
+            "Refusing to delete {} item(s) from '{}', more than max_delete allows ({}). Check this is the right target, then re-run with --force-delete or raise max_delete.",
+            deletions,
+            target_dir.display(),
+            limit
+        )),
+        _ => Ok(()),
+    }
+}
+
+
+fn count_files(dir: &Path) -> Result<u64, String> {
+    let mut count = 0;
+    let mut pending = vec![dir.to_path_buf()];
+    
+    while let Some(dir) = pending.pop() {
+        let entries = fs::read_dir(&dir)
+            .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+        
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => pending.push(entry.path()),
+                _ => count += 1,
+            }
+        }
+    }
+    
+    Ok(count)
+}
+
+
+// Delete files that are gone from `source` (the new snapshot) from the target
+// in one rsync run, falling back to removing them one at a time
+fn delete_files_from_target(
+    source: &Path,
+    target_dir: &Path,
+    files: &[PathBuf],
+    delete_mode: DeleteMode,
+    run_as: Option<&privileges::User>,
+    changed_files: &mut ChangedFiles,
+) -> Result<(), String> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    
+    let trash_dir = match delete_mode {
+        DeleteMode::Delete => None,
+        DeleteMode::Trash => Some(trash::new_trash_dir(target_dir)),
+    };
+    let deleted = match delete_files_via_rsync(source, target_dir, files, trash_dir.as_deref(), run_as, &changed_files.commands) {
+        Ok(deleted_count) => {
+            println!("Deletion complete: {} deleted", deleted_count);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Warning: Batch deletion failed, deleting one item at a time: {}", e);
+            delete_files_locally(target_dir, files, trash_dir)
+        }
+    };
+    if deleted.is_ok() {
+        changed_files.deleted(files);
+    }
+    deleted
+}
+
+
+// Paths in filter rules are patterns. rsync only treats a backslash as an
+// escape in a pattern that has wildcards in it, so the path is escaped only
+// when the rule will have some.
+fn filter_rule(path: &Path, suffix: &str) -> Vec<u8> {
+    let bytes = path.as_os_str().as_bytes();
+    let wildcard = |b: &u8| matches!(b, b'*' | b'?' | b'[');
+    let mut rule = vec![b'/'];
+    if suffix.bytes().any(|b| wildcard(&b)) || bytes.iter().any(wildcard) {
+        for &b in bytes {
+            if wildcard(&b) || b == b'\\' {
+                rule.push(b'\\');
+            }
+            rule.push(b);
+        }
+    } else {
+        rule.extend_from_slice(bytes);
+    }
+    rule.extend_from_slice(suffix.as_bytes());
+    rule
 }
 
 
-fn extract_files_for_deletion(changes: &[String], mountpoint: &str) -> Vec<String> {
-    let mut files_to_delete = Vec::new();
+// rsync the new snapshot over the target without transferring anything,
+// with a filter that only lets in the deleted paths and the directories above
+// them, so --delete removes exactly those. With a trash directory, --backup
+// moves them there instead.
+fn delete_files_via_rsync(
+    source: &Path,
+    target_dir: &Path,
+    files: &[PathBuf],
+    trash_dir: Option<&Path>,
+    run_as: Option<&privileges::User>,
+    commands: &Log,
+) -> Result<usize, String> {
+    match trash_dir {
+        Some(trash_dir) => println!("Moving {} item(s) to {}...", files.len(), trash_dir.display()),
+        None => println!("Deleting {} item(s) from target...", files.len()),
+    }
     
-    for change in changes {
-        if let Some((change_type, file_path)) = parse_zfs_diff_line(change) {
-            if change_type == '-' {
-                let relative_path = strip_mountpoint_prefix(&file_path, mountpoint);
-                if !relative_path.is_empty() {
-                    files_to_delete.push(relative_path);
-                }
+    let mut command = privileges::command("rsync", run_as);
+    command.args(["-rv", "--delete", "--existing", "--ignore-existing", "--from0", "--include-from=-", "--exclude=*"]);
+    if let Some(trash_dir) = trash_dir {
+        let mut arg = OsString::from("--backup-dir=");
+        arg.push(trash_dir);
+        command.args(["--backup", "--suffix="]).arg(arg);
+    }
+    command.arg(rsync_contents_arg(source)).arg(target_dir);
+    
+    let output = run_with_input(&mut command, "rsync", commands, |stdin| {
+        let mut parents = std::collections::HashSet::new();
+        for file in files {
+            let file = file.strip_prefix("/").unwrap_or(file);
+            let ancestors = file.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty());
+            let mut rules: Vec<Vec<u8>> = ancestors
+                .filter(|dir| parents.insert(dir.to_path_buf()))
+                .map(|dir| filter_rule(dir, "/"))
+                .collect();
+            rules.reverse();
+            rules.push(filter_rule(file, ""));
+            rules.push(filter_rule(file, "/***"));
+            for rule in rules {
+                stdin.write_all(&rule)?;
+                stdin.write_all(b"\0")?;
             }
         }
+        Ok(())
+    })?;
+    
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("rsync failed: {}", stderr.trim()));
     }
     
-    files_to_delete
+    let deleted: Vec<&[u8]> = output.stdout
+        .split(|&b| b == b'\n')
+        .filter_map(|line| line.strip_prefix(b"deleting "))
+        .collect();
+    for path in &deleted {
+        println!("  Deleted: {}", Path::new(OsStr::from_bytes(path)).display());
+    }
+    Ok(deleted.len())
 }
 
-fn delete_files_from_target(target_dir: &PathBuf, files: &[String]) -> Result<(), String> {
-    if files.is_empty() {
-        return Ok(());
-    }
-    
-    println!("Deleting {} item(s) from target...", files.len());
-    
+
+fn delete_files_locally(target_dir: &Path, files: &[PathBuf], trash_dir: Option<PathBuf>) -> Result<(), String> {
     let mut deleted_count = 0;
     let mut error_count = 0;
     
     for file in files {
         let target_path = target_dir.join(file);
         
-        // Check if path exists and what type it is
-        let result = if target_path.is_dir() {
-            println!("  Deleting directory: {}", file);
+        // Check if path exists and what type it is, without following a
+        // symlink, which is deleted itself whether or not it points anywhere
+        let metadata = fs::symlink_metadata(&target_path);
+        let result = if let Some(trash_dir) = &trash_dir
+            && metadata.is_ok()
+        {
+            println!("  Moving to trash: {}", file.display());
+            trash::move_to_trash(&target_path, &trash_dir.join(file))
+        } else if let Ok(metadata) = &metadata
+            && metadata.is_dir()
+        {
+            println!("  Deleting directory: {}", file.display());
             fs::remove_dir_all(&target_path)
-        } else if target_path.is_file() {
-            println!("  Deleting file: {}", file);
+        } else if metadata.is_ok() {
+            println!("  Deleting file: {}", file.display());
             fs::remove_file(&target_path)
         } else {
             // Path doesn't exist
-            println!("  Already gone: {}", file);
+            println!("  Already gone: {}", file.display());
             deleted_count += 1;
             continue;
         };
@@ -729,7 +4262,7 @@ fn delete_files_from_target(target_dir: &PathBuf, files: &[String]) -> Result<()
                 deleted_count += 1;
             }
             Err(e) => {
-                eprintln!("  Failed to delete {}: {}", file, e);
+                eprintln!("  Failed to delete {}: {}", file.display(), e);
                 error_count += 1;
             }
         }
@@ -745,10 +4278,12 @@ fn delete_files_from_target(target_dir: &PathBuf, files: &[String]) -> Result<()
 }
 
 
-fn get_latest_restic_snapshot(repository: &str) -> Result<Option<String>, String> {
-    let output = Command::new("restic")
-        .args(["-r", repository, "snapshots", "--json", "--last"])
-        .output()
+fn get_latest_restic_snapshot(restic_config: &ResticConfig, commands: &Log) -> Result<Option<String>, String> {
+    let output = restic_lock::output(restic_config, commands, || {
+        let mut command = privileges::restic(&restic_config.repository, restic_config.user.as_ref());
+        command.args(["snapshots", "--json", "--last"]);
+        command
+    })
         .map_err(|e| format!("Failed to execute restic: {}", e))?;
     
     if !output.status.success() {
@@ -781,20 +4316,22 @@ fn get_latest_restic_snapshot(repository: &str) -> Result<Option<String>, String
 }
 
 
-fn backup_restic(restic_config: &ResticConfig, conn: &Connection) -> Result<(), String> {
-    println!("=== Restic Repository: {} ===", restic_config.repository);
+fn backup_restic(
+    restic_config: &ResticConfig,
+    conn: &Connection,
+    options: &RunOptions,
+    tool_versions: &ToolVersions,
+    link_pool: &[PathBuf],
+    changed_files: &mut ChangedFiles,
+) -> Result<(), String> {
+    let commands = changed_files.commands.clone();
+    println!("=== Restic Repository: {}{} ===", restic_config.repository, run_id::job_label());
     
     check_target_directory(&restic_config.target_dir)?;
     
-    let last_backup = match get_last_backed_up_snapshot(conn, "restic", &restic_config.repository) {
-        Ok(snapshot) => snapshot,
-        Err(e) => {
-            eprintln!("Warning: Failed to query database: {}", e);
-            None
-        }
-    };
+    let last_backup = backup_base(conn, options, Source::Restic(restic_config), &commands)?;
     
-    let latest_snapshot = match get_latest_restic_snapshot(&restic_config.repository) {
+    let latest_snapshot = match get_latest_restic_snapshot(restic_config, &commands) {
         Ok(Some(snapshot)) => {
             println!("Latest snapshot: {}", snapshot);
             snapshot
@@ -806,29 +4343,161 @@ fn backup_restic(restic_config: &ResticConfig, conn: &Connection) -> Result<(),
     };
     
     println!("Target directory: {}", restic_config.target_dir.display());
+    events::emit(events::Event::SnapshotSelected { snapshot: &latest_snapshot, base: last_backup.as_deref() });
     
-    match last_backup {
-        None => {
-            println!("No previous backup found - performing full copy");
-            
-            // Mount the latest snapshot and rsync from it
-            let mount_point = PathBuf::from("/tmp/restic-mount-latest");
+    if last_backup.is_none() {
+        estimate::confirm_first_backup(Source::Restic(restic_config), &latest_snapshot, options, &commands)?;
+    }
+    if last_backup.as_deref() != Some(latest_snapshot.as_str()) {
+        inodes::preflight(conn, Source::Restic(restic_config), &latest_snapshot, &commands)?;
+        restic_cache::warm_up(restic_config, &latest_snapshot, &commands);
+    }
+    
+    if restic_config.layout == Layout::Versioned || restic_config.encryption.encrypt.is_some() {
+        if last_backup.as_deref() == Some(latest_snapshot.as_str()) {
+            println!("Already backed up - nothing to do");
+        } else {
+            let mount_point = restic_mount_point(&restic_config.repository);
             fs::create_dir_all(&mount_point)
                 .map_err(|e| format!("Failed to create mount point: {}", e))?;
             
-            let _mount_guard = mount_restic_snapshot(&restic_config.repository, &latest_snapshot, &mount_point)?;
+            let mount_guard = mount_restic_repository(restic_config, &mount_point, &commands)?;
+            let snapshot_path = restic_snapshot_path(&mount_guard, &latest_snapshot)?;
+            let staged = if restic_config.encryption.encrypt.is_some() {
+                encrypted::backup(conn, Source::Restic(restic_config), &latest_snapshot, &snapshot_path)?;
+                None
+            } else {
+                let sparse = sparse::for_full(restic_config.sparse, conn, "restic", &restic_config.repository);
+                Some(versioned::stage(
+                    &snapshot_path,
+                    &restic_config.target_dir,
+                    &latest_snapshot,
+                    None,
+                    CopyOptions {
+                        special_files: restic_config.special_files,
+                        symlinks: restic_config.symlinks,
+                        sparse,
+                        filter: restic_config.filter.as_ref(),
+                        run_as: restic_config.user.as_ref(),
+                    },
+                    link_pool,
+                    changed_files,
+                )?)
+            };
+            
+            changed_files.file_state = record_full_file_state(conn, Source::Restic(restic_config), &latest_snapshot, &snapshot_path);
+            // The version only joins the target once it has checked out
+            if let Some(staged) = staged {
+                verify_sample::verify(conn, Source::Restic(restic_config), &latest_snapshot, &snapshot_path, staged.dir())?;
+                staged.commit()?;
+            }
+            
+            record_successful_backup(
+                conn,
+                &options.hostname,
+                Source::Restic(restic_config),
+                &latest_snapshot,
+                &commands,
+                Some(changed_files),
+            )?;
+            
+            println!("Backup recorded successfully");
+        }
+        
+        println!();
+        return Ok(());
+    }
+    
+    let delete_limit = resolve_delete_limit(
+        conn,
+        options,
+        "restic",
+        &restic_config.repository,
+        restic_config.max_delete.as_ref(),
+        &restic_config.target_dir,
+    )?;
+    
+    if restic_config.mode == ResticMode::Restore {
+        if last_backup.as_deref() == Some(latest_snapshot.as_str()) {
+            println!("Already backed up - nothing to do");
+        } else {
+            backup_restic_via_restore(restic_config, &latest_snapshot, tool_versions, delete_limit, changed_files)?;
+            
+            changed_files.file_state = record_full_file_state(conn, Source::Restic(restic_config), &latest_snapshot, &restic_config.target_dir);
+            
+            record_successful_backup(
+                conn,
+                &options.hostname,
+                Source::Restic(restic_config),
+                &latest_snapshot,
+                &commands,
+                Some(changed_files),
+            )?;
+            
+            println!("Backup recorded successfully");
+        }
+        
+        if restic_config.delete_mode == DeleteMode::Trash {
+            purge_expired_trash(&restic_config.target_dir, restic_config.trash_retention_days);
+        }
+        
+        println!();
+        return Ok(());
+    }
+    
+    // Mount the whole repository once; individual snapshots are then found under ids/<short-id>
+    let mount_point = restic_mount_point(&restic_config.repository);
+    fs::create_dir_all(&mount_point)
+        .map_err(|e| format!("Failed to create mount point: {}", e))?;
+    
+    let drifted = match &last_backup {
+        Some(last_snap) => drift::check(conn, options, Source::Restic(restic_config), last_snap)?,
+        None => false,
+    };
+    let full_resync = last_backup.is_some() && (drifted || resync::is_due(conn, options.host_filter(), Source::Restic(restic_config)));
+    
+    match last_backup.filter(|_| !full_resync) {
+        None => {
+            if full_resync {
+                println!("Full resync due - rsyncing the whole snapshot with --checksum");
+            } else {
+                println!("No previous backup found - performing full copy");
+            }
+            
+            let mount_guard = mount_restic_repository(restic_config, &mount_point, &commands)?;
+            let snapshot_path = restic_snapshot_path(&mount_guard, &latest_snapshot)?;
+            
+            let sparse = sparse::for_full(restic_config.sparse, conn, "restic", &restic_config.repository);
+            run_rsync(
+                &snapshot_path,
+                &restic_config.target_dir,
+                delete_limit,
+                &[],
+                full_resync,
+                CopyOptions {
+                        special_files: restic_config.special_files,
+                        symlinks: restic_config.symlinks,
+                        sparse,
+                        filter: restic_config.filter.as_ref(),
+                        run_as: restic_config.user.as_ref(),
+                    },
+                changed_files,
+            )?;
+            symlinks::stub_full(restic_config.symlinks, &snapshot_path, &restic_config.target_dir, restic_config.filter.as_ref())?;
+            record_full_resync(conn, options, Source::Restic(restic_config));
             
-            let source_path = format!("{}/", mount_point.display());
-            run_rsync(&source_path, &restic_config.target_dir)?;
+            changed_files.file_state = record_full_file_state(conn, Source::Restic(restic_config), &latest_snapshot, &snapshot_path);
+            verify_sample::verify(conn, Source::Restic(restic_config), &latest_snapshot, &snapshot_path, &restic_config.target_dir)?;
             
-            // Mount will be unmounted when _mount_guard is dropped
+            // Mount will be unmounted when mount_guard is dropped
             
             record_successful_backup(
                 conn,
-                "restic",
-                &restic_config.repository,
+                &options.hostname,
+                Source::Restic(restic_config),
                 &latest_snapshot,
-                &restic_config.target_dir.to_string_lossy(),
+                &commands,
+                Some(changed_files),
             )?;
             
             println!("Backup recorded successfully");
@@ -839,39 +4508,72 @@ fn backup_restic(restic_config: &ResticConfig, conn: &Connection) -> Result<(),
             } else {
                 println!("Incremental backup needed (last: {}, current: {})", last_snap, latest_snapshot);
                 
-                // Mount both snapshots
-                let mount_old = PathBuf::from("/tmp/restic-mount-old");
-                let mount_new = PathBuf::from("/tmp/restic-mount-new");
-                fs::create_dir_all(&mount_old)
-                    .map_err(|e| format!("Failed to create mount point: {}", e))?;
-                fs::create_dir_all(&mount_new)
-                    .map_err(|e| format!("Failed to create mount point: {}", e))?;
-                
-                let _mount_guard_old = mount_restic_snapshot(&restic_config.repository, &last_snap, &mount_old)?;
-                let _mount_guard_new = mount_restic_snapshot(&restic_config.repository, &latest_snapshot, &mount_new)?;
+                let mount_guard = mount_restic_repository(restic_config, &mount_point, &commands)?;
+                let old_path = restic_snapshot_path(&mount_guard, &last_snap)?;
+                let new_path = restic_snapshot_path(&mount_guard, &latest_snapshot)?;
                 
                 // Get diff using rsync dry-run
-                let changes = get_restic_diff_via_rsync(&mount_old, &mount_new)?;
+                let (files_to_sync, files_to_delete) = get_diff_via_rsync(&new_path, &old_path, false, restic_config.filter.as_ref(), restic_config.user.as_ref(), &commands)?;
+                let files_to_sync = special_files::filter_list(restic_config.special_files, &new_path, files_to_sync);
                 
-                if changes.is_empty() {
+                if files_to_sync.is_empty() && files_to_delete.is_empty() {
                     println!("No changes detected between snapshots");
                 } else {
-                    println!("Found {} change(s)", changes.len());
+                    println!("Found {} change(s)", files_to_sync.len() + files_to_delete.len());
+                    
+                    // Delete removed files first
+                    if !files_to_delete.is_empty() {
+                        check_delete_limit(delete_limit, files_to_delete.len(), &restic_config.target_dir)?;
+                        delete_files_from_target(
+                            &new_path,
+                            &restic_config.target_dir,
+                            &files_to_delete,
+                            restic_config.delete_mode,
+                            restic_config.user.as_ref(),
+                            changed_files,
+                        )?;
+                    }
                     
-                    // Sync changed files from new snapshot
-                    let source_path = format!("{}/", mount_new.display());
-                    run_rsync_with_file_list(&source_path, &restic_config.target_dir, &changes)?;
+                    // Then sync changed files from new snapshot
+                    if !files_to_sync.is_empty() {
+                        let sparse = sparse::for_list(restic_config.sparse, &new_path, &files_to_sync);
+                        run_rsync_with_file_list(
+                            &new_path,
+                            &restic_config.target_dir,
+                            &files_to_sync,
+                            restic_config.symlinks,
+                            sparse,
+                            restic_config.user.as_ref(),
+                            changed_files,
+                        )?;
+                    }
+                    symlinks::stub_changes(
+                        restic_config.symlinks,
+                        &new_path,
+                        &restic_config.target_dir,
+                        &files_to_sync,
+                        &files_to_delete,
+                    )?;
                     
-                    // Note: Deletions would need to be handled separately
-                    // We could compare the file lists from both mounts
+                    changed_files.file_state = apply_file_state_changes(
+                        conn,
+                        "restic",
+                        &restic_config.repository,
+                        &latest_snapshot,
+                        &new_path,
+                        &files_to_sync,
+                        &files_to_delete,
+                    );
+                    verify_sample::verify(conn, Source::Restic(restic_config), &latest_snapshot, &new_path, &restic_config.target_dir)?;
                 }
                 
                 record_successful_backup(
                     conn,
-                    "restic",
-                    &restic_config.repository,
+                    &options.hostname,
+                    Source::Restic(restic_config),
                     &latest_snapshot,
-                    &restic_config.target_dir.to_string_lossy(),
+                    &commands,
+                    Some(changed_files),
                 )?;
                 
                 println!("Incremental backup recorded successfully");
@@ -879,106 +4581,552 @@ fn backup_restic(restic_config: &ResticConfig, conn: &Connection) -> Result<(),
         }
     }
     
+    if restic_config.delete_mode == DeleteMode::Trash {
+        purge_expired_trash(&restic_config.target_dir, restic_config.trash_retention_days);
+    }
+    
     println!();
     Ok(())
 }
 
+fn backup_restic_via_restore(
+    restic_config: &ResticConfig,
+    snapshot_id: &str,
+    tool_versions: &ToolVersions,
+    delete_limit: Option<u64>,
+    changed_files: &mut ChangedFiles,
+) -> Result<(), String> {
+    let commands = changed_files.commands.clone();
+    if tool_versions.restic_restore_overwrite() {
+        // Restore straight onto the target, only rewriting changed files and
+        // removing anything that isn't in the snapshot (excluded paths are kept)
+        let excludes = target_internal_excludes();
+        let mut args = vec!["--overwrite", "if-changed", "--delete"];
+        args.extend(excludes.iter().map(String::as_str));
+        
+        if delete_limit.is_some() {
+            let deletions = count_restic_restore_deletions(restic_config, snapshot_id, &restic_config.target_dir, &args, &commands)?;
+            check_delete_limit(delete_limit, deletions, &restic_config.target_dir)?;
+        }
+        
+        println!("Restoring snapshot {} directly onto target...", snapshot_id);
+        return run_restic_restore(restic_config, snapshot_id, &restic_config.target_dir, &args, &commands);
+    }
+    
+    // Older restic can't sync onto an existing tree, so restore into a staging
+    // directory and rsync that onto the target
+    let staging_dir = restic_config.staging_dir.as_ref().ok_or_else(|| {
+        format!(
+            "restic {} can't restore onto an existing target. Set staging_dir for repository '{}' or upgrade restic to 0.17 or later.",
+            tool_versions.restic.map(|v| v.to_string()).unwrap_or_else(|| "(unknown version)".to_string()),
+            restic_config.repository
+        )
+    })?;
+    
+    // Clear out anything left behind by an earlier run
+    if staging_dir.exists() {
+        fs::remove_dir_all(staging_dir)
+            .map_err(|e| format!("Failed to clear staging directory: {}", e))?;
+    }
+    fs::create_dir_all(staging_dir)
+        .map_err(|e| format!("Failed to create staging directory: {}", e))?;
+    
+    println!("Restoring snapshot {} into staging directory {}...", snapshot_id, staging_dir.display());
+    run_restic_restore(restic_config, snapshot_id, staging_dir, &[], &commands)?;
+    
+    let result = run_rsync(
+        staging_dir,
+        &restic_config.target_dir,
+        delete_limit,
+        &[],
+        false,
+        CopyOptions {
+            special_files: restic_config.special_files,
+            symlinks: restic_config.symlinks,
+            sparse: restic_config.sparse.unwrap_or(false),
+            filter: restic_config.filter.as_ref(),
+            run_as: restic_config.user.as_ref(),
+        },
+        changed_files,
+    );
+    
+    if let Err(e) = fs::remove_dir_all(staging_dir) {
+        eprintln!("Warning: Failed to clean up staging directory: {}", e);
+    }
+    
+    result
+}
+
+
+// How many paths a restore with --delete would remove from `target`, from a
+// dry run of it, so max_delete holds for restic restoring onto the target as
+// it does for rsync. With --json -vv restic reports each path it would touch.
+fn count_restic_restore_deletions(restic_config: &ResticConfig, snapshot_id: &str, target: &Path, args: &[&str], commands: &Log) -> Result<usize, String> {
+    let output = restic_lock::output(restic_config, commands, || {
+        let mut command = privileges::restic(&restic_config.repository, restic_config.user.as_ref());
+        command
+            .args(["restore", snapshot_id, "--target"])
+            .arg(target)
+            .args(args)
+            .args(["--dry-run", "--json", "-vv"]);
+        command
+    })
+        .map_err(|e| format!("Failed to execute restic restore: {}", e))?;
+    
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("restic restore --dry-run failed: {}", stderr.trim()));
+    }
+    
+    let deletions = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|message| message["message_type"] == "verbose_status" && message["action"] == "deleted")
+        .count();
+    Ok(deletions)
+}
+
+
+fn run_restic_restore(restic_config: &ResticConfig, snapshot_id: &str, target: &Path, extra_args: &[&str], commands: &Log) -> Result<(), String> {
+    let output = restic_lock::output(restic_config, commands, || {
+        let mut command = privileges::restic(&restic_config.repository, restic_config.user.as_ref());
+        command.args(["restore", snapshot_id, "--target"]).arg(target).args(extra_args);
+        command
+    })
+        .map_err(|e| format!("Failed to execute restic restore: {}", e))?;
+    
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("restic restore failed: {}", stderr.trim()));
+    }
+    
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    println!("{}", stdout);
+    
+    println!("Restore completed successfully");
+    events::emit(events::Event::TransferProgress { step: "restic restore", files: None, bytes: None });
+    Ok(())
+}
+
+
+// Where restic repositories are FUSE-mounted while their snapshots are read,
+// each in its own directory so several can be mounted at once
+const RESTIC_MOUNT_POINT: &str = "/tmp/restic-mount";
+
+fn restic_mount_point(repository: &str) -> PathBuf {
+    let name: String = repository
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    Path::new(RESTIC_MOUNT_POINT).join(name)
+}
+
 // RAII guard to ensure restic unmount
 struct ResticMountGuard {
     mount_point: PathBuf,
+    // Full IDs of the repository's snapshots, to tell which one ids/<short-id> is
+    snapshot_ids: Vec<String>,
 }
 
 impl Drop for ResticMountGuard {
     fn drop(&mut self) {
         println!("Unmounting restic at {}...", self.mount_point.display());
-        let _ = Command::new("fusermount")
-            .args(["-u", &self.mount_point.to_string_lossy()])
-            .output();
+        let _ = platform::fuse_unmount(&self.mount_point).output();
     }
 }
 
-fn mount_restic_snapshot(repository: &str, snapshot_id: &str, mount_point: &PathBuf) -> Result<ResticMountGuard, String> {
-    println!("Mounting restic snapshot {} at {}...", snapshot_id, mount_point.display());
+fn mount_restic_repository(restic_config: &ResticConfig, mount_point: &Path, commands: &Log) -> Result<ResticMountGuard, String> {
+    println!("Mounting restic repository {} at {}...", restic_config.repository, mount_point.display());
+    if !platform::fuse_available() {
+        return Err(format!(
+            "restic mount needs FUSE, and /dev/fuse isn't there{}; or set mode = \"restore\" for the repository",
+            if platform::OS == platform::Os::FreeBsd { " (kldload fusefs)" } else { "" }
+        ));
+    }
+    
+    // A mount left by an interrupted run would be read instead of this one
+    if mount_point.join("ids").exists() {
+        return Err(format!(
+            "Something is already mounted at {}, perhaps by an interrupted run; unmount it first",
+            mount_point.display()
+        ));
+    }
     
     // Start restic mount in background
-    let mut child = Command::new("restic")
-        .args([
-            "-r", repository,
-            "mount", &mount_point.to_string_lossy(),
-            "--snapshot-template", snapshot_id,
-        ])
-        .spawn()
+    let (mut child, started) = privileges::restic(&restic_config.repository, restic_config.user.as_ref())
+        .args(["mount", &mount_point.to_string_lossy()])
+        .recorded_spawn(commands)
         .map_err(|e| format!("Failed to start restic mount: {}", e))?;
+    // It stays running in the background until unmounted
+    started.finished(None);
+    
+    // Wait for the mount to be ready, giving up after 30 seconds
+    for _ in 0..30 {
+        if mount_point.join("ids").is_dir() {
+            // Unmounted again by the guard if the snapshots can't be listed
+            let mut guard = ResticMountGuard {
+                mount_point: mount_point.to_path_buf(),
+                snapshot_ids: Vec::new(),
+            };
+            guard.snapshot_ids = restic_snapshot_ids(restic_config, commands)?;
+            println!("Restic mounted successfully");
+            return Ok(guard);
+        }
+        
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(format!("restic mount exited early ({})", status));
+        }
+        
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
     
-    // Wait a bit for mount to be ready
-    std::thread::sleep(std::time::Duration::from_secs(2));
+    let _ = child.kill();
+    Err("Restic mount failed or not ready".to_string())
+}
+
+
+fn restic_snapshot_ids(restic_config: &ResticConfig, commands: &Log) -> Result<Vec<String>, String> {
+    let output = restic_lock::output(restic_config, commands, || {
+        let mut command = privileges::restic(&restic_config.repository, restic_config.user.as_ref());
+        command.args(["snapshots", "--json"]);
+        command
+    })
+        .map_err(|e| format!("Failed to execute restic: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("restic snapshots failed: {}", stderr.trim()));
+    }
+    let snapshots: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("Failed to parse restic snapshots: {}", e))?;
+    Ok(snapshots.iter().filter_map(|snapshot| Some(snapshot["id"].as_str()?.to_string())).collect())
+}
+
+
+// Where restic mount puts a snapshot's tree: under ids/, named by the short
+// (8 character) ID. `snapshot_id` may be a prefix of the full ID, and has to
+// be the only snapshot with its short ID, as the mount shows just one of them.
+fn restic_snapshot_dir(mount_point: &Path, snapshot_id: &str, snapshot_ids: &[String]) -> Result<PathBuf, String> {
+    let matching: Vec<&String> = snapshot_ids.iter().filter(|id| id.starts_with(snapshot_id)).collect();
+    let full_id = match matching.as_slice() {
+        [] => return Err(format!("Snapshot {} isn't in the repository", snapshot_id)),
+        [full_id] => *full_id,
+        _ => return Err(format!("Snapshot ID {} is ambiguous; give more of it", snapshot_id)),
+    };
     
-    // Check if mount succeeded by checking if directory is accessible
-    if !mount_point.join("snapshots").exists() {
-        let _ = child.kill();
-        return Err("Restic mount failed or not ready".to_string());
+    let short_id = full_id.get(..8).unwrap_or(full_id);
+    if snapshot_ids.iter().any(|id| id != full_id && id.starts_with(short_id)) {
+        return Err(format!(
+            "Snapshot {} shares its short ID {} with another snapshot, so restic mount can't tell them apart",
+            full_id, short_id
+        ));
     }
     
-    println!("Restic mounted successfully");
+    Ok(mount_point.join("ids").join(short_id))
+}
+
+
+// Path of a snapshot's tree inside a mounted repository
+fn restic_snapshot_path(mount: &ResticMountGuard, snapshot_id: &str) -> Result<PathBuf, String> {
+    let snapshot_path = restic_snapshot_dir(&mount.mount_point, snapshot_id, &mount.snapshot_ids)?;
     
-    Ok(ResticMountGuard {
-        mount_point: mount_point.clone(),
-    })
+    // Make sure we are reading the snapshot we asked for, not whatever restic considers latest
+    if !snapshot_path.is_dir() {
+        return Err(format!(
+            "Snapshot {} not found in restic mount at {}",
+            snapshot_id,
+            mount.mount_point.display()
+        ));
+    }
+    
+    Ok(snapshot_path)
 }
 
-fn get_restic_diff_via_rsync(old_mount: &PathBuf, new_mount: &PathBuf) -> Result<(Vec<String>, Vec<String>), String> {
+// Compare two trees with an rsync dry-run of source onto dest. Returns the paths
+// that would be transferred (new or modified in source) and the paths that
+// would be deleted (only present in dest).
+fn get_diff_via_rsync(
+    source: &Path,
+    dest: &Path,
+    checksum: bool,
+    filter: Option<&FilterFile>,
+    run_as: Option<&privileges::User>,
+    commands: &Log,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
     println!("Computing differences using rsync...");
     
-    let old_path = format!("{}/snapshots/latest/", old_mount.display());
-    let new_path = format!("{}/snapshots/latest/", new_mount.display());
+    // Like --itemize-changes, but without the " -> target" rsync appends to symlinks
+    let mut command = privileges::command("rsync", run_as);
+    command.arg(tools::rsync_archive("n8"));
+    command.args(["--out-format=%i %n", "--delete"]);
+    command.args(target_internal_excludes());
+    command.args(excludes::rsync_args(filter));
+    if checksum {
+        command.arg("--checksum");
+    }
     
-    // Compare new to old to find additions and modifications
-    let output = Command::new("rsync")
-        .args([
-            "-aAXHn",
-            "--itemize-changes",
-            &new_path,
-            &old_path,
-        ])
-        .output()
+    let output = command
+        .arg(rsync_contents_arg(source))
+        .arg(rsync_contents_arg(dest))
+        .recorded_output(commands)
         .map_err(|e| format!("Failed to execute rsync: {}", e))?;
     
-    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("rsync failed: {}", stderr.trim()));
+    }
     
     let mut added_modified = Vec::new();
+    let mut deleted = Vec::new();
     
-    for line in stdout.lines() {
-        if line.is_empty() || line.starts_with(".d") {
+    for line in output.stdout.split(|&b| b == b'\n') {
+        // Format: <11 character itemize code> <path>, e.g. ">f.st...... dir/file"
+        // or "*deleting   dir/file". The path is taken verbatim, leading spaces and all.
+        if line.len() < 13 || line[11] != b' ' {
             continue;
         }
-        if let Some((_, path)) = line.split_once("  ") {
-            added_modified.push(path.to_string());
+        let (code, path) = (&line[..11], PathBuf::from(OsString::from_vec(unescape_rsync_path(&line[12..]))));
+        
+        if code.starts_with(b"*deleting") {
+            deleted.push(path);
+        } else if !code.starts_with(b".d") {
+            added_modified.push(path);
         }
     }
     
-    // Compare old to new to find deletions
-    let output = Command::new("rsync")
-        .args([
-            "-aAXHn",
-            "--itemize-changes",
-            "--delete",
-            &old_path,
-            &new_path,
-        ])
-        .output()
-        .map_err(|e| format!("Failed to execute rsync: {}", e))?;
+    Ok((added_modified, deleted))
+}
+
+
+// Even with -8, rsync writes control characters in names as "\#" and three
+// octal digits, e.g. "\#012" for a newline
+fn unescape_rsync_path(field: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let escape = field.get(i..i + 5)
+            .filter(|escape| escape.starts_with(b"\\#") && escape[2..].iter().all(|d| (b'0'..=b'7').contains(d)));
+        match escape {
+            Some(escape) => {
+                let value = escape[2..].iter().fold(0u32, |value, d| value * 8 + u32::from(d - b'0'));
+                bytes.push(value as u8);
+                i += 5;
+            }
+            None => {
+                bytes.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    bytes
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
     
-    let stdout = String::from_utf8_lossy(&output.stdout);
     
-    let mut deleted = Vec::new();
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
     
-    for line in stdout.lines() {
-        if line.starts_with("*deleting") {
-            if let Some(path) = line.strip_prefix("*deleting   ") {
-                deleted.push(path.to_string());
-            }
+    
+    #[test]
+    fn restic_snapshot_dir_is_named_by_short_id() {
+        let snapshot_ids = ids(&["4f2a91c0d3e5b6a7", "9b8c7d6e5f4a3b2c"]);
+        let dir = restic_snapshot_dir(Path::new("/run/restic"), "4f2a91c0d3e5b6a7", &snapshot_ids).unwrap();
+        assert_eq!(dir, Path::new("/run/restic/ids/4f2a91c0"));
+    }
+    
+    
+    #[test]
+    fn restic_snapshot_dir_takes_a_prefix_of_the_full_id() {
+        let snapshot_ids = ids(&["4f2a91c0d3e5b6a7", "9b8c7d6e5f4a3b2c"]);
+        let dir = restic_snapshot_dir(Path::new("/run/restic"), "9b8c7d", &snapshot_ids).unwrap();
+        assert_eq!(dir, Path::new("/run/restic/ids/9b8c7d6e"));
+    }
+    
+    
+    #[test]
+    fn restic_snapshot_dir_rejects_a_missing_snapshot() {
+        let snapshot_ids = ids(&["4f2a91c0d3e5b6a7"]);
+        assert!(restic_snapshot_dir(Path::new("/run/restic"), "4f2a91c0ffffffff", &snapshot_ids).is_err());
+    }
+    
+    
+    #[test]
+    fn restic_snapshot_dir_rejects_an_ambiguous_prefix() {
+        let snapshot_ids = ids(&["4f2a91c0d3e5b6a7", "4f2a0000d3e5b6a7"]);
+        assert!(restic_snapshot_dir(Path::new("/run/restic"), "4f2a", &snapshot_ids).is_err());
+    }
+    
+    
+    #[test]
+    fn restic_snapshot_dir_rejects_a_short_id_collision() {
+        let snapshot_ids = ids(&["4f2a91c0d3e5b6a7", "4f2a91c0aaaaaaaa"]);
+        let error = restic_snapshot_dir(Path::new("/run/restic"), "4f2a91c0d3e5b6a7", &snapshot_ids).unwrap_err();
+        assert!(error.contains("short ID 4f2a91c0"), "{}", error);
+    }
+    
+    
+    fn change(change_type: char, file_type: char, path: &[u8], new_path: Option<&[u8]>) -> SnapshotChange {
+        SnapshotChange {
+            change_type,
+            file_type: Some(file_type),
+            path: PathBuf::from(OsStr::from_bytes(path)),
+            new_path: new_path.map(|new_path| PathBuf::from(OsStr::from_bytes(new_path))),
         }
     }
     
-    Ok((added_modified, deleted))
-}
\ No newline at end of file
+    
+    #[test]
+    fn mountpoint_keeps_its_spaces() {
+        assert_eq!(parse_mountpoint(b" /mnt/my data \n"), Path::new(" /mnt/my data "));
+        assert_eq!(parse_mountpoint(b"/tank/home"), Path::new("/tank/home"));
+    }
+    
+    
+    #[test]
+    fn mountpoint_needn_t_be_utf8() {
+        let mountpoint = parse_mountpoint(b"/mnt/caf\xe9\n");
+        assert_eq!(mountpoint.as_os_str().as_bytes(), b"/mnt/caf\xe9");
+    }
+    
+    
+    #[test]
+    fn paths_are_made_relative_to_an_awkward_mountpoint() {
+        let mountpoint = Path::new("/mnt/my data ");
+        let changes = [
+            change('M', '/', b"/mnt/my data /", None),
+            change('+', 'F', b"/mnt/my data /a b/new\\file", None),
+            change('M', 'F', b"/mnt/my data /caf\xe9.txt", None),
+            change('R', 'F', b"/mnt/my data /old", Some(b"/mnt/my data /new name")),
+            change('-', 'F', b"/mnt/my data /gone\nline", None),
+        ];
+        
+        assert_eq!(
+            changes.iter().filter_map(|change| synced_path(change, mountpoint)).collect::<Vec<_>>(),
+            [
+                (PathBuf::from("a b/new\\file"), false),
+                (PathBuf::from(OsStr::from_bytes(b"caf\xe9.txt")), false),
+                (PathBuf::from("new name"), false),
+            ]
+        );
+        let deleted: Vec<_> = changes.iter().filter_map(|change| deleted_path(change, mountpoint)).collect();
+        assert_eq!(deleted, [(PathBuf::from("gone\nline"), false)]);
+    }
+    
+    
+    #[test]
+    fn mountpoint_prefix_is_matched_by_component() {
+        // "/mnt/data2" isn't under "/mnt/data", so it is left as it is
+        assert_eq!(strip_mountpoint_prefix(Path::new("/mnt/data2/file"), Path::new("/mnt/data")), Path::new("/mnt/data2/file"));
+        assert_eq!(strip_mountpoint_prefix(Path::new("/mnt/data/file"), Path::new("/mnt/data/")), Path::new("file"));
+    }
+    
+    
+    #[test]
+    fn unescape_zfs_path_decodes_octal_escapes() {
+        assert_eq!(unescape_zfs_path(b"my\\0040file"), b"my file");
+        assert_eq!(unescape_zfs_path(b"back\\0134slash"), b"back\\slash");
+        assert_eq!(unescape_zfs_path(b"new\\0012line"), b"new\nline");
+        assert_eq!(unescape_zfs_path(b"caf\\0351"), b"caf\xe9");
+    }
+    
+    
+    #[test]
+    fn unescape_zfs_path_leaves_other_backslashes() {
+        // Not followed by four octal digits, so not an escape
+        assert_eq!(unescape_zfs_path(b"a\\b"), b"a\\b");
+        assert_eq!(unescape_zfs_path(b"a\\008"), b"a\\008");
+        assert_eq!(unescape_zfs_path(b"end\\"), b"end\\");
+        assert_eq!(unescape_zfs_path(b""), b"");
+    }
+    
+    
+    fn parse(line: &[u8], escaped: bool) -> SnapshotChange {
+        parse_zfs_diff_line(line, escaped).expect("line should parse")
+    }
+    
+    
+    #[test]
+    fn diff_line_fields() {
+        let change = parse(b"M\tF\t/tank/home/notes.txt", true);
+        assert_eq!(change.change_type, 'M');
+        assert_eq!(change.file_type, Some('F'));
+        assert_eq!(change.path, Path::new("/tank/home/notes.txt"));
+        assert_eq!(change.new_path, None);
+    }
+    
+    
+    #[test]
+    fn diff_line_unescapes_octal_names() {
+        let change = parse(b"+\tF\t/tank/home/my\\0040file\\0012two\\0134lines", true);
+        assert_eq!(change.path, Path::new("/tank/home/my file\ntwo\\lines"));
+        
+        let change = parse(b"+\tF\t/tank/home/caf\\0351", true);
+        assert_eq!(change.path.as_os_str().as_bytes(), b"/tank/home/caf\xe9");
+    }
+    
+    
+    #[test]
+    fn diff_line_rename_has_new_path() {
+        let change = parse(b"R\tF\t/tank/home/old\\0040name\t/tank/home/new\\0040name", true);
+        assert_eq!(change.change_type, 'R');
+        assert_eq!(change.path, Path::new("/tank/home/old name"));
+        assert_eq!(change.new_path.as_deref(), Some(Path::new("/tank/home/new name")));
+        
+        // A name with an arrow in it is only a name
+        let change = parse(b"R\tF\t/tank/a -> b\t/tank/c", false);
+        assert_eq!(change.path, Path::new("/tank/a -> b"));
+        assert_eq!(change.new_path.as_deref(), Some(Path::new("/tank/c")));
+    }
+    
+    
+    #[test]
+    fn diff_line_file_type_markers() {
+        for marker in ['F', '/', '@', '|', '=', '>', 'B', 'C', 'P'] {
+            let line = format!("M\t{}\t/tank/x", marker);
+            assert_eq!(parse(line.as_bytes(), true).file_type, Some(marker));
+        }
+        // Anything but a single character isn't a -F type, so isn't a line of -HF output
+        assert!(parse_zfs_diff_line(b"M\t/tank/x", true).is_none());
+        assert!(parse_zfs_diff_line(b"M\tFF\t/tank/x", true).is_none());
+        assert!(parse_zfs_diff_line(b"", true).is_none());
+    }
+    
+    
+    #[test]
+    fn diff_line_with_h_is_taken_as_it_is() {
+        // What zfs diff -h writes for a file really named with a backslash escape
+        let change = parse(b"+\tF\t/tank/literally\\0040\xff", false);
+        assert_eq!(change.path.as_os_str().as_bytes(), b"/tank/literally\\0040\xff");
+        
+        let change = parse(b"+\tF\t/tank/literally\\0134", true);
+        assert_eq!(change.path, Path::new("/tank/literally\\"));
+    }
+    
+    
+    #[test]
+    fn paths_replacing_a_directory_are_found() {
+        let root = std::env::temp_dir().join(format!("file-backup-test-{}", std::process::id()));
+        let (snapshot, target) = (root.join("snapshot"), root.join("target"));
+        for dir in [snapshot.join("dir"), target.join("a/b"), target.join("dir"), target.join("link")] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(snapshot.join("a"), b"").unwrap();
+        fs::write(snapshot.join("new"), b"").unwrap();
+        std::os::unix::fs::symlink("dir", snapshot.join("link")).unwrap();
+        
+        // A file, and a symlink, where the target has a directory
+        assert!(replaces_directory(&snapshot, &target, Path::new("a"), Symlinks::Preserve));
+        assert!(replaces_directory(&snapshot, &target, Path::new("link"), Symlinks::Preserve));
+        // Followed, the symlink is copied as the directory it points to
+        assert!(!replaces_directory(&snapshot, &target, Path::new("link"), Symlinks::Follow));
+        // A directory still, or nothing on the target yet
+        assert!(!replaces_directory(&snapshot, &target, Path::new("dir"), Symlinks::Preserve));
+        assert!(!replaces_directory(&snapshot, &target, Path::new("new"), Symlinks::Preserve));
+        
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+
+// With metadata_sidecar set, the extended attributes of every file are also
+// kept in files of their own under this directory on the target, for targets
+// (e.g. ext4 on a USB disk) that can't hold NFSv4 ACLs or some xattrs. ZFS
+// keeps ACLs in xattrs too (system.nfs4_acl, system.posix_acl_access), so
+// these carry them as well, and restore puts them back. With symlinks =
+// "stub" the store also keeps where each symlink pointed.
+pub const META_DIR: &str = ".file-backup-meta";
+const META_SUFFIX: &str = ".meta";
+const LINK_SUFFIX: &str = ".link";
+
+
+#[cfg(target_os = "linux")]
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+
+// Extended attribute names and values of a path, not following symlinks
+#[cfg(target_os = "linux")]
+fn read_xattrs(path: &Path) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let c_path = c_path(path)?;
+
+    let size = unsafe { libc::llistxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let error = io::Error::last_os_error();
+        // Filesystems without xattr support have none to keep
+        return if error.raw_os_error() == Some(libc::ENOTSUP) { Ok(Vec::new()) } else { Err(error) };
+    }
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe { libc::llistxattr(c_path.as_ptr(), names.as_mut_ptr().cast(), names.len()) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    names.truncate(size as usize);
+
+    let mut xattrs = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let size = unsafe { libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe { libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        value.truncate(size as usize);
+        xattrs.push((name.to_vec(), value));
+    }
+    Ok(xattrs)
+}
+
+
+// The l*xattr calls are Linux's; metadata_sidecar is refused elsewhere when
+// the config is loaded
+#[cfg(not(target_os = "linux"))]
+fn read_xattrs(_path: &Path) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+
+#[cfg(target_os = "linux")]
+fn write_xattr(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+    let c_path = c_path(path)?;
+    let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if unsafe { libc::lsetxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr().cast(), value.len(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+#[cfg(not(target_os = "linux"))]
+fn write_xattr(_path: &Path, _name: &[u8], _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+
+// Each attribute is stored as its name, a NUL, the value's length as four
+// little-endian bytes, then the value
+fn encode(xattrs: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (name, value) in xattrs {
+        bytes.extend_from_slice(name);
+        bytes.push(0);
+        bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(value);
+    }
+    bytes
+}
+
+
+fn decode(mut bytes: &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    while !bytes.is_empty() {
+        let end = bytes.iter().position(|&b| b == 0)?;
+        let name = bytes[..end].to_vec();
+        let length = u32::from_le_bytes(bytes.get(end + 1..end + 5)?.try_into().ok()?) as usize;
+        let value = bytes.get(end + 5..end + 5 + length)?.to_vec();
+        bytes = &bytes[end + 5 + length..];
+        xattrs.push((name, value));
+    }
+    Some(xattrs)
+}
+
+
+// "<META_DIR>/<path>.meta", and "<META_DIR>/.meta" for the root itself, so a
+// directory's own sidecar sits next to the directory holding its children's
+fn sidecar_path(target_dir: &Path, relative_path: &Path) -> PathBuf {
+    suffixed_path(target_dir, relative_path, META_SUFFIX)
+}
+
+
+// "<META_DIR>/<path>.link", holding the symlink's target as it was read
+fn link_path(target_dir: &Path, relative_path: &Path) -> PathBuf {
+    suffixed_path(target_dir, relative_path, LINK_SUFFIX)
+}
+
+
+fn suffixed_path(target_dir: &Path, relative_path: &Path, suffix: &str) -> PathBuf {
+    let mut path = target_dir.join(META_DIR).join(relative_path).into_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+
+fn update_sidecar(root: &Path, target_dir: &Path, relative_path: &Path) -> Result<bool, String> {
+    let path = root.join(relative_path);
+    let sidecar = sidecar_path(target_dir, relative_path);
+    let xattrs = read_xattrs(&path)
+        .map_err(|e| format!("Failed to read extended attributes of {}: {}", path.display(), e))?;
+
+    if xattrs.is_empty() {
+        return match fs::remove_file(&sidecar) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(format!("Failed to remove {}: {}", sidecar.display(), e))
+            }
+            _ => Ok(false),
+        };
+    }
+
+    if let Some(parent) = sidecar.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    fs::write(&sidecar, encode(&xattrs))
+        .map_err(|e| format!("Failed to write {}: {}", sidecar.display(), e))?;
+    Ok(true)
+}
+
+
+// Rebuild the whole store from a snapshot tree, after a full backup. Returns
+// how many paths had attributes to keep.
+pub fn record_full(root: &Path, target_dir: &Path) -> Result<usize, String> {
+    let meta_dir = target_dir.join(META_DIR);
+    match fs::remove_dir_all(&meta_dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(format!("Failed to remove {}: {}", meta_dir.display(), e));
+        }
+        _ => {}
+    }
+
+    let mut count = 0;
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(relative_dir) = pending.pop() {
+        if update_sidecar(root, target_dir, &relative_dir)? {
+            count += 1;
+        }
+
+        let dir = root.join(&relative_dir);
+        let entries = fs::read_dir(&dir)
+            .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+            let relative_path = relative_dir.join(entry.file_name());
+            let is_dir = entry.file_type()
+                .map_err(|e| format!("Failed to stat {}: {}", entry.path().display(), e))?
+                .is_dir();
+
+            if is_dir {
+                pending.push(relative_path);
+            } else if update_sidecar(root, target_dir, &relative_path)? {
+                count += 1;
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+
+// Update the store for an incremental diff: `synced` paths are re-read from
+// the snapshot tree at `root`, `deleted` ones (and anything beneath them) are
+// dropped
+pub fn record_changes(root: &Path, target_dir: &Path, synced: &[PathBuf], deleted: &[PathBuf]) -> Result<(), String> {
+    for path in deleted {
+        let path = path.strip_prefix("/").unwrap_or(path);
+        let sidecar = sidecar_path(target_dir, path);
+        let children = target_dir.join(META_DIR).join(path);
+        for result in [fs::remove_file(&sidecar), fs::remove_dir_all(&children)] {
+            if let Err(e) = result
+                && e.kind() != io::ErrorKind::NotFound
+            {
+                return Err(format!("Failed to remove metadata of {}: {}", path.display(), e));
+            }
+        }
+    }
+
+    for path in synced {
+        record_synced(root, target_dir, path)?;
+    }
+
+    Ok(())
+}
+
+
+// The sidecar of one path an incremental backup synced
+pub fn record_synced(root: &Path, target_dir: &Path, path: &Path) -> Result<(), String> {
+    update_sidecar(root, target_dir, path.strip_prefix("/").unwrap_or(path)).map(|_| ())
+}
+
+
+pub fn record_link(target_dir: &Path, relative_path: &Path, link: &Path) -> Result<(), String> {
+    let path = link_path(target_dir, relative_path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    fs::write(&path, link.as_os_str().as_bytes())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+
+pub fn forget_link(target_dir: &Path, relative_path: &Path) -> Result<(), String> {
+    let path = link_path(target_dir, relative_path);
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(format!("Failed to remove {}: {}", path.display(), e)),
+        _ => Ok(()),
+    }
+}
+
+
+// Where the symlink a stub on the target stands for pointed, if it is one
+pub fn link_target(target_dir: &Path, relative_path: &Path) -> Option<PathBuf> {
+    let bytes = fs::read(link_path(target_dir, relative_path)).ok()?;
+    Some(PathBuf::from(OsStr::from_bytes(&bytes)))
+}
+
+
+// Paths in the store with records ending in `suffix`, relative to the
+// tree they describe
+fn records(target_dir: &Path, suffix: &str) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+    let meta_dir = target_dir.join(META_DIR);
+    if !meta_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut records = Vec::new();
+    let mut pending = vec![meta_dir.clone()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = fs::read_dir(&dir)
+            .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+            let record = entry.path();
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                pending.push(record);
+                continue;
+            }
+
+            let relative = record.strip_prefix(&meta_dir).unwrap_or(&record).as_os_str().as_bytes();
+            if let Some(relative) = relative.strip_suffix(suffix.as_bytes()) {
+                let relative = PathBuf::from(OsStr::from_bytes(relative));
+                records.push((record, relative));
+            }
+        }
+    }
+    Ok(records)
+}
+
+
+// Drop every kept symlink target, before stubs are written for a whole tree
+pub fn clear_links(target_dir: &Path) -> Result<(), String> {
+    for (record, _) in records(target_dir, LINK_SUFFIX)? {
+        fs::remove_file(&record).map_err(|e| format!("Failed to remove {}: {}", record.display(), e))?;
+    }
+    Ok(())
+}
+
+
+// Turn the stubs in a restored tree back into the symlinks they stand for
+pub fn relink(target_dir: &Path, destination: &Path) -> Result<(), String> {
+    let links = records(target_dir, LINK_SUFFIX)?;
+    if links.is_empty() {
+        return Ok(());
+    }
+    println!("Recreating {} symlink(s) from {}...", links.len(), META_DIR);
+
+    let mut failures = 0;
+    for (record, relative) in &links {
+        let path = destination.join(relative);
+        let result = fs::read(record).and_then(|link| {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            std::os::unix::fs::symlink(OsStr::from_bytes(&link), &path)
+        });
+        if let Err(e) = result {
+            eprintln!("Warning: Failed to recreate symlink {}: {}", path.display(), e);
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        return Err(format!("{} symlink(s) couldn't be recreated", failures));
+    }
+    Ok(())
+}
+
+
+// Put the attributes kept in a target's store back onto a restored tree
+pub fn apply(target_dir: &Path, destination: &Path) -> Result<(), String> {
+    let meta_dir = target_dir.join(META_DIR);
+    if !meta_dir.is_dir() {
+        return Ok(());
+    }
+    println!("Reapplying extended attributes from {}...", meta_dir.display());
+
+    let mut applied = 0;
+    let mut failures = 0;
+    for (sidecar, relative) in records(target_dir, META_SUFFIX)? {
+        let path = destination.join(relative);
+        let xattrs = fs::read(&sidecar)
+            .ok()
+            .and_then(|bytes| decode(&bytes))
+            .ok_or_else(|| format!("Failed to read {}", sidecar.display()))?;
+        for (name, value) in &xattrs {
+            match write_xattr(&path, name, value) {
+                Ok(()) => applied += 1,
+                Err(e) => {
+                    eprintln!(
+                        "Warning: Failed to set {} on {}: {}",
+                        String::from_utf8_lossy(name),
+                        path.display(),
+                        e
+                    );
+                    failures += 1;
+                }
+            }
+        }
+    }
+
+    println!("Reapplied {} extended attribute(s)", applied);
+    if failures > 0 {
+        return Err(format!("{} extended attribute(s) couldn't be reapplied", failures));
+    }
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use rusqlite::{Connection, params};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::executed::{Log, Record};
+use crate::{Config, Layout, ResticMode, RunOptions, Source, child_env, db_export, queue, tools};
+
+
+// Where to move a source's backups, and how
+pub struct Migration {
+    pub to: PathBuf,
+    // Copy the last snapshot backed up rather than the old target, for when
+    // the old disk can't be read
+    pub reseed: bool,
+    // Compare contents when checking the copy
+    pub checksum: bool,
+}
+
+
+fn is_empty_dir(dir: &Path) -> Result<bool, String> {
+    Ok(fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?
+        .next()
+        .is_none())
+}
+
+
+fn copy_tree(from: &Path, to: &Path) -> Result<(), String> {
+    println!("Copying {} to {}...", from.display(), to.display());
+    let output = child_env::command("rsync")
+        .arg(tools::rsync_archive(""))
+        .args(["--numeric-ids", "--delete"])
+        .arg(crate::rsync_contents_arg(from))
+        .arg(to)
+        .recorded_output(&Log::default())
+        .map_err(|e| format!("Failed to execute rsync: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("rsync failed: {}", stderr.trim()));
+    }
+    Ok(())
+}
+
+
+fn verify_copy(from: &Path, to: &Path, checksum: bool) -> Result<(), String> {
+    let (differing, extra) = crate::get_diff_via_rsync(from, to, checksum, None, None, &Log::default())?;
+    if differing.is_empty() && extra.is_empty() {
+        println!("{} matches {}", to.display(), from.display());
+        return Ok(());
+    }
+    for path in differing.iter().take(20) {
+        println!("  differs: {}", path.display());
+    }
+    Err(format!(
+        "The copy doesn't match: {} path(s) differ, {} path(s) shouldn't be there; the old target is still the one in use",
+        differing.len(),
+        extra.len()
+    ))
+}
+
+
+// Point the backup history at the new target, in one transaction
+fn move_history(conn: &Connection, options: &RunOptions, source: Source, from: &Path, to: &Path) -> Result<usize, String> {
+    let tx = conn
+        .unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    let moved = tx
+        .execute(
+            "UPDATE backup_history SET target_dir = ?1
+             WHERE backup_type = ?2 AND source_name = ?3 AND target_dir = ?4 AND (?5 IS NULL OR hostname = ?5)",
+            params![to.to_string_lossy(), source.backup_type(), source.name(), from.to_string_lossy(), options.host_filter()],
+        )
+        .map_err(|e| format!("Failed to update backup history: {}", e))?;
+    tx.commit().map_err(|e| format!("Failed to commit: {}", e))?;
+    Ok(moved)
+}
+
+
+// Replace a quoted value in the config file where it appears there exactly
+// once, keeping the rest of the file as it was. Returns whether it did.
+pub fn replace_in_config(config_path: &Path, old: &str, new: &str) -> Result<bool, String> {
+    let contents = fs::read_to_string(config_path)
+        .map_err(|e| format!("Failed to read {}: {}", config_path.display(), e))?;
+    let old_value = format!("\"{}\"", old);
+    if contents.matches(&old_value).count() != 1 {
+        return Ok(false);
+    }
+
+    let updated = contents.replacen(&old_value, &format!("\"{}\"", new), 1);
+    let temp_path = config_path.with_extension("toml.tmp");
+    fs::write(&temp_path, updated)
+        .and_then(|()| fs::set_permissions(&temp_path, fs::metadata(config_path)?.permissions()))
+        .and_then(|()| fs::rename(&temp_path, config_path))
+        .map_err(|e| format!("Failed to update {}: {}", config_path.display(), e))?;
+    Ok(true)
+}
+
+
+// Change the source's target_dir in the config file, where the old path
+// appears exactly once; otherwise say what to change
+fn update_config(config_path: &Path, source: Source, from: &Path, to: &Path) -> Result<(), String> {
+    if replace_in_config(config_path, &from.display().to_string(), &to.display().to_string())? {
+        println!("Set target_dir for {} '{}' to {} in {}", source.kind(), source.name(), to.display(), config_path.display());
+    } else {
+        println!(
+            "Set target_dir = \"{}\" for {} '{}' in {} to back up to the new target",
+            to.display(),
+            source.kind(),
+            source.name(),
+            config_path.display()
+        );
+    }
+    Ok(())
+}
+
+
+// Move a source's backups to a new target, e.g. a disk replacing one that is
+// failing. The old target is copied, or with `reseed` the last snapshot backed
+// up is copied afresh, then checked; only then are the backup history and the
+// config switched over, so an interrupted migration leaves the old target in
+// use.
+pub fn migrate_target(
+    config: &Config,
+    config_path: &Path,
+    conn: &Connection,
+    options: &RunOptions,
+    source: &str,
+    migration: &Migration,
+) -> Result<(), String> {
+    let commands = Log::default();
+    let source = config.find_source(source)?;
+    let from = source.target_dir();
+    let to = migration.to.as_path();
+    println!("=== Migrating {} '{}' from {} to {} ===", source.kind(), source.name(), from.display(), to.display());
+
+    crate::check_target_directory(to)?;
+    let new_dir = fs::canonicalize(to).map_err(|e| format!("Failed to resolve {}: {}", to.display(), e))?;
+    if let Ok(old_dir) = fs::canonicalize(from)
+        && (new_dir.starts_with(&old_dir) || old_dir.starts_with(&new_dir))
+    {
+        return Err(format!("{} and {} overlap", from.display(), to.display()));
+    }
+    if !is_empty_dir(to)? {
+        return Err(format!("{} is not empty", to.display()));
+    }
+
+    let _lock = queue::lock(&options.database)?;
+
+    let mount_guard;
+    let tree = if migration.reseed {
+        let plain_mirror = source.layout() == Layout::Mirror
+            && source.encryption().encrypt.is_none()
+            && match source {
+                Source::Dataset(d) => d.zvol_mode.is_none() && !d.metadata_sidecar,
+                Source::Restic(r) => r.mode == ResticMode::Mount,
+            };
+        if !plain_mirror {
+            return Err("--reseed only works for plain mirror targets; copy the old target instead".to_string());
+        }
+        let snapshot = crate::get_last_backed_up_snapshot(conn, options.host_filter(), source, &commands)
+            .map_err(|e| format!("Failed to read backup history: {}", e))?
+            .ok_or_else(|| format!("No backup of '{}' to reseed from", source.name()))?;
+        let path = match source {
+            Source::Dataset(_) => crate::get_snapshot_mountpoint(&snapshot, &commands)?,
+            Source::Restic(restic_config) => {
+                let mount_point = crate::restic_mount_point(&restic_config.repository);
+                fs::create_dir_all(&mount_point).map_err(|e| format!("Failed to create mount point: {}", e))?;
+                mount_guard = crate::mount_restic_repository(restic_config, &mount_point, &commands)?;
+                crate::restic_snapshot_path(&mount_guard, &snapshot)?
+            }
+        };
+        println!("Reseeding from snapshot {}", snapshot);
+        path
+    } else {
+        crate::check_target_directory(from)?;
+        from.to_path_buf()
+    };
+
+    copy_tree(&tree, to)?;
+    verify_copy(&tree, to, migration.checksum)?;
+
+    let moved = move_history(conn, options, source, from, &new_dir)?;
+    println!("Moved {} backup record(s) to {}", moved, new_dir.display());
+    db_export::write_target_state(conn, &new_dir)?;
+    update_config(config_path, source, from, &new_dir)?;
+
+    println!("{} is now the target of '{}'; {} can be retired", new_dir.display(), source.name(), from.display());
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use crate::executed::{Log, Record};
+use crate::{Config, DatasetConfig, Layout, child_env, template};
+
+
+// What to do about child datasets mounted inside a dataset's tree. Their
+// files aren't in the parent's snapshot, only the empty directories they are
+// mounted on, so a backup of the parent alone silently leaves them out.
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Descend {
+    // Back up the parent only, warning about children that aren't configured
+    #[default]
+    Skip,
+    // Back up each child as its own source, into the matching place under
+    // the parent's target
+    Include,
+    // Refuse to back up the parent while unconfigured children are mounted in it
+    Error,
+}
+
+
+pub struct NestedDataset {
+    pub name: String,
+    // Where the child is mounted, relative to the parent's mountpoint
+    pub relative_path: PathBuf,
+}
+
+
+// Mounted descendants of a dataset whose mountpoints are inside its own
+pub fn find_nested(dataset: &str, commands: &Log) -> Result<Vec<NestedDataset>, String> {
+    let output = child_env::command("zfs")
+        .args(["list", "-H", "-r", "-t", "filesystem", "-o", "name,mounted,mountpoint", dataset])
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute zfs list: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("zfs list failed: {}", stderr.trim()));
+    }
+
+    // The dataset itself comes first, followed by its descendants
+    let mut lines = output.stdout
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, |&b| b == b'\t');
+            let name = String::from_utf8_lossy(fields.next()?).into_owned();
+            let mounted = fields.next()? == b"yes";
+            let mountpoint = PathBuf::from(OsStr::from_bytes(fields.next()?));
+            Some((name, mounted, mountpoint))
+        });
+
+    let Some((_, _, parent_mountpoint)) = lines.next() else {
+        return Ok(Vec::new());
+    };
+
+    Ok(lines
+        .filter(|(_, mounted, _)| *mounted)
+        .filter_map(|(name, _, mountpoint)| {
+            let relative_path = mountpoint.strip_prefix(&parent_mountpoint).ok()?.to_path_buf();
+            Some(NestedDataset { name, relative_path })
+        })
+        .filter(|nested| !nested.relative_path.as_os_str().is_empty())
+        .collect())
+}
+
+
+// Add a source for every child of a `descend = "include"` dataset, unless the
+// child is configured in its own right, and keep the parent's rsync off the
+// children's part of the target
+pub fn expand(config: &mut Config) -> Result<(), String> {
+    let mut children = Vec::new();
+
+    for parent in config.dataset.iter_mut().filter(|d| d.descend == Descend::Include) {
+        let nested = find_nested(&parent.name, &Log::default())
+            .map_err(|e| format!("Failed to find datasets nested in '{}': {}", parent.name, e))?;
+
+        for child in &nested {
+            parent.nested_excludes.push(child.relative_path.clone());
+        }
+
+        for child in &nested {
+            println!("Including nested dataset '{}' with '{}'", child.name, parent.name);
+
+            // Grandchildren are included too, so each child leaves out its own
+            let mut child_config = parent.clone();
+            child_config.name = child.name.clone();
+            // A templated target_dir is filled in for the child's own name
+            if parent.target_template.is_none() {
+                child_config.target_dir = parent.target_dir.join(&child.relative_path);
+            }
+            child_config.descend = Descend::Skip;
+            child_config.nested_excludes = nested
+                .iter()
+                .filter_map(|other| other.relative_path.strip_prefix(&child.relative_path).ok())
+                .filter(|path| !path.as_os_str().is_empty())
+                .map(|path| path.to_path_buf())
+                .collect();
+            children.push(child_config);
+        }
+    }
+
+    for child in children {
+        if !config.dataset.iter().any(|d| d.name == child.name) {
+            config.dataset.push(child);
+        }
+    }
+    template::expand(config);
+
+    Ok(())
+}
+
+
+pub fn validate(dataset_config: &DatasetConfig) -> Result<(), String> {
+    if dataset_config.zvol_mode.is_some()
+        && (dataset_config.layout != Layout::Mirror
+            || dataset_config.encryption.encrypt.is_some()
+            || dataset_config.descend == Descend::Include)
+    {
+        return Err("zvol_mode stores the volume in a restic repository, so layout, encrypt and descend don't apply".to_string());
+    }
+    if dataset_config.descend == Descend::Include
+        && (dataset_config.layout != Layout::Mirror || dataset_config.encryption.encrypt.is_some())
+    {
+        return Err("descend = \"include\" only works with the mirror layout and no encryption".to_string());
+    }
+    if dataset_config.par2_redundancy.is_some() && dataset_config.layout != Layout::Stream {
+        return Err("par2_redundancy needs layout = \"stream\"".to_string());
+    }
+    if (dataset_config.image_format.is_some() || dataset_config.image_compression.is_some())
+        && dataset_config.layout != Layout::Image
+    {
+        return Err("image_format and image_compression need layout = \"image\"".to_string());
+    }
+    if dataset_config.compression.is_some() && dataset_config.image_compression.is_some() {
+        return Err("Give compression or image_compression, not both".to_string());
+    }
+    if dataset_config.metadata_sidecar
+        && (dataset_config.layout != Layout::Mirror
+            || dataset_config.encryption.encrypt.is_some()
+            || dataset_config.zvol_mode.is_some())
+    {
+        return Err("metadata_sidecar only works with the mirror layout and no encryption".to_string());
+    }
+    if dataset_config.par2_redundancy.is_some_and(|redundancy| !(1..=100).contains(&redundancy)) {
+        return Err("par2_redundancy is a percentage from 1 to 100".to_string());
+    }
+    Ok(())
+}
+
+
+// For "skip" and "error": deal with mounted children that nothing backs up
+pub fn check(config: &Config, dataset_config: &DatasetConfig, commands: &Log) -> Result<(), String> {
+    if dataset_config.descend == Descend::Include {
+        return Ok(());
+    }
+
+    let nested = match find_nested(&dataset_config.name, commands) {
+        Ok(nested) => nested,
+        Err(e) => {
+            eprintln!("Warning: Couldn't check for datasets nested in '{}': {}", dataset_config.name, e);
+            return Ok(());
+        }
+    };
+    let unconfigured: Vec<&NestedDataset> = nested
+        .iter()
+        .filter(|child| !config.dataset.iter().any(|d| d.name == child.name))
+        .collect();
+    if unconfigured.is_empty() {
+        return Ok(());
+    }
+
+    let names: Vec<String> = unconfigured
+        .iter()
+        .map(|child| format!("'{}' (at {})", child.name, child.relative_path.display()))
+        .collect();
+    match dataset_config.descend {
+        Descend::Error => Err(format!(
+            "Nested dataset(s) {} aren't in the parent's snapshot and aren't configured; configure them or set descend",
+            names.join(", ")
+        )),
+        _ => {
+            eprintln!(
+                "Warning: Nested dataset(s) {} aren't backed up with '{}'; configure them or set descend = \"include\"",
+                names.join(", "),
+                dataset_config.name
+            );
+            Ok(())
+        }
+    }
+}
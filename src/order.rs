@@ -0,0 +1,63 @@
+use crate::{Config, Source};
+
+
+// `after` names sources that have to be backed up before this one in the same
+// run, e.g. the dataset a database dump is written to after the dump's own
+// source. Between sources free to go in either order, higher `priority` goes
+// first, then config order.
+pub fn validate(config: &Config) -> Result<(), String> {
+    let sources: Vec<Source> = config.sources().collect();
+
+    for source in &sources {
+        for name in source.after() {
+            if !sources.iter().any(|other| other.name() == name) {
+                return Err(format!("{} '{}': after names '{}', which isn't in the config", source.kind(), source.name(), name));
+            }
+            if name == source.name() {
+                return Err(format!("{} '{}': a source can't run after itself", source.kind(), source.name()));
+            }
+        }
+    }
+
+    // Ordering everything at once fails exactly when there is a cycle
+    let (_, stuck) = try_order(sources);
+    if !stuck.is_empty() {
+        let names: Vec<String> = stuck.iter().map(|source| format!("'{}'", source.name())).collect();
+        return Err(format!("Sources {} are all waiting to run after one another", names.join(", ")));
+    }
+    Ok(())
+}
+
+
+// Sources in the order to run them, and any left over waiting on each other
+fn try_order(mut pending: Vec<Source>) -> (Vec<Source>, Vec<Source>) {
+    let mut ordered: Vec<Source> = Vec::with_capacity(pending.len());
+
+    while !pending.is_empty() {
+        // Waiting only counts for sources that are part of this run
+        let ready = |source: &Source| {
+            source.after().iter().all(|name| !pending.iter().any(|other| other.name() == name))
+        };
+        let next = pending
+            .iter()
+            .enumerate()
+            .filter(|(_, source)| ready(source))
+            .max_by_key(|(index, source)| (source.priority(), std::cmp::Reverse(*index)))
+            .map(|(index, _)| index);
+
+        match next {
+            Some(index) => ordered.push(pending.remove(index)),
+            None => break,
+        }
+    }
+
+    (ordered, pending)
+}
+
+
+pub fn order(sources: Vec<Source>) -> Vec<Source> {
+    // validate turned cycles away when the config was loaded, so nothing is left over
+    let (mut ordered, stuck) = try_order(sources);
+    ordered.extend(stuck);
+    ordered
+}
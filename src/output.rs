@@ -0,0 +1,88 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+// --group-output: hold back what each parallel job prints and print it in one
+// block once the job is done, instead of prefixing each line as it comes
+static GROUP: AtomicBool = AtomicBool::new(false);
+
+// Held while a job's block is printed, so two finishing at once don't mix
+static PRINTING: Mutex<()> = Mutex::new(());
+
+
+// A source being backed up side by side with others on this thread
+struct Job {
+    prefix: String,
+    // Lines held back under --group-output, with whether each went to stderr
+    held: Vec<(bool, String)>,
+}
+
+thread_local! {
+    static JOB: RefCell<Option<Job>> = const { RefCell::new(None) };
+}
+
+
+pub fn group(group: bool) {
+    GROUP.store(group, Ordering::Relaxed);
+}
+
+
+// From here on, what this thread prints belongs to the named job
+pub fn start_job(name: &str) {
+    JOB.set(Some(Job { prefix: format!("[{}] ", name), held: Vec::new() }));
+}
+
+
+// Print the lines held back for the job, if any
+pub fn finish_job() {
+    let Some(job) = JOB.take() else {
+        return;
+    };
+    let _printing = PRINTING.lock().unwrap_or_else(|e| e.into_inner());
+    for (stderr, line) in job.held {
+        write_line(stderr, "", &line);
+    }
+}
+
+
+fn write_line(stderr: bool, prefix: &str, line: &str) {
+    // As with println!, output that can't be written is not worth failing over
+    let _ = if stderr {
+        writeln!(io::stderr().lock(), "{}{}", prefix, line)
+    } else {
+        writeln!(io::stdout().lock(), "{}{}", prefix, line)
+    };
+}
+
+
+// What println! and eprintln! do throughout the crate: print the line as it
+// is, or when part of a job, prefixed with the job's name or held back
+pub fn print(stderr: bool, args: fmt::Arguments) {
+    JOB.with_borrow_mut(|job| {
+        let Some(job) = job else {
+            write_line(stderr, "", &args.to_string());
+            return;
+        };
+        let text = args.to_string();
+        if GROUP.load(Ordering::Relaxed) {
+            job.held.extend(text.split('\n').map(|line| (stderr, line.to_string())));
+            return;
+        }
+        // Written in one go, so another job's lines can't end up in the
+        // middle of a message spanning several
+        let mut block = String::new();
+        for line in text.split('\n') {
+            block.push_str(if line.is_empty() { job.prefix.trim_end() } else { &job.prefix });
+            block.push_str(line);
+            block.push('\n');
+        }
+        let _ = if stderr {
+            io::stderr().lock().write_all(block.as_bytes())
+        } else {
+            io::stdout().lock().write_all(block.as_bytes())
+        };
+    });
+}
@@ -0,0 +1,282 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+
+// Backups keep files' owners as the numeric IDs of the host they were taken
+// on. Restoring onto a host where the same people have other IDs would hand
+// their files to someone else, so a source can say how the IDs correspond,
+// which restore applies as it copies the files back:
+//
+//   uid_map = { "1000" = "1500", "backup" = "0" }
+//   gid_map = { "100" = "users" }
+//
+// Keys are IDs or names on the backed-up host, values IDs or names on the
+// host restoring. With numeric_ids = false every backup also records the
+// names of the backed-up host's users and groups on the target, and IDs the
+// maps leave out are matched to this host's by name.
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct OwnershipConfig {
+    #[serde(default)]
+    pub uid_map: BTreeMap<String, String>,
+    #[serde(default)]
+    pub gid_map: BTreeMap<String, String>,
+    #[serde(default = "default_numeric_ids")]
+    pub numeric_ids: bool,
+}
+
+fn default_numeric_ids() -> bool {
+    true
+}
+
+impl OwnershipConfig {
+    // Whether restores change any owners at all
+    pub fn maps_ids(&self) -> bool {
+        !self.uid_map.is_empty() || !self.gid_map.is_empty() || !self.numeric_ids
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (option, map) in [("uid_map", &self.uid_map), ("gid_map", &self.gid_map)] {
+            if map.iter().any(|(from, to)| from.is_empty() || to.is_empty()) {
+                return Err(format!("{} has an empty ID or name", option));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// Names of the backed-up host's users and groups, one
+// "<uid|gid>\t<id>\t<name>" line each
+pub const ID_NAMES_FILE: &str = ".file-backup-ids";
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    User,
+    Group,
+}
+
+impl Kind {
+    fn label(self) -> &'static str {
+        match self {
+            Kind::User => "uid",
+            Kind::Group => "gid",
+        }
+    }
+
+    fn option(self) -> &'static str {
+        match self {
+            Kind::User => "uid_map",
+            Kind::Group => "gid_map",
+        }
+    }
+
+    // Accounts only in a directory service such as LDAP aren't in these, and
+    // are left to uid_map and gid_map
+    fn database(self) -> &'static str {
+        match self {
+            Kind::User => "/etc/passwd",
+            Kind::Group => "/etc/group",
+        }
+    }
+}
+
+
+// Name and ID of every account in this host's database; the ID is the
+// third field of both files
+fn local_accounts(kind: Kind) -> Vec<(String, u32)> {
+    let contents = fs::read_to_string(kind.database()).unwrap_or_default();
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((name.to_string(), id))
+        })
+        .collect()
+}
+
+
+// After a backup with numeric_ids = false, leave the names behind its IDs
+// on the target for a restore on another host to go by
+pub fn record_names(target_dir: &Path) -> Result<(), String> {
+    let mut contents = String::new();
+    for kind in [Kind::User, Kind::Group] {
+        for (name, id) in local_accounts(kind) {
+            contents.push_str(&format!("{}\t{}\t{}\n", kind.label(), id, name));
+        }
+    }
+    let path = target_dir.join(ID_NAMES_FILE);
+    let temp_path = target_dir.join(format!("{}.tmp", ID_NAMES_FILE));
+    fs::write(&temp_path, contents)
+        .and_then(|()| fs::rename(&temp_path, &path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+
+fn recorded_names(target_dir: &Path, kind: Kind) -> HashMap<u32, String> {
+    let contents = fs::read_to_string(target_dir.join(ID_NAMES_FILE)).unwrap_or_default();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                [label, id, name] if *label == kind.label() => Some((id.parse().ok()?, name.to_string())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+
+// How the IDs of a backup become this host's, by kind: backed-up ID to
+// restored ID, including the IDs that stay the same because the names match
+#[derive(Debug, Default)]
+pub struct Mapping {
+    users: BTreeMap<u32, u32>,
+    groups: BTreeMap<u32, u32>,
+    // Names recorded on the backed-up host, for the dry-run report
+    user_names: HashMap<u32, String>,
+    group_names: HashMap<u32, String>,
+}
+
+
+// One kind's IDs, backed-up to restored, and the names recorded for them
+type KindMapping = (BTreeMap<u32, u32>, HashMap<u32, String>);
+
+
+fn resolve_kind(config: &OwnershipConfig, target_dir: &Path, kind: Kind) -> Result<KindMapping, String> {
+    let recorded = recorded_names(target_dir, kind);
+    let local: HashMap<String, u32> = local_accounts(kind).into_iter().collect();
+    let mut ids = BTreeMap::new();
+
+    if !config.numeric_ids {
+        for (id, name) in &recorded {
+            if let Some(&local_id) = local.get(name) {
+                ids.insert(*id, local_id);
+            }
+        }
+    }
+
+    let map = match kind {
+        Kind::User => &config.uid_map,
+        Kind::Group => &config.gid_map,
+    };
+    for (from, to) in map {
+        let from_id = match from.parse::<u32>() {
+            Ok(id) => id,
+            Err(_) => recorded
+                .iter()
+                .find_map(|(id, name)| (name == from).then_some(*id))
+                .ok_or_else(|| {
+                    format!(
+                        "{}: '{}' isn't among the names recorded in {}; give its ID, or back up with numeric_ids = false",
+                        kind.option(),
+                        from,
+                        ID_NAMES_FILE
+                    )
+                })?,
+        };
+        let to_id = match to.parse::<u32>() {
+            Ok(id) => id,
+            Err(_) => *local
+                .get(to.as_str())
+                .ok_or_else(|| format!("{}: '{}' isn't in this host's {}", kind.option(), to, kind.database()))?,
+        };
+        ids.insert(from_id, to_id);
+    }
+
+    Ok((ids, recorded))
+}
+
+
+pub fn resolve(config: &OwnershipConfig, target_dir: &Path) -> Result<Mapping, String> {
+    if !config.maps_ids() {
+        return Ok(Mapping::default());
+    }
+    if !config.numeric_ids && !target_dir.join(ID_NAMES_FILE).is_file() {
+        eprintln!(
+            "Warning: {} has no {}, so owners can only be mapped by uid_map and gid_map",
+            target_dir.display(),
+            ID_NAMES_FILE
+        );
+    }
+    let (users, user_names) = resolve_kind(config, target_dir, Kind::User)?;
+    let (groups, group_names) = resolve_kind(config, target_dir, Kind::Group)?;
+    Ok(Mapping { users, groups, user_names, group_names })
+}
+
+
+impl Mapping {
+    // rsync --usermap and --groupmap for the IDs that change; both take
+    // numeric FROM values as IDs
+    pub fn rsync_args(&self) -> Vec<String> {
+        let pairs = |ids: &BTreeMap<u32, u32>| {
+            ids.iter()
+                .filter(|(from, to)| from != to)
+                .map(|(from, to)| format!("{}:{}", from, to))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let mut args = Vec::new();
+        for (option, ids) in [("--usermap", &self.users), ("--groupmap", &self.groups)] {
+            let pairs = pairs(ids);
+            if !pairs.is_empty() {
+                args.push(format!("{}={}", option, pairs));
+            }
+        }
+        args
+    }
+
+    // For restore --dry-run: how the owners found in `tree` would be
+    // mapped, naming the IDs nothing maps, which keep their numbers
+    pub fn report(&self, config: &OwnershipConfig, tree: &Path) -> Result<(), String> {
+        if !config.maps_ids() {
+            return Ok(());
+        }
+        let (mut uids, mut gids) = (BTreeSet::new(), BTreeSet::new());
+        let mut pending = vec![tree.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let entries = fs::read_dir(&dir).map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+            for entry in entries {
+                let entry = entry.map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+                let path = entry.path();
+                let metadata = fs::symlink_metadata(&path).map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+                uids.insert(metadata.uid());
+                gids.insert(metadata.gid());
+                let internal = dir == tree && crate::TARGET_INTERNAL_NAMES.iter().any(|name| entry.file_name() == *name);
+                if metadata.is_dir() && !internal {
+                    pending.push(path);
+                }
+            }
+        }
+
+        let mut unmapped = 0;
+        for (kind, found, ids, names) in [
+            (Kind::User, &uids, &self.users, &self.user_names),
+            (Kind::Group, &gids, &self.groups, &self.group_names),
+        ] {
+            for id in found {
+                let name = names.get(id).map(|name| format!(" ({})", name)).unwrap_or_default();
+                match ids.get(id) {
+                    Some(to) if to == id => println!("  {} {}{} stays {}", kind.label(), id, name, to),
+                    Some(to) => println!("  {} {}{} -> {}", kind.label(), id, name, to),
+                    None => {
+                        println!("  unmapped {} {}{}: kept as it is", kind.label(), id, name);
+                        unmapped += 1;
+                    }
+                }
+            }
+        }
+        if unmapped > 0 {
+            println!("{} ID(s) aren't mapped; add them to uid_map or gid_map if they differ on this host", unmapped);
+        }
+        Ok(())
+    }
+}
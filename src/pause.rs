@@ -0,0 +1,68 @@
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::Source;
+
+
+// Sources paused with `file-backup pause`, e.g. while a disk is being
+// migrated. A paused source is skipped by runs and reported as paused by
+// status until the pause runs out or it is resumed.
+pub fn create_table(conn: &Connection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS source_pauses (
+            hostname TEXT NOT NULL,
+            source_name TEXT NOT NULL,
+            paused_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            -- NULL for a pause that lasts until the source is resumed
+            paused_until DATETIME,
+            PRIMARY KEY(hostname, source_name)
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+
+    Ok(())
+}
+
+
+pub fn pause(conn: &Connection, hostname: &str, source_name: &str, duration_secs: Option<u64>) -> Result<(), String> {
+    conn.execute(
+        "INSERT OR REPLACE INTO source_pauses (hostname, source_name, paused_until)
+         VALUES (?1, ?2, CASE WHEN ?3 IS NULL THEN NULL ELSE datetime('now', '+' || ?3 || ' seconds') END)",
+        params![hostname, source_name, duration_secs.map(|secs| secs as i64)],
+    ).map_err(|e| format!("Failed to record pause: {}", e))?;
+    Ok(())
+}
+
+
+// Returns whether there was a pause to lift
+pub fn resume(conn: &Connection, hostname: &str, source_name: &str) -> Result<bool, String> {
+    conn.execute(
+        "DELETE FROM source_pauses WHERE hostname = ?1 AND source_name = ?2",
+        [hostname, source_name],
+    )
+    .map(|deleted| deleted > 0)
+    .map_err(|e| format!("Failed to lift pause: {}", e))
+}
+
+
+// Why a source isn't being backed up at the moment, if it isn't. With no
+// hostname, a pause on any host counts.
+pub fn reason(conn: &Connection, hostname: Option<&str>, source: Source) -> Result<Option<String>, String> {
+    if !source.enabled() {
+        return Ok(Some("disabled in the config".to_string()));
+    }
+
+    let pause: Option<Option<String>> = conn.query_row(
+        "SELECT paused_until FROM source_pauses
+         WHERE source_name = ?1 AND (?2 IS NULL OR hostname = ?2)
+           AND (paused_until IS NULL OR paused_until > datetime('now'))
+         ORDER BY paused_until IS NULL DESC, paused_until DESC
+         LIMIT 1",
+        params![source.name(), hostname],
+        |row| row.get(0),
+    ).optional().map_err(|e| format!("Failed to read pauses: {}", e))?;
+
+    Ok(pause.map(|until| match until {
+        Some(until) => format!("paused until {} UTC", until),
+        None => "paused until resumed".to_string(),
+    }))
+}
@@ -0,0 +1,166 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{child_env, executed::{Log, Record}};
+
+
+// The systems file-backup runs on: OpenZFS on Linux or FreeBSD, or the ZFS
+// of illumos. They agree on zfs and zpool, less so on the rest: how mounts
+// are listed, how a FUSE mount is undone, and what the kernel offers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Os {
+    Linux,
+    FreeBsd,
+    Illumos,
+}
+
+pub const OS: Os = if cfg!(target_os = "freebsd") {
+    Os::FreeBsd
+} else if cfg!(any(target_os = "illumos", target_os = "solaris")) {
+    Os::Illumos
+} else {
+    Os::Linux
+};
+
+impl Os {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Os::Linux => "Linux",
+            Os::FreeBsd => "FreeBSD",
+            Os::Illumos => "illumos",
+        }
+    }
+}
+
+
+// For config settings built on Linux-only tools (cryptsetup, hdparm,
+// /dev/disk/by-uuid), so a config using them fails on load elsewhere
+pub fn linux_only(setting: &str) -> Result<(), String> {
+    match OS {
+        Os::Linux => Ok(()),
+        os => Err(format!("{} is only supported on Linux, not {}", setting, os.name())),
+    }
+}
+
+
+// Mounted filesystems as (device, mount point), in the order they were mounted
+pub fn mounts() -> Result<Vec<(String, PathBuf)>, String> {
+    match OS {
+        // Space separated, with the kernel's octal escapes
+        Os::Linux => {
+            let mounts = fs::read_to_string("/proc/self/mounts")
+                .map_err(|e| format!("Failed to read /proc/self/mounts: {}", e))?;
+            Ok(parse_mounts(&mounts, true))
+        }
+        // Tab separated, and nothing is escaped
+        Os::Illumos => {
+            let mounts = fs::read_to_string("/etc/mnttab")
+                .map_err(|e| format!("Failed to read /etc/mnttab: {}", e))?;
+            Ok(parse_mounts(&mounts, false))
+        }
+        // No mount table file; `mount -p` prints one in fstab form, escaped
+        // like the Linux one but lined up with tabs
+        Os::FreeBsd => {
+            let output = child_env::command("mount")
+                .arg("-p")
+                .recorded_output(&Log::default())
+                .map_err(|e| format!("Failed to execute mount -p: {}", e))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("mount -p failed: {}", stderr.trim()));
+            }
+            Ok(parse_mounts(&String::from_utf8_lossy(&output.stdout), true))
+        }
+    }
+}
+
+
+// Where fields are escaped any whitespace separates them; where they aren't,
+// only a tab does
+fn parse_mounts(mounts: &str, escaped: bool) -> Vec<(String, PathBuf)> {
+    let field = |field: &str| if escaped { unescape_mount_field(field) } else { field.to_string() };
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields: Box<dyn Iterator<Item = &str>> =
+                if escaped { Box::new(line.split_whitespace()) } else { Box::new(line.split('\t')) };
+            let (device, mount_point) = (fields.next()?, fields.next()?);
+            Some((field(device), PathBuf::from(field(mount_point))))
+        })
+        .collect()
+}
+
+
+// Space, tab, newline and backslash in mount fields are written as
+// three-digit octal escapes, e.g. "\040"
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && let Some(octal) = field.get(i + 1..i + 4)
+            && let Ok(byte) = u8::from_str_radix(octal, 8)
+        {
+            unescaped.push(byte);
+            i += 4;
+        } else {
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+
+// Whether a FUSE filesystem such as `restic mount` could be mounted. illumos
+// has no FUSE; on FreeBSD the device only exists once fusefs is loaded.
+pub fn fuse_available() -> bool {
+    match OS {
+        Os::Illumos => false,
+        Os::Linux | Os::FreeBsd => Path::new("/dev/fuse").exists(),
+    }
+}
+
+
+// Undo a FUSE mount: fusermount on Linux, where unprivileged users can't
+// umount, the plain umount elsewhere
+pub fn fuse_unmount(mount_point: &Path) -> Command {
+    match OS {
+        Os::Linux => {
+            let mut command = child_env::command("fusermount");
+            command.arg("-u").arg(mount_point);
+            command
+        }
+        Os::FreeBsd | Os::Illumos => {
+            let mut command = child_env::command("umount");
+            command.arg(mount_point);
+            command
+        }
+    }
+}
+
+
+// Flush the filesystem holding an open file to its disk. Only Linux can do
+// just the one filesystem; elsewhere everything is synced.
+pub fn sync_filesystem(file: &File) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        if unsafe { libc::syncfs(file.as_raw_fd()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        file.sync_all()?;
+        unsafe {
+            libc::sync();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,228 @@
+use rusqlite::Connection;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::executed::{Log, Record};
+use crate::{DatasetConfig, RunOptions, Source, child_env};
+
+
+// What a dataset's backup does about the state of the pool it is read from
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct PoolChecksConfig {
+    // Pool maintenance a backup shouldn't add its reads to, e.g. ["scrub", "resilver"]
+    #[serde(default)]
+    pub avoid_during: Vec<Maintenance>,
+    #[serde(default)]
+    pub during_maintenance: DuringMaintenance,
+    // A pool that isn't ONLINE (DEGRADED, FAULTED, ...). Under "fail" a
+    // DEGRADED pool is still backed up with --allow-degraded, or while its
+    // target has no backup yet that could be overwritten.
+    #[serde(default)]
+    pub unhealthy_pool: UnhealthyPool,
+}
+
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Maintenance {
+    Scrub,
+    Resilver,
+}
+
+impl Maintenance {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Maintenance::Scrub => "scrub",
+            Maintenance::Resilver => "resilver",
+        }
+    }
+}
+
+
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuringMaintenance {
+    // Leave the source for a later run; the run records it as deferred
+    #[default]
+    Defer,
+    // Back up anyway, with a warning
+    Warn,
+}
+
+
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnhealthyPool {
+    #[default]
+    Warn,
+    // Leave the target as it is rather than write to it data read from the pool
+    Fail,
+}
+
+
+// What `zpool status` says about a pool
+pub struct PoolStatus {
+    pub state: String,
+    // Scrub or resilver in progress
+    pub running: Option<Maintenance>,
+}
+
+
+pub fn pool_of(dataset: &str) -> &str {
+    dataset.split('/').next().unwrap_or(dataset)
+}
+
+
+pub fn status(pool: &str, commands: &Log) -> Result<PoolStatus, String> {
+    let output = child_env::command("zpool")
+        .args(["status", pool])
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute zpool status: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("zpool status failed: {}", stderr.trim()));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let field = |name: &str| {
+        stdout
+            .lines()
+            .find_map(|line| line.trim_start().strip_prefix(name))
+            .map(|value| value.trim().to_string())
+    };
+    let state = field("state:").ok_or_else(|| format!("zpool status didn't give the state of '{}'", pool))?;
+    let running = field("scan:").and_then(|scan| {
+        [Maintenance::Scrub, Maintenance::Resilver]
+            .into_iter()
+            .find(|maintenance| scan.starts_with(&format!("{} in progress", maintenance.as_str())))
+    });
+    Ok(PoolStatus { state, running })
+}
+
+
+// Health of the pools the run's datasets are read from, as it was when the
+// run started, for the run summary
+static RUN_HEALTH: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+
+// State of each pool `zpool status -x` lists as having problems; it leaves
+// out the healthy ones
+fn unhealthy_pools() -> Result<BTreeMap<String, String>, String> {
+    let output = child_env::command("zpool")
+        .args(["status", "-x"])
+        .recorded_output(&Log::default())
+        .map_err(|e| format!("Failed to execute zpool status: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("zpool status failed: {}", stderr.trim()));
+    }
+
+    let mut pools = BTreeMap::new();
+    let mut pool = None;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let line = line.trim_start();
+        if let Some(name) = line.strip_prefix("pool:") {
+            pool = Some(name.trim().to_string());
+        } else if let Some(state) = line.strip_prefix("state:")
+            && let Some(name) = pool.take()
+        {
+            pools.insert(name, state.trim().to_string());
+        }
+    }
+    Ok(pools)
+}
+
+
+// Before a run, note the health of the pools its datasets are read from and
+// warn about the unhealthy ones
+pub fn check_run(sources: &[Source]) {
+    let mut health = RUN_HEALTH.lock().unwrap_or_else(|e| e.into_inner());
+    health.clear();
+    let mut pools: Vec<&str> = sources
+        .iter()
+        .filter_map(|source| match source {
+            Source::Dataset(d) => Some(pool_of(&d.name)),
+            Source::Restic(_) => None,
+        })
+        .collect();
+    pools.sort();
+    pools.dedup();
+    if pools.is_empty() {
+        return;
+    }
+
+    let unhealthy = match unhealthy_pools() {
+        Ok(unhealthy) => unhealthy,
+        Err(e) => {
+            eprintln!("Warning: Couldn't check the health of the pools: {}", e);
+            return;
+        }
+    };
+    for pool in pools {
+        let state = unhealthy.get(pool).map_or("ONLINE", String::as_str);
+        if state != "ONLINE" {
+            eprintln!("Warning: Pool '{}' is {}; see zpool status {}", pool, state, pool);
+        }
+        health.insert(pool.to_string(), state.to_string());
+    }
+}
+
+
+pub fn run_health() -> BTreeMap<String, String> {
+    RUN_HEALTH.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+
+// Leaving the source for later is kept apart from failing it
+pub enum PoolError {
+    Deferred(String),
+    Failed(String),
+}
+
+
+// Before backing a dataset up, check its pool is healthy and isn't busy with
+// maintenance the backup was asked to keep out of the way of
+pub fn preflight(conn: &Connection, options: &RunOptions, dataset_config: &DatasetConfig, commands: &Log) -> Result<(), PoolError> {
+    let checks = &dataset_config.pool_checks;
+    let pool = pool_of(&dataset_config.name);
+    let status = match status(pool, commands) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("Warning: Couldn't check the state of pool '{}': {}", pool, e);
+            return Ok(());
+        }
+    };
+
+    if status.state != "ONLINE" {
+        let message = format!("Pool '{}' is {}; see zpool status {}", pool, status.state, pool);
+        // A DEGRADED pool can still be read, and a target without a backup
+        // yet has nothing good on it to lose
+        let degraded_allowed = status.state == "DEGRADED"
+            && (options.allow_degraded
+                || crate::get_last_backed_up_snapshot(conn, options.host_filter(), Source::Dataset(dataset_config), commands)
+                    .is_ok_and(|snapshot| snapshot.is_none()));
+        match checks.unhealthy_pool {
+            UnhealthyPool::Fail if !degraded_allowed => {
+                return Err(PoolError::Failed(format!(
+                    "{}, and unhealthy_pool = \"fail\" keeps {} as it is; --allow-degraded backs it up anyway",
+                    message,
+                    dataset_config.target_dir.display()
+                )));
+            }
+            // Warned about when the run started, unless it has changed since
+            _ if run_health().get(pool) == Some(&status.state) => {}
+            _ => eprintln!("Warning: {}", message),
+        }
+    }
+
+    if let Some(maintenance) = status.running.filter(|running| checks.avoid_during.contains(running)) {
+        let message = format!("Pool '{}' has a {} in progress", pool, maintenance.as_str());
+        match checks.during_maintenance {
+            DuringMaintenance::Defer => return Err(PoolError::Deferred(format!("{}; leaving the backup until it is done", message))),
+            DuringMaintenance::Warn => eprintln!("Warning: {}; backing up anyway", message),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::{Config, child_env, restic_env};
+
+
+// With run_as set for a source, the rsync and restic commands run for it,
+// whether backing it up or reading its repository, run as that user rather
+// than as root, so a target path that is compromised or points somewhere
+// unexpected can only touch what that user can. zfs commands and file state
+// bookkeeping keep running as root. Ownership of the copied files can then only be kept where the user
+// owns them.
+//
+// What reaches the target other than through rsync and restic is still
+// done as root: deleting the paths a snapshot diff says were removed or
+// replaced, moving them to the trash and purging it, symlink stubs, the
+// metadata sidecar, encrypted objects and the version manifests. Those only
+// touch paths under the target built from the snapshot and file-backup's own
+// records, and doing them as the user would take a helper process per file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    name: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+    home: PathBuf,
+}
+
+
+fn lookup(name: &str) -> Result<User, String> {
+    let c_name = CString::new(name).map_err(|_| format!("Invalid user name '{}'", name))?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let code = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if code != 0 {
+        return Err(format!("Failed to look up user '{}': {}", name, io::Error::from_raw_os_error(code)));
+    }
+    if result.is_null() {
+        return Err(format!("No user named '{}'", name));
+    }
+    let home = PathBuf::from(unsafe { CStr::from_ptr(passwd.pw_dir) }.to_string_lossy().into_owned());
+
+    // Supplementary groups are looked up here, since after fork only plain
+    // system calls are safe
+    let mut groups = vec![0 as libc::gid_t; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let found = unsafe { libc::getgrouplist(c_name.as_ptr(), passwd.pw_gid, groups.as_mut_ptr(), &mut count) };
+        if found >= 0 {
+            groups.truncate(count as usize);
+            break;
+        }
+        let needed = (count as usize).max(groups.len() * 2);
+        groups.resize(needed, 0);
+    }
+
+    Ok(User { name: name.to_string(), uid: passwd.pw_uid, gid: passwd.pw_gid, groups, home })
+}
+
+
+// Look up the sources' run_as users when the config is loaded, so a missing
+// one fails up front
+pub fn prepare(config: &mut Config) -> Result<(), String> {
+    for dataset_config in &mut config.dataset {
+        if let Some(name) = &dataset_config.run_as {
+            let user = lookup(name).map_err(|e| format!("Dataset '{}': run_as: {}", dataset_config.name, e))?;
+            dataset_config.user = Some(user);
+        }
+    }
+    for restic_config in &mut config.restic {
+        if let Some(name) = &restic_config.run_as {
+            let user = lookup(name).map_err(|e| format!("Restic repository '{}': run_as: {}", restic_config.repository, e))?;
+            restic_config.user = Some(user);
+        }
+    }
+    Ok(())
+}
+
+
+// Before a source with run_as is backed up: switching to the user takes root
+pub fn check(run_as: Option<&User>) -> Result<(), String> {
+    if let Some(user) = run_as
+        && unsafe { libc::geteuid() } != 0
+        && unsafe { libc::geteuid() } != user.uid
+    {
+        return Err(format!("run_as = \"{}\" needs file-backup to run as root", user.name));
+    }
+    Ok(())
+}
+
+
+// A rsync or restic command for a source, set to run as its run_as user if
+// it has one. Only root can switch; run by anyone else, file-backup is
+// already unprivileged and the command runs as that user instead.
+pub fn command(program: &str, run_as: Option<&User>) -> Command {
+    let mut command = child_env::command(program);
+    let Some(user) = run_as.cloned() else {
+        return command;
+    };
+    let euid = unsafe { libc::geteuid() };
+    if euid != 0 || user.uid == euid {
+        return command;
+    }
+
+    // restic keeps its cache under $HOME
+    command.env("HOME", &user.home).env("USER", &user.name).env("LOGNAME", &user.name);
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setgroups(user.groups.len() as _, user.groups.as_ptr()) != 0
+                || libc::setgid(user.gid) != 0
+                || libc::setuid(user.uid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command
+}
+
+
+// A restic command for a configured repository, with its env
+pub fn restic(repository: &str, run_as: Option<&User>) -> Command {
+    let mut command = command("restic", run_as);
+    command.arg("-r").arg(repository);
+    restic_env::apply(&mut command, repository);
+    command
+}
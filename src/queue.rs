@@ -0,0 +1,109 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+
+// Only one process backs up at a time. Backups triggered while another run
+// holds the lock are queued, one source name per line, and picked up by the
+// lock holder before it finishes.
+fn lock_path(database: &Path) -> PathBuf {
+    database.with_extension("lock")
+}
+
+fn queue_path(database: &Path) -> PathBuf {
+    database.with_extension("queue")
+}
+
+
+// Held for as long as backups are running; the flock is released on drop
+pub struct RunLock {
+    _file: File,
+}
+
+
+fn open_lock(database: &Path) -> Result<File, String> {
+    let path = lock_path(database);
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|e| format!("Failed to open lock file {}: {}", path.display(), e))
+}
+
+
+pub fn try_lock(database: &Path) -> Result<Option<RunLock>, String> {
+    let file = open_lock(database)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        Ok(Some(RunLock { _file: file }))
+    } else {
+        Ok(None)
+    }
+}
+
+
+// Wait for any other run to finish, then take the lock
+pub fn lock(database: &Path) -> Result<RunLock, String> {
+    if let Some(lock) = try_lock(database)? {
+        return Ok(lock);
+    }
+
+    println!("Waiting for another backup run to finish...");
+    let file = open_lock(database)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(format!("Failed to lock {}: {}", lock_path(database).display(), std::io::Error::last_os_error()));
+    }
+    Ok(RunLock { _file: file })
+}
+
+
+pub fn enqueue(database: &Path, source_name: &str) -> Result<(), String> {
+    let path = queue_path(database);
+    let mut queue = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("Failed to open queue {}: {}", path.display(), e))?;
+
+    // Locked so a line is never half-written when the queue is taken
+    unsafe {
+        libc::flock(queue.as_raw_fd(), libc::LOCK_EX);
+    }
+    writeln!(queue, "{}", source_name)
+        .map_err(|e| format!("Failed to write queue {}: {}", path.display(), e))
+}
+
+
+pub fn has_entries(database: &Path) -> bool {
+    fs::metadata(queue_path(database)).is_ok_and(|m| m.len() > 0)
+}
+
+
+// Remove and return the queued source names, in order and without repeats.
+// Only call this while holding the run lock.
+pub fn take(_lock: &RunLock, database: &Path) -> Result<Vec<String>, String> {
+    let path = queue_path(database);
+    let mut queue = match OpenOptions::new().read(true).write(true).open(&path) {
+        Ok(queue) => queue,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to open queue {}: {}", path.display(), e)),
+    };
+    unsafe {
+        libc::flock(queue.as_raw_fd(), libc::LOCK_EX);
+    }
+
+    let mut contents = String::new();
+    queue.read_to_string(&mut contents)
+        .map_err(|e| format!("Failed to read queue {}: {}", path.display(), e))?;
+    queue.set_len(0)
+        .map_err(|e| format!("Failed to clear queue {}: {}", path.display(), e))?;
+
+    let mut names: Vec<String> = Vec::new();
+    for name in contents.lines().filter(|line| !line.is_empty()) {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
@@ -0,0 +1,81 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::process::{Command, Stdio};
+use std::time::{Instant, SystemTime};
+
+use crate::auto_snapshot::SnapshotTemplate;
+use crate::executed::{Log, Record};
+use crate::{child_env, zfs_allow};
+
+
+// quiesce = { command = "...", thaw = "..." }: shell commands run just before
+// and just after auto_snapshot snapshots the dataset, so a database or VM on
+// it can flush and hold its writes, making the snapshot consistent
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct QuiesceConfig {
+    pub command: String,
+    pub thaw: String,
+}
+
+
+// Runs the thaw command when dropped, whether or not the snapshot worked
+struct Frozen<'a> {
+    thaw: &'a str,
+    since: Instant,
+}
+
+impl Drop for Frozen<'_> {
+    fn drop(&mut self) {
+        match run_hook(self.thaw) {
+            Ok(()) => println!("Thawed after {:.1}s frozen", self.since.elapsed().as_secs_f64()),
+            Err(e) => eprintln!("Warning: thaw command failed after {:.1}s frozen: {}", self.since.elapsed().as_secs_f64(), e),
+        }
+    }
+}
+
+
+fn run_hook(command: &str) -> Result<(), String> {
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .stdout(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to execute '{}': {}", command, e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("'{}' exited with {}: {}", command, output.status, stderr.trim()));
+    }
+    Ok(())
+}
+
+
+// Snapshot the dataset for this backup, quiescing it around the snapshot when
+// configured. Returns the snapshot's name.
+pub fn take_snapshot(dataset: &str, template: &SnapshotTemplate, quiesce: Option<&QuiesceConfig>, commands: &Log) -> Result<String, String> {
+    let snapshot = format!("{}@{}", dataset, template.format(SystemTime::now()));
+
+    // Thawing is set up before freezing, so a freeze command that fails
+    // half-way still gets undone
+    let _frozen = match quiesce {
+        Some(quiesce) => {
+            println!("Quiescing '{}'...", dataset);
+            let frozen = Frozen { thaw: &quiesce.thaw, since: Instant::now() };
+            run_hook(&quiesce.command).map_err(|e| format!("Quiesce command failed: {}", e))?;
+            Some(frozen)
+        }
+        None => None,
+    };
+
+    let output = child_env::command("zfs")
+        .args(["snapshot", &snapshot])
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute zfs snapshot: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("exists") {
+            return Err(format!("Snapshot {} exists already; snapshot_template needs %S to take more than one a minute", snapshot));
+        }
+        return Err(zfs_allow::failure("snapshot", "snapshot", &snapshot, &stderr));
+    }
+    println!("Took snapshot {}", snapshot);
+    Ok(snapshot)
+}
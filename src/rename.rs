@@ -0,0 +1,199 @@
+use rusqlite::{Connection, params};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::executed::{Log, Record};
+use crate::{RunOptions, child_env, db_export, estimate, migrate, queue, versioned};
+
+
+// Tables keyed by source name, whether each has a backup_type column, and
+// the column holding snapshot names, which for a dataset start with its name.
+// File state and the like aren't kept per host, so a rename covers every host
+// sharing the database.
+const TABLES: [(&str, bool, Option<&str>); 10] = [
+    ("backup_history", true, Some("snapshot_name")),
+    ("file_state", true, Some("last_snapshot")),
+    ("encrypted_files", true, None),
+    ("transfer_stats", true, None),
+    ("run_sources", true, None),
+    ("full_resyncs", true, None),
+    ("executed_commands", true, None),
+    ("source_pauses", false, None),
+    ("zvol_backups", false, Some("snapshot_name")),
+    ("restic_stats", false, None),
+];
+
+
+fn backup_types(conn: &Connection, source_name: &str) -> Result<Vec<String>, String> {
+    let mut stmt = conn
+        .prepare("SELECT DISTINCT backup_type FROM backup_history WHERE source_name = ?1")
+        .map_err(|e| format!("Failed to read backup history: {}", e))?;
+    stmt.query_map([source_name], |row| row.get(0))
+        .and_then(|rows| rows.collect())
+        .map_err(|e| format!("Failed to read backup history: {}", e))
+}
+
+
+// Carry the source's history over to its new name in one transaction, and
+// return the targets it was backed up to
+fn rename_history(conn: &Connection, backup_type: &str, old: &str, new: &str) -> Result<Vec<PathBuf>, String> {
+    let tx = conn
+        .unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    let mut renamed = 0;
+    for (table, has_type, snapshot_column) in TABLES {
+        // Matched with substr rather than LIKE, which would read _ and % in
+        // dataset names as wildcards
+        let snapshots = match snapshot_column {
+            Some(column) if backup_type == "dataset" => format!(
+                ", {column} = CASE WHEN substr({column}, 1, length(?1) + 1) = ?1 || '@'
+                 THEN ?2 || substr({column}, length(?1) + 1) ELSE {column} END"
+            ),
+            _ => String::new(),
+        };
+        let sql = format!("UPDATE {table} SET source_name = ?2{snapshots} WHERE source_name = ?1");
+        let changed = if has_type {
+            tx.execute(&format!("{sql} AND backup_type = ?3"), params![old, new, backup_type])
+        } else {
+            tx.execute(&sql, params![old, new])
+        };
+        renamed += changed.map_err(|e| format!("Failed to rename '{}' in {}: {}", old, table, e))?;
+    }
+
+    let targets: Vec<PathBuf> = {
+        let mut stmt = tx
+            .prepare("SELECT DISTINCT target_dir FROM backup_history WHERE backup_type = ?1 AND source_name = ?2")
+            .map_err(|e| format!("Failed to read backup history: {}", e))?;
+        stmt.query_map([backup_type, new], |row| row.get::<_, String>(0))
+            .and_then(|rows| rows.map(|row| row.map(PathBuf::from)).collect::<Result<_, _>>())
+            .map_err(|e| format!("Failed to read backup history: {}", e))?
+    };
+    tx.commit().map_err(|e| format!("Failed to commit: {}", e))?;
+
+    println!("Renamed {} database row(s) from '{}' to '{}'", renamed, old, new);
+    Ok(targets)
+}
+
+
+// Bring the state files on the source's targets up to date with the database
+fn rewrite_targets(backup_type: &str, old: &str, new: &str, conn: &Connection, targets: &[PathBuf]) {
+    for target_dir in targets {
+        if !target_dir.is_dir() {
+            eprintln!("Warning: {} isn't there to update; its state file still names '{}'", target_dir.display(), old);
+            continue;
+        }
+        if let Err(e) = db_export::write_target_state(conn, target_dir) {
+            eprintln!("Warning: {}", e);
+        }
+        if backup_type == "dataset"
+            && let Err(e) = versioned::rename_dataset(target_dir, old, new)
+        {
+            eprintln!("Warning: {}", e);
+        }
+    }
+}
+
+
+// After a dataset or restic repository is renamed or moved, carry its backups
+// on under the new name instead of starting again from a full copy
+pub fn rename_source(config_path: &Path, conn: &Connection, options: &RunOptions, old: &str, new: &str) -> Result<(), String> {
+    let backup_type = match backup_types(conn, old)?.as_slice() {
+        [] => return Err(format!("No backups of '{}' in the database", old)),
+        [backup_type] => backup_type.clone(),
+        _ => return Err(format!("'{}' has been backed up both as a dataset and as a restic repository", old)),
+    };
+    if !backup_types(conn, new)?.is_empty() {
+        return Err(format!("'{}' has backups of its own already", new));
+    }
+    println!("=== Renaming '{}' to '{}' ===", old, new);
+
+    let _lock = queue::lock(&options.database)?;
+
+    let targets = rename_history(conn, &backup_type, old, new)?;
+    rewrite_targets(&backup_type, old, new, conn, &targets);
+
+    if migrate::replace_in_config(config_path, old, new)? {
+        println!("Renamed '{}' to '{}' in {}", old, new, config_path.display());
+    } else {
+        println!("Change '{}' to '{}' in {} if it isn't already", old, new, config_path.display());
+    }
+    Ok(())
+}
+
+
+// Guid of each of the dataset's snapshots
+fn snapshot_guids(dataset: &str, commands: &Log) -> Result<HashMap<String, String>, String> {
+    let output = child_env::command("zfs")
+        .args(["list", "-H", "-t", "snapshot", "-d", "1", "-o", "guid,name", dataset])
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute zfs list: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("zfs list failed: {}", stderr.trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(guid, name)| (guid.to_string(), name.to_string()))
+        .collect())
+}
+
+
+fn dataset_exists(dataset: &str, commands: &Log) -> bool {
+    child_env::command("zfs")
+        .args(["list", "-H", "-o", "name", dataset])
+        .recorded_output(commands)
+        .is_ok_and(|output| output.status.success())
+}
+
+
+// Before a dataset's first full copy, look for a snapshot of it that was
+// backed up before under another name: renaming a dataset keeps the guids of
+// its snapshots. When someone is at the terminal offer to carry on from
+// there; returns whether the history was renamed.
+pub fn detect(conn: &Connection, options: &RunOptions, dataset: &str, commands: &Log) -> Result<bool, String> {
+    let guids = match snapshot_guids(dataset, commands) {
+        Ok(guids) => guids,
+        Err(e) => {
+            eprintln!("Warning: Can't check whether '{}' was renamed: {}", dataset, e);
+            return Ok(false);
+        }
+    };
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT source_name, snapshot_guid FROM backup_history
+             WHERE backup_type = 'dataset' AND source_name != ?1 AND snapshot_guid IS NOT NULL
+               AND (?2 IS NULL OR hostname = ?2)
+             ORDER BY backed_up_epoch DESC, id DESC",
+        )
+        .map_err(|e| format!("Failed to read backup history: {}", e))?;
+    let backed_up: Vec<(String, String)> = stmt
+        .query_map(params![dataset, options.host_filter()], |row| Ok((row.get(0)?, row.get(1)?)))
+        .and_then(|rows| rows.collect())
+        .map_err(|e| format!("Failed to read backup history: {}", e))?;
+
+    let Some((old, snapshot)) = backed_up
+        .into_iter()
+        .find_map(|(old, guid)| {
+            // Snapshots sent to another dataset keep their guids as well
+            guids.get(&guid).filter(|_| !dataset_exists(&old, commands)).map(|snapshot| (old, snapshot.clone()))
+        })
+    else {
+        return Ok(false);
+    };
+
+    println!("'{}' looks like '{}' renamed: {} was backed up before under the old name", dataset, old, snapshot);
+    if !options.confirm_first_backup {
+        println!("Run `file-backup rename-source {} {}` to carry on its backups instead of copying it afresh", old, dataset);
+        return Ok(false);
+    }
+    if !estimate::ask(&format!("Carry on the backups of '{}' as '{}'?", old, dataset))? {
+        return Ok(false);
+    }
+
+    let targets = rename_history(conn, "dataset", &old, dataset)?;
+    rewrite_targets("dataset", &old, dataset, conn, &targets);
+    Ok(true)
+}
+
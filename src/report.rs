@@ -0,0 +1,362 @@
+use rusqlite::{Connection, OptionalExtension, params};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{Config, Source};
+
+
+// [report] section: when html is set, the report is rewritten after every run
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ReportConfig {
+    pub html: Option<PathBuf>,
+    #[serde(default = "default_last_runs")]
+    pub last: usize,
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        ReportConfig {
+            html: None,
+            last: default_last_runs(),
+        }
+    }
+}
+
+fn default_last_runs() -> usize {
+    20
+}
+
+
+// What is known about the latest backup of one source
+pub struct SourceFreshness {
+    pub kind: &'static str,
+    pub name: String,
+    pub target_dir: String,
+    pub last_snapshot: Option<String>,
+    pub last_backup: Option<String>,
+    pub age_secs: Option<i64>,
+    // Totals of the recorded file state, i.e. what is on the target
+    pub size_bytes: u64,
+    pub file_count: u64,
+    pub last_status: Option<String>,
+    pub last_duration_secs: Option<i64>,
+}
+
+
+pub fn source_freshness(conn: &Connection, hostname: Option<&str>, source: Source) -> Result<SourceFreshness, String> {
+    let last: Option<(String, String, Option<i64>)> = conn.query_row(
+        "SELECT snapshot_name, backup_timestamp,
+                CAST(strftime('%s', 'now') AS INTEGER) - backed_up_epoch
+         FROM backup_history
+         WHERE backup_type = ?1 AND source_name = ?2 AND (?3 IS NULL OR hostname = ?3)
+         ORDER BY backed_up_epoch DESC, id DESC LIMIT 1",
+        params![source.backup_type(), source.name(), hostname],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ).optional().map_err(|e| format!("Failed to read backup history: {}", e))?;
+
+    let (size_bytes, file_count): (i64, i64) = conn.query_row(
+        "SELECT COALESCE(SUM(size), 0), COUNT(*) FROM file_state WHERE backup_type = ?1 AND source_name = ?2",
+        params![source.backup_type(), source.name()],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).map_err(|e| format!("Failed to read file state: {}", e))?;
+
+    let last_attempt: Option<(String, i64)> = conn.query_row(
+        "SELECT run_sources.status, run_sources.duration_secs FROM run_sources JOIN runs ON runs.id = run_sources.run_id
+         WHERE backup_type = ?1 AND source_name = ?2 AND (?3 IS NULL OR runs.hostname = ?3)
+         ORDER BY run_sources.run_id DESC LIMIT 1",
+        params![source.backup_type(), source.name(), hostname],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional().map_err(|e| format!("Failed to read run results: {}", e))?;
+    let (last_status, last_duration_secs) = last_attempt.unzip();
+
+    let (last_snapshot, last_backup, age_secs) = match last {
+        Some((snapshot, timestamp, age)) => (Some(snapshot), Some(timestamp), age),
+        None => (None, None, None),
+    };
+
+    Ok(SourceFreshness {
+        kind: source.kind(),
+        name: source.name().to_string(),
+        target_dir: source.target_dir().display().to_string(),
+        last_snapshot,
+        last_backup,
+        age_secs,
+        size_bytes: size_bytes as u64,
+        file_count: file_count as u64,
+        last_status,
+        last_duration_secs,
+    })
+}
+
+
+struct RunResult {
+    id: i64,
+    hostname: Option<String>,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    sources: Vec<(String, String, Option<String>, i64)>,
+    // Sources rsync skipped paths for: (name, exit code, one message per line)
+    skipped: Vec<(String, i64, String)>,
+}
+
+
+fn recent_runs(conn: &Connection, last: usize) -> Result<Vec<RunResult>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT id, hostname, started_at, finished_at FROM runs ORDER BY id DESC LIMIT ?1"
+    ).map_err(|e| format!("Failed to read runs: {}", e))?;
+    let mut runs = stmt.query_map([last as i64], |row| {
+        Ok(RunResult {
+            id: row.get(0)?,
+            hostname: row.get(1)?,
+            started_at: row.get(2)?,
+            finished_at: row.get(3)?,
+            sources: Vec::new(),
+            skipped: Vec::new(),
+        })
+    })
+    .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+    .map_err(|e| format!("Failed to read runs: {}", e))?;
+
+    let mut stmt = conn.prepare(
+        "SELECT source_name, status, error, duration_secs FROM run_sources WHERE run_id = ?1 ORDER BY rowid"
+    ).map_err(|e| format!("Failed to read run results: {}", e))?;
+    for run in &mut runs {
+        run.sources = stmt.query_map([run.id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Failed to read run results: {}", e))?;
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT source_name, rsync_exit_code, skipped_files FROM run_sources
+         WHERE run_id = ?1 AND skipped_files IS NOT NULL ORDER BY rowid"
+    ).map_err(|e| format!("Failed to read run results: {}", e))?;
+    for run in &mut runs {
+        run.skipped = stmt.query_map([run.id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Failed to read run results: {}", e))?;
+    }
+
+    Ok(runs)
+}
+
+
+struct Attempt {
+    started_at: Option<String>,
+    backup_type: String,
+    name: String,
+    status: String,
+    duration_secs: i64,
+    error: Option<String>,
+    job_id: Option<String>,
+}
+
+
+// Print the most recent backup attempts, newest first, with what went wrong
+pub fn print_history(conn: &Connection, hostname: Option<&str>, source: Option<&str>, last: usize) -> Result<(), String> {
+    let mut stmt = conn.prepare(
+        "SELECT runs.started_at, run_sources.backup_type, run_sources.source_name, run_sources.status,
+                run_sources.duration_secs, run_sources.error, run_sources.job_id
+         FROM run_sources JOIN runs ON runs.id = run_sources.run_id
+         WHERE (?1 IS NULL OR run_sources.source_name = ?1) AND (?2 IS NULL OR runs.hostname = ?2)
+         ORDER BY run_sources.run_id DESC, run_sources.rowid DESC LIMIT ?3"
+    ).map_err(|e| format!("Failed to read run results: {}", e))?;
+    let attempts: Vec<Attempt> = stmt
+        .query_map(params![source, hostname, last as i64], |row| {
+            Ok(Attempt {
+                started_at: row.get(0)?,
+                backup_type: row.get(1)?,
+                name: row.get(2)?,
+                status: row.get(3)?,
+                duration_secs: row.get(4)?,
+                error: row.get(5)?,
+                job_id: row.get(6)?,
+            })
+        })
+        .and_then(|rows| rows.collect())
+        .map_err(|e| format!("Failed to read run results: {}", e))?;
+
+    if attempts.is_empty() {
+        println!("No backup attempts recorded");
+        return Ok(());
+    }
+    for attempt in attempts {
+        println!(
+            "{}  {:<21} {} '{}' ({}s)",
+            attempt.started_at.as_deref().unwrap_or("-"),
+            attempt.status,
+            attempt.backup_type,
+            attempt.name,
+            attempt.duration_secs
+        );
+        if let Some(error) = attempt.error {
+            println!("    {}", error);
+            // To find the run's log and rows by
+            if let Some(job_id) = attempt.job_id {
+                println!("    job {}", job_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
+
+
+pub fn format_age(secs: i64) -> String {
+    let secs = secs.max(0);
+    match secs {
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86_400 => format!("{}h {}m", s / 3600, s / 60 % 60),
+        s => format!("{}d {}h", s / 86_400, s / 3600 % 24),
+    }
+}
+
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+
+pub fn render_html(config: &Config, conn: &Connection, hostname: Option<&str>, last: usize) -> Result<String, String> {
+    let freshness = config
+        .sources()
+        .map(|source| source_freshness(conn, hostname, source))
+        .collect::<Result<Vec<_>, _>>()?;
+    let runs = recent_runs(conn, last)?;
+
+    let mut html = String::new();
+    html.push_str(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Backup report</title>\n<style>\n\
+         body { font-family: sans-serif; margin: 2em; }\n\
+         table { border-collapse: collapse; margin-bottom: 2em; }\n\
+         th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }\n\
+         .ok { background: #dfd; } .failed, .stale-snapshot, .device-failed { background: #fdd; } .device-not-present, .locked, .skipped-device-failed, .deferred { background: #ffd; }\n\
+         </style>\n</head>\n<body>\n",
+    );
+    let _ = writeln!(html, "<h1>Backup report</h1>\n<p>Generated {}</p>", crate::clock::iso_utc(std::time::SystemTime::now()));
+
+    html.push_str("<h2>Sources</h2>\n<table>\n<tr><th>Source</th><th>Target</th><th>Last snapshot</th><th>Last backup</th><th>Age</th><th>Size</th><th>Files</th><th>Last result</th></tr>\n");
+    for source in &freshness {
+        let status = source.last_status.as_deref().unwrap_or("");
+        let _ = writeln!(
+            html,
+            "<tr><td>{} {}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td class=\"{}\">{}</td></tr>",
+            source.kind,
+            escape(&source.name),
+            escape(&source.target_dir),
+            escape(source.last_snapshot.as_deref().unwrap_or("never")),
+            escape(source.last_backup.as_deref().unwrap_or("")),
+            match (&source.last_snapshot, source.age_secs) {
+                (_, Some(age)) => format_age(age),
+                (Some(_), None) => "unknown".to_string(),
+                (None, None) => String::new(),
+            },
+            format_bytes(source.size_bytes),
+            source.file_count,
+            status,
+            status
+        );
+    }
+    html.push_str("</table>\n");
+
+    if !config.restic.is_empty() {
+        html.push_str("<h2>Restic repositories</h2>\n<table>\n<tr><th>Repository</th><th>Size</th><th>Snapshots</th><th>Since last run</th><th>Target full in</th></tr>\n");
+        for restic_config in &config.restic {
+            let Some(growth) = crate::restic_stats::growth(conn, hostname, &restic_config.repository, &restic_config.target_dir)? else {
+                continue;
+            };
+            let since_last = match growth.since_last {
+                Some(grown) if grown >= 0 => format!("+{}", format_bytes(grown as u64)),
+                Some(shrunk) => format!("-{}", format_bytes(shrunk.unsigned_abs())),
+                None => String::new(),
+            };
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&restic_config.repository),
+                format_bytes(growth.size_bytes),
+                growth.snapshot_count,
+                since_last,
+                growth.days_to_full.map(|days| format!("{:.0} days", days)).unwrap_or_default()
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("<h2>Recent runs</h2>\n<table>\n<tr><th>Started</th><th>Finished</th><th>Host</th><th>Succeeded</th><th>Failed</th></tr>\n");
+    for run in &runs {
+        let succeeded = run.sources.iter().filter(|(_, status, _, _)| status == "ok").count();
+        let _ = writeln!(
+            html,
+            "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            if succeeded == run.sources.len() { "ok" } else { "failed" },
+            escape(run.started_at.as_deref().unwrap_or("")),
+            escape(run.finished_at.as_deref().unwrap_or("unfinished")),
+            escape(run.hostname.as_deref().unwrap_or("")),
+            succeeded,
+            run.sources.len() - succeeded
+        );
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Failures</h2>\n<table>\n<tr><th>Run started</th><th>Source</th><th>Result</th><th>Error</th></tr>\n");
+    for run in &runs {
+        for (name, status, error, _) in run.sources.iter().filter(|(_, status, _, _)| status != "ok") {
+            let _ = writeln!(
+                html,
+                "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                status,
+                escape(run.started_at.as_deref().unwrap_or("")),
+                escape(name),
+                status,
+                escape(error.as_deref().unwrap_or(""))
+            );
+        }
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Skipped files</h2>\n<table>\n<tr><th>Run started</th><th>Source</th><th>rsync exit code</th><th>Skipped</th></tr>\n");
+    for run in &runs {
+        for (name, code, skipped) in &run.skipped {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(run.started_at.as_deref().unwrap_or("")),
+                escape(name),
+                code,
+                escape(skipped).replace('\n', "<br>")
+            );
+        }
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    Ok(html)
+}
+
+
+pub fn write_html(config: &Config, conn: &Connection, hostname: Option<&str>, last: usize, path: &Path) -> Result<(), String> {
+    let html = render_html(config, conn, hostname, last)?;
+
+    // Write then rename so a web server never serves a half-written page
+    let temp_path = path.with_extension("html.tmp");
+    fs::write(&temp_path, html)
+        .map_err(|e| format!("Failed to write {}: {}", temp_path.display(), e))?;
+    fs::rename(&temp_path, path)
+        .map_err(|e| format!("Failed to rename {}: {}", temp_path.display(), e))?;
+
+    println!("Wrote report to {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,243 @@
+use rusqlite::Connection;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::executed::{Log, Record};
+use crate::{Config, Layout, Source, child_env, db_export, encrypted, images, streams};
+
+
+// Everything needed to get a source back from its target on a replacement
+// machine that has neither file-backup nor its database: a restore script
+// and the dataset, pool and disk layout of the machine the backup came from
+pub const RESCUE_DIR: &str = ".file-backup-rescue";
+const RESTORE_SCRIPT: &str = "restore.sh";
+
+
+// Output of a command run to describe the system, or a note of why there is none
+fn describe(program: &str, args: &[&str]) -> String {
+    let command_line = format!("{} {}", program, args.join(" "));
+    match child_env::command(program).args(args).output() {
+        Ok(output) if output.status.success() => {
+            format!("# {}\n{}", command_line, String::from_utf8_lossy(&output.stdout))
+        }
+        Ok(output) => format!("# {} failed: {}\n", command_line, String::from_utf8_lossy(&output.stderr).trim()),
+        Err(e) => format!("# {} couldn't be run: {}\n", command_line, e),
+    }
+}
+
+
+// Quote a string for a shell script
+pub fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+
+// `zfs create` for the dataset with the properties set on it locally, to run
+// before restoring into it
+fn create_command(dataset: &str) -> Result<String, String> {
+    let output = child_env::command("zfs")
+        .args(["get", "-Hp", "-s", "local", "-o", "property,value", "all", dataset])
+        .recorded_output(&Log::default())
+        .map_err(|e| format!("Failed to execute zfs get: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("zfs get failed: {}", stderr.trim()));
+    }
+
+    let mut command = String::from("zfs create -p");
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some((property, value)) = line.split_once('\t') {
+            command.push_str(&format!(" \\\n    -o {}={}", property, quote(value)));
+        }
+    }
+    command.push_str(&format!(" \\\n    {}\n", quote(dataset)));
+    Ok(command)
+}
+
+
+// The part of the script that copies the data back, which depends on how the
+// target holds it
+fn restore_steps(source: Source) -> String {
+    let excludes = crate::target_internal_excludes()
+        .iter()
+        .map(|exclude| quote(exclude))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if let Source::Dataset(dataset_config) = source
+        && let Some(mode) = dataset_config.zvol_mode
+    {
+        let file_name = dataset_config.name.replace('/', "_");
+        return match mode {
+            crate::zvol::ZvolMode::Send => format!(
+                "# The volume is a zfs send stream in the restic repository that is this target\n\
+                 restic -r \"$TARGET\" dump latest /{}.zfs | zfs receive \"${{1:-{}}}\"\n",
+                file_name, dataset_config.name
+            ),
+            crate::zvol::ZvolMode::Device => format!(
+                "# The volume is a raw image in the restic repository that is this target;\n\
+                 # create a volume at least as large first (see dataset-properties.txt)\n\
+                 restic -r \"$TARGET\" dump latest /{}.img | dd of=\"/dev/zvol/${{1:-{}}}\" bs=1M\n",
+                file_name, dataset_config.name
+            ),
+        };
+    }
+
+    if source.encryption().encrypt.is_some() {
+        return format!(
+            "# Files are age-encrypted under {objects}/ and listed in the encrypted\n\
+             # manifest; this needs age, jq and the identity the backup was made for.\n\
+             # Bytes of names that aren't UTF-8 are escaped in it as \\xHH and\n\
+             # backslashes doubled, which printf %b undoes. Objects are decompressed\n\
+             # with what their entry's compression names, zstd or lz4 being needed then\n\
+             IDENTITY=${{IDENTITY:?set IDENTITY to the age identity file}}\n\
+             MANIFEST=$(mktemp)\n\
+             trap 'rm -f \"$MANIFEST\"' EXIT\n\
+             age -d -i \"$IDENTITY\" \"$TARGET/{manifest}\" > \"$MANIFEST\"\n\
+             jq -j '.entries[] | select(.kind == \"dir\") | .path, \"\\u0000\"' \"$MANIFEST\" |\n\
+             while IFS= read -r -d '' path; do printf -v path '%b' \"$path\"; mkdir -p \"$DEST/$path\"; done\n\
+             jq -j '.entries[] | select(.kind == \"symlink\") | .path, \"\\u0000\", .target, \"\\u0000\"' \"$MANIFEST\" |\n\
+             while IFS= read -r -d '' path && IFS= read -r -d '' link; do\n\
+             \x20   printf -v path '%b' \"$path\"; printf -v link '%b' \"$link\"\n\
+             \x20   ln -s \"$link\" \"$DEST/$path\"\n\
+             done\n\
+             jq -j '.entries[] | select(.kind == \"file\") | .path, \"\\u0000\", .object, \"\\u0000\", (.mode % 4096 | tostring), \"\\u0000\", (.compression // \"none\"), \"\\u0000\"' \"$MANIFEST\" |\n\
+             while IFS= read -r -d '' path && IFS= read -r -d '' object && IFS= read -r -d '' mode && IFS= read -r -d '' compression; do\n\
+             \x20   printf -v path '%b' \"$path\"\n\
+             \x20   age -d -i \"$IDENTITY\" \"$TARGET/{objects}/${{object:0:2}}/$object.age\" |\n\
+             \x20   case \"$compression\" in\n\
+             \x20       zstd*) zstd -q -d -c ;;\n\
+             \x20       lz4) lz4 -q -d -c ;;\n\
+             \x20       *) cat ;;\n\
+             \x20   esac > \"$DEST/$path\"\n\
+             \x20   chmod \"$(printf '%o' \"$mode\")\" \"$DEST/$path\"\n\
+             done\n",
+            objects = encrypted::OBJECTS_DIR,
+            manifest = encrypted::MANIFEST_FILE,
+        );
+    }
+
+    match source.layout() {
+        Layout::Mirror => format!("rsync -aAXH {} \"$TARGET/\" \"$DEST/\"\n", excludes),
+        Layout::Versioned => "# Each backup is a complete tree in a dated directory; take the newest,\n\
+             # or set VERSION to an older one\n\
+             VERSION=${VERSION:-$(ls -1 \"$TARGET\" | grep -E '^[0-9]{8}-[0-9]{6}$' | tail -n 1)}\n\
+             rsync -aAXH \"$TARGET/$VERSION/\" \"$DEST/\"\n"
+            .to_string(),
+        Layout::Stream => format!(
+            "# Streams have to be received in the order {index} lists them, the\n\
+             # first into a dataset that doesn't exist yet, decompressed with what\n\
+             # the index's fifth column names\n\
+             DATASET=${{1:-{name}}}\n\
+             TAB=$(printf '\\t')\n\
+             while IFS=\"$TAB\" read -r file from to size compression; do\n\
+             \x20   if [ -f \"$TARGET/$file.par2\" ]; then par2 verify -q \"$TARGET/$file.par2\"; fi\n\
+             \x20   case \"$compression\" in\n\
+             \x20       zstd*) zstd -q -d -c \"$TARGET/$file\" ;;\n\
+             \x20       lz4) lz4 -q -d -c \"$TARGET/$file\" ;;\n\
+             \x20       *) cat \"$TARGET/$file\" ;;\n\
+             \x20   esac | zfs receive -F \"$DATASET\"\n\
+             done < \"$TARGET/{index}\"\n",
+            index = streams::STREAM_INDEX,
+            name = source.name(),
+        ),
+        Layout::Image => format!(
+            "# Each backup is an image of the whole snapshot, listed in {index} with\n\
+             # its snapshot; take the newest, or set IMAGE to an older one\n\
+             IMAGE=${{IMAGE:-$(tail -n 1 \"$TARGET/{index}\" | cut -f1)}}\n\
+             case \"$IMAGE\" in\n\
+             \x20   *.erofs) fsck.erofs --extract=\"$DEST\" \"$TARGET/$IMAGE\" ;;\n\
+             \x20   *) unsquashfs -f -d \"$DEST\" \"$TARGET/$IMAGE\" ;;\n\
+             esac\n",
+            index = images::IMAGE_INDEX,
+        ),
+    }
+}
+
+
+fn restore_script(source: Source) -> String {
+    let destination = match source {
+        Source::Dataset(dataset_config) if dataset_config.zvol_mode.is_none() && dataset_config.layout != Layout::Stream => {
+            "DEST=${1:?usage: restore.sh DESTINATION}\nmkdir -p \"$DEST\"\n"
+        }
+        Source::Restic(_) => "DEST=${1:?usage: restore.sh DESTINATION}\nmkdir -p \"$DEST\"\n",
+        Source::Dataset(_) => "",
+    };
+    let recreate = match source {
+        Source::Dataset(_) => "# To recreate the dataset with its original properties first, run\n# zfs-create.sh (after creating the pool, see zpool-layout.txt)\n",
+        Source::Restic(_) => "",
+    };
+
+    format!(
+        "#!/bin/bash\n\
+         # Restore {kind} '{name}' from this backup disk, without file-backup.\n\
+         # Written by file-backup make-rescue.\n\
+         set -euo pipefail\n\
+         TARGET=$(cd \"$(dirname \"$0\")/..\" && pwd)\n\
+         {recreate}{destination}\n{steps}",
+        kind = source.kind(),
+        name = source.name(),
+        recreate = recreate,
+        destination = destination,
+        steps = restore_steps(source),
+    )
+}
+
+
+fn write_file(dir: &Path, name: &str, contents: &str, executable: bool) -> Result<(), String> {
+    let path = dir.join(name);
+    fs::write(&path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    if executable {
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("Failed to set permissions on {}: {}", path.display(), e))?;
+    }
+    println!("  Wrote {}", name);
+    Ok(())
+}
+
+
+pub fn make_rescue(config: &Config, conn: &Connection, source: &str) -> Result<(), String> {
+    let source = config.find_source(source)?;
+    let target_dir = source.target_dir();
+    crate::check_target_directory(target_dir)?;
+
+    let rescue_dir = target_dir.join(RESCUE_DIR);
+    fs::create_dir_all(&rescue_dir)
+        .map_err(|e| format!("Failed to create {}: {}", rescue_dir.display(), e))?;
+    println!("Writing rescue bundle for '{}' to {}...", source.name(), rescue_dir.display());
+
+    write_file(&rescue_dir, RESTORE_SCRIPT, &restore_script(source), true)?;
+
+    if let Source::Dataset(dataset_config) = source {
+        let create = create_command(&dataset_config.name)?;
+        write_file(&rescue_dir, "zfs-create.sh", &format!("#!/bin/sh\nset -eu\n{}", create), true)?;
+        write_file(&rescue_dir, "dataset-properties.txt", &describe("zfs", &["get", "-H", "all", &dataset_config.name]), false)?;
+
+        let pool = dataset_config.name.split('/').next().unwrap_or(&dataset_config.name);
+        let layout = [
+            describe("zpool", &["status", "-P", pool]),
+            describe("zpool", &["list", "-v", "-P", pool]),
+            describe("zpool", &["get", "-H", "all", pool]),
+        ];
+        write_file(&rescue_dir, "zpool-layout.txt", &layout.join("\n"), false)?;
+    }
+    write_file(
+        &rescue_dir,
+        "partitions.txt",
+        &describe("lsblk", &["-o", "NAME,SIZE,TYPE,FSTYPE,PARTTYPE,PARTLABEL,UUID,MOUNTPOINT"]),
+        false,
+    )?;
+
+    // The source's rows, in the format `file-backup db import --json` reads
+    let mut export = db_export::read_database(conn)?;
+    export.backup_history.retain(|row| row.backup_type == source.backup_type() && row.source_name == source.name());
+    export.runs.clear();
+    export.file_state.retain(|row| row.backup_type == source.backup_type() && row.source_name == source.name());
+    let json = serde_json::to_string_pretty(&export)
+        .map_err(|e| format!("Failed to serialize database: {}", e))?;
+    write_file(&rescue_dir, "database.json", &json, false)?;
+
+    println!("Rescue bundle written; run {} on the replacement machine", rescue_dir.join(RESTORE_SCRIPT).display());
+    Ok(())
+}
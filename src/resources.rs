@@ -0,0 +1,150 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::env;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::OnceLock;
+
+
+// Set in the environment of the re-executed process so it doesn't try to
+// enter a scope again
+const IN_SCOPE_VAR: &str = "FILE_BACKUP_IN_SCOPE";
+
+
+// CPU and IO scheduling for the rsync, restic and zfs processes a backup
+// spawns. Set under [resources] for every source, and per source to override.
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct SchedulingConfig {
+    // -20 (highest priority) to 19 (lowest)
+    pub nice: Option<i32>,
+    pub io_class: Option<IoClass>,
+    // 0 (highest) to 7 (lowest), within io_class
+    pub io_priority: Option<u8>,
+}
+
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    // Only gets disk time when nothing else wants it
+    Idle,
+}
+
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct ResourcesConfig {
+    #[serde(flatten)]
+    pub scheduling: SchedulingConfig,
+    // When either is set the whole run is placed in a transient systemd scope
+    // with these limits, e.g. memory_max = "2G", io_weight = 20
+    pub memory_max: Option<String>,
+    pub io_weight: Option<u32>,
+}
+
+
+// The scheduling the process started with, restored for sources that don't
+// set their own
+fn original_scheduling() -> (i32, i32) {
+    static ORIGINAL: OnceLock<(i32, i32)> = OnceLock::new();
+    *ORIGINAL.get_or_init(|| unsafe {
+        let nice = libc::getpriority(libc::PRIO_PROCESS, 0);
+        (nice, get_ioprio())
+    })
+}
+
+
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: i32 = 13;
+
+
+// IO priorities are Linux's own; elsewhere there is none to get, and -1
+// leaves it alone
+#[cfg(target_os = "linux")]
+fn get_ioprio() -> i32 {
+    unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) as i32 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_ioprio() -> i32 {
+    -1
+}
+
+
+#[cfg(target_os = "linux")]
+fn set_ioprio(ioprio: i32) -> std::io::Result<()> {
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_ioprio(_ioprio: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "io_class is only supported on Linux"))
+}
+
+
+// Set the priority this process, and so every process it spawns from now on,
+// runs at. Lowering nice again needs root, so that only warns on failure.
+pub fn apply(global: &SchedulingConfig, source: &SchedulingConfig) {
+    let (original_nice, original_ioprio) = original_scheduling();
+
+    let nice = source.nice.or(global.nice).unwrap_or(original_nice);
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        eprintln!("Warning: Failed to set nice {}: {}", nice, std::io::Error::last_os_error());
+    }
+
+    let ioprio = match source.io_class.or(global.io_class) {
+        Some(class) => {
+            let class_value = match class {
+                IoClass::Realtime => 1,
+                IoClass::BestEffort => 2,
+                IoClass::Idle => 3,
+            };
+            let level = source.io_priority.or(global.io_priority).unwrap_or(4).min(7) as i32;
+            (class_value << IOPRIO_CLASS_SHIFT) | level
+        }
+        None => original_ioprio,
+    };
+    if ioprio >= 0
+        && let Err(e) = set_ioprio(ioprio)
+    {
+        eprintln!("Warning: Failed to set IO priority: {}", e);
+    }
+}
+
+
+// Re-run this command inside a transient systemd scope carrying the
+// configured memory and IO limits. Only returns if that isn't needed or
+// systemd-run couldn't be started.
+pub fn enter_scope(resources: &ResourcesConfig) {
+    if resources.memory_max.is_none() && resources.io_weight.is_none() {
+        return;
+    }
+    if env::var_os(IN_SCOPE_VAR).is_some() {
+        return;
+    }
+
+    let mut args = env::args_os();
+    let Some(program) = args.next() else {
+        return;
+    };
+    let program = env::current_exe().map(|p| p.into_os_string()).unwrap_or(program);
+
+    let mut command = Command::new("systemd-run");
+    command.args(["--scope", "--quiet", "--collect"]);
+    if let Some(memory_max) = &resources.memory_max {
+        command.arg(format!("--property=MemoryMax={}", memory_max));
+    }
+    if let Some(io_weight) = resources.io_weight {
+        command.arg(format!("--property=IOWeight={}", io_weight));
+    }
+    command.arg("--").arg(program).args(args).env(IN_SCOPE_VAR, "1");
+
+    println!("Entering a transient systemd scope for resource limits...");
+    let e = command.exec();
+    eprintln!("Warning: Failed to run systemd-run, continuing without resource limits: {}", e);
+}
@@ -0,0 +1,114 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::executed::{Log, Record};
+use crate::{ResticConfig, privileges, report};
+
+
+// [restic_cache] section. restic mount and restore read a lot of tree data,
+// which is slow from a cold cache; a persistent cache shared by all runs
+// (and users, with run_as) keeps it warm.
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct ResticCacheConfig {
+    // RESTIC_CACHE_DIR for every restic command [default: restic's own, under $HOME]
+    pub dir: Option<PathBuf>,
+    // Read the trees of the snapshot about to be copied before mounting or
+    // restoring it, with `restic stats`
+    #[serde(default)]
+    pub warm_up: bool,
+}
+
+
+static CONFIG: Mutex<Option<ResticCacheConfig>> = Mutex::new(None);
+
+// --no-cache
+static NO_CACHE: AtomicBool = AtomicBool::new(false);
+
+
+pub fn configure(config: &ResticCacheConfig) {
+    *CONFIG.lock().unwrap_or_else(|e| e.into_inner()) = Some(config.clone());
+}
+
+
+pub fn disable() {
+    NO_CACHE.store(true, Ordering::Relaxed);
+}
+
+
+fn config() -> ResticCacheConfig {
+    CONFIG.lock().unwrap_or_else(|e| e.into_inner()).clone().unwrap_or_default()
+}
+
+
+// Point a restic command at the configured cache, or at none
+pub fn apply(command: &mut Command) {
+    if NO_CACHE.load(Ordering::Relaxed) {
+        command.arg("--no-cache");
+    } else if let Some(dir) = config().dir {
+        command.env("RESTIC_CACHE_DIR", dir);
+    }
+}
+
+
+pub fn warm_up(restic_config: &ResticConfig, snapshot: &str, commands: &Log) {
+    if !config().warm_up || NO_CACHE.load(Ordering::Relaxed) {
+        return;
+    }
+    println!("Warming the restic cache with the trees of snapshot {}...", snapshot);
+    let result = privileges::restic(&restic_config.repository, restic_config.user.as_ref())
+        .args(["stats", "--json", snapshot])
+        .stdout(Stdio::null())
+        .recorded_output(commands);
+    match result {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            eprintln!("Warning: Failed to warm the restic cache: {}", stderr.trim());
+        }
+        Err(e) => eprintln!("Warning: Failed to warm the restic cache: {}", e),
+    }
+}
+
+
+// Where restic keeps its cache, as restic itself works it out
+fn cache_dir() -> Option<PathBuf> {
+    config()
+        .dir
+        .or_else(|| env::var_os("RESTIC_CACHE_DIR").map(PathBuf::from))
+        .or_else(|| env::var_os("XDG_CACHE_HOME").map(|dir| PathBuf::from(dir).join("restic")))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache").join("restic")))
+}
+
+
+fn directory_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => directory_size(&entry.path()),
+            Ok(file_type) if file_type.is_file() => entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+            _ => 0,
+        })
+        .sum()
+}
+
+
+// For the end of a run that used restic
+pub fn print_size() {
+    if NO_CACHE.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(dir) = cache_dir()
+        && dir.is_dir()
+    {
+        println!("Restic cache: {} in {}", report::format_bytes(directory_size(&dir)), dir.display());
+    }
+}
@@ -0,0 +1,110 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use crate::{Config, ResticConfig};
+
+
+// Variables every restic command for one repository gets, on top of
+// [environment], for what its backend needs: AWS keys for an s3: repository,
+// a password for rest:, an ssh config for sftp:. Either inline,
+//
+//   env = { AWS_ACCESS_KEY_ID = "...", AWS_SECRET_ACCESS_KEY = "..." }
+//
+// or as NAME=value lines in env_file, which keeps secrets out of a config
+// that is more widely readable. env wins where both set a variable.
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct ResticEnvConfig {
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    pub env_file: Option<PathBuf>,
+    // env_file and env together, read when the config is loaded
+    #[serde(skip)]
+    pub vars: BTreeMap<String, String>,
+}
+
+
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+
+// NAME=value lines as a shell or systemd's EnvironmentFile would have them;
+// blank lines and # comments are skipped, as are an "export " in front and
+// quotes around the value
+fn read_env_file(path: &Path) -> Result<BTreeMap<String, String>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read env_file {}: {}", path.display(), e))?;
+    let mut vars = BTreeMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(format!("env_file {} line {}: expected NAME=value", path.display(), number + 1));
+        };
+        let value = [('"', '"'), ('\'', '\'')]
+            .iter()
+            .find_map(|&(open, close)| value.strip_prefix(open)?.strip_suffix(close))
+            .unwrap_or(value);
+        vars.insert(name.trim().to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+
+// Read each repository's env_file and check the variable names. Called when
+// the config is loaded, so a missing file fails the config check rather than
+// the backend turning the repository away part way through a run.
+pub fn prepare(config: &mut Config) -> Result<(), String> {
+    for restic_config in &mut config.restic {
+        let settings = &mut restic_config.restic_env;
+        let mut vars = match &settings.env_file {
+            Some(path) => read_env_file(path).map_err(|e| format!("Restic repository '{}': {}", restic_config.repository, e))?,
+            None => BTreeMap::new(),
+        };
+        vars.extend(settings.env.iter().map(|(name, value)| (name.clone(), value.clone())));
+        if let Some(name) = vars.keys().find(|name| !valid_name(name)) {
+            return Err(format!(
+                "Restic repository '{}': '{}' isn't a valid environment variable name",
+                restic_config.repository, name
+            ));
+        }
+        if vars.contains_key("RESTIC_REPOSITORY") {
+            return Err(format!(
+                "Restic repository '{}': RESTIC_REPOSITORY in env would point restic at another repository",
+                restic_config.repository
+            ));
+        }
+        settings.vars = vars;
+    }
+    Ok(())
+}
+
+
+static REPOSITORIES: Mutex<BTreeMap<String, BTreeMap<String, String>>> = Mutex::new(BTreeMap::new());
+
+
+pub fn configure(restic: &[ResticConfig]) {
+    *REPOSITORIES.lock().unwrap_or_else(|e| e.into_inner()) = restic
+        .iter()
+        .filter(|restic_config| !restic_config.restic_env.vars.is_empty())
+        .map(|restic_config| (restic_config.repository.clone(), restic_config.restic_env.vars.clone()))
+        .collect();
+}
+
+
+// Give a restic command for the repository its variables
+pub fn apply(command: &mut Command, repository: &str) {
+    if let Some(vars) = REPOSITORIES.lock().unwrap_or_else(|e| e.into_inner()).get(repository) {
+        command.envs(vars);
+    }
+}
@@ -0,0 +1,91 @@
+use std::io;
+use std::process::{Command, Output};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::executed::{Log, Record};
+use crate::units::ConfigDuration;
+use crate::{ResticConfig, clock, privileges, report};
+
+
+const LOCKED_MESSAGE: &str = "repository is already locked";
+
+
+// Run a restic command. A run that crashed can leave an exclusive lock behind
+// that makes every later command fail; with unlock_stale_after set, locks all
+// older than that are removed and the command is run once more.
+pub fn output(restic_config: &ResticConfig, commands: &Log, build: impl Fn() -> Command) -> io::Result<Output> {
+    let output = build().recorded_output(commands)?;
+    if output.status.success() || !String::from_utf8_lossy(&output.stderr).contains(LOCKED_MESSAGE) {
+        return Ok(output);
+    }
+    let Some(ConfigDuration(max_age)) = restic_config.unlock_stale_after else {
+        return Ok(output);
+    };
+
+    match remove_stale_locks(restic_config, max_age, commands) {
+        Ok(true) => build().recorded_output(commands),
+        Ok(false) => Ok(output),
+        Err(e) => {
+            eprintln!("Warning: Failed to check the locks of restic repository {}: {}", restic_config.repository, e);
+            Ok(output)
+        }
+    }
+}
+
+
+fn restic(restic_config: &ResticConfig, args: &[&str], commands: &Log) -> Result<String, String> {
+    let output = privileges::restic(&restic_config.repository, restic_config.user.as_ref())
+        .arg("--no-lock")
+        .args(args)
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute restic: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("restic {} failed: {}", args.join(" "), stderr.trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+
+// Ages of the repository's locks, in seconds
+fn lock_ages(restic_config: &ResticConfig, commands: &Log) -> Result<Vec<(String, i64)>, String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    restic(restic_config, &["list", "locks"], commands)?
+        .lines()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            let lock: serde_json::Value = serde_json::from_str(&restic(restic_config, &["cat", "lock", id], commands)?)
+                .map_err(|e| format!("Failed to parse lock {}: {}", id, e))?;
+            let created = lock["time"]
+                .as_str()
+                .and_then(clock::epoch_from_rfc3339)
+                .ok_or_else(|| format!("Lock {} has no readable time", id))?;
+            Ok((id.to_string(), now - created))
+        })
+        .collect()
+}
+
+
+// Returns whether locks were removed
+fn remove_stale_locks(restic_config: &ResticConfig, max_age: u64, commands: &Log) -> Result<bool, String> {
+    let repository = &restic_config.repository;
+    let ages = lock_ages(restic_config, commands)?;
+    let Some(youngest) = ages.iter().map(|(_, age)| *age).min() else {
+        return Ok(false);
+    };
+    if youngest < max_age as i64 {
+        println!(
+            "Restic repository {} is locked by something from {} ago, more recent than unlock_stale_after; leaving it",
+            repository,
+            report::format_age(youngest)
+        );
+        return Ok(false);
+    }
+
+    for (id, age) in &ages {
+        println!("Removing stale lock {} from restic repository {}, created {} ago", id, repository, report::format_age(*age));
+    }
+    restic(restic_config, &["unlock", "--remove-all"], commands)?;
+    Ok(true)
+}
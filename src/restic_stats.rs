@@ -0,0 +1,164 @@
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+
+use crate::executed::{Log, Record};
+use crate::{ResticConfig, estimate, privileges, report};
+
+
+// Size of each restic repository, as `restic stats --mode raw-data` gives it,
+// taken before and after every mirror of it. A repository only grows on the
+// target as fast as its backups do, so the rate it grew at over the last
+// month says when the target disk it is mirrored to will run out of room.
+pub fn create_table(conn: &Connection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS restic_stats (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hostname TEXT NOT NULL,
+            source_name TEXT NOT NULL,
+            recorded_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            size_before INTEGER,
+            size_bytes INTEGER NOT NULL,
+            snapshot_count INTEGER NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+
+    Ok(())
+}
+
+
+pub struct RepoStats {
+    pub size_bytes: u64,
+    pub snapshot_count: u64,
+}
+
+
+pub fn measure(restic_config: &ResticConfig, commands: &Log) -> Result<RepoStats, String> {
+    let output = privileges::restic(&restic_config.repository, restic_config.user.as_ref())
+        .args(["stats", "--json", "--mode", "raw-data"])
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute restic stats: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("restic stats failed: {}", stderr.trim()));
+    }
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("Failed to parse restic stats output: {}", e))?;
+    Ok(RepoStats {
+        size_bytes: stats["total_size"].as_u64().ok_or_else(|| "restic stats didn't report total_size".to_string())?,
+        snapshot_count: stats["snapshots_count"].as_u64().unwrap_or(0),
+    })
+}
+
+
+// Measure the repository before mirroring it; a failure only costs the stats
+pub fn before(restic_config: &ResticConfig, commands: &Log) -> Option<RepoStats> {
+    measure(restic_config, commands)
+        .inspect_err(|e| eprintln!("Warning: Couldn't measure restic repository '{}': {}", restic_config.repository, e))
+        .ok()
+}
+
+
+// After a successful mirror, measure the repository again and record it
+pub fn record(conn: &Connection, hostname: &str, restic_config: &ResticConfig, before: Option<RepoStats>, commands: &Log) {
+    let after = match measure(restic_config, commands) {
+        Ok(after) => after,
+        Err(e) => {
+            eprintln!("Warning: Couldn't measure restic repository '{}': {}", restic_config.repository, e);
+            return;
+        }
+    };
+    let result = conn.execute(
+        "INSERT INTO restic_stats (hostname, source_name, size_before, size_bytes, snapshot_count) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            hostname,
+            restic_config.repository,
+            before.as_ref().map(|before| before.size_bytes as i64),
+            after.size_bytes as i64,
+            after.snapshot_count as i64
+        ],
+    );
+    if let Err(e) = result {
+        eprintln!("Warning: Failed to record restic repository stats: {}", e);
+        return;
+    }
+    // Something else backed up to it meanwhile, which this mirror may have missed
+    if let Some(before) = before.filter(|before| before.snapshot_count != after.snapshot_count) {
+        println!(
+            "Repository went from {} to {} snapshot(s) while it was mirrored",
+            before.snapshot_count, after.snapshot_count
+        );
+    }
+
+    match growth(conn, Some(hostname), &restic_config.repository, &restic_config.target_dir) {
+        Ok(Some(growth)) => println!("Repository: {}", growth.describe()),
+        Ok(None) => {}
+        Err(e) => eprintln!("Warning: {}", e),
+    }
+}
+
+
+// How a repository has grown, from the stats recorded after its mirrors
+pub struct Growth {
+    pub size_bytes: u64,
+    pub snapshot_count: u64,
+    // Since the mirror before the latest one
+    pub since_last: Option<i64>,
+    // At the rate it grew over the last 30 days, until the target is full
+    pub days_to_full: Option<f64>,
+}
+
+impl Growth {
+    pub fn describe(&self) -> String {
+        let mut description = format!("{} in {} snapshot(s)", report::format_bytes(self.size_bytes), self.snapshot_count);
+        match self.since_last {
+            Some(grown) if grown >= 0 => description.push_str(&format!(", {} more than last run", report::format_bytes(grown as u64))),
+            Some(shrunk) => description.push_str(&format!(", {} less than last run", report::format_bytes(shrunk.unsigned_abs()))),
+            None => {}
+        }
+        if let Some(days) = self.days_to_full {
+            description.push_str(&format!("; the target fills up in about {:.0} day(s) at this rate", days));
+        }
+        description
+    }
+}
+
+
+pub fn growth(conn: &Connection, hostname: Option<&str>, repository: &str, target_dir: &Path) -> Result<Option<Growth>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT size_bytes, snapshot_count, julianday(recorded_at) FROM restic_stats
+         WHERE source_name = ?1 AND (?2 IS NULL OR hostname = ?2)
+         ORDER BY id DESC LIMIT 2"
+    ).map_err(|e| format!("Failed to read restic stats: {}", e))?;
+    let latest: Vec<(i64, i64, f64)> = stmt
+        .query_map(params![repository, hostname], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .and_then(|rows| rows.collect())
+        .map_err(|e| format!("Failed to read restic stats: {}", e))?;
+    let Some(&(size_bytes, snapshot_count, latest_day)) = latest.first() else {
+        return Ok(None);
+    };
+
+    let month_ago: Option<(i64, f64)> = conn.query_row(
+        "SELECT size_bytes, julianday(recorded_at) FROM restic_stats
+         WHERE source_name = ?1 AND (?2 IS NULL OR hostname = ?2) AND recorded_at >= datetime('now', '-30 days')
+         ORDER BY id LIMIT 1",
+        params![repository, hostname],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional().map_err(|e| format!("Failed to read restic stats: {}", e))?;
+    let days_to_full = month_ago.and_then(|(then_bytes, then_day)| {
+        let per_day = (size_bytes - then_bytes) as f64 / (latest_day - then_day);
+        // An hour of history is too little to go by
+        if latest_day - then_day < 1.0 / 24.0 || per_day <= 0.0 {
+            return None;
+        }
+        let free = estimate::free_space(target_dir).ok()?;
+        Some(free as f64 / per_day)
+    });
+
+    Ok(Some(Growth {
+        size_bytes: size_bytes as u64,
+        snapshot_count: snapshot_count as u64,
+        since_last: latest.get(1).map(|&(previous, _, _)| size_bytes - previous),
+        days_to_full,
+    }))
+}
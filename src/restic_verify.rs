@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::executed::{Log, Record};
+use crate::{ResticConfig, excludes, privileges, sha256};
+
+
+const MAX_REPORTED_MISMATCHES: usize = 20;
+
+
+// A file, directory or symlink of a restic snapshot, as `restic ls --json`
+// lists it
+struct Node {
+    // As stored in the snapshot, e.g. "/home/alice/notes.txt"
+    path: String,
+    kind: String,
+    size: u64,
+}
+
+impl Node {
+    fn relative(&self) -> &str {
+        self.path.trim_start_matches('/')
+    }
+}
+
+
+fn list_snapshot(restic_config: &ResticConfig, snapshot: &str) -> Result<Vec<Node>, String> {
+    let (mut child, started) = privileges::restic(&restic_config.repository, restic_config.user.as_ref())
+        .args(["ls", "--json", snapshot])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .recorded_spawn(&Log::default())
+        .map_err(|e| format!("Failed to execute restic ls: {}", e))?;
+
+    // The snapshot itself comes first, then a line per node
+    let mut nodes = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let Ok(node) = serde_json::from_str::<serde_json::Value>(&line) else {
+                continue;
+            };
+            if node["struct_type"] != "node" {
+                continue;
+            }
+            let (Some(path), Some(kind)) = (node["path"].as_str(), node["type"].as_str()) else {
+                continue;
+            };
+            nodes.push(Node {
+                path: path.to_string(),
+                kind: kind.to_string(),
+                // Left out of the JSON when it is zero
+                size: node["size"].as_u64().unwrap_or(0),
+            });
+        }
+    }
+    let output = child.wait_with_output().map_err(|e| format!("Failed to execute restic ls: {}", e))?;
+    started.finished(Some(&output.status));
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("restic ls failed: {}", stderr.trim()));
+    }
+    Ok(nodes)
+}
+
+
+// Hash a file of the snapshot as restic dump gives it. restic checks each
+// blob it reads against the hash it is stored under, so what comes out is
+// what was backed up.
+fn hash_from_repository(restic_config: &ResticConfig, snapshot: &str, path: &str) -> Result<String, String> {
+    let (mut child, started) = privileges::restic(&restic_config.repository, restic_config.user.as_ref())
+        .args(["dump", snapshot, path])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .recorded_spawn(&Log::default())
+        .map_err(|e| format!("Failed to execute restic dump: {}", e))?;
+    let hash = child
+        .stdout
+        .take()
+        .ok_or_else(|| "Failed to read restic dump output".to_string())
+        .and_then(|stdout| sha256::hash_reader(stdout).map_err(|e| format!("Failed to read restic dump output: {}", e)));
+    let output = child.wait_with_output().map_err(|e| format!("Failed to execute restic dump: {}", e))?;
+    started.finished(Some(&output.status));
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("restic dump of {} failed: {}", path, stderr.trim()));
+    }
+    hash
+}
+
+
+// Which `count` of `total` files to compare by content: a partial shuffle,
+// seeded from /dev/urandom
+fn sample(total: usize, count: usize) -> Vec<usize> {
+    let mut seed = [0u8; 8];
+    let mut state = match File::open("/dev/urandom").and_then(|mut urandom| urandom.read_exact(&mut seed)) {
+        Ok(()) => u64::from_le_bytes(seed),
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64),
+    };
+    // splitmix64
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+
+    let mut indices: Vec<usize> = (0..total).collect();
+    let count = count.min(total);
+    for i in 0..count {
+        let j = i + (next() % (total - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices.truncate(count);
+    indices
+}
+
+
+// Paths on the target below `dir`, relative to `root`, and whether each is a
+// directory, other than the tool's own files at the top
+fn walk_target(root: &Path, dir: &Path, paths: &mut Vec<(String, bool)>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if dir == root && crate::TARGET_INTERNAL_NAMES.iter().any(|name| entry.file_name() == *name) {
+            continue;
+        }
+        let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+        paths.push((relative.to_string_lossy().into_owned(), is_dir));
+        if is_dir {
+            walk_target(root, &path, paths)?;
+        }
+    }
+    Ok(())
+}
+
+
+// Check a restic source's target against the snapshot last backed up to it
+// without mounting the repository: every path's presence, type and size from
+// `restic ls`, which only reads the snapshot's trees, and the contents of a
+// sample of `percent` of the files through restic dump. Only the sampled
+// files are read in full, on either side.
+pub fn verify(restic_config: &ResticConfig, snapshot: &str, tree: &Path, percent: f64) -> Result<(), String> {
+    println!(
+        "Verifying {} against restic snapshot {} (sizes, and contents of {}% of the files)...",
+        tree.display(),
+        snapshot,
+        percent
+    );
+    let filter = restic_config.filter.as_ref();
+
+    let nodes: Vec<Node> = list_snapshot(restic_config, snapshot)?
+        .into_iter()
+        .filter(|node| !excludes::matches(Path::new(node.relative()), node.kind == "dir", filter))
+        .collect();
+
+    let mut mismatches = Vec::new();
+    let mut matching_files = Vec::new();
+    for node in &nodes {
+        let relative = node.relative();
+        // Devices, fifos and sockets are only there with special_files
+        if relative.is_empty() || !matches!(node.kind.as_str(), "file" | "dir" | "symlink") {
+            continue;
+        }
+        let metadata = match fs::symlink_metadata(tree.join(relative)) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                mismatches.push(format!("  missing: {}", relative));
+                continue;
+            }
+        };
+        let file_type = metadata.file_type();
+        let same_kind = match node.kind.as_str() {
+            "file" => file_type.is_file(),
+            "dir" => file_type.is_dir(),
+            _ => file_type.is_symlink(),
+        };
+        if !same_kind {
+            mismatches.push(format!("  differs: {} (not a {} on the target)", relative, node.kind));
+        } else if node.kind == "file" && metadata.len() != node.size {
+            mismatches.push(format!("  differs: {} ({} bytes, {} in the snapshot)", relative, metadata.len(), node.size));
+        } else if node.kind == "file" {
+            matching_files.push(node);
+        }
+    }
+
+    let in_snapshot: HashSet<&str> = nodes.iter().map(|node| node.relative()).collect();
+    let mut on_target = Vec::new();
+    walk_target(tree, tree, &mut on_target)?;
+    let extra: Vec<&String> = on_target
+        .iter()
+        .filter(|(path, is_dir)| !in_snapshot.contains(path.as_str()) && !excludes::matches(Path::new(path), *is_dir, filter))
+        .map(|(path, _)| path)
+        .collect();
+    mismatches.extend(extra.iter().map(|path| format!("  not in snapshot: {}", path)));
+
+    let count = (matching_files.len() as f64 * percent / 100.0).ceil() as usize;
+    let sampled = sample(matching_files.len(), count);
+    println!(
+        "{} path(s) checked by size; comparing {} of {} file(s) with restic dump...",
+        nodes.len(),
+        sampled.len(),
+        matching_files.len()
+    );
+    let mut differing_contents = 0;
+    for index in sampled {
+        let node = matching_files[index];
+        let target_hash = sha256::hash_file(&tree.join(node.relative()))
+            .map_err(|e| format!("Failed to read {}: {}", tree.join(node.relative()).display(), e))?;
+        if hash_from_repository(restic_config, snapshot, &node.path)? != target_hash {
+            mismatches.push(format!("  differs: {} (contents)", node.relative()));
+            differing_contents += 1;
+        }
+    }
+
+    if mismatches.is_empty() {
+        println!("Target matches snapshot");
+        return Ok(());
+    }
+    for mismatch in mismatches.iter().take(MAX_REPORTED_MISMATCHES) {
+        println!("{}", mismatch);
+    }
+    if mismatches.len() > MAX_REPORTED_MISMATCHES {
+        println!("  ... and {} more", mismatches.len() - MAX_REPORTED_MISMATCHES);
+    }
+    Err(format!(
+        "Target does not match snapshot: {} path(s) differ ({} by contents), {} path(s) not in snapshot",
+        mismatches.len() - extra.len(),
+        differing_contents,
+        extra.len()
+    ))
+}
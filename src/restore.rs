@@ -0,0 +1,280 @@
+use rusqlite::Connection;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::executed::{Log, Record};
+use crate::symlinks::Symlinks;
+use crate::units::Percentage;
+use crate::{Config, Layout, RunOptions, Source, adopt, child_env, clock, encrypted, images, metadata, ownership, restic_verify, tools, versioned};
+
+
+// Share of a restic source's files verify reads back from the repository
+// when neither --sample nor verify_sample says
+const DEFAULT_RESTIC_SAMPLE: f64 = 1.0;
+
+
+// What part of a backup to restore, and from when
+pub struct Selection {
+    // rsync patterns relative to the root of the backup, e.g. "home/alice/**"
+    pub paths: Vec<String>,
+    // For the versioned layout, the latest version at or before this UTC time,
+    // or the version of this snapshot
+    pub as_of: Option<String>,
+    // Only list what would be restored and what it would overwrite
+    pub dry_run: bool,
+}
+
+
+// The tree a restore copies back: in the versioned layout the latest version,
+// or the latest up to `as_of`, otherwise the target itself
+fn restorable_tree(source: Source, as_of: Option<&str>) -> Result<PathBuf, String> {
+    let target_dir = source.target_dir();
+    if let Source::Dataset(dataset_config) = source
+        && dataset_config.zvol_mode.is_some()
+    {
+        return Err(format!(
+            "'{}' is stored in the restic repository {}; use restic dump to get it back",
+            dataset_config.name,
+            target_dir.display()
+        ));
+    }
+    match source.layout() {
+        Layout::Mirror if as_of.is_some() => {
+            return Err(format!("'{}' uses the mirror layout, which only holds the latest backup", source.name()));
+        }
+        Layout::Mirror => return Ok(target_dir.to_path_buf()),
+        Layout::Stream => {
+            return Err(format!(
+                "'{}' is stored as zfs send streams; receive the files listed in {} in order with zfs receive, through zstd -d or lz4 -d where the index names it, or use the rescue kit's restore.sh",
+                source.name(),
+                target_dir.join(crate::streams::STREAM_INDEX).display()
+            ));
+        }
+        Layout::Image => {
+            return Err(format!(
+                "'{}' is stored as the images listed in {}; restore unpacks one, or mount it with mount -o loop,ro",
+                source.name(),
+                target_dir.join(images::IMAGE_INDEX).display()
+            ));
+        }
+        Layout::Versioned => {}
+    }
+
+    let versions = versioned::list_versions(target_dir)?;
+    let version = match as_of {
+        Some(as_of) if let Some(version) = versioned::version_of_snapshot(target_dir, as_of)? => version,
+        Some(as_of) => {
+            let limit = clock::compact_from_iso(as_of)
+                .ok_or_else(|| format!("Invalid --as-of time '{}': expected e.g. 2024-05-01 or 2024-05-01T02:30:00", as_of))?;
+            versions
+                .into_iter()
+                .rfind(|version| *version <= limit)
+                .ok_or_else(|| format!("No version in {} as old as {}", target_dir.display(), as_of))?
+        }
+        None => versions
+            .into_iter()
+            .next_back()
+            .ok_or_else(|| format!("No versions in {}", target_dir.display()))?,
+    };
+    match versioned::read_manifest(target_dir)?.versions.get(&version) {
+        Some(entry) => println!("Restoring from version {}, snapshot {}", version, entry.snapshot),
+        None => println!("Restoring from version {}", version),
+    }
+    Ok(target_dir.join(version))
+}
+
+
+// rsync filters letting in only the selected paths: every directory is
+// walked so patterns deeper down can match, and directories left empty are
+// pruned
+fn selection_filters(paths: &[String]) -> Vec<String> {
+    if paths.is_empty() {
+        return Vec::new();
+    }
+    let mut filters = vec!["--prune-empty-dirs".to_string(), "--include=*/".to_string()];
+    for path in paths {
+        filters.push(format!("--include=/{}", path.trim_start_matches('/')));
+    }
+    filters.push("--exclude=*".to_string());
+    filters
+}
+
+
+// List what a restore would copy, told apart by whether something is already
+// at that path in the destination
+fn preview(tree: &Path, destination: &Path, filters: &[String], owners: &[String]) -> Result<(), String> {
+    let output = child_env::command("rsync")
+        .arg(tools::rsync_archive("n8"))
+        .arg("--out-format=%i %n")
+        .args(crate::target_internal_excludes())
+        .args(filters)
+        .args(owners)
+        .arg(crate::rsync_contents_arg(tree))
+        .arg(destination)
+        .recorded_output(&Log::default())
+        .map_err(|e| format!("Failed to execute rsync: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("rsync failed: {}", stderr.trim()));
+    }
+
+    let (mut restored, mut overwritten) = (0, 0);
+    for line in output.stdout.split(|&b| b == b'\n') {
+        // Same itemized format as get_diff_via_rsync; directories are left out
+        if line.len() < 13 || line[11] != b' ' || line[1] == b'd' {
+            continue;
+        }
+        let path = crate::unescape_rsync_path(&line[12..]);
+        let path = Path::new(OsStr::from_bytes(&path)).display().to_string();
+        if line[2..11].iter().all(|&b| b == b'+') {
+            println!("  restore: {}", path);
+            restored += 1;
+        } else {
+            println!("  overwrite: {}", path);
+            overwritten += 1;
+        }
+    }
+
+    println!("Would restore {} new item(s) and overwrite {} in {}", restored, overwritten, destination.display());
+    Ok(())
+}
+
+
+// Create the destination, which for a whole backup has to be empty
+fn prepare_destination(destination: &Path, selecting: bool) -> Result<(), String> {
+    fs::create_dir_all(destination)
+        .map_err(|e| format!("Failed to create {}: {}", destination.display(), e))?;
+    let not_empty = fs::read_dir(destination)
+        .map_err(|e| format!("Failed to read {}: {}", destination.display(), e))?
+        .next()
+        .is_some();
+    if not_empty && !selecting {
+        return Err(format!("{} is not empty; restore selected paths with --path, or into an empty directory", destination.display()));
+    }
+    Ok(())
+}
+
+
+// Copy a backup of a source, or the selected paths of it, out of its target
+// into `destination`. A whole backup is only restored into an empty
+// directory; selected paths may overwrite what is already there.
+pub fn restore(
+    config: &Config,
+    source: &str,
+    destination: &Path,
+    identity_file: Option<&Path>,
+    selection: &Selection,
+) -> Result<(), String> {
+    let source = config.find_source(source)?;
+    println!("=== Restoring {}: {} ===", source.kind(), source.name());
+
+    crate::check_target_directory(source.target_dir())?;
+    if source.layout() == Layout::Image {
+        if selection.dry_run {
+            return Err("--dry-run isn't supported for the image layout; list an image with unsquashfs -l".to_string());
+        }
+        let image = images::select(source.target_dir(), selection.as_of.as_deref())?;
+        prepare_destination(destination, !selection.paths.is_empty())?;
+        return images::extract(&image, destination, &selection.paths);
+    }
+    let tree = restorable_tree(source, selection.as_of.as_deref())?;
+    let filters = selection_filters(&selection.paths);
+
+    if source.encryption().encrypt.is_some() && (!filters.is_empty() || selection.dry_run) {
+        return Err("An encrypted target can only be restored in full".to_string());
+    }
+
+    let mapping = ownership::resolve(source.ownership(), source.target_dir())?;
+    if selection.dry_run {
+        preview(&tree, destination, &filters, &mapping.rsync_args())?;
+        return mapping.report(source.ownership(), &tree);
+    }
+
+    prepare_destination(destination, !filters.is_empty())?;
+
+    if source.encryption().encrypt.is_some() {
+        let identity = encrypted::identity(source.encryption(), identity_file)?;
+        return encrypted::restore(source.target_dir(), destination, &identity);
+    }
+
+    println!("Copying {} to {}...", tree.display(), destination.display());
+
+    let output = child_env::command("rsync")
+        .arg(tools::rsync_archive(""))
+        .args(crate::target_internal_excludes())
+        .args(&filters)
+        .args(mapping.rsync_args())
+        .arg(crate::rsync_contents_arg(&tree))
+        .arg(destination)
+        .recorded_output(&Log::default())
+        .map_err(|e| format!("Failed to execute rsync: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("rsync failed: {}", stderr.trim()));
+    }
+
+    // Before the attributes, which may be on the symlinks themselves
+    if source.symlinks() == Symlinks::Stub {
+        metadata::relink(source.target_dir(), destination)?;
+    }
+    if let Source::Dataset(dataset_config) = source
+        && dataset_config.metadata_sidecar
+    {
+        metadata::apply(source.target_dir(), destination)?;
+    }
+
+    println!("Restored to {}", destination.display());
+    Ok(())
+}
+
+
+// Check that a target still holds what was backed up: encrypted targets are
+// decrypted in full, others are compared with the last snapshot backed up
+pub fn verify(
+    config: &Config,
+    conn: &Connection,
+    options: &RunOptions,
+    source: &str,
+    identity_file: Option<&Path>,
+    checksum: bool,
+    sample: Option<f64>,
+) -> Result<(), String> {
+    let source = config.find_source(source)?;
+    println!("=== Verifying {}: {} ===", source.kind(), source.name());
+    if let Some(percent) = sample
+        && !(0.0..=100.0).contains(&percent)
+    {
+        return Err(format!("--sample {} is outside 0 to 100", percent));
+    }
+
+    crate::check_target_directory(source.target_dir())?;
+
+    if source.encryption().encrypt.is_some() {
+        let identity = encrypted::identity(source.encryption(), identity_file)?;
+        return encrypted::verify(source.target_dir(), &identity);
+    }
+
+    let snapshot = crate::get_last_backed_up_snapshot(conn, options.host_filter(), source, &Log::default())
+        .map_err(|e| format!("Failed to read backup history: {}", e))?
+        .ok_or_else(|| format!("No backup of '{}' to verify against", source.name()))?;
+    let tree = restorable_tree(source, None)?;
+
+    match source {
+        Source::Dataset(_) => {
+            let snapshot_mountpoint = crate::get_snapshot_mountpoint(&snapshot, &Log::default())?;
+            adopt::verify_target(&snapshot_mountpoint, &tree, checksum, source)
+        }
+        // The repository already holds a hash of every blob, so only the
+        // sample is read back rather than the whole snapshot through a mount
+        Source::Restic(restic_config) => {
+            let percent = match sample {
+                _ if checksum => 100.0,
+                Some(percent) => percent,
+                None => restic_config.verify_sample.map_or(DEFAULT_RESTIC_SAMPLE, |Percentage(percent)| percent),
+            };
+            restic_verify::verify(restic_config, &snapshot, &tree, percent)
+        }
+    }
+}
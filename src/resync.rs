@@ -0,0 +1,68 @@
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::Source;
+
+
+// Incremental backups only copy what zfs diff or the rsync dry run reports,
+// so anything that goes wrong on the target between them (a bad sector, a
+// file changed by hand) stays wrong. With full_resync_every the whole
+// snapshot is rsynced again with --checksum at that cadence, and the time of
+// the last such pass is kept here.
+pub fn create_table(conn: &Connection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS full_resyncs (
+            hostname TEXT NOT NULL,
+            backup_type TEXT NOT NULL,
+            source_name TEXT NOT NULL,
+            resynced_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY(hostname, backup_type, source_name)
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+
+    Ok(())
+}
+
+
+// Called after every full rsync of a source, the first backup included
+pub fn record(conn: &Connection, hostname: &str, source: Source) -> Result<(), String> {
+    conn.execute(
+        "INSERT OR REPLACE INTO full_resyncs (hostname, backup_type, source_name) VALUES (?1, ?2, ?3)",
+        params![hostname, source.backup_type(), source.name()],
+    ).map_err(|e| format!("Failed to record full resync: {}", e))?;
+    Ok(())
+}
+
+
+// Seconds since the last full pass over a source. Sources first backed up
+// before full passes were recorded count from their oldest backup instead.
+pub fn age_secs(conn: &Connection, hostname: Option<&str>, source: Source) -> Result<Option<i64>, String> {
+    conn.query_row(
+        "SELECT CAST(strftime('%s', 'now') AS INTEGER) - COALESCE(
+                    (SELECT CAST(strftime('%s', MAX(resynced_at)) AS INTEGER) FROM full_resyncs
+                     WHERE backup_type = ?1 AND source_name = ?2 AND (?3 IS NULL OR hostname = ?3)),
+                    (SELECT MIN(backed_up_epoch) FROM backup_history
+                     WHERE backup_type = ?1 AND source_name = ?2 AND (?3 IS NULL OR hostname = ?3))
+                )",
+        params![source.backup_type(), source.name(), hostname],
+        |row| row.get(0),
+    ).optional()
+    .map(Option::flatten)
+    .map_err(|e| format!("Failed to read full resyncs: {}", e))
+}
+
+
+// Whether this run should rsync the whole snapshot instead of applying a diff
+pub fn is_due(conn: &Connection, hostname: Option<&str>, source: Source) -> bool {
+    let Some(every) = source.full_resync_every() else {
+        return false;
+    };
+    match age_secs(conn, hostname, source) {
+        Ok(Some(age)) => age >= every.0 as i64,
+        Ok(None) => false,
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            false
+        }
+    }
+}
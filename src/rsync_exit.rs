@@ -0,0 +1,109 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::process::Output;
+
+use crate::changed_files::RecordChanges;
+
+
+// [rsync] section: exit codes that only make a backup a warning rather than a
+// failure. 24 (files vanished during the transfer) is harmless for the live
+// trees restic and versioned backups can read; 23 (partial transfer) can be
+// added where special files or unreadable paths are expected. Whatever rsync
+// skipped is listed in the run's results and in the report.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct RsyncConfig {
+    #[serde(default = "default_warn_exit_codes")]
+    pub warn_exit_codes: Vec<i32>,
+    // Keep the itemized changes of each backup in the database
+    #[serde(default)]
+    pub record_changes: Option<RecordChanges>,
+}
+
+impl Default for RsyncConfig {
+    fn default() -> Self {
+        RsyncConfig {
+            warn_exit_codes: default_warn_exit_codes(),
+            record_changes: None,
+        }
+    }
+}
+
+fn default_warn_exit_codes() -> Vec<i32> {
+    vec![24]
+}
+
+
+// What rsync reported while backing up a source, and which of its codes
+// only make that a warning
+#[derive(Debug, Default)]
+pub struct SourceExits {
+    warn_exit_codes: Vec<i32>,
+    pub exit_code: Option<i32>,
+    pub skipped: Vec<String>,
+}
+
+impl SourceExits {
+    pub fn new(config: &RsyncConfig) -> SourceExits {
+        SourceExits { warn_exit_codes: config.warn_exit_codes.clone(), ..SourceExits::default() }
+    }
+}
+
+
+// As listed in rsync(1)
+fn meaning(code: i32) -> &'static str {
+    match code {
+        1 => "syntax or usage error",
+        2 => "protocol incompatibility",
+        3 => "errors selecting input/output files or dirs",
+        5 => "error starting client-server protocol",
+        10 => "error in socket I/O",
+        11 => "error in file I/O",
+        12 => "error in rsync protocol data stream",
+        20 => "received SIGUSR1 or SIGINT",
+        22 => "error allocating memory",
+        23 => "partial transfer due to error",
+        24 => "partial transfer due to vanished source files",
+        25 => "the --max-delete limit stopped deletions",
+        30 => "timeout in data send/receive",
+        35 => "timeout waiting for daemon connection",
+        _ => "unknown error",
+    }
+}
+
+
+// The per-file messages in rsync's stderr, without the summary line at the end
+fn skipped_files(stderr: &str) -> Vec<String> {
+    stderr
+        .lines()
+        .filter(|line| line.starts_with("file has vanished") || line.starts_with("rsync: "))
+        .map(str::to_string)
+        .collect()
+}
+
+
+// Check how an rsync run ended. Codes configured as warnings are reported and
+// noted in `exits` for the run's results, and count as success.
+pub fn check(output: &Output, exits: &mut SourceExits) -> Result<(), String> {
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let Some(code) = output.status.code() else {
+        return Err(format!("rsync was killed: {}", stderr.trim()));
+    };
+
+    let skipped = skipped_files(&stderr);
+    exits.exit_code = Some(code);
+    exits.skipped.extend(skipped.iter().cloned());
+
+    if !exits.warn_exit_codes.contains(&code) {
+        return Err(format!("rsync failed with exit code {} ({}): {}", code, meaning(code), stderr.trim()));
+    }
+
+    eprintln!("Warning: rsync exited with code {} ({}), {} path(s) skipped:", code, meaning(code), skipped.len());
+    for line in &skipped {
+        eprintln!("  {}", line);
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+// Every run gets a random UUID, and every source backed up in it a job ID
+// made from it, e.g. "0f8e6c1a-5d2b-4c7e-9a31-6b0d2e4f7c95.3". Both go in
+// the run's output, its rows in the database and the run log and summary on
+// each target, so a failure seen months later in one of them can be matched
+// to the others.
+static RUN: Mutex<Option<String>> = Mutex::new(None);
+
+thread_local! {
+    static JOB: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+
+// A version 4 UUID from /dev/urandom
+fn new_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    if File::open("/dev/urandom").and_then(|mut urandom| urandom.read_exact(&mut bytes)).is_err() {
+        // Unique enough for telling runs apart
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+        bytes[..12].copy_from_slice(&nanos.to_le_bytes()[..12]);
+        bytes[12..].copy_from_slice(&std::process::id().to_le_bytes());
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+
+// Start a new run, returning its UUID
+pub fn start_run() -> String {
+    let run = new_uuid();
+    *RUN.lock().unwrap_or_else(|e| e.into_inner()) = Some(run.clone());
+    run
+}
+
+
+// The UUID of the run in progress, or of the last one
+pub fn current() -> Option<String> {
+    RUN.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+
+// From here on, this thread backs up the run's `number`th source
+pub fn start_job(run: &str, number: usize) -> String {
+    let job = format!("{}.{}", run, number);
+    JOB.set(Some(job.clone()));
+    job
+}
+
+
+pub fn finish_job() {
+    JOB.set(None);
+}
+
+
+pub fn job() -> Option<String> {
+    JOB.with_borrow(|job| job.clone())
+}
+
+
+// What to add to a source's heading to name its job, if it is part of a run
+pub fn job_label() -> String {
+    job().map(|job| format!(" (job {})", job)).unwrap_or_default()
+}
@@ -0,0 +1,279 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::fd::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::executed::ExecutedCommand;
+use crate::status::State;
+
+
+// Each run leaves "<timestamp>.log" (everything printed during the run) and
+// "<timestamp>.json" (a summary) here on every target it touched, so a disk
+// carries its own backup history
+pub const RUN_LOG_DIR: &str = ".file-backup-logs";
+
+// Runs kept in RUN_LOG_DIR; older logs are removed when a new one is written
+const KEPT_RUN_LOGS: usize = 100;
+
+
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    // The run's UUID, as in its output and the runs table
+    pub run_id: Option<String>,
+    pub hostname: String,
+    pub started_at: String,
+    pub finished_at: String,
+    // Health of the pools the datasets were read from when the run started, e.g. ONLINE or DEGRADED
+    pub pools: BTreeMap<String, String>,
+    pub sources: Vec<SourceSummary>,
+}
+
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SourceSummary {
+    // The run's UUID and the source's place in it, e.g. "<uuid>.3"
+    pub job_id: Option<String>,
+    pub kind: &'static str,
+    pub name: String,
+    pub target_dir: String,
+    pub status: SourceStatus,
+    pub error: Option<String>,
+    // What anomaly::check found unusual about a backup that did go ahead
+    pub anomalies: Vec<String>,
+    // Non-zero rsync exit code, whether it failed the backup or was only a warning
+    pub rsync_exit_code: Option<i32>,
+    pub skipped_files: Vec<String>,
+    // Devices, sockets and FIFOs left out under special_files = "skip" or "warn"
+    pub special_files_skipped: u64,
+    pub duration_secs: u64,
+    // Mount point of the target device, when it failed
+    pub failed_device: Option<String>,
+    // The zfs, rsync and restic commands its backup ran
+    pub commands: Vec<ExecutedCommand>,
+}
+
+impl SourceSummary {
+    // On the status scale, as fleet run rates a remote source: a missing
+    // disk, a locked key or a busy pool is what status would warn about, as
+    // are anomalies, while a backup that didn't happen otherwise is critical
+    pub fn state(&self) -> State {
+        match self.status {
+            SourceStatus::Ok if self.anomalies.is_empty() => State::Ok,
+            SourceStatus::Ok | SourceStatus::DeviceNotPresent | SourceStatus::Locked | SourceStatus::Deferred => State::Warn,
+            SourceStatus::Failed | SourceStatus::StaleSnapshot | SourceStatus::DeviceFailed | SourceStatus::SkippedDeviceFailed => {
+                State::Crit
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceStatus {
+    Ok,
+    Failed,
+    DeviceNotPresent,
+    // An encrypted dataset whose key isn't loaded
+    Locked,
+    // The newest snapshot is older than max_snapshot_age
+    StaleSnapshot,
+    // The target device gave out during this backup
+    DeviceFailed,
+    // Not tried, as the target device gave out earlier in the run
+    SkippedDeviceFailed,
+    // Left for a later run while the pool is busy with a scrub or resilver
+    Deferred,
+}
+
+impl SourceStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceStatus::Ok => "ok",
+            SourceStatus::Failed => "failed",
+            SourceStatus::DeviceNotPresent => "device-not-present",
+            SourceStatus::Locked => "locked",
+            SourceStatus::StaleSnapshot => "stale-snapshot",
+            SourceStatus::DeviceFailed => "device-failed",
+            SourceStatus::SkippedDeviceFailed => "skipped-device-failed",
+            SourceStatus::Deferred => "deferred",
+        }
+    }
+}
+
+
+// Where --summary sends the summary of a whole run
+pub enum SummaryOutput {
+    File(PathBuf),
+    // The original stdout, for `fleet run` to read over SSH
+    Stdout(File),
+}
+
+impl SummaryOutput {
+    // "-" is stdout, which from then on carries only the summary: everything
+    // else printed goes to stderr
+    pub fn open(path: &Path) -> Result<SummaryOutput, String> {
+        if path != Path::new("-") {
+            return Ok(SummaryOutput::File(path.to_path_buf()));
+        }
+        flush_std();
+        let saved = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if saved < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+            return Err(format!("Failed to redirect output: {}", io::Error::last_os_error()));
+        }
+        Ok(SummaryOutput::Stdout(unsafe { File::from_raw_fd(saved) }))
+    }
+
+    pub fn write(self, summary: &RunSummary) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(summary)
+            .map_err(|e| format!("Failed to serialize run summary: {}", e))?;
+        match self {
+            SummaryOutput::File(path) => {
+                fs::write(&path, json).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+            }
+            SummaryOutput::Stdout(mut stdout) => {
+                writeln!(stdout, "{}", json).map_err(|e| format!("Failed to write run summary: {}", e))
+            }
+        }
+    }
+}
+
+
+// Copies everything written to stdout and stderr (by this process and the
+// commands it runs) into a buffer, while still passing it through
+pub struct Capture {
+    // (redirected fd, duplicate of the original)
+    saved_fds: Vec<(i32, i32)>,
+    buffer: Arc<Mutex<Vec<u8>>>,
+    finished: Receiver<()>,
+}
+
+impl Capture {
+    pub fn start() -> Result<Capture, String> {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let (sender, finished) = mpsc::channel();
+        let mut saved_fds = Vec::new();
+
+        flush_std();
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            let mut pipe_fds = [0; 2];
+            let (saved, passthrough) = unsafe {
+                if libc::pipe(pipe_fds.as_mut_ptr()) != 0 {
+                    return Err(format!("Failed to create pipe: {}", io::Error::last_os_error()));
+                }
+                (libc::dup(fd), libc::dup(fd))
+            };
+            if saved < 0 || passthrough < 0 {
+                return Err(format!("Failed to duplicate output: {}", io::Error::last_os_error()));
+            }
+            unsafe {
+                libc::dup2(pipe_fds[1], fd);
+                libc::close(pipe_fds[1]);
+            }
+            saved_fds.push((fd, saved));
+
+            let mut reader = unsafe { File::from_raw_fd(pipe_fds[0]) };
+            let mut passthrough = unsafe { File::from_raw_fd(passthrough) };
+            let buffer = Arc::clone(&buffer);
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let mut chunk = [0; 8192];
+                while let Ok(count) = reader.read(&mut chunk) {
+                    if count == 0 {
+                        break;
+                    }
+                    let _ = passthrough.write_all(&chunk[..count]);
+                    if let Ok(mut buffer) = buffer.lock() {
+                        buffer.extend_from_slice(&chunk[..count]);
+                    }
+                }
+                let _ = sender.send(());
+            });
+        }
+
+        Ok(Capture { saved_fds, buffer, finished })
+    }
+
+    // Put the original stdout and stderr back and return what was captured.
+    // A background command still holding the pipe open (a restic mount that
+    // is slow to exit) only delays this by a few seconds.
+    pub fn finish(self) -> String {
+        flush_std();
+        for (fd, saved) in &self.saved_fds {
+            unsafe {
+                libc::dup2(*saved, *fd);
+                libc::close(*saved);
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        for _ in &self.saved_fds {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if self.finished.recv_timeout(remaining).is_err() {
+                break;
+            }
+        }
+
+        let buffer = self.buffer.lock().map(|b| b.clone()).unwrap_or_default();
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+
+fn flush_std() {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+}
+
+
+// Write this run's log and summary into the target's log directory, then drop
+// the oldest runs beyond KEPT_RUN_LOGS
+pub fn write(target_dir: &Path, run_name: &str, log: &str, summary: &RunSummary) -> Result<(), String> {
+    let log_dir = target_dir.join(RUN_LOG_DIR);
+    fs::create_dir_all(&log_dir)
+        .map_err(|e| format!("Failed to create {}: {}", log_dir.display(), e))?;
+
+    let json = serde_json::to_string_pretty(summary)
+        .map_err(|e| format!("Failed to serialize run summary: {}", e))?;
+
+    let log_path = log_dir.join(format!("{}.log", run_name));
+    fs::write(&log_path, log)
+        .map_err(|e| format!("Failed to write {}: {}", log_path.display(), e))?;
+    let summary_path = log_dir.join(format!("{}.json", run_name));
+    fs::write(&summary_path, json)
+        .map_err(|e| format!("Failed to write {}: {}", summary_path.display(), e))?;
+
+    rotate(&log_dir)
+}
+
+
+fn rotate(log_dir: &Path) -> Result<(), String> {
+    let entries = fs::read_dir(log_dir)
+        .map_err(|e| format!("Failed to read {}: {}", log_dir.display(), e))?;
+
+    let mut runs: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| name.strip_suffix(".json").map(str::to_string))
+        .collect();
+    runs.sort();
+
+    let expired = runs.len().saturating_sub(KEPT_RUN_LOGS);
+    for run_name in &runs[..expired] {
+        for extension in ["log", "json"] {
+            let path = log_dir.join(format!("{}.{}", run_name, extension));
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!("Warning: Failed to remove old run log {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
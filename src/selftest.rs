@@ -0,0 +1,262 @@
+use clap::ValueEnum;
+use std::env;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use crate::runlog::SourceStatus;
+use crate::{RunOptions, child_env, nested, sha256, tools};
+
+
+// `selftest`: back up throwaway data end to end, a full backup and then an
+// incremental one after files were changed, renamed and deleted, checking
+// the target against the data after each. Everything lives in a scratch
+// directory that is removed afterwards, so it is safe to run on a new host
+// before the real config exists.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Kind {
+    /// A dataset on a pool made from a file, which needs root
+    Zfs,
+    /// A restic repository, copied out with restic restore so no FUSE is needed
+    Restic,
+}
+
+
+// Removes the scratch pool and directory however the test ends
+struct Scratch {
+    dir: PathBuf,
+    pool: Option<String>,
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            println!("Destroying pool {}...", pool);
+            if let Err(e) = run(child_env::command("zpool").args(["destroy", "-f", pool]), "zpool destroy") {
+                eprintln!("Warning: {}", e);
+            }
+        }
+        println!("Removing {}...", self.dir.display());
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            eprintln!("Warning: Failed to remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+
+fn run(command: &mut Command, what: &str) -> Result<(), String> {
+    let output = command.output().map_err(|e| format!("Failed to execute {}: {}", what, e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{} failed: {}", what, stderr.trim()));
+    }
+    Ok(())
+}
+
+
+fn write(root: &Path, path: &str, contents: &[u8]) -> Result<(), String> {
+    let path = root.join(path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    fs::write(&path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+
+fn write_initial_data(root: &Path) -> Result<(), String> {
+    let large: Vec<u8> = (0..1024 * 1024u32).map(|i| (i * 7 % 251) as u8).collect();
+    write(root, "notes.txt", b"first version\n")?;
+    write(root, "data/large.bin", &large)?;
+    write(root, "data/old/report.txt", b"to be moved\n")?;
+    write(root, "data/obsolete.txt", b"to be deleted\n")?;
+    write(root, "name with spaces/caf\u{e9}.txt", b"unusual names\n")?;
+    write(root, "empty", b"")?;
+    symlink("notes.txt", root.join("link")).map_err(|e| format!("Failed to create symlink: {}", e))
+}
+
+
+// Changes for the incremental backup; returns the paths that should be gone
+fn change_data(root: &Path) -> Result<Vec<&'static str>, String> {
+    write(root, "notes.txt", b"second version, a little longer\n")?;
+    write(root, "data/new.txt", b"added later\n")?;
+    fs::rename(root.join("data/old/report.txt"), root.join("data/report.txt"))
+        .and_then(|()| fs::remove_dir(root.join("data/old")))
+        .and_then(|()| fs::remove_file(root.join("data/obsolete.txt")))
+        .map_err(|e| format!("Failed to change test data: {}", e))?;
+    Ok(vec!["data/old", "data/obsolete.txt"])
+}
+
+
+// Check that `copy` holds every file under `root` unchanged and none of
+// `gone`; returns how many files were compared
+fn verify(root: &Path, copy: &Path, gone: &[&str]) -> Result<usize, String> {
+    fn compare(root: &Path, copy: &Path, relative: &Path) -> Result<usize, String> {
+        let entries = fs::read_dir(root.join(relative))
+            .map_err(|e| format!("Failed to read {}: {}", root.join(relative).display(), e))?;
+        let mut compared = 0;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read {}: {}", root.join(relative).display(), e))?;
+            let path = relative.join(entry.file_name());
+            let (original, copied) = (root.join(&path), copy.join(&path));
+            let file_type = entry.file_type().map_err(|e| format!("Failed to read {}: {}", original.display(), e))?;
+            if file_type.is_dir() {
+                compared += compare(root, copy, &path)?;
+            } else if file_type.is_symlink() {
+                if fs::read_link(&original).ok() != fs::read_link(&copied).ok() {
+                    return Err(format!("Symlink {} wasn't copied as it is", path.display()));
+                }
+                compared += 1;
+            } else {
+                let expected = sha256::hash_file(&original).map_err(|e| format!("Failed to read {}: {}", original.display(), e))?;
+                match sha256::hash_file(&copied) {
+                    Ok(hash) if hash == expected => compared += 1,
+                    Ok(_) => return Err(format!("{} differs on the target", path.display())),
+                    Err(e) => return Err(format!("{} is missing from the target: {}", path.display(), e)),
+                }
+            }
+        }
+        Ok(compared)
+    }
+
+    let compared = compare(root, copy, Path::new(""))?;
+    for path in gone {
+        if copy.join(path).symlink_metadata().is_ok() {
+            return Err(format!("{} was removed but is still on the target", path));
+        }
+    }
+    Ok(compared)
+}
+
+
+// Make the scratch source and return the config for it, the directory the
+// data is written to and where its copy ends up on the target
+fn prepare(kind: Kind, scratch: &mut Scratch) -> Result<(String, PathBuf, PathBuf), String> {
+    let dir = scratch.dir.clone();
+    let target = dir.join("target");
+    fs::create_dir_all(&target).map_err(|e| format!("Failed to create {}: {}", target.display(), e))?;
+
+    match kind {
+        Kind::Zfs => {
+            let pool = format!("fbselftest{}", process::id());
+            let image = dir.join("pool.img");
+            fs::File::create(&image)
+                .and_then(|file| file.set_len(256 * 1024 * 1024))
+                .map_err(|e| format!("Failed to create {}: {}", image.display(), e))?;
+            println!("Creating pool {} on {}...", pool, image.display());
+            run(
+                child_env::command("zpool")
+                    .args(["create", "-O"])
+                    .arg(format!("mountpoint={}", dir.join("pool").display()))
+                    .arg(&pool)
+                    .arg(&image),
+                "zpool create",
+            )?;
+            scratch.pool = Some(pool.clone());
+            let dataset = format!("{}/data", pool);
+            run(child_env::command("zfs").args(["create", &dataset]), "zfs create")?;
+
+            let config = format!(
+                "[[dataset]]\nname = \"{}\"\ntarget_dir = \"{}\"\n",
+                dataset,
+                target.display()
+            );
+            Ok((config, dir.join("pool").join("data"), target))
+        }
+        Kind::Restic => {
+            // restic reads the password from the environment, and nothing
+            // else is running yet to read it at the same time
+            unsafe {
+                env::remove_var("RESTIC_PASSWORD_FILE");
+                env::remove_var("RESTIC_PASSWORD_COMMAND");
+                env::set_var("RESTIC_PASSWORD", format!("selftest-{}", process::id()));
+            }
+            let repository = dir.join("repo");
+            println!("Creating restic repository {}...", repository.display());
+            run(child_env::command("restic").arg("-r").arg(&repository).arg("init"), "restic init")?;
+
+            let data = dir.join("data");
+            fs::create_dir_all(&data).map_err(|e| format!("Failed to create {}: {}", data.display(), e))?;
+            let config = format!(
+                "[[restic]]\nrepository = \"{}\"\ntarget_dir = \"{}\"\nmode = \"restore\"\nstaging_dir = \"{}\"\n",
+                repository.display(),
+                target.display(),
+                dir.join("staging").display()
+            );
+            // restic keeps the absolute path of what it backed up
+            let copy = target.join(data.strip_prefix("/").unwrap_or(&data));
+            Ok((config, data, copy))
+        }
+    }
+}
+
+
+fn take_snapshot(kind: Kind, scratch: &Scratch, data: &Path, name: &str) -> Result<(), String> {
+    match kind {
+        Kind::Zfs => {
+            let pool = scratch.pool.as_deref().unwrap_or_default();
+            run(child_env::command("zfs").args(["snapshot", &format!("{}/data@{}", pool, name)]), "zfs snapshot")
+        }
+        Kind::Restic => run(
+            child_env::command("restic")
+                .arg("-r")
+                .arg(scratch.dir.join("repo"))
+                .args(["backup", "--tag", name])
+                .arg(data),
+            "restic backup",
+        ),
+    }
+}
+
+
+pub fn run_selftest(kind: Option<Kind>) -> Result<(), String> {
+    let kind = kind.unwrap_or(if unsafe { libc::geteuid() } == 0 { Kind::Zfs } else { Kind::Restic });
+    let mut scratch = Scratch {
+        dir: env::temp_dir().join(format!("file-backup-selftest-{}", process::id())),
+        pool: None,
+    };
+    fs::create_dir_all(&scratch.dir).map_err(|e| format!("Failed to create {}: {}", scratch.dir.display(), e))?;
+    println!("Testing a {} backup in {}", if kind == Kind::Zfs { "zfs" } else { "restic" }, scratch.dir.display());
+
+    let (config_text, data, copy) = prepare(kind, &mut scratch)?;
+    let config_path = scratch.dir.join("config.toml");
+    fs::write(&config_path, config_text).map_err(|e| format!("Failed to write {}: {}", config_path.display(), e))?;
+    let mut config = crate::load_config(&config_path)?;
+    let tool_versions = tools::detect_tool_versions(&config)?;
+    nested::expand(&mut config)?;
+
+    let options = RunOptions {
+        hostname: crate::get_hostname(),
+        any_host: false,
+        force_delete: false,
+        allow_degraded: false,
+        confirm_first_backup: false,
+        database: scratch.dir.join("backup.db"),
+        unprivileged: false,
+        since: None,
+        print_changes: false,
+    };
+    let conn = crate::init_database(&options.database, &options.hostname)?;
+    let sources: Vec<_> = config.sources().collect();
+
+    write_initial_data(&data)?;
+    let mut gone = Vec::new();
+    for (step, snapshot) in [("full", "selftest-1"), ("incremental", "selftest-2")] {
+        if step == "incremental" {
+            gone = change_data(&data)?;
+        }
+        take_snapshot(kind, &scratch, &data, snapshot)?;
+
+        println!("\n--- Self test: {} backup ---", step);
+        let summaries = crate::run_backups(&config, &conn, &options, &tool_versions, &sources);
+        if let Some(summary) = summaries.iter().find(|summary| summary.status != SourceStatus::Ok) {
+            return Err(format!("{} backup failed: {}", step, summary.error.as_deref().unwrap_or("no result")));
+        }
+        if summaries.is_empty() {
+            return Err(format!("{} backup didn't run", step));
+        }
+        let compared = verify(&data, &copy, &gone).map_err(|e| format!("{} backup: {}", step, e))?;
+        println!("--- Self test: {} backup matches the data ({} files) ---", step, compared);
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::executed::{Log, Record};
+use crate::Source;
+use crate::{child_env, privileges, report, restic_lock};
+use crate::units::ConfigDuration;
+
+
+// A source whose snapshots stopped being taken would otherwise be "already
+// backed up" every run, so with max_snapshot_age set the newest snapshot is
+// checked before backing up
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct SnapshotAgeConfig {
+    pub max_snapshot_age: Option<ConfigDuration>,
+    #[serde(default)]
+    pub stale_snapshot: StaleSnapshot,
+}
+
+
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StaleSnapshot {
+    // Don't back the source up; the run records it as stale-snapshot
+    #[default]
+    Fail,
+    // Back up what there is, with a warning
+    Warn,
+}
+
+
+// Creation time of the newest snapshot, in seconds since the epoch
+fn newest_snapshot_time(source: Source, commands: &Log) -> Result<Option<i64>, String> {
+    match source {
+        Source::Dataset(dataset_config) => {
+            let output = child_env::command("zfs")
+                .args(["list", "-H", "-p", "-t", "snapshot", "-d", "1", "-o", "creation", "-s", "creation"])
+                .arg(&dataset_config.name)
+                .recorded_output(commands)
+                .map_err(|e| format!("Failed to execute zfs list: {}", e))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("zfs list failed: {}", stderr.trim()));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next_back()
+                .and_then(|line| line.trim().parse().ok()))
+        }
+        Source::Restic(restic_config) => {
+            let output = restic_lock::output(restic_config, commands, || {
+                let mut command = privileges::restic(&restic_config.repository, restic_config.user.as_ref());
+                command.args(["snapshots", "--json", "--latest", "1"]);
+                command
+            })
+                .map_err(|e| format!("Failed to execute restic: {}", e))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("restic snapshots failed: {}", stderr.trim()));
+            }
+            let snapshots: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)
+                .map_err(|e| format!("Failed to parse restic snapshots: {}", e))?;
+            Ok(snapshots
+                .iter()
+                .filter_map(|snapshot| snapshot["time"].as_str().and_then(crate::clock::epoch_from_rfc3339))
+                .max())
+        }
+    }
+}
+
+
+// Err if the newest snapshot is too old and the source shouldn't be backed
+// up. A source with no snapshots at all is left for the backup to report.
+pub fn check(source: Source, commands: &Log) -> Result<(), String> {
+    let config = source.snapshot_age();
+    let Some(ConfigDuration(max_age)) = config.max_snapshot_age else {
+        return Ok(());
+    };
+
+    let newest = match newest_snapshot_time(source, commands) {
+        Ok(Some(newest)) => newest,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            eprintln!("Warning: Couldn't check the age of the newest snapshot: {}", e);
+            return Ok(());
+        }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let age = now - newest;
+    if age <= max_age as i64 {
+        return Ok(());
+    }
+
+    let message = format!(
+        "The newest snapshot of {} '{}' is {} old, more than max_snapshot_age ({}); check that snapshots are still being taken",
+        source.kind(),
+        source.name(),
+        report::format_age(age),
+        report::format_age(max_age as i64)
+    );
+    match config.stale_snapshot {
+        StaleSnapshot::Fail => Err(message),
+        StaleSnapshot::Warn => {
+            eprintln!("Warning: {}", message);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use rusqlite::Connection;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+
+// VM and disk images, which are usually mostly holes. Without --sparse rsync
+// writes the holes out as zeros and the target copy takes up the image's
+// full apparent size.
+const IMAGE_EXTENSIONS: &[&str] = &["img", "raw", "qcow2", "vmdk", "vdi", "vhd", "vhdx", "iso"];
+
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+
+// A disk image, or a file with less than half of it allocated
+fn looks_sparse(path: &Path) -> bool {
+    is_image(path)
+        || fs::symlink_metadata(path)
+            .is_ok_and(|metadata| metadata.is_file() && metadata.blocks() * 512 < metadata.len() / 2)
+}
+
+
+// With sparse unset, --sparse is used when any of the files about to be
+// synced looks sparse
+pub fn for_list(setting: Option<bool>, root: &Path, files: &[PathBuf]) -> bool {
+    setting.unwrap_or_else(|| {
+        let found = files.iter().any(|file| looks_sparse(&root.join(file.strip_prefix("/").unwrap_or(file))));
+        if found {
+            println!("Sparse files among the changes - rsyncing with --sparse");
+        }
+        found
+    })
+}
+
+
+// For a full copy, walking the snapshot first would take as long as the copy,
+// so with sparse unset the decision is made from the file state of the last
+// backup: --sparse if that had any disk images
+pub fn for_full(setting: Option<bool>, conn: &Connection, backup_type: &str, source_name: &str) -> bool {
+    setting.unwrap_or_else(|| {
+        let patterns: Vec<String> = IMAGE_EXTENSIONS.iter().map(|extension| format!("*.{}", extension)).collect();
+        let found = patterns.iter().any(|pattern| {
+            conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM file_state WHERE backup_type = ?1 AND source_name = ?2 AND lower(path) GLOB ?3)",
+                [backup_type, source_name, pattern],
+                |row| row.get::<_, bool>(0),
+            ).unwrap_or(false)
+        });
+        if found {
+            println!("Disk images found in the last backup - rsyncing with --sparse");
+        }
+        found
+    })
+}
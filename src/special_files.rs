@@ -0,0 +1,104 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::cell::Cell;
+
+
+// What to do with device nodes, sockets and FIFOs, which targets such as FAT
+// or exFAT disks can't hold
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecialFiles {
+    // Copy them like everything else (rsync -a)
+    #[default]
+    Preserve,
+    // Leave them out, only counting them
+    Skip,
+    // Leave them out and name each one in a warning
+    Warn,
+}
+
+impl SpecialFiles {
+    // -a implies --devices and --specials
+    pub fn rsync_args(self) -> &'static [&'static str] {
+        match self {
+            SpecialFiles::Preserve => &[],
+            SpecialFiles::Skip | SpecialFiles::Warn => &["--no-devices", "--no-specials"],
+        }
+    }
+}
+
+
+// Special files left out while backing up the current source, on this thread
+thread_local! {
+    static SKIPPED: Cell<u64> = const { Cell::new(0) };
+}
+
+const RSYNC_SKIP_PREFIX: &str = "skipping non-regular file ";
+
+
+pub fn start_source() {
+    SKIPPED.set(0);
+}
+
+
+pub fn skipped() -> u64 {
+    SKIPPED.get()
+}
+
+
+fn note_skipped(policy: SpecialFiles, path: &str) {
+    SKIPPED.set(SKIPPED.get() + 1);
+    if policy == SpecialFiles::Warn {
+        eprintln!("Warning: Skipped special file {}", path);
+    }
+}
+
+
+fn is_special(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| {
+        let file_type = metadata.file_type();
+        file_type.is_block_device() || file_type.is_char_device() || file_type.is_fifo() || file_type.is_socket()
+    })
+}
+
+
+// Whether a changed path under `root` is a special file to leave out
+pub fn skip(policy: SpecialFiles, root: &Path, file: &Path) -> bool {
+    if policy == SpecialFiles::Preserve {
+        return false;
+    }
+    let special = is_special(&root.join(file.strip_prefix("/").unwrap_or(file)));
+    if special {
+        note_skipped(policy, &file.to_string_lossy());
+    }
+    special
+}
+
+
+// Drop special files from a list of changed paths under `root`
+pub fn filter_list(policy: SpecialFiles, root: &Path, files: Vec<PathBuf>) -> Vec<PathBuf> {
+    files.into_iter().filter(|file| !skip(policy, root, file)).collect()
+}
+
+
+// rsync -v names every special file it leaves out; count those lines and
+// take them out of the output, which would otherwise be mostly them
+pub fn filter_output(policy: SpecialFiles, stdout: &str) -> String {
+    if policy == SpecialFiles::Preserve {
+        return stdout.to_string();
+    }
+    let mut kept = String::with_capacity(stdout.len());
+    for line in stdout.lines() {
+        match line.strip_prefix(RSYNC_SKIP_PREFIX) {
+            Some(path) => note_skipped(policy, path.trim_matches('"')),
+            None => {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+    }
+    kept
+}
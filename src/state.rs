@@ -0,0 +1,34 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+
+const DEFAULT_DATABASE: &str = "/var/lib/file-backup/backup.db";
+
+
+// The database to use: --database when given, else the config's
+//
+//   [state]
+//   path = "/var/lib/file-backup/offsite.db"
+//
+// so profiles run from the same host, onsite and offsite say, each keep their
+// own state and cron entries only need --config. A relative path is taken
+// from the config's directory. Only [state] is looked at here, and a config
+// that can't be read or parsed leaves the default, as the full load reports
+// what is wrong with it.
+pub fn database_path(config_path: &Path, database: Option<&Path>) -> Result<PathBuf, String> {
+    if let Some(database) = database {
+        return Ok(database.to_path_buf());
+    }
+    let table = fs::read_to_string(config_path)
+        .ok()
+        .and_then(|contents| contents.parse::<toml::Table>().ok());
+    let Some(value) = table.as_ref().and_then(|table| table.get("state")).and_then(|state| state.get("path")) else {
+        return Ok(PathBuf::from(DEFAULT_DATABASE));
+    };
+
+    let path = value
+        .as_str()
+        .filter(|path| !path.is_empty())
+        .ok_or_else(|| format!("[state] path must be the path of the database file, not {}", value))?;
+    Ok(config_path.parent().unwrap_or(Path::new("")).join(path))
+}
@@ -0,0 +1,202 @@
+use rusqlite::Connection;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{Config, pause, resync};
+use crate::report::{self, SourceFreshness};
+use crate::units::{ByteSize, ConfigDuration};
+
+
+// Per-source limits checked by `status`
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct Thresholds {
+    pub warn_age: Option<ConfigDuration>,
+    pub crit_age: Option<ConfigDuration>,
+    // A target holding less than this probably isn't a backup of the right thing
+    pub min_expected_size: Option<ByteSize>,
+}
+
+
+// Ordered by severity, so the worst of several states is the maximum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum State {
+    Ok,
+    Warn,
+    Unknown,
+    Crit,
+}
+
+impl State {
+    pub fn label(&self) -> &'static str {
+        match self {
+            State::Ok => "OK",
+            State::Warn => "WARN",
+            State::Unknown => "UNKNOWN",
+            State::Crit => "CRIT",
+        }
+    }
+
+    // As in the Nagios/Icinga plugin API
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            State::Ok => 0,
+            State::Warn => 1,
+            State::Crit => 2,
+            State::Unknown => 3,
+        }
+    }
+}
+
+
+// Judge the latest backup of a source against its thresholds, returning the
+// state and the reasons for it
+pub fn evaluate(freshness: &SourceFreshness, thresholds: &Thresholds) -> (State, Vec<String>) {
+    let mut state = State::Ok;
+    let mut reasons = Vec::new();
+
+    if freshness.last_snapshot.is_none() {
+        return (State::Crit, vec!["never backed up".to_string()]);
+    }
+
+    // Rows from before backed_up_epoch existed may not have had it backfilled,
+    // so the age can't be judged against the thresholds
+    match freshness.age_secs {
+        None => {
+            state = state.max(State::Warn);
+            reasons.push("time of last backup unknown".to_string());
+        }
+        Some(age) => {
+            if let Some(ConfigDuration(crit_age)) = thresholds.crit_age
+                && age > crit_age as i64
+            {
+                state = state.max(State::Crit);
+                reasons.push(format!("last backup older than {}", report::format_age(crit_age as i64)));
+            } else if let Some(ConfigDuration(warn_age)) = thresholds.warn_age
+                && age > warn_age as i64
+            {
+                state = state.max(State::Warn);
+                reasons.push(format!("last backup older than {}", report::format_age(warn_age as i64)));
+            }
+        }
+    }
+
+    if let Some(ByteSize(min_size)) = thresholds.min_expected_size
+        && freshness.size_bytes < min_size
+    {
+        state = state.max(State::Crit);
+        reasons.push(format!("smaller than the expected {}", report::format_bytes(min_size)));
+    }
+
+    match freshness.last_status.as_deref() {
+        None | Some("ok") => {}
+        Some("stale-snapshot") => {
+            state = state.max(State::Crit);
+            reasons.push("newest snapshot was older than max_snapshot_age at the most recent attempt".to_string());
+        }
+        Some("locked") => {
+            state = state.max(State::Warn);
+            reasons.push("locked at the most recent attempt".to_string());
+        }
+        Some("deferred") => {
+            state = state.max(State::Warn);
+            reasons.push("deferred for pool maintenance at the most recent attempt".to_string());
+        }
+        Some("device-failed" | "skipped-device-failed") => {
+            state = state.max(State::Warn);
+            reasons.push("the target device failed at the most recent attempt".to_string());
+        }
+        Some(_) => {
+            state = state.max(State::Warn);
+            reasons.push("the most recent attempt failed".to_string());
+        }
+    }
+
+    (state, reasons)
+}
+
+
+// Print one line per source and return the worst state, for use as the exit code
+pub fn print_status(config: &Config, conn: &Connection, hostname: Option<&str>, source: Option<&str>) -> Result<State, String> {
+    let sources = match source {
+        Some(name) => vec![config.find_source(name)?],
+        None => config.sources().collect(),
+    };
+
+    let mut worst = State::Ok;
+    for source in sources {
+        // A paused source isn't expected to be up to date
+        if let Some(reason) = pause::reason(conn, hostname, source)? {
+            println!("{} - {} '{}': {}", State::Ok.label(), source.kind(), source.name(), reason);
+            continue;
+        }
+
+        let freshness = report::source_freshness(conn, hostname, source)?;
+        let (state, reasons) = evaluate(&freshness, source.thresholds());
+        worst = worst.max(state);
+
+        let mut details = Vec::new();
+        if let Some(snapshot) = &freshness.last_snapshot {
+            let when = match freshness.age_secs {
+                Some(age) => format!("{} ago", report::format_age(age)),
+                None => "at an unknown time".to_string(),
+            };
+            details.push(format!("last backup {} ({}), {}", when, snapshot, report::format_bytes(freshness.size_bytes)));
+        }
+        if source.full_resync_every().is_some() {
+            match resync::age_secs(conn, hostname, source)? {
+                Some(age) => details.push(format!("last full resync {} ago", report::format_age(age))),
+                None => details.push("no full resync yet".to_string()),
+            }
+        }
+        details.extend(reasons);
+        println!("{} - {} '{}': {}", state.label(), freshness.kind, freshness.name, details.join("; "));
+    }
+
+    Ok(worst)
+}
+
+
+// Nagios/Icinga check for a single source: one status line with perfdata,
+// exiting with the plugin API code
+pub fn check(config: &Config, conn: &Connection, hostname: Option<&str>, source: &str) -> State {
+    let result = config.find_source(source).and_then(|source| {
+        let freshness = report::source_freshness(conn, hostname, source)?;
+        Ok((freshness, source.thresholds(), pause::reason(conn, hostname, source)?))
+    });
+    let (freshness, thresholds, paused) = match result {
+        Ok(found) => found,
+        Err(e) => {
+            println!("UNKNOWN - {}", e);
+            return State::Unknown;
+        }
+    };
+
+    let (state, reasons) = match paused {
+        Some(reason) => (State::Ok, vec![reason]),
+        None => evaluate(&freshness, thresholds),
+    };
+    let summary = match (&freshness.last_snapshot, freshness.age_secs) {
+        (Some(snapshot), Some(age)) => format!("{} backed up {} ago", snapshot, report::format_age(age)),
+        (Some(snapshot), None) => format!("{} backed up at an unknown time", snapshot),
+        _ => format!("{} '{}'", freshness.kind, freshness.name),
+    };
+    let reasons = if reasons.is_empty() { String::new() } else { format!(" ({})", reasons.join(", ")) };
+
+    // Perfdata is label=value[unit];warn;crit;min, with empty fields left out
+    let threshold = |limit: Option<ConfigDuration>| limit.map(|ConfigDuration(secs)| secs.to_string()).unwrap_or_default();
+    let mut perfdata = Vec::new();
+    if let Some(age) = freshness.age_secs {
+        perfdata.push(format!("age={}s;{};{};0", age, threshold(thresholds.warn_age), threshold(thresholds.crit_age)));
+    }
+    perfdata.push(format!(
+        "bytes={}B;;{};0",
+        freshness.size_bytes,
+        thresholds.min_expected_size.map(|ByteSize(min)| format!("{}:", min)).unwrap_or_default()
+    ));
+    if let Some(duration) = freshness.last_duration_secs {
+        perfdata.push(format!("duration={}s;;;0", duration));
+    }
+
+    println!("{} - {}{} | {}", state.label(), summary, reasons, perfdata.join(" "));
+    state
+}
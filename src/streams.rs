@@ -0,0 +1,386 @@
+use rusqlite::Connection;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use crate::compression::Compression;
+use crate::executed::{Log, Record};
+use crate::{DatasetConfig, RunOptions, Source, child_env, estimate, events, zfs_allow};
+
+
+// In the stream layout each backup is a `zfs send` stream in its own file,
+// the first one full and the rest incremental on the one before, for
+// archiving to media that are written once and put away. The index lists the
+// files in the order they have to be received.
+pub const STREAM_INDEX: &str = ".file-backup-streams";
+
+
+pub struct StreamFile {
+    pub file: String,
+    // The snapshot an incremental stream is based on; None for a full stream
+    pub from: Option<String>,
+    pub to: String,
+    pub size: u64,
+    pub compression: Compression,
+}
+
+
+// Stream files listed in the index, oldest first
+pub fn read_index(target_dir: &Path) -> Result<Vec<StreamFile>, String> {
+    let path = target_dir.join(STREAM_INDEX);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+
+    // One "<file>\t<from snapshot or ->\t<to snapshot>\t<size>[\t<compression>]"
+    // line per stream; uncompressed streams leave the compression out
+    contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let (file, from, to, size, compression) = match fields.as_slice() {
+                [file, from, to, size] => (file, from, to, size, Compression::None),
+                [file, from, to, size, compression] => (
+                    file,
+                    from,
+                    to,
+                    size,
+                    Compression::parse(compression).map_err(|e| format!("Invalid line in {}: {}", path.display(), e))?,
+                ),
+                _ => return Err(format!("Invalid line in {}: {}", path.display(), line)),
+            };
+            Ok(StreamFile {
+                file: file.to_string(),
+                from: (*from != "-").then(|| from.to_string()),
+                to: to.to_string(),
+                size: size.parse().map_err(|_| format!("Invalid size in {}: {}", path.display(), line))?,
+                compression,
+            })
+        })
+        .collect()
+}
+
+
+fn index_line(stream: &StreamFile) -> String {
+    let mut line = format!("{}\t{}\t{}\t{}", stream.file, stream.from.as_deref().unwrap_or("-"), stream.to, stream.size);
+    if stream.compression != Compression::None {
+        line.push_str(&format!("\t{}", stream.compression));
+    }
+    line.push('\n');
+    line
+}
+
+
+fn append_index(target_dir: &Path, stream: &StreamFile) -> Result<(), String> {
+    let path = target_dir.join(STREAM_INDEX);
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    index.write_all(index_line(stream).as_bytes())
+        .and_then(|()| index.sync_all())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+
+fn write_stream(from: Option<&str>, to: &str, path: &Path, compression: Compression, commands: &Log) -> Result<u64, String> {
+    // Written under a temporary name so a partial stream is never indexed
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let file = File::create(&temp_path)
+        .map_err(|e| format!("Failed to create {}: {}", temp_path.display(), e))?;
+
+    if let Err(e) = send_to(from, to, &file, compression, commands) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    file.sync_all()
+        .map_err(|e| format!("Failed to sync {}: {}", temp_path.display(), e))?;
+    let size = file.metadata()
+        .map_err(|e| format!("Failed to stat {}: {}", temp_path.display(), e))?
+        .len();
+    fs::rename(&temp_path, path)
+        .map_err(|e| format!("Failed to rename {}: {}", temp_path.display(), e))?;
+
+    Ok(size)
+}
+
+
+// zfs send into `file`, through the compressor if there is one
+fn send_to(from: Option<&str>, to: &str, file: &File, compression: Compression, commands: &Log) -> Result<(), String> {
+    let open = || file.try_clone().map(Stdio::from).map_err(|e| format!("Failed to open stream file: {}", e));
+    let tool = compression.tool().unwrap_or("zfs");
+    let mut compressor = match compression.compress_command() {
+        Some(mut command) => Some(
+            command
+                .stdin(Stdio::piped())
+                .stdout(open()?)
+                .stderr(Stdio::piped())
+                .recorded_spawn(commands)
+                .map_err(|e| format!("Failed to execute {}: {}", tool, e))?,
+        ),
+        None => None,
+    };
+    let stdout = match &mut compressor {
+        Some((child, _)) => Stdio::from(child.stdin.take().ok_or_else(|| format!("Failed to write to {}", tool))?),
+        None => open()?,
+    };
+
+    let mut command = child_env::command("zfs");
+    command.arg("send");
+    if let Some(from) = from {
+        command.args(["-i", from]);
+    }
+    let output = command
+        .arg(to)
+        .stdout(stdout)
+        .stderr(Stdio::piped())
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute zfs send: {}", e));
+    // The command keeps the compressor's stdin open until it is dropped
+    drop(command);
+
+    // A compressor that failed cut zfs send off too, and says why
+    if let Some((child, started)) = compressor {
+        let compressed = child.wait_with_output();
+        started.finished(compressed.as_ref().ok().map(|output| &output.status));
+        let compressed = compressed.map_err(|e| format!("Failed to execute {}: {}", tool, e))?;
+        if !compressed.status.success() {
+            return Err(format!("{} failed: {}", tool, String::from_utf8_lossy(&compressed.stderr).trim()));
+        }
+    }
+
+    let output = output?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(zfs_allow::failure("send", "send", to, &stderr));
+    }
+    Ok(())
+}
+
+
+fn par2_path(stream_path: &Path) -> PathBuf {
+    let mut path = stream_path.as_os_str().to_owned();
+    path.push(".par2");
+    PathBuf::from(path)
+}
+
+
+fn run_par2(args: &[&str], par2_file: &Path, extra: Option<&Path>) -> Result<bool, String> {
+    let mut command = child_env::command("par2");
+    command.args(args).arg(par2_file);
+    if let Some(extra) = extra {
+        command.arg(extra);
+    }
+    let output = command.output().map_err(|e| format!("Failed to execute par2: {}", e))?;
+    Ok(output.status.success())
+}
+
+
+pub fn backup(dataset_config: &DatasetConfig, conn: &Connection, options: &RunOptions, commands: &Log) -> Result<(), String> {
+    let target_dir = &dataset_config.target_dir;
+    crate::check_target_directory(target_dir)?;
+
+    let last_backup = match crate::get_last_backed_up_snapshot(conn, options.host_filter(), Source::Dataset(dataset_config), commands) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("Warning: Failed to query database: {}", e);
+            None
+        }
+    };
+    let latest_snapshot = crate::get_latest_snapshot(&dataset_config.name, commands)?
+        .ok_or_else(|| format!("No snapshots found for dataset '{}'", dataset_config.name))?;
+    println!("Latest snapshot: {}", latest_snapshot);
+    println!("Target directory: {}", target_dir.display());
+    events::emit(events::Event::SnapshotSelected { snapshot: &latest_snapshot, base: last_backup.as_deref() });
+
+    if last_backup.as_deref() == Some(latest_snapshot.as_str()) {
+        println!("Already backed up - nothing to do");
+        println!();
+        return Ok(());
+    }
+
+    // An incremental stream is only any use on top of the last one on the target
+    let index = read_index(target_dir)?;
+    let from = match (&last_backup, index.last()) {
+        (Some(last), Some(stream)) if stream.to == *last => Some(last.as_str()),
+        (Some(last), _) => {
+            eprintln!("Warning: {} isn't the last stream on the target, so writing a full stream", last);
+            None
+        }
+        (None, _) => None,
+    };
+    if from.is_none() {
+        estimate::confirm_first_backup(Source::Dataset(dataset_config), &latest_snapshot, options, commands)?;
+    }
+
+    // Numbered on from the last stream, as gc removes streams from the start
+    let number = index
+        .last()
+        .and_then(|stream| stream.file.split('-').next()?.parse::<usize>().ok())
+        .unwrap_or(0) + 1;
+    let compression = dataset_config.compression.unwrap_or(Compression::None);
+    let file = format!("{:06}-{}.zfs{}", number, if from.is_some() { "incr" } else { "full" }, compression.extension());
+    let path = target_dir.join(&file);
+    match from {
+        Some(from) => println!("Writing incremental stream {} -> {} to {}...", from, latest_snapshot, file),
+        None => println!("Writing full stream of {} to {}...", latest_snapshot, file),
+    }
+    let size = write_stream(from, &latest_snapshot, &path, compression, commands)?;
+    println!("Wrote {}", crate::report::format_bytes(size));
+    events::emit(events::Event::TransferProgress { step: "zfs send", files: None, bytes: Some(size) });
+
+    if let Some(redundancy) = dataset_config.par2_redundancy {
+        println!("Creating {}% parity data...", redundancy);
+        if !run_par2(&["create", "-q", &format!("-r{}", redundancy)], &par2_path(&path), Some(&path))? {
+            return Err(format!("par2 failed to create parity data for {}", file));
+        }
+    }
+
+    append_index(target_dir, &StreamFile {
+        file,
+        from: from.map(|from| from.to_string()),
+        to: latest_snapshot.clone(),
+        size,
+        compression,
+    })?;
+    crate::record_successful_backup(
+        conn,
+        &options.hostname,
+        Source::Dataset(dataset_config),
+        &latest_snapshot,
+        commands,
+        None,
+    )?;
+
+    println!("Backup recorded successfully");
+    println!();
+    Ok(())
+}
+
+
+// Check every stream file in the index against its recorded size and, where
+// there is parity data, with par2, repairing damaged files if asked to
+pub fn verify(target_dir: &Path, repair: bool) -> Result<(), String> {
+    let index = read_index(target_dir)?;
+    println!("Verifying {} stream file(s) in {}...", index.len(), target_dir.display());
+
+    let mut failures = 0;
+    for stream in &index {
+        let path = target_dir.join(&stream.file);
+        let par2_file = par2_path(&path);
+
+        if par2_file.is_file() {
+            if run_par2(&["verify", "-q"], &par2_file, None)? {
+                println!("  ok: {}", stream.file);
+            } else if repair && run_par2(&["repair", "-q"], &par2_file, None)? {
+                println!("  repaired: {}", stream.file);
+            } else {
+                println!("  damaged: {}", stream.file);
+                failures += 1;
+            }
+            continue;
+        }
+
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() != stream.size => {
+                println!("  wrong size: {} ({} bytes, expected {})", stream.file, metadata.len(), stream.size);
+                failures += 1;
+            }
+            // A compressed stream's own checksums go further than its size
+            Ok(_) if !stream.compression.test(&path, &Log::default())? => {
+                println!("  damaged ({} -t): {}", stream.compression.tool().unwrap_or("-"), stream.file);
+                failures += 1;
+            }
+            Ok(_) => println!("  ok (no parity data): {}", stream.file),
+            Err(e) => {
+                println!("  missing: {} ({})", stream.file, e);
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(format!("{} stream file(s) failed verification", failures));
+    }
+    println!("All stream files verified");
+    Ok(())
+}
+
+
+// Files belonging to a stream on the target: the stream itself and its par2
+// index and recovery volumes
+fn stream_files(target_dir: &Path, stream: &str) -> Result<Vec<PathBuf>, String> {
+    let prefix = format!("{}.", stream);
+    let mut files: Vec<PathBuf> = fs::read_dir(target_dir)
+        .map_err(|e| format!("Failed to read {}: {}", target_dir.display(), e))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry.file_name().to_str().is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".par2"))
+        })
+        .map(|entry| entry.path())
+        .collect();
+    files.push(target_dir.join(stream));
+    files.sort();
+    Ok(files)
+}
+
+
+// Remove the streams that aren't needed to restore any of the newest `keep`
+// snapshots: restoring one needs the latest full stream at or before it and
+// every incremental since, so only streams older than that full one can go
+pub fn gc(target_dir: &Path, keep: usize, dry_run: bool) -> Result<(), String> {
+    let index = read_index(target_dir)?;
+    let oldest_kept = index.len().saturating_sub(keep);
+    let chain_start = index
+        .iter()
+        .take(oldest_kept + 1)
+        .rposition(|stream| stream.from.is_none())
+        .unwrap_or(0);
+    let (obsolete, kept) = index.split_at(chain_start);
+
+    println!(
+        "{} stream(s) in {}: keeping {} from {}, removing {}",
+        index.len(),
+        target_dir.display(),
+        kept.len(),
+        kept.first().map(|stream| stream.file.as_str()).unwrap_or("none"),
+        obsolete.len()
+    );
+    if obsolete.is_empty() {
+        return Ok(());
+    }
+    if dry_run {
+        for stream in obsolete {
+            println!("  Would remove {} ({})", stream.file, stream.to);
+        }
+        return Ok(());
+    }
+
+    // Drop the streams from the index first, so an interrupted gc never
+    // leaves the index listing files that are gone
+    let path = target_dir.join(STREAM_INDEX);
+    let temp_path = target_dir.join(format!("{}.tmp", STREAM_INDEX));
+    let contents: String = kept.iter().map(index_line).collect();
+    fs::write(&temp_path, contents)
+        .map_err(|e| format!("Failed to write {}: {}", temp_path.display(), e))?;
+    fs::rename(&temp_path, &path)
+        .map_err(|e| format!("Failed to rename {}: {}", temp_path.display(), e))?;
+
+    for stream in obsolete {
+        println!("  Removing {} ({})", stream.file, stream.to);
+        for file in stream_files(target_dir, &stream.file)? {
+            fs::remove_file(&file)
+                .map_err(|e| format!("Failed to remove {}: {}", file.display(), e))?;
+        }
+    }
+
+    Ok(())
+}
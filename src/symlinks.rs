@@ -0,0 +1,140 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::filter_file::FilterFile;
+use crate::metadata;
+
+
+// What to do with symlinks, which targets such as FAT or exFAT disks can't
+// hold
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Symlinks {
+    // Copy them as symlinks (rsync -a)
+    #[default]
+    Preserve,
+    // Copy what they point to instead; rsync fails on ones that point nowhere
+    Follow,
+    // Leave them out
+    Skip,
+    // Write a small text file in place of each, and keep where it pointed in
+    // the metadata store on the target, from which restore makes it again
+    Stub,
+}
+
+impl Symlinks {
+    // -a implies --links
+    pub fn rsync_args(self) -> &'static [&'static str] {
+        match self {
+            Symlinks::Preserve => &[],
+            Symlinks::Follow => &["--copy-links"],
+            Symlinks::Skip | Symlinks::Stub => &["--no-links"],
+        }
+    }
+}
+
+
+const STUB_HEADER: &[u8] = b"file-backup: this was a symbolic link to\n";
+
+
+fn write_stub(root: &Path, target_dir: &Path, relative_path: &Path) -> Result<bool, String> {
+    let path = root.join(relative_path);
+    let is_symlink = fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink());
+    if !is_symlink {
+        metadata::forget_link(target_dir, relative_path)?;
+        return Ok(false);
+    }
+
+    let link = fs::read_link(&path).map_err(|e| format!("Failed to read symlink {}: {}", path.display(), e))?;
+    let stub = target_dir.join(relative_path);
+    let mut contents = STUB_HEADER.to_vec();
+    contents.extend_from_slice(link.as_os_str().as_bytes());
+    contents.push(b'\n');
+    fs::write(&stub, contents).map_err(|e| format!("Failed to write {}: {}", stub.display(), e))?;
+    metadata::record_link(target_dir, relative_path, &link)?;
+    Ok(true)
+}
+
+
+// After a full rsync with symlinks = "stub", which left the symlinks out,
+// write a stub for every one in the snapshot tree at `root`
+pub fn stub_full(policy: Symlinks, root: &Path, target_dir: &Path, filter: Option<&FilterFile>) -> Result<(), String> {
+    if policy != Symlinks::Stub {
+        return Ok(());
+    }
+    metadata::clear_links(target_dir)?;
+
+    let mut count = 0;
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative_dir) = pending.pop() {
+        let dir = root.join(&relative_dir);
+        let entries = fs::read_dir(&dir)
+            .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+            let relative_path = relative_dir.join(entry.file_name());
+            let file_type = entry.file_type()
+                .map_err(|e| format!("Failed to stat {}: {}", entry.path().display(), e))?;
+            if file_type.is_dir() {
+                if !crate::excludes::matches(&relative_path, true, filter) {
+                    pending.push(relative_path);
+                }
+            } else if file_type.is_symlink()
+                && !crate::excludes::matches(&relative_path, false, filter)
+                && write_stub(root, target_dir, &relative_path)?
+            {
+                count += 1;
+            }
+        }
+    }
+
+    println!("Wrote stubs for {} symlink(s), keeping their targets in {}", count, metadata::META_DIR);
+    Ok(())
+}
+
+
+// Whether a path an incremental backup is syncing is a symlink in the
+// snapshot tree at `root`, so needs a stub once rsync is done. One that no
+// longer is a symlink has the target kept for it dropped.
+pub fn needs_stub(policy: Symlinks, root: &Path, target_dir: &Path, path: &Path) -> Result<bool, String> {
+    if policy != Symlinks::Stub {
+        return Ok(false);
+    }
+    let path = path.strip_prefix("/").unwrap_or(path);
+    if fs::symlink_metadata(root.join(path)).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        return Ok(true);
+    }
+    metadata::forget_link(target_dir, path)?;
+    Ok(false)
+}
+
+
+// The same for an incremental backup: stubs for the `synced` paths that are
+// symlinks, and the kept targets of `deleted` ones dropped
+pub fn stub_changes(
+    policy: Symlinks,
+    root: &Path,
+    target_dir: &Path,
+    synced: &[PathBuf],
+    deleted: &[PathBuf],
+) -> Result<(), String> {
+    if policy != Symlinks::Stub {
+        return Ok(());
+    }
+    for path in deleted {
+        metadata::forget_link(target_dir, path.strip_prefix("/").unwrap_or(path))?;
+    }
+    let mut count = 0;
+    for path in synced {
+        if write_stub(root, target_dir, path.strip_prefix("/").unwrap_or(path))? {
+            count += 1;
+        }
+    }
+    if count > 0 {
+        println!("Wrote stubs for {} symlink(s)", count);
+    }
+    Ok(())
+}
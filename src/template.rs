@@ -0,0 +1,92 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::{Config, clock};
+
+
+// Placeholders a target_dir may contain, filled in for each run:
+//   {pool}     the dataset's pool, e.g. tank for tank/home/alice
+//   {dataset}  the dataset's name within its pool, e.g. home/alice
+//   {date}     the day the run starts, in UTC, e.g. 2024-05-01
+// so one entry, and the children a descend = "include" dataset brings in, can
+// fan out into a hierarchy such as /mnt/usb/{pool}/{dataset}
+const PLACEHOLDERS: [&str; 3] = ["pool", "dataset", "date"];
+
+
+fn placeholders(template: &str) -> Result<Vec<&str>, String> {
+    let mut found = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            return Err(format!("target_dir '{}' has a {{ without a closing }}", template));
+        };
+        let name = &rest[start + 1..start + end];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(format!("target_dir '{}': unknown placeholder {{{}}}; use {{pool}}, {{dataset}} or {{date}}", template, name));
+        }
+        found.push(name);
+        rest = &rest[start + end + 1..];
+    }
+    if rest.contains('}') {
+        return Err(format!("target_dir '{}' has a }} without an opening {{", template));
+    }
+    Ok(found)
+}
+
+
+fn fill(template: &Path, dataset: Option<&str>, date: &str) -> PathBuf {
+    let (pool, within_pool) = match dataset {
+        Some(name) => name.split_once('/').unwrap_or((name, "")),
+        None => ("", ""),
+    };
+    let filled = template
+        .to_string_lossy()
+        .replace("{pool}", pool)
+        .replace("{dataset}", within_pool)
+        .replace("{date}", date);
+    PathBuf::from(filled)
+}
+
+
+// Note which target_dirs are templates, checking their placeholders, and fill
+// them in. Called when the config is loaded.
+pub fn prepare(config: &mut Config) -> Result<(), String> {
+    for dataset_config in &mut config.dataset {
+        let template = dataset_config.target_dir.to_string_lossy().into_owned();
+        if !placeholders(&template).map_err(|e| format!("Dataset '{}': {}", dataset_config.name, e))?.is_empty() {
+            dataset_config.target_template = Some(dataset_config.target_dir.clone());
+        }
+    }
+    for restic_config in &mut config.restic {
+        let template = restic_config.target_dir.to_string_lossy().into_owned();
+        let found = placeholders(&template).map_err(|e| format!("Restic repository '{}': {}", restic_config.repository, e))?;
+        if found.iter().any(|&name| name != "date") {
+            return Err(format!(
+                "Restic repository '{}': {{pool}} and {{dataset}} are only for datasets",
+                restic_config.repository
+            ));
+        }
+        if !found.is_empty() {
+            restic_config.target_template = Some(restic_config.target_dir.clone());
+        }
+    }
+    expand(config);
+    Ok(())
+}
+
+
+// Fill in the templated target_dirs for a run starting now. The daemon calls
+// this before each run, so {date} moves on from one day to the next.
+pub fn expand(config: &mut Config) {
+    let date = clock::iso_utc(SystemTime::now())[..10].to_string();
+    for dataset_config in &mut config.dataset {
+        if let Some(template) = &dataset_config.target_template {
+            dataset_config.target_dir = fill(template, Some(&dataset_config.name), &date);
+        }
+    }
+    for restic_config in &mut config.restic {
+        if let Some(template) = &restic_config.target_template {
+            restic_config.target_dir = fill(template, None, &date);
+        }
+    }
+}
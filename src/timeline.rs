@@ -0,0 +1,236 @@
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::executed::{Log, Record};
+use crate::{Layout, Source, child_env, clock, images, privileges, restic_lock, streams, versioned};
+
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+    BackedUp,
+    // Never backed up, though a later snapshot was: the backups skipped it,
+    // or it was rotated away on the source before a backup got to it
+    Gap,
+    // Newer than the last backup
+    Pending,
+}
+
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    snapshot: String,
+    // When it was taken, while it is still on the source
+    taken_at: Option<String>,
+    on_source: bool,
+    backed_up_at: Option<String>,
+    // The version, stream or image file, or restic snapshot it is kept in on
+    // the target; for a mirror, the newest backup is what the target holds
+    on_target: Option<String>,
+    status: Status,
+    #[serde(skip)]
+    time: i64,
+}
+
+
+fn iso(epoch: i64) -> String {
+    clock::iso_utc(UNIX_EPOCH + Duration::from_secs(epoch.max(0) as u64))
+}
+
+
+// The source's snapshots, oldest first, with when each was taken
+fn source_snapshots(source: Source) -> Result<Vec<(String, i64)>, String> {
+    match source {
+        Source::Dataset(dataset_config) => {
+            let output = child_env::command("zfs")
+                .args(["list", "-H", "-p", "-t", "snapshot", "-d", "1", "-o", "name,creation", "-s", "creation"])
+                .arg(&dataset_config.name)
+                .recorded_output(&Log::default())
+                .map_err(|e| format!("Failed to execute zfs list: {}", e))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("zfs list failed: {}", stderr.trim()));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|line| {
+                    let (name, creation) = line.split_once('\t')?;
+                    Some((name.to_string(), creation.trim().parse().ok()?))
+                })
+                .collect())
+        }
+        Source::Restic(restic_config) => {
+            let output = restic_lock::output(restic_config, &Log::default(), || {
+                let mut command = privileges::restic(&restic_config.repository, restic_config.user.as_ref());
+                command.args(["snapshots", "--json"]);
+                command
+            })
+                .map_err(|e| format!("Failed to execute restic: {}", e))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("restic snapshots failed: {}", stderr.trim()));
+            }
+            let snapshots: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)
+                .map_err(|e| format!("Failed to parse restic snapshots: {}", e))?;
+            let mut snapshots: Vec<(String, i64)> = snapshots
+                .iter()
+                .filter_map(|snapshot| {
+                    let time = snapshot["time"].as_str().and_then(clock::epoch_from_rfc3339)?;
+                    Some((snapshot["id"].as_str()?.to_string(), time))
+                })
+                .collect();
+            snapshots.sort_by_key(|(_, time)| *time);
+            Ok(snapshots)
+        }
+    }
+}
+
+
+// Snapshots backed up, oldest first, with when
+fn backed_up(conn: &Connection, hostname: Option<&str>, source: Source) -> Result<Vec<(String, String)>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT snapshot_name, backup_timestamp FROM backup_history
+         WHERE backup_type = ?1 AND source_name = ?2 AND (?3 IS NULL OR hostname = ?3)
+         ORDER BY backed_up_epoch, id"
+    ).map_err(|e| format!("Failed to query backup history: {}", e))?;
+    stmt.query_map(rusqlite::params![source.backup_type(), source.name(), hostname], |row| Ok((row.get(0)?, row.get(1)?)))
+        .and_then(|rows| rows.collect())
+        .map_err(|e| format!("Failed to query backup history: {}", e))
+}
+
+
+// What each snapshot is kept in on the target, for the layouts that keep
+// more than the newest backup
+fn target_holdings(conn: &Connection, source: Source) -> Result<Option<HashMap<String, String>>, String> {
+    let target_dir = source.target_dir();
+    let holdings = match source {
+        Source::Dataset(d) if d.zvol_mode.is_some() => {
+            let mut stmt = conn.prepare("SELECT snapshot_name, restic_snapshot_id FROM zvol_backups WHERE source_name = ?1")
+                .map_err(|e| format!("Failed to query zvol backups: {}", e))?;
+            stmt.query_map([&d.name], |row| Ok((row.get(0)?, row.get(1)?)))
+                .and_then(|rows| rows.collect())
+                .map_err(|e| format!("Failed to query zvol backups: {}", e))?
+        }
+        _ => match source.layout() {
+            Layout::Mirror => return Ok(None),
+            Layout::Versioned => versioned::read_manifest(target_dir)?
+                .versions
+                .into_iter()
+                .filter(|(_, entry)| entry.status == versioned::VersionStatus::Complete)
+                .map(|(version, entry)| (entry.snapshot, version))
+                .collect(),
+            Layout::Stream => streams::read_index(target_dir)?.into_iter().map(|stream| (stream.to, stream.file)).collect(),
+            Layout::Image => images::read_index(target_dir)?.into_iter().map(|image| (image.snapshot, image.file)).collect(),
+        },
+    };
+    Ok(Some(holdings))
+}
+
+
+fn entries(conn: &Connection, hostname: Option<&str>, source: Source) -> Result<Vec<Entry>, String> {
+    let snapshots = match source_snapshots(source) {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            eprintln!("Warning: Couldn't list the snapshots of {} '{}': {}", source.kind(), source.name(), e);
+            Vec::new()
+        }
+    };
+    let history = backed_up(conn, hostname, source)?;
+    let holdings = target_holdings(conn, source)?;
+    let on_target = |snapshot: &str, newest: bool| match &holdings {
+        Some(holdings) => holdings.get(snapshot).cloned(),
+        None => newest.then(|| source.target_dir().display().to_string()),
+    };
+
+    let mut entries: Vec<Entry> = snapshots
+        .iter()
+        .map(|(snapshot, time)| Entry {
+            snapshot: snapshot.clone(),
+            taken_at: Some(iso(*time)),
+            on_source: true,
+            backed_up_at: None,
+            on_target: None,
+            status: Status::Pending,
+            time: *time,
+        })
+        .collect();
+    let newest_backup = history.last().map(|(snapshot, _)| snapshot.clone());
+    for (snapshot, at) in history {
+        // backup_timestamp is SQLite's CURRENT_TIMESTAMP, in UTC
+        let time = clock::epoch_from_rfc3339(&format!("{}Z", at.replace(' ', "T"))).unwrap_or(0);
+        let held = on_target(&snapshot, newest_backup.as_ref() == Some(&snapshot));
+        match entries.iter_mut().find(|entry| entry.snapshot == snapshot) {
+            Some(entry) => {
+                entry.backed_up_at = Some(at);
+                entry.on_target = held;
+                entry.status = Status::BackedUp;
+            }
+            // Gone from the source, so placed by when it was backed up
+            None => entries.push(Entry {
+                snapshot,
+                taken_at: None,
+                on_source: false,
+                backed_up_at: Some(at),
+                on_target: held,
+                status: Status::BackedUp,
+                time,
+            }),
+        }
+    }
+    entries.sort_by_key(|entry| entry.time);
+
+    if let Some(last) = entries.iter().rposition(|entry| entry.status == Status::BackedUp) {
+        for entry in &mut entries[..last] {
+            if entry.status == Status::Pending {
+                entry.status = Status::Gap;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+
+// The timeline command: the source's snapshots, which of them were backed
+// up and which the target still has, oldest first
+pub fn timeline(conn: &Connection, hostname: Option<&str>, source: Source, json: bool) -> Result<(), String> {
+    let entries = entries(conn, hostname, source)?;
+    if json {
+        let json = serde_json::to_string_pretty(&entries).map_err(|e| format!("Failed to serialize timeline: {}", e))?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    println!("Timeline of {} '{}' ({} layout), oldest first:", source.kind(), source.name(), format!("{:?}", source.layout()).to_lowercase());
+    if entries.is_empty() {
+        println!("  No snapshots or backups");
+        return Ok(());
+    }
+    let width = entries.iter().map(|entry| entry.snapshot.len()).max().unwrap_or(0);
+    println!("  src bak tgt  {:<width$}  {:<20}  {:<19}  target", "snapshot", "taken", "backed up");
+    for entry in &entries {
+        let note = match (&entry.on_target, entry.status) {
+            (_, Status::Gap) => "<- gap: never backed up".to_string(),
+            (_, Status::Pending) => "pending".to_string(),
+            (Some(held), _) => held.clone(),
+            (None, _) => "-".to_string(),
+        };
+        println!(
+            "   {}   {}   {}   {:<width$}  {:<20}  {:<19}  {}",
+            if entry.on_source { '*' } else { '-' },
+            if entry.status == Status::BackedUp { '*' } else { '.' },
+            if entry.on_target.is_some() { '*' } else { '.' },
+            entry.snapshot,
+            entry.taken_at.as_deref().unwrap_or("-"),
+            entry.backed_up_at.as_deref().unwrap_or("-"),
+            note
+        );
+    }
+    println!("  (src: still on the source, - gone; bak: backed up; tgt: still on the target)");
+
+    let gaps = entries.iter().filter(|entry| entry.status == Status::Gap).count();
+    let backups = entries.iter().filter(|entry| entry.status == Status::BackedUp).count();
+    println!("{} backup(s), {} snapshot(s) never backed up between them", backups, gaps);
+    Ok(())
+}
@@ -0,0 +1,310 @@
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::platform::{self, Os};
+use crate::images::ImageFormat;
+use crate::{Config, Layout, child_env};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Version { major, minor, patch }
+    }
+
+    // Find the version in the first line of `--version` output, e.g.
+    // "zfs-2.1.5-1ubuntu6", "restic 0.16.4 compiled with go1.21.6" or
+    // "rsync  version 3.2.7  protocol version 31". A word like "v1.5.7" wins,
+    // since zstd and lz4 put "64-bit" before theirs. Otherwise it's the first
+    // dotted number, where digits that are part of a word, as in "par2cmdline
+    // version 0.8.1", don't count, except after a "v".
+    pub fn parse_from_output(output: &str) -> Option<Version> {
+        let first_line = output.lines().next()?;
+        let tagged = first_line.split_whitespace().find_map(|word| {
+            let number = word.strip_prefix('v')?;
+            number.starts_with(|c: char| c.is_ascii_digit()).then_some(number)
+        });
+        if let Some(number) = tagged {
+            return Version::parse_number(number);
+        }
+
+        let start = first_line.char_indices().find_map(|(i, c)| {
+            let previous = first_line[..i].chars().next_back();
+            let in_word = previous.is_some_and(|p| p.is_ascii_alphanumeric() && p != 'v');
+            (c.is_ascii_digit() && !in_word).then_some(i)
+        })?;
+        Version::parse_number(&first_line[start..])
+    }
+
+    // The dotted number at the start of `text`, with missing parts as 0
+    fn parse_number(text: &str) -> Option<Version> {
+        let number: String = text
+            .chars()
+            .take_while(|c| c.is_ascii_digit() || *c == '.')
+            .collect();
+
+        let mut parts = number.split('.').filter(|p| !p.is_empty()).map(|p| p.parse::<u32>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+        let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+
+        Some(Version::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+
+// An external command the backup process shells out to
+pub struct ExternalTool {
+    pub name: &'static str,
+    version_args: &'static [&'static str],
+    min_version: Version,
+}
+
+pub const RSYNC: ExternalTool = ExternalTool { name: "rsync", version_args: &["--version"], min_version: Version::new(3, 1, 0) };
+pub const RESTIC: ExternalTool = ExternalTool { name: "restic", version_args: &["version"], min_version: Version::new(0, 12, 0) };
+pub const ZFS: ExternalTool = ExternalTool { name: "zfs", version_args: &["version"], min_version: Version::new(0, 8, 0) };
+pub const PAR2: ExternalTool = ExternalTool { name: "par2", version_args: &["-V"], min_version: Version::new(0, 6, 0) };
+pub const AGE: ExternalTool = ExternalTool { name: "age", version_args: &["--version"], min_version: Version::new(1, 0, 0) };
+// 4.4 brought zstd, the default compression
+pub const MKSQUASHFS: ExternalTool = ExternalTool { name: "mksquashfs", version_args: &["-version"], min_version: Version::new(4, 4, 0) };
+pub const MKFS_EROFS: ExternalTool = ExternalTool { name: "mkfs.erofs", version_args: &["-V"], min_version: Version::new(1, 0, 0) };
+// 1.3 made -T0 use every core
+pub const ZSTD: ExternalTool = ExternalTool { name: "zstd", version_args: &["-V"], min_version: Version::new(1, 3, 0) };
+pub const LZ4: ExternalTool = ExternalTool { name: "lz4", version_args: &["-V"], min_version: Version::new(1, 8, 0) };
+
+
+// Oldest supported version of each tool, whether or not a config needs it
+pub fn minimum_versions() -> Vec<(&'static str, Version)> {
+    [&RSYNC, &RESTIC, &ZFS, &PAR2, &AGE, &MKSQUASHFS, &MKFS_EROFS, &ZSTD, &LZ4].iter().map(|tool| (tool.name, tool.min_version)).collect()
+}
+
+
+// Versions of the tools detected at startup. A tool that the config doesn't
+// need, or whose version output couldn't be parsed, is left as None.
+#[derive(Debug, Default)]
+pub struct ToolVersions {
+    pub rsync: Option<Version>,
+    pub restic: Option<Version>,
+    pub zfs: Option<Version>,
+    pub age: Option<Version>,
+}
+
+impl ToolVersions {
+    // `zfs diff -h` (don't octal-escape non-printable paths) appeared in OpenZFS 2.0
+    pub fn zfs_diff_no_escape(&self) -> bool {
+        self.zfs.is_some_and(|v| v >= Version::new(2, 0, 0))
+    }
+
+    // `restic restore --overwrite` and `--delete` appeared in restic 0.17
+    pub fn restic_restore_overwrite(&self) -> bool {
+        self.restic.is_some_and(|v| v >= Version::new(0, 17, 0))
+    }
+}
+
+
+fn required_tools(config: &Config) -> Vec<&'static ExternalTool> {
+    let mut tools = Vec::new();
+
+    // Both datasets and restic repositories are copied to the target with rsync
+    if !config.dataset.is_empty() || !config.restic.is_empty() {
+        tools.push(&RSYNC);
+    }
+    if !config.dataset.is_empty() {
+        tools.push(&ZFS);
+    }
+    // ZVOLs with zvol_mode are stored in a restic repository on the target
+    if !config.restic.is_empty() || config.dataset.iter().any(|d| d.zvol_mode.is_some()) {
+        tools.push(&RESTIC);
+    }
+    if config.sources().any(|source| source.encryption().encrypt.is_some()) {
+        tools.push(&AGE);
+    }
+    // Images compress with their own tool
+    let compresses_with = |tool: &str| {
+        config.sources().any(|source| {
+            source.layout() != Layout::Image && source.compression().and_then(|c| c.tool()) == Some(tool)
+        })
+    };
+    if compresses_with("zstd") {
+        tools.push(&ZSTD);
+    }
+    if compresses_with("lz4") {
+        tools.push(&LZ4);
+    }
+    if config.dataset.iter().any(|d| d.par2_redundancy.is_some()) {
+        tools.push(&PAR2);
+    }
+    let uses_images = |format: ImageFormat| {
+        config.dataset.iter().any(|d| d.layout == Layout::Image && d.image_format.unwrap_or_default() == format)
+    };
+    if uses_images(ImageFormat::Squashfs) {
+        tools.push(&MKSQUASHFS);
+    }
+    if uses_images(ImageFormat::Erofs) {
+        tools.push(&MKFS_EROFS);
+    }
+
+    tools
+}
+
+
+// Whether the rsync found can copy ACLs (-A) and extended attributes (-X).
+// Builds without them are common outside Linux, e.g. FreeBSD's package
+// without xattrs, and refuse the whole command if asked for either.
+#[derive(Debug, Clone, Copy)]
+struct RsyncCapabilities {
+    acls: bool,
+    xattrs: bool,
+}
+
+static RSYNC_CAPABILITIES: Mutex<RsyncCapabilities> = Mutex::new(RsyncCapabilities { acls: true, xattrs: true });
+
+
+// `rsync --version` lists what it was built with under "Capabilities:",
+// saying "no ACLs" or "no xattrs" for what it lacks
+fn note_rsync_capabilities(version_output: &str) {
+    let lacks = |capability: &str| {
+        version_output
+            .split([',', '\n'])
+            .any(|item| item.trim() == format!("no {}", capability))
+    };
+    let capabilities = RsyncCapabilities { acls: !lacks("ACLs"), xattrs: !lacks("xattrs") };
+    if !capabilities.acls {
+        eprintln!("Warning: rsync was built without ACL support, so ACLs won't be copied");
+    }
+    if !capabilities.xattrs {
+        eprintln!("Warning: rsync was built without xattr support, so extended attributes won't be copied");
+    }
+    *RSYNC_CAPABILITIES.lock().unwrap_or_else(|e| e.into_inner()) = capabilities;
+}
+
+
+// rsync's archive options: -a, with ACLs and xattrs where rsync supports
+// them, and hard links, followed by `extra` single-letter options, e.g.
+// rsync_archive("v") for "-aAXHv"
+pub fn rsync_archive(extra: &str) -> String {
+    let capabilities = *RSYNC_CAPABILITIES.lock().unwrap_or_else(|e| e.into_inner());
+    format!(
+        "-a{}{}H{}",
+        if capabilities.acls { "A" } else { "" },
+        if capabilities.xattrs { "X" } else { "" },
+        extra
+    )
+}
+
+
+// The version of a tool, and its full version output
+fn detect_tool_version(tool: &ExternalTool) -> Result<(Option<Version>, String), String> {
+    let output = match child_env::command(tool.name)
+        .args(tool.version_args)
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        // illumos ZFS, like the ZFS FreeBSD had before OpenZFS, has no
+        // `zfs version`; it is taken to be older than OpenZFS 2.0
+        Ok(_) if tool.name == "zfs" && platform::OS != Os::Linux => {
+            println!("Found zfs without a version subcommand, taken to be pre-OpenZFS");
+            return Ok((None, String::new()));
+        }
+        Ok(_) => return Err(format!("{} command failed", tool.name)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!(
+                "{} is not installed in {}. Please install {} (version {} or later), or set path in the [environment] section, and try again.",
+                tool.name, child_env::path(), tool.name, tool.min_version
+            ));
+        }
+        Err(e) => return Err(format!("Failed to check for {}: {}", tool.name, e)),
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = match Version::parse_from_output(&stdout) {
+        Some(version) => version,
+        None => {
+            eprintln!(
+                "Warning: Could not determine {} version from '{}', assuming it is supported",
+                tool.name,
+                stdout.lines().next().unwrap_or("").trim()
+            );
+            return Ok((None, stdout.into_owned()));
+        }
+    };
+
+    if version < tool.min_version {
+        return Err(format!(
+            "{} {} is too old. Please upgrade to version {} or later and try again.",
+            tool.name, version, tool.min_version
+        ));
+    }
+
+    Ok((Some(version), stdout.into_owned()))
+}
+
+
+pub fn detect_tool_versions(config: &Config) -> Result<ToolVersions, String> {
+    let mut versions = ToolVersions::default();
+
+    for tool in required_tools(config) {
+        let (version, output) = detect_tool_version(tool)?;
+        if let Some(v) = version {
+            println!("Found {} {}", tool.name, v);
+        }
+
+        match tool.name {
+            "rsync" => {
+                versions.rsync = version;
+                note_rsync_capabilities(&output);
+            }
+            "restic" => versions.restic = version,
+            "zfs" => versions.zfs = version,
+            "age" => versions.age = version,
+            _ => {}
+        }
+    }
+
+    Ok(versions)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_after_v_wins_over_earlier_numbers() {
+        assert_eq!(
+            Version::parse_from_output("*** Zstandard CLI (64-bit) v1.5.7 ***\n"),
+            Some(Version::new(1, 5, 7))
+        );
+        assert_eq!(
+            Version::parse_from_output("*** lz4 command line interface 64-bits v1.9.4 ***\n"),
+            Some(Version::new(1, 9, 4))
+        );
+    }
+
+    #[test]
+    fn version_from_first_standalone_number() {
+        assert_eq!(Version::parse_from_output("zfs-2.1.5-1ubuntu6\nzfs-kmod-2.1.5\n"), Some(Version::new(2, 1, 5)));
+        assert_eq!(
+            Version::parse_from_output("restic 0.16.4 compiled with go1.21.6 on linux/amd64\n"),
+            Some(Version::new(0, 16, 4))
+        );
+        assert_eq!(
+            Version::parse_from_output("rsync  version 3.2.7  protocol version 31\n"),
+            Some(Version::new(3, 2, 7))
+        );
+        assert_eq!(Version::parse_from_output("par2cmdline version 0.8.1\n"), Some(Version::new(0, 8, 1)));
+        assert_eq!(Version::parse_from_output("no version here\n"), None);
+    }
+}
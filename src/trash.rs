@@ -0,0 +1,61 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::clock;
+
+
+// Directory at the root of a target that deleted files are moved into when
+// a source uses delete_mode = "trash"
+pub const TRASH_DIR: &str = ".file-backup-trash";
+
+
+// A fresh, timestamped directory to move this run's deletions into
+pub fn new_trash_dir(target_dir: &Path) -> PathBuf {
+    target_dir.join(TRASH_DIR).join(clock::compact_utc(SystemTime::now()))
+}
+
+
+pub fn move_to_trash(target_path: &Path, trash_path: &Path) -> std::io::Result<()> {
+    if let Some(parent) = trash_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(target_path, trash_path)
+}
+
+
+// Remove trash directories older than `retention` (all of them when None).
+// Returns how many were removed.
+pub fn purge(target_dir: &Path, retention: Option<Duration>) -> Result<usize, String> {
+    let trash_root = target_dir.join(TRASH_DIR);
+    if !trash_root.is_dir() {
+        return Ok(0);
+    }
+
+    let entries = fs::read_dir(&trash_root)
+        .map_err(|e| format!("Failed to read {}: {}", trash_root.display(), e))?;
+
+    let mut purged = 0;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read {}: {}", trash_root.display(), e))?;
+        let path = entry.path();
+
+        if let Some(retention) = retention {
+            let age = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+            // Keep anything whose age can't be determined
+            if age.is_none_or(|age| age < retention) {
+                continue;
+            }
+        }
+
+        println!("  Purging {}", path.display());
+        let result = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        result.map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+        purged += 1;
+    }
+
+    Ok(purged)
+}
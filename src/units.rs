@@ -0,0 +1,374 @@
+use serde::Deserialize;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::clock;
+
+
+// A length of time in the config, either seconds (warn_age = 3600) or a
+// number with a unit (warn_age = "36h"); s, m, h, d and w are understood
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "toml::Value")]
+pub struct ConfigDuration(pub u64);
+
+impl TryFrom<toml::Value> for ConfigDuration {
+    type Error = String;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        match &value {
+            toml::Value::Integer(secs) if *secs >= 0 => Ok(ConfigDuration(*secs as u64)),
+            toml::Value::String(text) => parse_duration(text).map(ConfigDuration),
+            _ => Err(format!("invalid duration {}, expected seconds or a string like \"36h\"", value)),
+        }
+    }
+}
+
+
+// A number and unit, or several run together as in "2h30m" or "1d 12h": s,
+// m, h, d and w are understood. A number on its own is seconds.
+pub fn parse_duration(text: &str) -> Result<u64, String> {
+    let invalid = |problem: String| {
+        format!("invalid duration '{}': {}; expected a number with a unit like \"36h\", \"90d\" or \"2h30m\"", text, problem)
+    };
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        return Err(invalid("it is empty".to_string()));
+    }
+    if let Ok(secs) = rest.parse::<u64>() {
+        return Ok(secs);
+    }
+
+    let mut total: u64 = 0;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let (number, after) = rest.split_at(split);
+        if number.is_empty() {
+            return Err(invalid(format!("'{}' should start with a number", rest)));
+        }
+        let after = after.trim_start();
+        let split = after.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(after.len());
+        let (unit, after) = after.split_at(split);
+
+        let multiplier = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86_400,
+            "w" => 7 * 86_400,
+            "" => return Err(invalid(format!("{} has no unit", number))),
+            _ => return Err(invalid(format!("'{}' isn't a unit", unit))),
+        };
+        total = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(multiplier))
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(|| invalid("it is too long".to_string()))?;
+        rest = after.trim_start();
+    }
+
+    Ok(total)
+}
+
+
+// A length of time given in days (trash_retention_days = 30), or as a string
+// with a unit like ConfigDuration (trash_retention_days = "36h"). Held in seconds.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "toml::Value")]
+pub struct Days(pub u64);
+
+impl TryFrom<toml::Value> for Days {
+    type Error = String;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        match &value {
+            toml::Value::Integer(days) if *days >= 0 => Ok(Days(*days as u64 * 86_400)),
+            toml::Value::String(text) => parse_duration(text).map(Days),
+            _ => Err(format!("invalid duration {}, expected days or a string like \"90d\"", value)),
+        }
+    }
+}
+
+
+// When the daemon starts a run: a while after the last one started
+// (schedule = "6h"), or at the times a cron line gives (schedule = "30 2 * * *").
+// "hourly@:15", "daily@02:30" and "weekly@sun 03:00" are shorthands for cron
+// lines, and "hourly", "daily" and "weekly" run on the hour, at midnight and
+// at midnight on Sunday. Times are UTC, like every time file-backup shows.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "toml::Value")]
+pub enum Schedule {
+    Every(u64),
+    Cron(Cron),
+}
+
+impl TryFrom<toml::Value> for Schedule {
+    type Error = String;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        match &value {
+            toml::Value::Integer(secs) if *secs > 0 => Ok(Schedule::Every(*secs as u64)),
+            toml::Value::String(text) => parse_schedule(text),
+            _ => Err(format!("invalid schedule {}, expected a string like \"daily@02:00\" or \"6h\"", value)),
+        }
+    }
+}
+
+
+// The five fields of a cron line, each as the set of values it allows
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    text: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // As cron has it, when both days and weekdays are given a date
+    // matching either will do
+    any_day: bool,
+    any_weekday: bool,
+}
+
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+
+pub fn parse_schedule(text: &str) -> Result<Schedule, String> {
+    let invalid = |problem: String| {
+        format!(
+            "invalid schedule '{}': {}; expected an interval like \"6h\", a time like \"daily@02:00\" or a cron line like \"0 2 * * *\"",
+            text, problem
+        )
+    };
+    let trimmed = text.trim();
+    let (period, at) = match trimmed.split_once('@') {
+        Some((period, at)) => (period, Some(at.trim())),
+        None => (trimmed, None),
+    };
+    // "HH:MM" as the minute and hour fields of a cron line
+    let time_of_day = |time: &str| -> Result<String, String> {
+        let (hour, minute) = time.split_once(':').ok_or_else(|| invalid(format!("'{}' isn't a time like 02:00", time)))?;
+        match (hour.parse::<u32>(), minute.parse::<u32>()) {
+            (Ok(hour), Ok(minute)) if hour < 24 && minute < 60 => Ok(format!("{} {}", minute, hour)),
+            _ => Err(invalid(format!("'{}' isn't a time like 02:00", time))),
+        }
+    };
+
+    let line = match (period, at) {
+        ("hourly", None) => "0 * * * *".to_string(),
+        ("hourly", Some(at)) => {
+            let minute = at.strip_prefix(':').and_then(|minute| minute.parse::<u32>().ok()).filter(|&minute| minute < 60);
+            format!("{} * * * *", minute.ok_or_else(|| invalid(format!("'{}' isn't a minute past the hour like :15", at)))?)
+        }
+        ("daily", None) => "0 0 * * *".to_string(),
+        ("daily", Some(at)) => format!("{} * * *", time_of_day(at)?),
+        ("weekly", None) => "0 0 * * sun".to_string(),
+        ("weekly", Some(at)) => {
+            let (day, time) = at.split_once(' ').unwrap_or((at, "00:00"));
+            format!("{} * * {}", time_of_day(time.trim())?, day)
+        }
+        (_, Some(_)) => return Err(invalid(format!("'{}' should be hourly, daily or weekly", period))),
+        _ if trimmed.split_whitespace().count() == 5 => trimmed.to_string(),
+        _ => {
+            return match parse_duration(trimmed) {
+                Ok(0) => Err(invalid("the interval is zero".to_string())),
+                Ok(secs) => Ok(Schedule::Every(secs)),
+                Err(_) => Err(invalid("it is neither an interval, a time nor a cron line of five fields".to_string())),
+            };
+        }
+    };
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let field = |index: usize, name: &str, min: u32, max: u32, names: &[&str]| {
+        parse_cron_field(fields[index], min, max, names).map_err(|problem| invalid(format!("{} field: {}", name, problem)))
+    };
+    let mut weekdays = field(4, "weekday", 0, 7, &WEEKDAYS)?;
+    // Sunday is both 0 and 7
+    if weekdays & (1 << 7) != 0 {
+        weekdays |= 1;
+    }
+    let cron = Cron {
+        text: trimmed.to_string(),
+        minutes: field(0, "minute", 0, 59, &[])?,
+        hours: field(1, "hour", 0, 23, &[])?,
+        days: field(2, "day", 1, 31, &[])?,
+        months: field(3, "month", 1, 12, &MONTHS)?,
+        weekdays,
+        any_day: fields[2] == "*",
+        any_weekday: fields[4] == "*",
+    };
+    // Checked over the first years of 1970, which take in a leap day
+    if cron.next_after(UNIX_EPOCH).is_none() {
+        return Err(invalid("it never comes round".to_string()));
+    }
+    Ok(Schedule::Cron(cron))
+}
+
+
+// A cron field: a comma-separated list of *, values and ranges, each
+// optionally stepped, e.g. "*/15", "1-5" or "mon,wed,fri"
+fn parse_cron_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |text: &str| -> Result<u32, String> {
+        let lowercase = text.to_ascii_lowercase();
+        let number = match names.iter().position(|name| *name == lowercase) {
+            // Month names count from 1, weekday names from 0
+            Some(index) => index as u32 + min.min(1),
+            None => text.parse().map_err(|_| format!("'{}' isn't a number", text))?,
+        };
+        if !(min..=max).contains(&number) {
+            return Err(format!("{} is outside {} to {}", number, min, max));
+        }
+        Ok(number)
+    };
+
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&step| step > 0).ok_or_else(|| format!("'{}' isn't a step", step))?),
+            None => (part, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            return Err(format!("the range {} runs backwards", range));
+        }
+        for number in (first..=last).step_by(step as usize) {
+            set |= 1 << number;
+        }
+    }
+    Ok(set)
+}
+
+
+impl Cron {
+    fn matches_day(&self, days_since_epoch: i64) -> bool {
+        let (_, month, day) = clock::civil_from_days(days_since_epoch);
+        let weekday = (days_since_epoch + 4).rem_euclid(7);
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        let either = match (self.any_day, self.any_weekday) {
+            (false, false) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        };
+        self.months & (1 << month) != 0 && either
+    }
+
+    // The first minute after `after` the line matches, looked for over the
+    // next five years
+    fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let start = after.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) / 60 * 60 + 60;
+        let end = start + 5 * 366 * 86_400;
+        let mut time = start;
+        while time < end {
+            let (days, secs_of_day) = ((time / 86_400) as i64, time % 86_400);
+            if !self.matches_day(days) {
+                time = (time / 86_400 + 1) * 86_400;
+            } else if self.hours & (1 << (secs_of_day / 3600)) == 0 {
+                time = (time / 3600 + 1) * 3600;
+            } else if self.minutes & (1 << (secs_of_day / 60 % 60)) == 0 {
+                time += 60;
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(time));
+            }
+        }
+        None
+    }
+}
+
+
+impl Schedule {
+    // When the run after one starting at `started` is due
+    pub fn next_after(&self, started: SystemTime) -> SystemTime {
+        match self {
+            Schedule::Every(secs) => started + Duration::from_secs(*secs),
+            // A line that never comes round is refused when it is parsed
+            Schedule::Cron(cron) => cron.next_after(started).unwrap_or(started + Duration::from_secs(86_400)),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Schedule::Every(secs) => write!(f, "every {}s", secs),
+            Schedule::Cron(cron) => write!(f, "at {} (UTC)", cron.text),
+        }
+    }
+}
+
+
+// A size in the config, either bytes (min_expected_size = 1048576) or a
+// number with a binary unit (min_expected_size = "10G")
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "toml::Value")]
+pub struct ByteSize(pub u64);
+
+impl TryFrom<toml::Value> for ByteSize {
+    type Error = String;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        match &value {
+            toml::Value::Integer(bytes) if *bytes >= 0 => Ok(ByteSize(*bytes as u64)),
+            toml::Value::String(text) => parse_size(text).map(ByteSize),
+            _ => Err(format!("invalid size {}, expected bytes or a string like \"10G\"", value)),
+        }
+    }
+}
+
+
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size '{}', expected a number with a unit like \"500M\" or \"10G\"", text);
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+
+    // K, KB and KiB all mean 1024 bytes
+    let unit = unit.trim().trim_end_matches(['B', 'b']).trim_end_matches('i');
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        "P" => 1 << 50,
+        _ => return Err(invalid()),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+
+// A share of something in the config, either a number of percent
+// (verify_sample = 1) or a string with a percent sign (verify_sample = "0.5%")
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "toml::Value")]
+pub struct Percentage(pub f64);
+
+impl TryFrom<toml::Value> for Percentage {
+    type Error = String;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        let percent = match &value {
+            toml::Value::Integer(percent) => *percent as f64,
+            toml::Value::Float(percent) => *percent,
+            toml::Value::String(text) => text
+                .trim()
+                .trim_end_matches('%')
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid percentage '{}', expected something like \"1%\"", text))?,
+            _ => return Err(format!("invalid percentage {}, expected a number or a string like \"1%\"", value)),
+        };
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(format!("percentage {} is outside 0 to 100", percent));
+        }
+        Ok(Percentage(percent))
+    }
+}
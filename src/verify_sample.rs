@@ -0,0 +1,80 @@
+use rusqlite::{Connection, params};
+use std::fs;
+use std::path::Path;
+
+use crate::Source;
+use crate::sha256;
+use crate::units::Percentage;
+
+
+// With verify_sample set, a random share of the files a backup copied is read
+// back after it and hashed on both the snapshot and the target. Cheap USB
+// disks corrupt data quietly; this catches it in the run that wrote it, and
+// the hashes that matched are kept in the file state.
+pub fn verify(conn: &Connection, source: Source, snapshot_name: &str, root: &Path, target_root: &Path) -> Result<(), String> {
+    let Some(Percentage(percent)) = source.verify_sample() else {
+        return Ok(());
+    };
+
+    let changed: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM file_state WHERE backup_type = ?1 AND source_name = ?2 AND last_snapshot = ?3",
+        params![source.backup_type(), source.name(), snapshot_name],
+        |row| row.get(0),
+    ).map_err(|e| format!("Failed to read file state: {}", e))?;
+    let sample_size = (changed as f64 * percent / 100.0).ceil() as i64;
+    if sample_size == 0 {
+        return Ok(());
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT path FROM file_state WHERE backup_type = ?1 AND source_name = ?2 AND last_snapshot = ?3
+         ORDER BY random() LIMIT ?4",
+    ).map_err(|e| format!("Failed to read file state: {}", e))?;
+    let paths = stmt
+        .query_map(params![source.backup_type(), source.name(), snapshot_name, sample_size], |row| row.get::<_, String>(0))
+        .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read file state: {}", e))?;
+
+    println!("Verifying a sample of {} of {} copied file(s) by SHA-256...", paths.len(), changed);
+
+    let mut verified = 0;
+    let mut mismatched = Vec::new();
+    for path in &paths {
+        let source_path = root.join(path);
+        // Symlinks are compared by rsync itself, and have nothing to hash
+        if !fs::symlink_metadata(&source_path).is_ok_and(|metadata| metadata.is_file()) {
+            continue;
+        }
+
+        let (source_hash, target_hash) = match (sha256::hash_file(&source_path), sha256::hash_file(&target_root.join(path))) {
+            (Ok(source_hash), Ok(target_hash)) => (source_hash, target_hash),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("Warning: Couldn't hash {}: {}", path, e);
+                continue;
+            }
+        };
+        if source_hash != target_hash {
+            mismatched.push(path.as_str());
+            continue;
+        }
+
+        verified += 1;
+        if let Err(e) = conn.execute(
+            "UPDATE file_state SET hash = ?4 WHERE backup_type = ?1 AND source_name = ?2 AND path = ?3",
+            params![source.backup_type(), source.name(), path, source_hash],
+        ) {
+            eprintln!("Warning: Failed to record hash of {}: {}", path, e);
+        }
+    }
+
+    if !mismatched.is_empty() {
+        return Err(format!(
+            "{} of {} sampled file(s) differ between the snapshot and the target, which may be failing: {}",
+            mismatched.len(),
+            paths.len(),
+            mismatched.join(", ")
+        ));
+    }
+    println!("Verified {} sampled file(s)", verified);
+    Ok(())
+}
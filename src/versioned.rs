@@ -0,0 +1,470 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::executed::{Log, Record};
+use crate::changed_files::ChangedFiles;
+use crate::{clock, events, excludes, privileges, report, tools, CopyOptions};
+use crate::special_files;
+
+
+// In the versioned layout every backup is a complete tree in its own dated
+// directory under the target, with unchanged files hard-linked to the
+// previous version. Nothing already on the target is ever modified or
+// removed; files that disappeared from the source are only recorded here.
+pub const DELETIONS_MANIFEST: &str = ".file-backup-deletions.log";
+
+// Which snapshot each version directory holds, and whether it was completed
+pub const VERSIONS_MANIFEST: &str = ".file-backup-manifest.json";
+
+// A version is written under this suffix and renamed once complete and
+// checked, so a failed run leaves a directory that list_versions ignores and
+// gc removes, and the newest version is never a half-written one
+const PARTIAL_SUFFIX: &str = ".partial";
+
+// The most --link-dest directories rsync takes
+const MAX_LINK_DESTS: usize = 20;
+
+
+fn is_version_name(name: &str) -> bool {
+    // Names come from clock::compact_utc, e.g. "20240501-023000"
+    name.len() == 15
+        && name.char_indices().all(|(i, c)| if i == 8 { c == '-' } else { c.is_ascii_digit() })
+}
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionStatus {
+    // Being written, or left behind by a run that failed
+    Partial,
+    Complete,
+}
+
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VersionEntry {
+    pub snapshot: String,
+    // The ZFS snapshot's guid, which survives it being renamed or sent
+    // elsewhere; restic snapshot IDs are unique already
+    pub guid: Option<String>,
+    pub status: VersionStatus,
+    // Versions of other sources in the link pool that this one may share
+    // files with, as full paths
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub linked_to: Vec<String>,
+}
+
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Manifest {
+    // By version directory name
+    #[serde(default)]
+    pub versions: BTreeMap<String, VersionEntry>,
+}
+
+
+// The target's manifest; targets versioned before there was one have none
+pub fn read_manifest(target_dir: &Path) -> Result<Manifest, String> {
+    let path = target_dir.join(VERSIONS_MANIFEST);
+    let json = match fs::read(&path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Manifest::default()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    serde_json::from_slice(&json).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+
+fn update_manifest(target_dir: &Path, update: impl FnOnce(&mut Manifest)) -> Result<(), String> {
+    let mut manifest = read_manifest(target_dir)?;
+    update(&mut manifest);
+
+    let json = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| format!("Failed to serialize manifest: {}", e))?;
+    let path = target_dir.join(VERSIONS_MANIFEST);
+    let temp_path = target_dir.join(format!("{}.tmp", VERSIONS_MANIFEST));
+    fs::write(&temp_path, json)
+        .and_then(|()| fs::rename(&temp_path, &path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+
+// The complete version holding `snapshot`, given as its full name, the part
+// after the @, or a restic snapshot ID
+pub fn version_of_snapshot(target_dir: &Path, snapshot: &str) -> Result<Option<String>, String> {
+    let manifest = read_manifest(target_dir)?;
+    Ok(manifest
+        .versions
+        .into_iter()
+        .filter(|(_, entry)| entry.status == VersionStatus::Complete)
+        .rfind(|(_, entry)| {
+            entry.snapshot == snapshot
+                || entry.snapshot.rsplit_once('@').is_some_and(|(_, name)| name == snapshot)
+                || (snapshot.len() >= 8 && !snapshot.contains('@') && entry.snapshot.starts_with(snapshot))
+        })
+        .map(|(version, _)| version))
+}
+
+
+// The dataset was renamed, and its snapshots with it: keep what the manifest
+// says about them in step, so restore --as-of finds them by their new names
+pub fn rename_dataset(target_dir: &Path, old: &str, new: &str) -> Result<(), String> {
+    if !target_dir.join(VERSIONS_MANIFEST).exists() {
+        return Ok(());
+    }
+    let old_prefix = format!("{}@", old);
+    update_manifest(target_dir, |manifest| {
+        for entry in manifest.versions.values_mut() {
+            if let Some(name) = entry.snapshot.strip_prefix(&old_prefix) {
+                entry.snapshot = format!("{}@{}", new, name);
+            }
+        }
+    })
+}
+
+
+// Existing version directories, oldest first
+pub fn list_versions(target_dir: &Path) -> Result<Vec<String>, String> {
+    let entries = fs::read_dir(target_dir)
+        .map_err(|e| format!("Failed to read {}: {}", target_dir.display(), e))?;
+
+    let mut versions: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_version_name(name))
+        .collect();
+    versions.sort();
+
+    Ok(versions)
+}
+
+
+// A version copied into its partial directory, not yet part of the target
+pub struct StagedVersion {
+    target_dir: PathBuf,
+    version: String,
+    partial_dir: PathBuf,
+    previous: Option<String>,
+    // Where the rsync that commit runs is noted
+    commands: Log,
+}
+
+impl StagedVersion {
+    // Where the version is until it is committed, for checking it
+    pub fn dir(&self) -> &Path {
+        &self.partial_dir
+    }
+
+    // Rename the version into place and note what it no longer has. Returns
+    // the new version's name.
+    pub fn commit(self) -> Result<String, String> {
+        let version_dir = self.target_dir.join(&self.version);
+        fs::rename(&self.partial_dir, &version_dir)
+            .map_err(|e| format!("Failed to rename {}: {}", self.partial_dir.display(), e))?;
+        update_manifest(&self.target_dir, |manifest| {
+            if let Some(entry) = manifest.versions.get_mut(&self.version) {
+                entry.status = VersionStatus::Complete;
+            }
+        })?;
+
+        if let Some(previous) = &self.previous {
+            let (_, deleted) = crate::get_diff_via_rsync(&version_dir, &self.target_dir.join(previous), false, None, None, &self.commands)?;
+            record_deletions(&self.target_dir, &self.version, &deleted)?;
+        }
+
+        println!("Version {} created successfully", self.version);
+        Ok(self.version)
+    }
+}
+
+
+// Sources sharing a link_pool, e.g. a dataset and a clone of it, each
+// hard-link unchanged files to the newest versions of the others as well as
+// their own, so a file they have in common is stored once on the disk.
+// `link_pool` is the target directories of the others. Of those this is the
+// newest complete version of each on the same filesystem as the target;
+// hard links can't cross to another.
+fn pool_versions(target_dir: &Path, link_pool: &[PathBuf]) -> Vec<PathBuf> {
+    let Ok(device) = fs::metadata(target_dir).map(|metadata| metadata.dev()) else {
+        return Vec::new();
+    };
+    link_pool
+        .iter()
+        .filter(|dir| match fs::metadata(dir) {
+            Ok(metadata) if metadata.dev() == device => true,
+            Ok(_) => {
+                println!("Not linking to {}, which is on another filesystem", dir.display());
+                false
+            }
+            // Not backed up yet, or its device isn't mounted
+            Err(_) => false,
+        })
+        .filter_map(|dir| Some(dir.join(list_versions(dir).ok()?.pop()?)))
+        .collect()
+}
+
+
+// How much of a version rsync copied rather than hard-linked, from the
+// "Total file size" and "Total transferred file size" lines of --stats, in
+// the C locale child_env runs rsync in
+pub fn transfer_totals(stats: &str) -> Option<(u64, u64)> {
+    let bytes = |label: &str| -> Option<u64> {
+        let line = stats.lines().find_map(|line| line.strip_prefix(label))?;
+        line.split_whitespace().next()?.replace(',', "").parse().ok()
+    };
+    Some((bytes("Total file size: ")?, bytes("Total transferred file size: ")?))
+}
+
+
+// Copy `source`, the tree of `snapshot`, into the partial directory of a new
+// version, hard-linking files that are unchanged since the previous version
+// or the same as in the newest version of another source in the link pool
+pub fn stage(
+    source: &Path,
+    target_dir: &Path,
+    snapshot: &str,
+    guid: Option<String>,
+    copy: CopyOptions,
+    link_pool: &[PathBuf],
+    changed_files: &mut ChangedFiles,
+) -> Result<StagedVersion, String> {
+    let CopyOptions { special_files, symlinks, sparse, filter, run_as } = copy;
+    let previous = list_versions(target_dir)?.pop();
+    let mut linked_to = pool_versions(target_dir, link_pool);
+    let room = MAX_LINK_DESTS - usize::from(previous.is_some());
+    if linked_to.len() > room {
+        eprintln!(
+            "Warning: Only linking to {} of the {} other versions in the link pool, the most rsync takes",
+            room,
+            linked_to.len()
+        );
+        linked_to.truncate(room);
+    }
+    let version = clock::compact_utc(SystemTime::now());
+    let version_dir = target_dir.join(&version);
+    let partial_dir = target_dir.join(format!("{}{}", version, PARTIAL_SUFFIX));
+
+    if version_dir.exists() {
+        return Err(format!("Version directory {} already exists", version_dir.display()));
+    }
+
+    println!("Creating version {} in {}...", version, target_dir.display());
+    update_manifest(target_dir, |manifest| {
+        let entry = VersionEntry {
+            snapshot: snapshot.to_string(),
+            guid,
+            status: VersionStatus::Partial,
+            linked_to: linked_to.iter().map(|dir| dir.to_string_lossy().into_owned()).collect(),
+        };
+        manifest.versions.insert(version.clone(), entry);
+    })?;
+
+    let mut command = privileges::command("rsync", run_as);
+    command.arg(tools::rsync_archive("v"));
+    command.arg("--stats");
+    command.args(special_files.rsync_args());
+    command.args(symlinks.rsync_args());
+    command.args(changed_files.rsync_args());
+    command.args(excludes::rsync_args(filter));
+    if sparse {
+        command.arg("--sparse");
+    }
+    if let Some(previous) = &previous {
+        println!("Hard-linking unchanged files to version {}", previous);
+        // Relative link-dest paths are resolved against the destination directory
+        command.arg(format!("--link-dest=../{}", previous));
+    }
+    for dir in &linked_to {
+        println!("Hard-linking files in common with {}", dir.display());
+        let mut arg = OsString::from("--link-dest=");
+        arg.push(dir);
+        command.arg(arg);
+    }
+
+    let output = command
+        .arg(crate::rsync_contents_arg(source))
+        .arg(crate::rsync_contents_arg(&partial_dir))
+        .recorded_output(&changed_files.commands)
+        .map_err(|e| format!("Failed to execute rsync: {}", e))?;
+
+    crate::rsync_exit::check(&output, &mut changed_files.exits)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    println!("{}", special_files::filter_output(special_files, &stdout));
+    changed_files.collect(&output.stdout);
+    if !linked_to.is_empty()
+        && let Some((total, transferred)) = transfer_totals(&stdout)
+    {
+        println!(
+            "Copied {} of {}; the rest is hard-linked to the previous version or the link pool",
+            report::format_bytes(transferred),
+            report::format_bytes(total)
+        );
+    }
+    events::emit(events::Event::TransferProgress {
+        step: "rsync of version",
+        files: None,
+        bytes: transfer_totals(&stdout).map(|(_, transferred)| transferred),
+    });
+
+    Ok(StagedVersion {
+        target_dir: target_dir.to_path_buf(),
+        version,
+        partial_dir,
+        previous,
+        commands: changed_files.commands.clone(),
+    })
+}
+
+
+fn record_deletions(target_dir: &Path, version: &str, deleted: &[PathBuf]) -> Result<(), String> {
+    if deleted.is_empty() {
+        return Ok(());
+    }
+
+    println!("Recording {} deletion(s) in {}", deleted.len(), DELETIONS_MANIFEST);
+
+    let manifest_path = target_dir.join(DELETIONS_MANIFEST);
+    let mut manifest = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&manifest_path)
+        .map_err(|e| format!("Failed to open {}: {}", manifest_path.display(), e))?;
+
+    // One "<version>\t<path>" line per path that is gone as of that version,
+    // with the path written as the raw bytes of the name
+    for path in deleted {
+        write!(manifest, "{}\t", version)
+            .and_then(|()| manifest.write_all(path.as_os_str().as_bytes()))
+            .and_then(|()| manifest.write_all(b"\n"))
+            .map_err(|e| format!("Failed to write {}: {}", manifest_path.display(), e))?;
+    }
+
+    Ok(())
+}
+
+
+// Remove all but the newest `keep` versions. Only lists what would go unless
+// `confirm` is set, since this is the one place versions are ever deleted.
+pub fn prune(target_dir: &Path, keep: usize, confirm: bool, link_pool: &[PathBuf]) -> Result<(), String> {
+    let versions = list_versions(target_dir)?;
+
+    if versions.len() <= keep {
+        println!("{} version(s) in {}, nothing to prune", versions.len(), target_dir.display());
+        return Ok(());
+    }
+
+    let (expired, kept) = versions.split_at(versions.len() - keep);
+    println!("Keeping {} version(s), pruning {}:", kept.len(), expired.len());
+
+    for version in expired {
+        let version_dir: PathBuf = target_dir.join(version);
+        if confirm {
+            println!("  Removing {}", version);
+            fs::remove_dir_all(&version_dir)
+                .map_err(|e| format!("Failed to remove {}: {}", version_dir.display(), e))?;
+            update_manifest(target_dir, |manifest| {
+                manifest.versions.remove(version);
+            })?;
+        } else {
+            println!("  Would remove {}", version);
+        }
+    }
+
+    if !confirm {
+        println!("Nothing removed; re-run with --confirm to prune these versions");
+    }
+    note_link_pool(link_pool);
+
+    Ok(())
+}
+
+
+// Removing a version in a link pool only frees the files no other source's
+// versions have linked to
+fn note_link_pool(link_pool: &[PathBuf]) {
+    if !link_pool.is_empty() {
+        println!("Files shared with other sources in the link pool take up room until their versions are removed too");
+    }
+}
+
+
+// Remove versions left half-written by failed runs, and all but the newest
+// `keep` complete ones. Each version is a full tree, so no other version
+// depends on a removed one, nor does another source's in the link pool: a
+// file stays as long as any version has a link to it.
+pub fn gc(target_dir: &Path, keep: usize, dry_run: bool, link_pool: &[PathBuf]) -> Result<(), String> {
+    let mut partial: Vec<String> = fs::read_dir(target_dir)
+        .map_err(|e| format!("Failed to read {}: {}", target_dir.display(), e))?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.strip_suffix(PARTIAL_SUFFIX).is_some_and(is_version_name))
+        .collect();
+    partial.sort();
+
+    let versions = list_versions(target_dir)?;
+    let expired = &versions[..versions.len().saturating_sub(keep)];
+    println!(
+        "{} version(s) in {}: keeping {}, removing {} and {} incomplete",
+        versions.len(),
+        target_dir.display(),
+        versions.len() - expired.len(),
+        expired.len(),
+        partial.len()
+    );
+
+    for name in partial.iter().chain(expired) {
+        let dir = target_dir.join(name);
+        if dry_run {
+            println!("  Would remove {}", name);
+        } else {
+            println!("  Removing {}", name);
+            fs::remove_dir_all(&dir)
+                .map_err(|e| format!("Failed to remove {}: {}", dir.display(), e))?;
+        }
+    }
+    note_link_pool(link_pool);
+    if dry_run {
+        return Ok(());
+    }
+
+    // Drop what the manifest says about versions that are gone, however they went
+    update_manifest(target_dir, |manifest| {
+        manifest.versions.retain(|version, entry| match entry.status {
+            VersionStatus::Complete => target_dir.join(version).is_dir(),
+            VersionStatus::Partial => target_dir.join(format!("{}{}", version, PARTIAL_SUFFIX)).is_dir(),
+        });
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn transfer_totals_from_c_locale_stats() {
+        let stats = "Number of files: 1,204 (reg: 1,100, dir: 104)\n\
+                     Total file size: 1,234,567 bytes\n\
+                     Total transferred file size: 56,789 bytes\n";
+        assert_eq!(transfer_totals(stats), Some((1_234_567, 56_789)));
+    }
+
+    // stage gets its rsync from privileges::command, and transfer_totals only
+    // reads the C locale's format
+    #[test]
+    fn rsync_runs_in_the_c_locale() {
+        let command = privileges::command("rsync", None);
+        let envs: Vec<_> = command.get_envs().collect();
+        assert!(envs.contains(&(OsStr::new("LC_ALL"), Some(OsStr::new("C")))));
+    }
+}
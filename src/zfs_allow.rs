@@ -0,0 +1,67 @@
+use std::ffi::CStr;
+
+use crate::Source;
+
+
+// Backups can run without root, with `zfs allow` delegating the zfs
+// permissions they need to the user running them: reading a snapshot through
+// .zfs/snapshot needs none, `zfs diff` needs diff, and the stream layout and
+// zvol_mode need send. auto_snapshot needs snapshot, and keep_auto_snapshots
+// destroy and mount.
+// When a zfs command is refused, its error says what to delegate.
+pub fn failure(operation: &str, permission: &str, dataset: &str, stderr: &str) -> String {
+    let message = format!("zfs {} failed: {}", operation, stderr.trim());
+    if unsafe { libc::geteuid() } == 0 || !stderr.to_ascii_lowercase().contains("permission denied") {
+        return message;
+    }
+
+    let dataset = dataset.split_once('@').map_or(dataset, |(dataset, _)| dataset);
+    format!(
+        "{}. Delegate the permission with: zfs allow -u {} {} {}",
+        message,
+        current_user().unwrap_or_else(|| "<user>".to_string()),
+        permission,
+        dataset
+    )
+}
+
+
+fn current_user() -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let code = unsafe { libc::getpwuid_r(libc::geteuid(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if code != 0 || result.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(passwd.pw_name) }.to_string_lossy().into_owned())
+}
+
+
+// With --unprivileged, a source using something only root can do fails
+// before anything is done rather than part way through
+pub fn check_unprivileged(source: Source) -> Result<(), String> {
+    let mut needs_root = Vec::new();
+    if let Source::Dataset(dataset_config) = source
+        && dataset_config.keys.loads_key()
+    {
+        needs_root.push("loading encryption keys (mounting the dataset)");
+    }
+    if source.device().luks_uuid.is_some() {
+        needs_root.push("luks_uuid (opening LUKS containers)");
+    }
+    if source.immutable().is_some() {
+        needs_root.push("immutable (setting the immutable attribute)");
+    }
+    if source.spin_down() {
+        needs_root.push("spin_down (hdparm)");
+    }
+    if source.run_as().is_some() {
+        needs_root.push("run_as (switching users)");
+    }
+
+    if needs_root.is_empty() {
+        return Ok(());
+    }
+    Err(format!("--unprivileged was given, but these settings need root: {}", needs_root.join(", ")))
+}
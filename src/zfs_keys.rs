@@ -0,0 +1,183 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::executed::{Log, Record};
+use crate::{child_env, zfs_allow};
+
+
+// Loading the keys of a natively encrypted dataset that is locked when the
+// backup starts. The keys are unloaded again once the dataset is backed up.
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct DatasetKeyConfig {
+    // Load the key from the dataset's own keylocation
+    #[serde(default)]
+    pub load_key: bool,
+    // Instead of the dataset's keylocation, e.g. "file:///root/keys/tank.key"
+    pub zfs_key_location: Option<String>,
+    // Shell command printing the key or passphrase
+    pub zfs_key_command: Option<String>,
+}
+
+impl DatasetKeyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.zfs_key_location.is_some() && self.zfs_key_command.is_some() {
+            return Err("zfs_key_location and zfs_key_command can't both be set".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn loads_key(&self) -> bool {
+        self.load_key || self.zfs_key_location.is_some() || self.zfs_key_command.is_some()
+    }
+}
+
+
+// A locked dataset that isn't configured to be unlocked is expected (e.g. a
+// dataset only unlocked while someone is using it), so it is kept apart from
+// real failures
+pub enum UnlockError {
+    Locked(String),
+    Failed(String),
+}
+
+
+// Keys loaded and the dataset mounted for a backup, undone on drop
+pub struct KeyGuard {
+    dataset: String,
+    encryption_root: String,
+    mounted: bool,
+    commands: Log,
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        if self.mounted {
+            match child_env::command("zfs").args(["unmount", &self.dataset]).recorded_output(&self.commands) {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => {
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    eprintln!("Warning: Failed to unmount dataset '{}': {}", self.dataset, stderr.trim());
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Warning: Failed to execute zfs unmount: {}", e);
+                    return;
+                }
+            }
+        }
+
+        println!("Unloading key for '{}'", self.encryption_root);
+        match child_env::command("zfs").args(["unload-key", &self.encryption_root]).recorded_output(&self.commands) {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                eprintln!("Warning: Failed to unload key for '{}': {}", self.encryption_root, stderr.trim());
+            }
+            Err(e) => eprintln!("Warning: Failed to execute zfs unload-key: {}", e),
+        }
+    }
+}
+
+
+fn zfs_get(dataset: &str, properties: &str, commands: &Log) -> Result<Vec<String>, String> {
+    let output = child_env::command("zfs")
+        .args(["get", "-H", "-o", "value", properties, dataset])
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute zfs get: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("zfs get failed: {}", stderr.trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(|line| line.to_string()).collect())
+}
+
+
+fn load_key(encryption_root: &str, config: &DatasetKeyConfig, commands: &Log) -> Result<(), String> {
+    println!("Loading key for '{}'", encryption_root);
+
+    let output = if let Some(command) = &config.zfs_key_command {
+        let key = Command::new("sh")
+            .args(["-c", command])
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| format!("Failed to execute zfs_key_command: {}", e))?;
+        if !key.status.success() {
+            return Err(format!("zfs_key_command exited with {}", key.status));
+        }
+
+        // With keylocation=prompt and no terminal, zfs reads the key from stdin
+        let (mut child, started) = child_env::command("zfs")
+            .args(["load-key", "-L", "prompt", encryption_root])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .recorded_spawn(commands)
+            .map_err(|e| format!("Failed to execute zfs load-key: {}", e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(&key.stdout)
+                .map_err(|e| format!("Failed to pass key to zfs load-key: {}", e))?;
+        }
+        let output = child.wait_with_output();
+        started.finished(output.as_ref().ok().map(|output| &output.status));
+        output
+    } else {
+        let mut command = child_env::command("zfs");
+        command.arg("load-key");
+        if let Some(location) = &config.zfs_key_location {
+            command.args(["-L", location]);
+        }
+        command.arg(encryption_root).recorded_output(commands)
+    }
+    .map_err(|e| format!("Failed to execute zfs load-key: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(zfs_allow::failure("load-key", "load-key", encryption_root, &stderr));
+    }
+    Ok(())
+}
+
+
+// If the dataset is encrypted and its key isn't loaded, load it (when
+// configured to) and mount the dataset so its snapshots can be read
+pub fn unlock(dataset: &str, config: &DatasetKeyConfig, commands: &Log) -> Result<Option<KeyGuard>, UnlockError> {
+    let values = zfs_get(dataset, "encryptionroot,keystatus,mounted", commands).map_err(UnlockError::Failed)?;
+    let [encryption_root, key_status, mounted] = values.as_slice() else {
+        return Err(UnlockError::Failed(format!("Unexpected zfs get output for '{}'", dataset)));
+    };
+
+    if key_status != "unavailable" {
+        return Ok(None);
+    }
+    if !config.loads_key() {
+        return Err(UnlockError::Locked(format!(
+            "Dataset '{}' is locked: the key for encryption root '{}' isn't loaded",
+            dataset, encryption_root
+        )));
+    }
+
+    load_key(encryption_root, config, commands).map_err(UnlockError::Failed)?;
+    let mut guard = KeyGuard {
+        dataset: dataset.to_string(),
+        encryption_root: encryption_root.clone(),
+        mounted: false,
+        commands: commands.clone(),
+    };
+
+    if mounted != "yes" {
+        println!("Mounting dataset '{}'", dataset);
+        let output = child_env::command("zfs")
+            .args(["mount", dataset])
+            .recorded_output(commands)
+            .map_err(|e| UnlockError::Failed(format!("Failed to execute zfs mount: {}", e)))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(UnlockError::Failed(zfs_allow::failure("mount", "mount", dataset, &stderr)));
+        }
+        guard.mounted = true;
+    }
+
+    Ok(Some(guard))
+}
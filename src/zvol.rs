@@ -0,0 +1,219 @@
+use rusqlite::{Connection, params};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+
+use crate::executed::{Log, Record, Started};
+use crate::{DatasetConfig, RunOptions, Source, child_env, estimate, events, privileges, zfs_allow};
+
+
+// A ZVOL can't be copied file by file, so it is streamed into a restic
+// repository at the target directory instead, where the blocks of a VM disk
+// that didn't change between snapshots are deduplicated
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ZvolMode {
+    // The `zfs send` stream of the snapshot
+    Send,
+    // The raw snapshot device under /dev/zvol, which needs snapdev=visible
+    Device,
+}
+
+
+pub fn create_table(conn: &Connection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS zvol_backups (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hostname TEXT,
+            source_name TEXT NOT NULL,
+            snapshot_name TEXT NOT NULL,
+            restic_snapshot_id TEXT NOT NULL,
+            backup_timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create table: {}", e))?;
+
+    Ok(())
+}
+
+
+fn is_volume(dataset: &str, commands: &Log) -> Result<bool, String> {
+    let output = child_env::command("zfs")
+        .args(["get", "-H", "-o", "value", "type", dataset])
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute zfs get: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("zfs get failed: {}", stderr.trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim() == "volume")
+}
+
+
+// Create the repository on the first backup to an empty target
+fn ensure_repository(target_dir: &Path, run_as: Option<&privileges::User>, commands: &Log) -> Result<(), String> {
+    if target_dir.join("config").is_file() {
+        return Ok(());
+    }
+
+    println!("Initializing restic repository in {}", target_dir.display());
+    let output = privileges::command("restic", run_as)
+        .arg("-r")
+        .arg(target_dir)
+        .arg("init")
+        .recorded_output(commands)
+        .map_err(|e| format!("Failed to execute restic init: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("restic init failed: {}", stderr.trim()));
+    }
+    Ok(())
+}
+
+
+// zfs send, when the stream comes from it rather than the volume's device
+type Sender = (Child, Started);
+
+
+fn open_source(mode: ZvolMode, snapshot: &str, commands: &Log) -> Result<(Option<Sender>, Stdio, String), String> {
+    let (volume, snapshot_name) = snapshot.split_once('@')
+        .ok_or_else(|| format!("Invalid snapshot name format: {}", snapshot))?;
+    // The name the stream is stored under in the restic snapshot
+    let file_name = volume.replace('/', "_");
+
+    match mode {
+        ZvolMode::Send => {
+            let (mut child, started) = child_env::command("zfs")
+                .args(["send", snapshot])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .recorded_spawn(commands)
+                .map_err(|e| format!("Failed to execute zfs send: {}", e))?;
+            let stdout = child.stdout.take().ok_or("Failed to read zfs send output")?;
+            Ok((Some((child, started)), Stdio::from(stdout), format!("{}.zfs", file_name)))
+        }
+        ZvolMode::Device => {
+            let device = PathBuf::from("/dev/zvol").join(snapshot);
+            let file = std::fs::File::open(&device).map_err(|e| {
+                format!(
+                    "Failed to open {} ({}); set snapdev=visible on the volume to back up snapshot '{}' as a device",
+                    device.display(),
+                    e,
+                    snapshot_name
+                )
+            })?;
+            Ok((None, Stdio::from(file), format!("{}.img", file_name)))
+        }
+    }
+}
+
+
+// Stream the snapshot into restic and return the ID of the restic snapshot
+// it was stored as
+fn stream_to_restic(mode: ZvolMode, snapshot: &str, target_dir: &Path, run_as: Option<&privileges::User>, commands: &Log) -> Result<String, String> {
+    let (sender, input, file_name) = open_source(mode, snapshot, commands)?;
+
+    println!("Streaming {} into restic repository {} as {}...", snapshot, target_dir.display(), file_name);
+    let (mut restic, restic_started) = privileges::command("restic", run_as)
+        .arg("-r")
+        .arg(target_dir)
+        .args(["backup", "--json", "--stdin", "--stdin-filename", &file_name, "--tag", "file-backup", "--tag", snapshot])
+        .stdin(input)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .recorded_spawn(commands)
+        .map_err(|e| format!("Failed to execute restic backup: {}", e))?;
+
+    // restic reports progress and a final summary as JSON lines
+    let mut snapshot_id = None;
+    if let Some(stdout) = restic.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let Ok(message) = serde_json::from_str::<serde_json::Value>(&line) else {
+                continue;
+            };
+            if message["message_type"] == "summary" {
+                snapshot_id = message["snapshot_id"].as_str().map(|id| id.to_string());
+                println!(
+                    "Added {} to the repository ({} processed)",
+                    crate::report::format_bytes(message["data_added"].as_u64().unwrap_or(0)),
+                    crate::report::format_bytes(message["total_bytes_processed"].as_u64().unwrap_or(0))
+                );
+            }
+        }
+    }
+    let restic_output = restic.wait_with_output()
+        .map_err(|e| format!("Failed to execute restic backup: {}", e))?;
+    restic_started.finished(Some(&restic_output.status));
+
+    // Only now that restic has read everything can the sender be waited on
+    if let Some((sender, sender_started)) = sender {
+        let send_output = sender.wait_with_output()
+            .map_err(|e| format!("Failed to execute zfs send: {}", e))?;
+        sender_started.finished(Some(&send_output.status));
+        if !send_output.status.success() {
+            let stderr = String::from_utf8_lossy(&send_output.stderr);
+            return Err(zfs_allow::failure("send", "send", snapshot, &stderr));
+        }
+    }
+    if !restic_output.status.success() {
+        let stderr = String::from_utf8_lossy(&restic_output.stderr);
+        return Err(format!("restic backup failed: {}", stderr.trim()));
+    }
+
+    snapshot_id.ok_or_else(|| "restic backup didn't report a snapshot ID".to_string())
+}
+
+
+pub fn backup(dataset_config: &DatasetConfig, mode: ZvolMode, conn: &Connection, options: &RunOptions, commands: &Log) -> Result<(), String> {
+    crate::check_target_directory(&dataset_config.target_dir)?;
+
+    if !is_volume(&dataset_config.name, commands)? {
+        return Err(format!("'{}' is not a ZVOL, so zvol_mode doesn't apply to it", dataset_config.name));
+    }
+
+    let last_backup = match crate::get_last_backed_up_snapshot(conn, options.host_filter(), Source::Dataset(dataset_config), commands) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("Warning: Failed to query database: {}", e);
+            None
+        }
+    };
+    let latest_snapshot = crate::get_latest_snapshot(&dataset_config.name, commands)?
+        .ok_or_else(|| format!("No snapshots found for dataset '{}'", dataset_config.name))?;
+    println!("Latest snapshot: {}", latest_snapshot);
+    println!("Target repository: {}", dataset_config.target_dir.display());
+    events::emit(events::Event::SnapshotSelected { snapshot: &latest_snapshot, base: last_backup.as_deref() });
+
+    if last_backup.as_deref() == Some(latest_snapshot.as_str()) {
+        println!("Already backed up - nothing to do");
+        println!();
+        return Ok(());
+    }
+    if last_backup.is_none() {
+        estimate::confirm_first_backup(Source::Dataset(dataset_config), &latest_snapshot, options, commands)?;
+    }
+
+    ensure_repository(&dataset_config.target_dir, dataset_config.user.as_ref(), commands)?;
+    let restic_snapshot_id = stream_to_restic(mode, &latest_snapshot, &dataset_config.target_dir, dataset_config.user.as_ref(), commands)?;
+    println!("Stored as restic snapshot {}", restic_snapshot_id);
+    events::emit(events::Event::TransferProgress { step: "zfs send to restic", files: None, bytes: None });
+
+    conn.execute(
+        "INSERT INTO zvol_backups (hostname, source_name, snapshot_name, restic_snapshot_id) VALUES (?1, ?2, ?3, ?4)",
+        params![options.hostname, dataset_config.name, latest_snapshot, restic_snapshot_id],
+    ).map_err(|e| format!("Failed to record restic snapshot in database: {}", e))?;
+    crate::record_successful_backup(
+        conn,
+        &options.hostname,
+        Source::Dataset(dataset_config),
+        &latest_snapshot,
+        commands,
+        None,
+    )?;
+
+    println!("Backup recorded successfully");
+    println!();
+    Ok(())
+}